@@ -1,2 +1,2 @@
 from .snr import SNR
-from .ttest import Ttest
+from .ttest import Ttest, MTtest
@@ -24,6 +24,31 @@ class Ttest:
         Number of samples in a single trace.
     d : int
         Maximal statistical order of the :math:`t`-test.
+    ks_bins : int, optional
+        If not `None`, histograms with `ks_bins` bins (at most 65536) over the
+        `np.int16` range are accumulated for each set, such that the
+        Kolmogorov-Smirnov statistic can be computed with `get_ks`.
+    robust_center : bool, optional
+        If `True`, an approximation of the running median of each set is
+        tracked (with the P² algorithm), such that it can be used as a robust
+        center for the statistics of order 2 and above with
+        `get_ttest_robust`.
+    samples : array_like of int, optional
+        If not `None`, strictly increasing indices in `[0, ns)` of the samples
+        for which the statistics are computed (e.g., a time window where the
+        leakage is expected), which saves memory and time. The traces given to
+        `fit_u` still have `ns` samples, and the results are `NaN` for the
+        other samples.
+    variance_tol : float, optional
+        A sample whose variance is at most `variance_tol` in both sets is
+        considered constant (e.g., a stuck ADC channel), and its statistics
+        are `NaN` instead of infinite or arbitrarily large (see
+        `get_zero_variance`). By default, only exactly constant samples.
+    variance_floor : float, optional
+        If not `None`, the variances :math:`v_i` are clamped to at least
+        `variance_floor` in the statistic, which avoids spuriously large
+        values when a set has few traces with a tiny variance (e.g., with a
+        quantized ADC).
 
     Examples
     --------
@@ -50,13 +75,44 @@ class Ttest:
 
     """
 
-    def __init__(self, ns, d):
+    def __init__(
+        self,
+        ns,
+        d,
+        ks_bins=None,
+        robust_center=False,
+        samples=None,
+        variance_tol=0.0,
+        variance_floor=None,
+    ):
         self._ns = ns
         self._d = d
+        self._ks_bins = ks_bins
+        self._robust_center = robust_center
+        self._n = 0
 
-        self._ttest = _scalib_ext.Ttest(ns, d)
+        if samples is not None:
+            samples = [int(s) for s in samples]
+            if any(s1 >= s2 for s1, s2 in zip(samples, samples[1:])):
+                raise ValueError("samples must be strictly increasing.")
+            if samples and not (0 <= samples[0] and samples[-1] < ns):
+                raise ValueError("samples must be in [0, ns).")
 
-    def fit_u(self, l, x):
+        self._ttest = _scalib_ext.Ttest(ns, d, samples)
+        if ks_bins is not None:
+            if not (0 < ks_bins <= 2**16):
+                raise ValueError("ks_bins must be in [1, 65536].")
+            self._ttest.enable_ks(ks_bins)
+        if robust_center:
+            self._ttest.enable_robust_center()
+        if not variance_tol >= 0.0:
+            raise ValueError("variance_tol must be non-negative.")
+        self._ttest.set_variance_tolerance(variance_tol)
+        if variance_floor is not None and not variance_floor >= 0.0:
+            raise ValueError("variance_floor must be non-negative.")
+        self._ttest.set_variance_floor(variance_floor)
+
+    def fit_u(self, l, x, weights=None, interruptible=False):
         r"""Updates the Ttest estimation with samples of `l` for the sets `x`.
         This method may be called multiple times.
 
@@ -68,6 +124,20 @@ class Ttest:
         x : array_like, np.uint16
             Set in which each trace belongs. Must be of shape `(n,)`, must be
             `np.uint16` and must contain only `0` and `1`.
+        weights : array_like, np.float64, optional
+            Non-negative weight of each trace, of shape `(n,)` (e.g., for
+            importance sampling). Each trace contributes to the moments with
+            its weight, and the number of samples :math:`n_i` is the sum of
+            the weights. Not supported with `ks_bins` and `robust_center`.
+        interruptible : bool, optional
+            Apply the traces by chunks, such that the update can be
+            interrupted (e.g., with Ctrl-C) between two chunks.
+
+        Notes
+        -----
+        If an interruptible update is interrupted, the state contains the
+        first traces of `l` that have been fully applied, and the remaining
+        ones can be given to a later call.
         """
         nl, nsl = l.shape
         nx = x.shape[0]
@@ -76,8 +146,366 @@ class Ttest:
         if not (nsl == self._ns):
             raise Exception(f"Expected second dim of l to have size {self._ns}.")
 
-        self._ttest.update(l, x)
+        if weights is not None:
+            if self._ks_bins is not None or self._robust_center:
+                raise ValueError(
+                    "weights are not supported with ks_bins and robust_center."
+                )
+            weights = np.asarray(weights, dtype=np.float64)
+            if weights.shape != (nl,):
+                raise ValueError(f"Expected weights with shape ({nl},)")
+        try:
+            if weights is None:
+                self._ttest.update(l, x, interruptible)
+            else:
+                self._ttest.update_weighted(l, x, weights, interruptible)
+        finally:
+            self._n = int(self._ttest.get_n_samples().sum())
+
+    def get_ttest(self, reference=None):
+        r"""Return the current Ttest estimation with an array of shape `(d,ns)`.
+
+        Parameters
+        ----------
+        reference : array_like, np.float64, optional
+            Reference mean of shape `(ns,)` around which the statistics of
+            order 2 and above are centered (e.g., given by a theoretical
+            model). If `None`, the empirical mean of each set is used.
+        """
+        if reference is not None:
+            reference = np.asarray(reference, dtype=np.float64)
+            if reference.shape != (self._ns,):
+                raise ValueError(f"Expected reference with shape ({self._ns},)")
+        return self._ttest.get_ttest(reference)
+
+    def get_ttest_at(self, sample):
+        r"""Return the current Ttest estimation of the sample `sample` only,
+        with an array of shape `(d,)`. This is cheaper than `get_ttest` (e.g.,
+        to monitor a point of interest while fitting).
+
+        Parameters
+        ----------
+        sample : int
+            Index of the sample, in `[0, ns)`.
+        """
+        if not (0 <= sample < self._ns):
+            raise ValueError(f"sample must be in [0, {self._ns}).")
+        return self._ttest.get_ttest_at(sample)
+
+    def concat_samples(self, other):
+        r"""Return a Ttest whose samples are the ones of `self` followed by the
+        ones of `other`, where both have been fitted with the same traces (e.g.,
+        by different workers on disjoint sample ranges).
+
+        Parameters
+        ----------
+        other : Ttest
+            Ttest with the same order `d`, fitted with the same number of traces
+            in each set, and with the same `ks_bins` and `robust_center`.
+        """
+        if (self._ks_bins, self._robust_center) != (
+            other._ks_bins,
+            other._robust_center,
+        ):
+            raise ValueError("ks_bins and robust_center must be the same.")
+        res = Ttest.__new__(Ttest)
+        res._ns = self._ns + other._ns
+        res._d = self._d
+        res._ks_bins = self._ks_bins
+        res._robust_center = self._robust_center
+        res._n = self._n
+        res._ttest = self._ttest.concat_samples(other._ttest)
+        return res
+
+    def get_leaky_samples(self, threshold=4.5):
+        r"""Return the samples where the absolute value of the Ttest (see
+        `get_ttest`) exceeds `threshold`.
+
+        Parameters
+        ----------
+        threshold : float
+            Threshold on :math:`|t|` (4.5 is the usual TVLA threshold).
+
+        Returns
+        -------
+        leaky : list of tuples
+            For each order, a tuple `(indices, t)` with the indices of the
+            samples above the threshold (in increasing order) and their Ttest
+            values.
+        """
+        return self._ttest.get_leaky_samples(threshold)
+
+    def get_ttest_ratio(self, threshold=4.5):
+        r"""Return the Ttest (see `get_ttest`) divided by `threshold`, with an
+        array of shape `(d,ns)`: the samples with an absolute value above 1.0
+        are the ones returned by `get_leaky_samples` (e.g., to plot the
+        progress of the detection).
+
+        Parameters
+        ----------
+        threshold : float
+            Threshold on :math:`|t|` (4.5 is the usual TVLA threshold).
+        """
+        return self._ttest.get_ttest_ratio(threshold)
+
+    def get_ci(self, alpha=0.05):
+        r"""Return the confidence interval with level `1-alpha` on the
+        difference between the means of the two sets :math:`u_0 - u_1`, with
+        an array of shape `(2,ns)` containing the lower and upper bounds.
+
+        The interval is based on the Welch standard error and on the
+        :math:`t`-distribution with Welch-Satterthwaite degrees of freedom. The
+        bounds are `NaN` if a set contains less than two traces.
+
+        Parameters
+        ----------
+        alpha : float
+            Significance level, in :math:`(0, 1)`.
+        """
+        if not (0 < alpha < 1):
+            raise ValueError("alpha must be in (0, 1).")
+        return self._ttest.get_ci(alpha)
+
+    def get_pvalue(self):
+        r"""Return the two-sided p-values of the current Ttest estimation with
+        an array of shape `(d,ns)`.
+
+        The p-values are based on the :math:`t`-distribution with
+        Welch-Satterthwaite degrees of freedom. They are not corrected for
+        multiple comparisons (see `get_pvalue_corrected`).
+        """
+        return self._ttest.get_pvalue()
+
+    def get_pvalue_corrected(self, method="bonferroni"):
+        r"""Return the p-values of `get_pvalue` corrected for the multiple
+        comparisons over all the `d*ns` tests, with an array of shape `(d,ns)`.
+        NaN p-values (e.g., for constant samples) are left NaN and are not
+        counted as tests.
+
+        Parameters
+        ----------
+        method : string
+            `"bonferroni"` multiplies the p-values by the number of tests
+            (controls the family-wise error rate), `"bh"` applies the
+            Benjamini-Hochberg procedure (controls the false discovery rate).
+        """
+        if method not in ("bonferroni", "bh"):
+            raise ValueError(f"Unknown p-value correction method '{method}'.")
+        return self._ttest.get_pvalue_corrected(method)
+
+    def get_ttest_robust(self):
+        r"""Return the current Ttest estimation with an array of shape `(d,ns)`,
+        where the statistics of order 2 and above are centered around the
+        (approximate) median of each set instead of its mean. This is less
+        sensitive to outliers in the traces. Requires `robust_center`."""
+        self._check_robust_center()
+        return self._ttest.get_ttest_robust()
+
+    def get_medians(self):
+        r"""Return the current approximation of the median of each set with an
+        array of shape `(2,ns)`. Requires `robust_center`."""
+        self._check_robust_center()
+        return self._ttest.get_medians()
+
+    def get_ks(self):
+        r"""Return the two-sample Kolmogorov-Smirnov statistic between the two
+        sets with an array of shape `(ns,)`. This is the maximal distance
+        between the empirical cumulative distribution functions of the sets,
+        up to the resolution of the histograms. Requires `ks_bins`."""
+        if self._ks_bins is None:
+            raise ValueError("Requires a Ttest with ks_bins.")
+        return self._ttest.get_ks()
+
+    def get_means(self):
+        r"""Return the current estimation of the mean of each set with an array
+        of shape `(2,ns)`."""
+        return self._ttest.get_means()
+
+    def get_variances(self):
+        r"""Return the current estimation of the variance of each set with an
+        array of shape `(2,ns)`."""
+        return self._ttest.get_variances()
+
+    def get_zero_variance(self):
+        r"""Return the samples that are constant (see `variance_tol`), for
+        which the statistics are `NaN`, with a boolean array of shape `(ns,)`.
+        """
+        return self._ttest.get_zero_variance()
+
+    def set_snapshot_interval(self, interval):
+        r"""Records a snapshot of the means and variances every `interval`
+        traces fed to `fit_u`.
+
+        Parameters
+        ----------
+        interval : int or None
+            Number of traces between two snapshots. `None` disables the
+            snapshots.
+        """
+        if interval is not None and interval <= 0:
+            raise ValueError("Snapshot interval must be positive.")
+        self._ttest.set_snapshot_interval(interval)
+
+    def take_snapshots(self):
+        r"""Return the snapshots recorded since the last call to this method.
+
+        Returns
+        -------
+        snapshots : list of tuples
+            Each snapshot is a tuple `(n_samples, means, variances)` where
+            `n_samples` is the number of traces in each set (shape `(2,)`),
+            and `means` and `variances` have shape `(2,ns)`.
+        """
+        return self._ttest.take_snapshots()
+
+    def set_ttest_checkpoints(self, counts):
+        r"""Records the Ttest (see `get_ttest`) when the total number of
+        traces fed to `fit_u` reaches each of the `counts` (e.g., at
+        logarithmically spaced counts to monitor the convergence).
+
+        Parameters
+        ----------
+        counts : array_like of int
+            Strictly increasing numbers of traces, larger than the number of
+            traces already fed to `fit_u`. Replaces the previous counts.
+        """
+        counts = [int(c) for c in counts]
+        if any(c1 >= c2 for c1, c2 in zip(counts, counts[1:])):
+            raise ValueError("Checkpoints must be strictly increasing.")
+        if counts and counts[0] <= self._n:
+            raise ValueError(
+                "Checkpoints must be larger than the current number of traces."
+            )
+        self._ttest.set_ttest_checkpoints(counts)
+
+    def take_ttest_checkpoints(self):
+        r"""Return the Ttest recorded at the checkpoints reached since the
+        last call to this method, with an array of shape `(n_checkpoints,d,ns)`.
+        """
+        return self._ttest.take_ttest_checkpoints()
+
+    def set_convergence_window(self, window):
+        r"""Evaluates the convergence of the moments every `window` traces fed
+        to `fit_u` (see `get_moment_convergence`).
+
+        Parameters
+        ----------
+        window : int or None
+            Number of traces between two evaluations. `None` disables the
+            evaluation.
+        """
+        if window is not None and window <= 0:
+            raise ValueError("Convergence window must be positive.")
+        self._ttest.set_convergence_window(window)
+
+    def get_moment_convergence(self):
+        r"""Return, for each order from 1 to `2*d`, the largest relative change
+        of the moments (over the samples and the sets) during the last window of
+        traces (see `set_convergence_window`), with an array of shape `(2*d,)`.
+        The moments are the mean, the variance, and the standardized moments of
+        order 3 and above. This can be used to stop the acquisition once the
+        high-order moments are stable. The changes are `NaN` until the end of
+        the second window.
+        """
+        return self._ttest.get_moment_convergence()
+
+    def get_skewness(self):
+        r"""Return the current estimation of the skewness of each set with an
+        array of shape `(2,ns)`. Requires `d >= 2`."""
+        self._check_order(2)
+        return self._ttest.get_skewness()
+
+    def get_kurtosis(self):
+        r"""Return the current estimation of the kurtosis (non-excess, that is
+        3.0 for a normal distribution) of each set with an array of shape
+        `(2,ns)`. Requires `d >= 2`."""
+        self._check_order(2)
+        return self._ttest.get_kurtosis()
+
+    def _check_robust_center(self):
+        if not self._robust_center:
+            raise ValueError("Requires a Ttest with robust_center.")
+
+    def _check_order(self, d):
+        if self._d < d:
+            raise ValueError(f"Requires a Ttest of order d >= {d}.")
+
+
+class MTtest:
+    r"""Computes the multivariate :math:`t`-test between two sets :math:`i` of
+    traces, on the centered product of `d` samples. This highlights the leakage
+    of implementations masked at order :math:`d`, where each share leaks in a
+    different sample.
+
+    For each tuple of samples :math:`(s_1, \dots, s_d)`, the combined variable
+
+    .. math::
+        z = \prod_{j=1}^d (l_{s_j} - \mu_{s_j})
+
+    is computed, where :math:`\mu_{s_j}` is the mean of the sample
+    :math:`s_j` in the set :math:`i`, and the statistic is the first-order
+    :math:`t`-test on :math:`z`.
+
+    The statistics are accumulated in a single pass (the means are not needed
+    beforehand). This requires `2*(3**d + d)` floating-point values (8 bytes
+    each) for each tuple.
+
+    Parameters
+    ----------
+    ns : int
+        Number of samples in a single trace.
+    tuples : array_like of int
+        Indices in `[0, ns)` of the samples combined by each test, with shape
+        `(n_tuples, d)`.
+
+    Examples
+    --------
+    >>> from scalib.metrics import MTtest
+    >>> import numpy as np
+    >>> traces = np.random.randint(0,256,(100,200),dtype=np.int16)
+    >>> X = np.random.randint(0,2,100,dtype=np.uint16)
+    >>> mttest = MTtest(200,tuples=[[0,10,20],[1,11,21]])
+    >>> mttest.fit_u(traces,X)
+    >>> t = mttest.get_ttest()
+    """
+
+    def __init__(self, ns, tuples):
+        tuples = np.asarray(tuples)
+        if tuples.ndim != 2 or tuples.shape[1] == 0:
+            raise ValueError("tuples must have shape (n_tuples, d) with d > 0.")
+        if np.any(tuples < 0) or np.any(tuples >= ns):
+            raise ValueError("tuples must be in [0, ns).")
+        self._ns = ns
+        self._tuples = tuples.astype(np.uint32)
+        self._mttest = _scalib_ext.MTtest(ns, self._tuples)
+
+    def fit_u(self, l, x):
+        r"""Updates the MTtest estimation with samples of `l` for the sets `x`.
+        This method may be called multiple times.
+
+        Parameters
+        ----------
+        l : array_like, np.int16
+            Array that contains the signal. The array must
+            be of dimension `(n, ns)` and its type must be `np.int16`.
+        x : array_like, np.uint16
+            Set in which each trace belongs. Must be of shape `(n,)`, must be
+            `np.uint16` and must contain only `0` and `1`.
+        """
+        nl, nsl = l.shape
+        nx = x.shape[0]
+        if not (nx == nl):
+            raise ValueError(f"Expected x with shape ({nl},)")
+        if not (nsl == self._ns):
+            raise Exception(f"Expected second dim of l to have size {self._ns}.")
+        self._mttest.update(l, x)
 
     def get_ttest(self):
-        r"""Return the current Ttest estimation with an array of shape `(d,ns)`."""
-        return self._ttest.get_ttest()
+        r"""Return the current MTtest estimation with an array of shape
+        `(n_tuples,)`."""
+        return self._mttest.get_ttest()
+
+    def get_means(self):
+        r"""Return the current estimation of the mean of the combined variable
+        of each set with an array of shape `(2,n_tuples)`."""
+        return self._mttest.get_means()
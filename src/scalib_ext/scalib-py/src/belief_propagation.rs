@@ -82,10 +82,14 @@ pub fn to_func(function: &PyDict) -> Func {
         panic!("func {} value is not recognized", func);
     }
 
-    Func {
-        neighboors: neighboors,
-        functype: f,
-    }
+    let enabled = function.get_item("enabled").map(|enabled| {
+        let enabled: PyReadonlyArray1<bool> = enabled.extract().unwrap();
+        enabled.as_array().to_owned()
+    });
+
+    let mut func = Func::new(neighboors, f);
+    func.enabled = enabled;
+    func
 }
 
 /// Run the belief propagation algorithm on the python representation of a factor graph.
@@ -128,8 +132,8 @@ pub fn run_bp(
             n,
             progress,
         )
-        .unwrap();
-    });
+    })
+    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
 
     variables_rust
         .iter()
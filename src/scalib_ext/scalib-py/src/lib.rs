@@ -31,6 +31,7 @@ fn str2method(s: &str) -> Result<ranklib::RankingMethod, &str> {
 fn _scalib_ext(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<snr::SNR>()?;
     m.add_class::<ttest::Ttest>()?;
+    m.add_class::<ttest::MTtest>()?;
     m.add_class::<lda::LDA>()?;
     m.add_class::<lda::LdaAcc>()?;
 
@@ -1,7 +1,34 @@
 //! Python wrapper for SCALib's Ttest
 
-use numpy::{PyArray2, PyReadonlyArray1, PyReadonlyArray2, ToPyArray};
+use numpy::{PyArray1, PyArray2, PyArray3, PyReadonlyArray1, PyReadonlyArray2, ToPyArray};
 use pyo3::prelude::*;
+use scalib::ttest::TtestError;
+use std::ops::Range;
+
+/// Number of traces between two checks for a signal during the interruptible updates.
+const INTERRUPT_CHUNK: usize = 1 << 12;
+
+/// Run the update `f` on the `n` traces (given as a range of indices) without the GIL.
+/// If `interruptible`, the traces are given to `f` in chunks of `INTERRUPT_CHUNK` and the Python
+/// signals (e.g., a Ctrl-C) are checked between the chunks. On a signal, the state contains the
+/// chunks applied before, and the error of the signal handler is returned.
+/// The inputs must be valid for all the chunks when `interruptible`, such that an invalid update
+/// is not partially applied.
+fn update_chunks<F>(py: Python, n: usize, interruptible: bool, mut f: F) -> PyResult<()>
+where
+    F: FnMut(Range<usize>) -> Result<(), TtestError> + Send,
+{
+    let to_py = |e: TtestError| pyo3::exceptions::PyValueError::new_err(e.to_string());
+    if !interruptible {
+        return py.allow_threads(|| f(0..n)).map_err(to_py);
+    }
+    for start in (0..n).step_by(INTERRUPT_CHUNK) {
+        let end = std::cmp::min(start + INTERRUPT_CHUNK, n);
+        py.allow_threads(|| f(start..end)).map_err(to_py)?;
+        py.check_signals()?;
+    }
+    Ok(())
+}
 
 #[pyclass]
 pub(crate) struct Ttest {
@@ -14,24 +41,343 @@ impl Ttest {
     /// Create a new Ttest state.
     /// ns: traces length
     /// d: order of the Ttest
-    fn new(ns: usize, d: usize) -> Self {
-        Self {
-            inner: scalib::ttest::Ttest::new(ns, d),
-        }
+    /// samples: indices of the samples to accumulate (all if None)
+    fn new(ns: usize, d: usize, samples: Option<Vec<usize>>) -> Self {
+        let inner = match samples {
+            Some(samples) => scalib::ttest::Ttest::new_window(ns, d, samples),
+            None => scalib::ttest::Ttest::new(ns, d),
+        };
+        Self { inner }
     }
     /// Update the Ttest state with n fresh traces
     /// traces: the leakage traces with shape (n,ns)
     /// y: realization of random variables with shape (n,)
-    fn update(&mut self, py: Python, traces: PyReadonlyArray2<i16>, y: PyReadonlyArray1<u16>) {
+    /// interruptible: check for signals such as Ctrl-C between chunks of traces, in which case
+    /// only a prefix of the traces may be applied (see `update_chunks`)
+    fn update(
+        &mut self,
+        py: Python,
+        traces: PyReadonlyArray2<i16>,
+        y: PyReadonlyArray1<u16>,
+        interruptible: bool,
+    ) -> PyResult<()> {
+        let traces = traces.as_array();
+        let y = y.as_array();
+        let inner = &mut self.inner;
+        let valid = traces.shape()[0] == y.len();
+        update_chunks(py, y.len(), interruptible && valid, |r| {
+            inner.update(
+                traces.slice(ndarray::s![r.clone(), ..]),
+                y.slice(ndarray::s![r]),
+            )
+        })
+    }
+
+    /// Update the Ttest state with n fresh traces, each trace contributing with its weight.
+    /// traces: the leakage traces with shape (n,ns)
+    /// y: realization of random variables with shape (n,)
+    /// weights: non-negative weights of the traces with shape (n,)
+    /// interruptible: see `update`
+    fn update_weighted(
+        &mut self,
+        py: Python,
+        traces: PyReadonlyArray2<i16>,
+        y: PyReadonlyArray1<u16>,
+        weights: PyReadonlyArray1<f64>,
+        interruptible: bool,
+    ) -> PyResult<()> {
         let traces = traces.as_array();
         let y = y.as_array();
-        py.allow_threads(|| self.inner.update(traces, y));
+        let weights = weights.as_array();
+        let inner = &mut self.inner;
+        let valid = traces.shape()[0] == y.len()
+            && weights.len() == y.len()
+            && weights.iter().all(|w| w.is_finite() && *w >= 0.0);
+        update_chunks(py, y.len(), interruptible && valid, |r| {
+            inner.update_weighted(
+                traces.slice(ndarray::s![r.clone(), ..]),
+                y.slice(ndarray::s![r.clone()]),
+                weights.slice(ndarray::s![r]),
+            )
+        })
     }
 
     /// Generate the actual Ttest metric based on the current state.
+    /// reference: center of the statistics of order 2 and above with shape (ns,), the empirical
+    /// means if None.
+    /// return array axes (d,ns)
+    fn get_ttest<'py>(
+        &mut self,
+        py: Python<'py>,
+        reference: Option<PyReadonlyArray1<f64>>,
+    ) -> PyResult<&'py PyArray2<f64>> {
+        let ttest = match reference {
+            Some(reference) => {
+                let reference = reference.as_array();
+                py.allow_threads(|| self.inner.get_ttest_centered(reference))
+            }
+            None => py.allow_threads(|| self.inner.get_ttest()),
+        };
+        Ok(&(ttest.to_pyarray(py)))
+    }
+
+    /// Ttest state with the samples of self followed by the ones of other.
+    fn concat_samples(&self, other: PyRef<Ttest>) -> PyResult<Ttest> {
+        let inner = self
+            .inner
+            .concat_samples(&other.inner)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+        Ok(Ttest { inner })
+    }
+
+    /// Ttest metric of a single sample.
+    /// return array axes (d,)
+    fn get_ttest_at<'py>(
+        &mut self,
+        py: Python<'py>,
+        sample: usize,
+    ) -> PyResult<&'py PyArray1<f64>> {
+        let ttest = self
+            .inner
+            .get_ttest_at(sample)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+        Ok(&(ttest.to_pyarray(py)))
+    }
+
+    /// Samples where the Ttest exceeds threshold in absolute value, for each order: their
+    /// indices and Ttest values.
+    fn get_leaky_samples<'py>(
+        &mut self,
+        py: Python<'py>,
+        threshold: f64,
+    ) -> PyResult<Vec<(&'py PyArray1<usize>, &'py PyArray1<f64>)>> {
+        let leaky = py.allow_threads(|| self.inner.get_leaky_samples(threshold));
+        Ok(leaky
+            .iter()
+            .map(|(indices, values)| (indices.to_pyarray(py), values.to_pyarray(py)))
+            .collect())
+    }
+
+    /// Ttest divided by threshold, exceeding 1.0 in absolute value for the leaky samples.
+    /// return array axes (d,ns)
+    fn get_ttest_ratio<'py>(
+        &mut self,
+        py: Python<'py>,
+        threshold: f64,
+    ) -> PyResult<&'py PyArray2<f64>> {
+        if !(threshold > 0.0) {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "threshold must be positive.",
+            ));
+        }
+        let ratio = py.allow_threads(|| self.inner.get_ttest_ratio(threshold));
+        Ok(&(ratio.to_pyarray(py)))
+    }
+
+    /// Confidence interval with level 1-alpha on the difference of the means of the classes.
+    /// return array axes (2,ns), the bounds being (lower, upper)
+    fn get_ci<'py>(&mut self, py: Python<'py>, alpha: f64) -> PyResult<&'py PyArray2<f64>> {
+        let ci = py.allow_threads(|| self.inner.get_ci(alpha));
+        Ok(&(ci.to_pyarray(py)))
+    }
+
+    /// Two-sided p-values of the Ttest statistics.
+    /// return array axes (d,ns)
+    fn get_pvalue<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        let pvalue = py.allow_threads(|| self.inner.get_pvalue());
+        Ok(&(pvalue.to_pyarray(py)))
+    }
+
+    /// P-values corrected for the multiple comparisons over all the samples and orders.
+    /// method: "bonferroni" or "bh" (Benjamini-Hochberg).
+    /// return array axes (d,ns)
+    fn get_pvalue_corrected<'py>(
+        &mut self,
+        py: Python<'py>,
+        method: &str,
+    ) -> PyResult<&'py PyArray2<f64>> {
+        let method = match method {
+            "bonferroni" => scalib::ttest::PValueCorrection::Bonferroni,
+            "bh" => scalib::ttest::PValueCorrection::BenjaminiHochberg,
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Invalid p-value correction method '{}'.",
+                    method
+                )))
+            }
+        };
+        let pvalue = py.allow_threads(|| self.inner.get_pvalue_corrected(method));
+        Ok(&(pvalue.to_pyarray(py)))
+    }
+
+    /// Skewness of the traces for each class.
+    /// return array axes (2,ns)
+    fn get_skewness<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        let skewness = py.allow_threads(|| self.inner.get_skewness());
+        Ok(&(skewness.to_pyarray(py)))
+    }
+
+    /// Kurtosis of the traces for each class.
+    /// return array axes (2,ns)
+    fn get_kurtosis<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        let kurtosis = py.allow_threads(|| self.inner.get_kurtosis());
+        Ok(&(kurtosis.to_pyarray(py)))
+    }
+
+    /// Mean of the traces for each class.
+    /// return array axes (2,ns)
+    fn get_means<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        Ok(&(self.inner.get_means().to_pyarray(py)))
+    }
+
+    /// Variance of the traces for each class.
+    /// return array axes (2,ns)
+    fn get_variances<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        Ok(&(self.inner.get_variances().to_pyarray(py)))
+    }
+
+    /// Accumulate histograms with nbins bins for the KS statistic.
+    fn enable_ks(&mut self, nbins: usize) {
+        self.inner.enable_ks(nbins);
+    }
+
+    /// Kolmogorov-Smirnov statistic between the two classes.
+    /// return array axes (ns,)
+    fn get_ks<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray1<f64>> {
+        let ks = py.allow_threads(|| self.inner.get_ks());
+        Ok(&(ks.to_pyarray(py)))
+    }
+
+    /// Track the running medians, to be used as robust centers.
+    fn enable_robust_center(&mut self) {
+        self.inner.enable_robust_center();
+    }
+
+    /// Approximate median of the traces for each class.
+    /// return array axes (2,ns)
+    fn get_medians<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        Ok(&(self.inner.get_medians().to_pyarray(py)))
+    }
+
+    /// Ttest metric with the statistics of order 2 and above centered around the medians.
     /// return array axes (d,ns)
-    fn get_ttest<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+    fn get_ttest_robust<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        let ttest = py.allow_threads(|| self.inner.get_ttest_robust());
+        Ok(&(ttest.to_pyarray(py)))
+    }
+
+    /// Number of traces accumulated in each class.
+    /// return array axes (2,)
+    fn get_n_samples<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray1<u64>> {
+        Ok(&(self.inner.get_n_samples().to_pyarray(py)))
+    }
+
+    /// Consider the samples with a variance at most tol in both classes as constant.
+    fn set_variance_tolerance(&mut self, tol: f64) {
+        self.inner.set_variance_tolerance(tol);
+    }
+
+    /// Clamp the variances of both classes to at least floor in the Ttest (no floor if None).
+    fn set_variance_floor(&mut self, floor: Option<f64>) {
+        self.inner.set_variance_floor(floor);
+    }
+
+    /// Samples that are constant, for which the Ttest is NaN.
+    /// return array axes (ns,)
+    fn get_zero_variance<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray1<bool>> {
+        Ok(&(self.inner.get_zero_variance().to_pyarray(py)))
+    }
+
+    /// Take a snapshot of the means and variances every interval traces (None to disable).
+    fn set_snapshot_interval(&mut self, interval: Option<u64>) {
+        self.inner.set_snapshot_interval(interval);
+    }
+
+    /// Return the snapshots (n_samples, means, variances) taken since the last call.
+    fn take_snapshots<'py>(
+        &mut self,
+        py: Python<'py>,
+    ) -> PyResult<Vec<(&'py PyArray1<u64>, &'py PyArray2<f64>, &'py PyArray2<f64>)>> {
+        Ok(self
+            .inner
+            .take_snapshots()
+            .iter()
+            .map(|snap| {
+                (
+                    snap.n_samples.to_pyarray(py),
+                    snap.mean.to_pyarray(py),
+                    snap.variance.to_pyarray(py),
+                )
+            })
+            .collect())
+    }
+
+    /// Record the Ttest when the number of traces reaches each of the counts.
+    fn set_ttest_checkpoints(&mut self, counts: Vec<u64>) {
+        self.inner.set_ttest_checkpoints(counts);
+    }
+
+    /// Evaluate the convergence of the moments every window traces (None to disable).
+    fn set_convergence_window(&mut self, window: Option<u64>) {
+        self.inner.set_convergence_window(window);
+    }
+
+    /// Largest relative change of the moments of each order over the last window.
+    /// return array axes (2*d,)
+    fn get_moment_convergence<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray1<f64>> {
+        Ok(&(self.inner.get_moment_convergence().to_pyarray(py)))
+    }
+
+    /// Return the Ttest recorded at the checkpoints reached since the last call.
+    /// return array axes (snapshot,d,ns)
+    fn take_ttest_checkpoints<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray3<f64>> {
+        Ok(&(self.inner.take_ttest_checkpoints().to_pyarray(py)))
+    }
+}
+
+#[pyclass]
+pub(crate) struct MTtest {
+    inner: scalib::mttest::MTtest,
+}
+
+#[pymethods]
+impl MTtest {
+    #[new]
+    /// Create a new multivariate Ttest state.
+    /// ns: traces length
+    /// tuples: indices of the samples combined by each test with shape (n_tuples,d)
+    fn new(ns: usize, tuples: PyReadonlyArray2<u32>) -> Self {
+        let tuples = tuples.as_array().mapv(|x| x as usize);
+        Self {
+            inner: scalib::mttest::MTtest::new(ns, tuples),
+        }
+    }
+
+    /// Update the multivariate Ttest state with n fresh traces
+    /// traces: the leakage traces with shape (n,ns)
+    /// y: realization of random variables with shape (n,)
+    fn update(
+        &mut self,
+        py: Python,
+        traces: PyReadonlyArray2<i16>,
+        y: PyReadonlyArray1<u16>,
+    ) -> PyResult<()> {
+        let traces = traces.as_array();
+        let y = y.as_array();
+        py.allow_threads(|| self.inner.update(traces, y))
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+    }
+
+    /// Generate the actual multivariate Ttest metric based on the current state.
+    /// return array axes (n_tuples,)
+    fn get_ttest<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray1<f64>> {
         let ttest = py.allow_threads(|| self.inner.get_ttest());
         Ok(&(ttest.to_pyarray(py)))
     }
+
+    /// Mean of the centered product of the samples of each tuple for each class.
+    /// return array axes (2,n_tuples)
+    fn get_means<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyArray2<f64>> {
+        Ok(&(self.inner.get_means().to_pyarray(py)))
+    }
 }
@@ -2,6 +2,7 @@ use criterion::{
     criterion_group, criterion_main, AxisScale, BenchmarkId, Criterion, PlotConfiguration,
 };
 use ndarray::{s, Array1, Array2};
+use scalib::belief_propagation::{self as bp, Func, FuncType, Var, VarType};
 use std::fmt;
 #[inline(always)]
 fn fwht(a: &mut [f64], len: usize) {
@@ -119,11 +120,124 @@ fn xors_bench(c: &mut Criterion) {
     }
 }
 
+/// Chain x[i+1] = x[i] ^ k[i] of len XOR function nodes, where x[0] and the k[i] are profiled.
+fn xor_chain(len: usize, n: usize, nc: usize) -> (Vec<Func>, Vec<Var>) {
+    let profiled = |neighboors| Var {
+        neighboors,
+        vartype: VarType::ProfilePara {
+            distri_orig: Array2::from_elem((n, nc), 1.0 / nc as f64),
+            distri_current: Array2::from_elem((n, nc), 1.0 / nc as f64),
+        },
+    };
+    let functions = (0..len)
+        .map(|i| Func::new(vec![3 * i + 2, 3 * i, 3 * i + 1], FuncType::XOR))
+        .collect();
+    let mut variables: Vec<Var> = (0..len).map(|i| profiled(vec![3 * i + 1])).collect();
+    variables.push(profiled(vec![0]));
+    variables.extend((1..=len).map(|i| {
+        let mut neighboors = vec![3 * i - 1];
+        if i < len {
+            neighboors.push(3 * i);
+        }
+        Var {
+            neighboors,
+            vartype: VarType::NotProfilePara {
+                distri_current: Array2::ones((n, nc)),
+            },
+        }
+    }));
+    (functions, variables)
+}
+
+fn layout_bench(c: &mut Criterion) {
+    let (len, n) = (16, 100);
+    let mut group = c.benchmark_group("bp_layout");
+    for nc in [16, 256].iter() {
+        let (functions, mut variables) = xor_chain(len, n, *nc);
+        for layout in [bp::EdgeLayout::Separate, bp::EdgeLayout::Contiguous].iter() {
+            let mut workspace = bp::BpWorkspace::new().with_layout(*layout);
+            let id = fmt::format(format_args!("{:?}", layout));
+            group.bench_with_input(BenchmarkId::new(id, nc), nc, |b, nc| {
+                b.iter(|| {
+                    bp::run_bp_workspace(
+                        &functions,
+                        &mut variables,
+                        3,
+                        3 * len,
+                        *nc,
+                        n,
+                        false,
+                        bp::RunBpOptions::default(),
+                        &mut workspace,
+                    )
+                    .unwrap()
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+/// ADD and XOR function nodes with many copies, copy by copy or batched (see `Func::with_batched`).
+fn batched_bench(c: &mut Criterion) {
+    let n = 10000;
+    let mut group = c.benchmark_group("batched");
+    for nc in [16, 256].iter() {
+        let msgs: Vec<Array2<f64>> = (0..3)
+            .map(|_| Array2::from_elem((n, *nc), 1.0 / *nc as f64))
+            .collect();
+        let ops: [(&str, fn(&mut [&mut Array2<f64>])); 4] = [
+            ("adds", bp::adds),
+            ("adds_batched", bp::adds_batched),
+            ("xors", bp::xors),
+            ("xors_batched", bp::xors_batched),
+        ];
+        for (id, op) in ops.iter() {
+            group.bench_with_input(BenchmarkId::new(*id, nc), nc, |b, _| {
+                b.iter(|| {
+                    let mut inputs = msgs.clone();
+                    op(&mut inputs.iter_mut().collect::<Vec<_>>())
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Single-copy ADD, XOR and MUL function nodes with two inputs, without the belief propagation
+/// plumbing.
+fn single_copy_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_copy");
+    let ops: [(&str, fn(&mut [Array1<f64>])); 3] = [
+        ("add_msgs", bp::add_msgs),
+        ("xor_msgs", bp::xor_msgs),
+        ("mul_msgs", bp::mul_msgs),
+    ];
+    for nc in [16, 256, 257].iter() {
+        let msgs: Vec<Array1<f64>> = (0..3)
+            .map(|i| Array1::from_shape_fn(*nc, |x| 1.0 + ((x * (i + 3)) % 7) as f64))
+            .collect();
+        for (id, op) in ops.iter() {
+            // the XOR requires a power of two
+            if *id == "xor_msgs" && !nc.is_power_of_two() {
+                continue;
+            }
+            group.bench_with_input(BenchmarkId::new(*id, nc), nc, |b, _| {
+                b.iter(|| {
+                    let mut inputs = msgs.clone();
+                    op(&mut inputs)
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
 fn alternate_measurement() -> Criterion {
     Criterion::default().sample_size(50)
 }
 
 criterion_group!(name=benches;
                 config = alternate_measurement();
-                targets=xors_bench);
+                targets=xors_bench, layout_bench, batched_bench, single_copy_bench);
 criterion_main!(benches);
@@ -9,11 +9,18 @@
 //! The values on the factor graph are probability distribution of values in GF(2)^n.
 
 use indicatif::{ProgressBar, ProgressFinish, ProgressIterator, ProgressStyle};
-use ndarray::{s, Array1, Array2, Axis};
+use ndarray::{
+    s, Array1, Array2, Array3, ArrayBase, ArrayD, ArrayViewMut1, ArrayViewMut2, Axis, Data,
+    DataMut, Ix1, Ix2, IxDyn, OwnedRepr, ViewRepr, Zip,
+};
 use rayon::prelude::*;
 use realfft::RealFftPlanner;
 use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::collections::{BTreeMap, BinaryHeap};
 use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use mod_exp::mod_exp;
 
 /// Statistical distribution of a Para node.
@@ -49,16 +56,30 @@ pub struct Var {
     pub vartype: VarType,
 }
 
+#[derive(Clone)]
 pub enum FuncType {
     /// Bitwise AND of variables
     AND,
-    /// Bitwise XOR of variables
+    /// Bitwise XOR of variables: the output (first edge) is the XOR of all the other edges, for
+    /// any number of inputs (see `Func::xor`).
     XOR,
     /// Modular ADD of variables
     ADD,
     /// Modular MUL of variables
     MUL,
+    /// Bitwise NAND of two variables: `!(a & b)`, masked to `nc-1` (`nc` must be a power of two).
+    NAND,
+    /// Bitwise NOR of two variables: `!(a | b)`, masked to `nc-1` (`nc` must be a power of two).
+    NOR,
+    /// Modular negation of a variable: `(nc - a) % nc` (e.g., to build a subtraction from ADD).
+    NEG,
+    /// Additive noise: the output (first edge) is the input plus an independent noise modulo
+    /// `nc`, whose distribution over the differences [0, nc) is `kernel` (e.g., a quantized
+    /// Gaussian measurement noise), see `FuncType::noise`.
+    NOISE { kernel: Array1<f64> },
     /// Bitwise XOR of variables, XORing additionally a public variable.
+    /// The public value of each copy may be `UNKNOWN_CST`, in which case it is uniformly
+    /// distributed (this also applies to ANDCST, ADDCST and MULCST).
     XORCST(Array1<u32>),
     /// Bitwise AND of variables, ANDing additionally a public variable.
     ANDCST(Array1<u32>),
@@ -66,8 +87,348 @@ pub enum FuncType {
     ADDCST(Array1<u32>),
     /// Modular MUL of variables, MULing additionally a public variable.
     MULCST(Array1<u32>),
-    /// Lookup table function.
+    /// Lookup table function (the output may have a different size than the input, see
+    /// `FuncType::lookup_resize`).
     LOOKUP(Array1<u32>),
+    /// Lookup table function with a different table for each copy, with axes (copy, input)
+    /// (e.g., key-dependent S-boxes that are publicly known), see `FuncType::lookup_para`.
+    #[allow(non_camel_case_types)]
+    LOOKUP_PARA(Array2<u32>),
+    /// Lookup function whose image is computed on the fly, without materializing the table.
+    LOOKUPMAP(Arc<dyn LookupMap>),
+    /// User-defined operator.
+    CUSTOM(Arc<dyn FactorOp>),
+}
+
+/// Public value of a copy of a XORCST, ANDCST, ADDCST or MULCST function node that is not known:
+/// the function node behaves as the binary operator (e.g., XOR) with a uniformly distributed
+/// second input.
+pub const UNKNOWN_CST: u32 = u32::MAX;
+
+/// Operator of a function node.
+pub trait FactorOp: Send + Sync {
+    /// Compute the messages from the function node to its adjacent variable nodes, based on the
+    /// messages from the variable nodes in `edges` (one `(n, nc)` array per adjacent edge, in the
+    /// order of `Func::ordered_neighboors`). The messages are updated in-place.
+    fn update(&self, edges: &mut [&mut Array2<f64>]);
+}
+
+/// Mapping of a lookup function node, from [0, nc_in) to [0, nc_out).
+pub trait LookupMap: Send + Sync {
+    /// Size of the input.
+    fn nc_in(&self) -> usize;
+    /// Image of the input `i`.
+    fn image(&self, i: usize) -> usize;
+}
+
+/// Dense table (as in `FuncType::LOOKUP`).
+impl LookupMap for Array1<u32> {
+    fn nc_in(&self) -> usize {
+        self.len()
+    }
+    fn image(&self, i: usize) -> usize {
+        self[i] as usize
+    }
+}
+
+/// Affine map `i -> (a*i + b) % nc` over [0, nc).
+#[derive(Debug, Clone, Copy)]
+pub struct AffineMap {
+    pub a: u64,
+    pub b: u64,
+    pub nc: usize,
+}
+
+impl LookupMap for AffineMap {
+    fn nc_in(&self) -> usize {
+        self.nc
+    }
+    fn image(&self, i: usize) -> usize {
+        ((self.a.wrapping_mul(i as u64).wrapping_add(self.b)) % (self.nc as u64)) as usize
+    }
+}
+
+impl FuncType {
+    /// Lookup table function, checking that `table` is a permutation of [0, nc).
+    pub fn lookup(table: Array1<u32>, nc: usize) -> Result<Self, BPError> {
+        if !is_permutation(&table, nc) {
+            return Err(BPError::InvalidTable(
+                "table is not a permutation of [0, nc).".to_owned(),
+            ));
+        }
+        Ok(FuncType::LOOKUP(table))
+    }
+
+    /// Lookup table function, where `table` maps [0, nc) to [0, nc) but may not be bijective.
+    pub fn lookup_non_bijective(table: Array1<u32>, nc: usize) -> Result<Self, BPError> {
+        Self::lookup_resize(table, nc, nc)
+    }
+
+    /// Lookup table function, where `table` maps [0, nc_in) to [0, nc_out) (e.g., a DES S-box
+    /// maps 6 bits to 4 bits).
+    /// The distributions of the input and output variables must have `nc_in` and `nc_out`
+    /// columns, respectively.
+    pub fn lookup_resize(table: Array1<u32>, nc_in: usize, nc_out: usize) -> Result<Self, BPError> {
+        if table.len() != nc_in {
+            return Err(BPError::InvalidTable(
+                "table length is not the input size.".to_owned(),
+            ));
+        }
+        if table.iter().any(|x| *x as usize >= nc_out) {
+            return Err(BPError::InvalidTable(
+                "table values are not in [0, nc_out).".to_owned(),
+            ));
+        }
+        Ok(FuncType::LOOKUP(table))
+    }
+
+    /// Lookup table function with a table for each copy, where `tables` (with axes (copy,
+    /// input)) maps [0, nc_in) to [0, nc_out) for each of the `n` copies.
+    pub fn lookup_para(
+        tables: Array2<u32>,
+        n: usize,
+        nc_in: usize,
+        nc_out: usize,
+    ) -> Result<Self, BPError> {
+        if tables.dim() != (n, nc_in) {
+            return Err(BPError::InvalidTable(format!(
+                "tables have shape {:?}, expected {:?}.",
+                tables.dim(),
+                (n, nc_in)
+            )));
+        }
+        if tables.iter().any(|x| *x as usize >= nc_out) {
+            return Err(BPError::InvalidTable(
+                "table values are not in [0, nc_out).".to_owned(),
+            ));
+        }
+        Ok(FuncType::LOOKUP_PARA(tables))
+    }
+
+    /// Additive noise function node, where `kernel` is the (possibly unnormalized) distribution
+    /// of the noise over [0, nc).
+    pub fn noise(kernel: Array1<f64>, nc: usize) -> Result<Self, BPError> {
+        if kernel.len() != nc {
+            return Err(BPError::InvalidTable(format!(
+                "kernel has length {}, expected {}.",
+                kernel.len(),
+                nc
+            )));
+        }
+        if kernel.iter().any(|x| !x.is_finite() || *x < 0.0) || kernel.sum() <= 0.0 {
+            return Err(BPError::InvalidTable(
+                "kernel is not a non-zero distribution.".to_owned(),
+            ));
+        }
+        Ok(FuncType::NOISE { kernel })
+    }
+}
+
+/// Fuse a chain of deterministic function nodes with one input into a single LOOKUP function node.
+/// The input of `ops[0]` has size `nc`, the output of each operation is the input of the next
+/// one, and ADDCST and MULCST are computed modulo `nc`.
+/// This only applies to chains of XORCST, ANDCST, ADDCST, MULCST (whose public value must be the
+/// same for all the copies), NEG, LOOKUP and LOOKUPMAP function nodes.
+pub fn fuse(ops: &[FuncType], nc: usize) -> Result<FuncType, BPError> {
+    let mut table: Array1<u32> = (0..nc as u32).collect();
+    for (i, op) in ops.iter().enumerate() {
+        // smallest field size that contains the values before the operation
+        let nc_op = table.iter().max().map(|x| *x as usize + 1).unwrap_or(0);
+        match op {
+            FuncType::XORCST(values)
+            | FuncType::ANDCST(values)
+            | FuncType::ADDCST(values)
+            | FuncType::MULCST(values) => {
+                let value = values[0];
+                if values.iter().any(|v| *v != value || *v == UNKNOWN_CST) {
+                    return Err(BPError::InvalidTable(format!(
+                        "public values of operation {} differ across copies or are unknown.",
+                        i
+                    )));
+                }
+                table.mapv_inplace(|x| cst_output(op, x as usize, value, nc) as u32);
+            }
+            FuncType::NEG => {
+                if nc_op > nc {
+                    return Err(BPError::InvalidTable(format!(
+                        "values before operation {} are larger than nc.",
+                        i
+                    )));
+                }
+                table.mapv_inplace(|x| neg_map(nc).image(x as usize) as u32);
+            }
+            FuncType::LOOKUP(t) => {
+                if nc_op > t.len() {
+                    return Err(BPError::InvalidTable(format!(
+                        "table of operation {} is too short.",
+                        i
+                    )));
+                }
+                table.mapv_inplace(|x| t[x as usize]);
+            }
+            FuncType::LOOKUPMAP(map) => {
+                if nc_op > map.nc_in() {
+                    return Err(BPError::InvalidTable(format!(
+                        "map of operation {} is too short.",
+                        i
+                    )));
+                }
+                table.mapv_inplace(|x| map.image(x as usize) as u32);
+            }
+            _ => return Err(BPError::UnsupportedFunction { function: i }),
+        }
+    }
+    Ok(FuncType::LOOKUP(table))
+}
+
+/// Factor graph after the contraction of deterministic chains (see `contract_chains`).
+pub struct ContractedGraph {
+    pub functions: Vec<Func>,
+    pub variables: Vec<Var>,
+    /// Number of edges in the contracted graph.
+    pub edge: usize,
+    /// Index of each variable of the original graph in the contracted graph (None if it has
+    /// been contracted).
+    pub var_map: Vec<Option<usize>>,
+}
+
+/// Contract the chains of deterministic function nodes with one input (see `fuse`), such as
+/// x -> XORCST -> t -> LOOKUP -> y, into a single LOOKUP function node (x -> LOOKUP -> y).
+/// The intermediate variables of a chain are removed: these must be `NotProfilePara` variables
+/// adjacent only to the output of a function node of the chain and to the input of the next one
+/// (hence, they bring no information to the belief propagation). Function nodes that are
+/// disabled for some copies, or whose public values differ across copies, are not contracted.
+/// The marginals of the remaining variables are unchanged by the belief propagation, while the
+/// edges are renumbered.
+pub fn contract_chains(
+    functions: Vec<Func>,
+    variables: Vec<Var>,
+    edge: usize,
+    nc: usize,
+) -> ContractedGraph {
+    let contractible = |f: &Func| {
+        f.neighboors.len() == 2
+            && f.enabled.is_none()
+            && matches!(
+                f.functype,
+                FuncType::XORCST(_)
+                    | FuncType::ANDCST(_)
+                    | FuncType::ADDCST(_)
+                    | FuncType::MULCST(_)
+                    | FuncType::NEG
+                    | FuncType::LOOKUP(_)
+                    | FuncType::LOOKUPMAP(_)
+            )
+    };
+    // function node adjacent to each edge
+    let mut edge_func: Vec<Option<usize>> = vec![None; edge];
+    for (i, f) in functions.iter().enumerate() {
+        f.neighboors.iter().for_each(|e| edge_func[*e] = Some(i));
+    }
+    let mut functions: Vec<Option<Func>> = functions.into_iter().map(Some).collect();
+    let mut removed = vec![false; variables.len()];
+    for (v, var) in variables.iter().enumerate() {
+        if !matches!(var.vartype, VarType::NotProfilePara { .. }) || var.neighboors.len() != 2 {
+            continue;
+        }
+        // var is the output of f1 and the input of f2
+        let (f1, f2) = match (edge_func[var.neighboors[0]], edge_func[var.neighboors[1]]) {
+            (Some(a), Some(b)) if a != b => {
+                let (fa, fb) = (
+                    functions[a].as_ref().unwrap(),
+                    functions[b].as_ref().unwrap(),
+                );
+                if !contractible(fa) || !contractible(fb) {
+                    continue;
+                }
+                let (na, nb) = (fa.ordered_neighboors(), fb.ordered_neighboors());
+                if na[0] == var.neighboors[0] && nb[1] == var.neighboors[1] {
+                    (a, b)
+                } else if nb[0] == var.neighboors[1] && na[1] == var.neighboors[0] {
+                    (b, a)
+                } else {
+                    continue;
+                }
+            }
+            _ => continue,
+        };
+        let (func1, func2) = (
+            functions[f1].as_ref().unwrap(),
+            functions[f2].as_ref().unwrap(),
+        );
+        let fused = match fuse(&[func1.functype.clone(), func2.functype.clone()], nc) {
+            Ok(fused) => fused,
+            Err(_) => continue,
+        };
+        let input = func1.ordered_neighboors()[1];
+        let output = func2.ordered_neighboors()[0];
+        functions[f1] = None;
+        functions[f2] = Some(Func::new(vec![output, input], fused));
+        edge_func[input] = Some(f2);
+        removed[v] = true;
+    }
+
+    // renumber the remaining edges
+    let functions: Vec<Func> = functions.into_iter().flatten().collect();
+    let mut edge_map: Vec<Option<usize>> = vec![None; edge];
+    let mut new_edge = 0;
+    for f in functions.iter() {
+        for e in f.neighboors.iter() {
+            if edge_map[*e].is_none() {
+                edge_map[*e] = Some(new_edge);
+                new_edge += 1;
+            }
+        }
+    }
+    let mut var_map = vec![None; variables.len()];
+    let variables: Vec<Var> = variables
+        .into_iter()
+        .enumerate()
+        .filter(|(v, _)| !removed[*v])
+        .enumerate()
+        .map(|(new_v, (v, mut var))| {
+            var_map[v] = Some(new_v);
+            for e in var.neighboors.iter_mut() {
+                *e = *edge_map[*e].get_or_insert_with(|| {
+                    new_edge += 1;
+                    new_edge - 1
+                });
+            }
+            var
+        })
+        .collect();
+    let functions = functions
+        .into_iter()
+        .map(|mut f| {
+            f.neighboors
+                .iter_mut()
+                .for_each(|e| *e = edge_map[*e].unwrap());
+            f
+        })
+        .collect();
+    ContractedGraph {
+        functions,
+        variables,
+        edge: new_edge,
+        var_map,
+    }
+}
+
+/// Test whether `table` is a permutation of [0, nc), that is, every value in [0, nc) appears
+/// exactly once in the table.
+pub fn is_permutation(table: &Array1<u32>, nc: usize) -> bool {
+    if table.len() != nc {
+        return false;
+    }
+    let mut seen = vec![false; nc];
+    for x in table.iter() {
+        let x = *x as usize;
+        if x >= nc || seen[x] {
+            return false;
+        }
+        seen[x] = true;
+    }
+    true
 }
 
 /// A function node in the graph.
@@ -75,16 +436,307 @@ pub struct Func {
     /// Ids of edges adjacent to the function node.
     pub neighboors: Vec<usize>,
     pub functype: FuncType,
+    /// For each copy, whether the function node is enabled (None if enabled for all copies).
+    /// A disabled copy sends uniform messages (i.e., no information).
+    pub enabled: Option<Array1<bool>>,
+    /// Index in `neighboors` of the output edge of the function node (0 by default, see
+    /// `Func::with_output`). The operators see the output as their first edge, followed by the
+    /// inputs in the order of `neighboors` (see `Func::ordered_neighboors`).
+    pub output: usize,
+    /// Whether the copies of an ADD or XOR function node are processed in parallel (see
+    /// `Func::with_batched`).
+    batched: bool,
+}
+
+impl Func {
+    /// Function node that is enabled for all copies.
+    pub fn new(neighboors: Vec<usize>, functype: FuncType) -> Self {
+        Func {
+            neighboors,
+            functype,
+            enabled: None,
+            output: 0,
+            batched: false,
+        }
+    }
+
+    /// Same function node, whose output is the edge `neighboors[output]` instead of the first
+    /// one (e.g., for a LOOKUP function node whose input edge comes first).
+    pub fn with_output(mut self, output: usize) -> Self {
+        assert!(
+            output < self.neighboors.len(),
+            "The output must be one of the adjacent edges."
+        );
+        self.output = output;
+        self
+    }
+
+    /// Same function node, whose messages are computed with `adds_batched` or `xors_batched`
+    /// (if it is an ADD or XOR function node), processing its copies in parallel instead of
+    /// sequentially. Both give the same messages, the fastest depends on the number of copies
+    /// and on the size of the field.
+    pub fn with_batched(mut self, batched: bool) -> Self {
+        self.batched = batched;
+        self
+    }
+
+    /// Ids of the adjacent edges, with the output edge first (see `Func::output`), followed by the
+    /// inputs in the order of `neighboors`.
+    pub fn ordered_neighboors(&self) -> Vec<usize> {
+        let mut res = self.neighboors.clone();
+        if !res.is_empty() {
+            res[..=self.output].rotate_right(1);
+        }
+        res
+    }
+
+    /// XOR function node whose output is the edge `output` and whose inputs are the edges
+    /// `inputs`, for any number of inputs (the output is the first edge of the function node).
+    pub fn xor(output: usize, inputs: &[usize]) -> Result<Self, BPError> {
+        if inputs.is_empty() {
+            return Err(BPError::InvalidGraph(
+                "XOR function node has no input.".to_owned(),
+            ));
+        }
+        let mut neighboors = vec![output];
+        neighboors.extend_from_slice(inputs);
+        if let Some(e) = neighboors
+            .iter()
+            .enumerate()
+            .find_map(|(i, e)| neighboors[..i].contains(e).then(|| *e))
+        {
+            return Err(BPError::InvalidGraph(format!(
+                "edge {} is adjacent twice to the XOR function node.",
+                e
+            )));
+        }
+        Ok(Func::new(neighboors, FuncType::XOR))
+    }
+
+    /// Metadata of the function node, in a graph over a field of size `nc`.
+    pub fn describe(&self, nc: usize) -> FuncInfo {
+        let op = match &self.functype {
+            FuncType::AND => "AND",
+            FuncType::XOR => "XOR",
+            FuncType::ADD => "ADD",
+            FuncType::MUL => "MUL",
+            FuncType::NAND => "NAND",
+            FuncType::NOR => "NOR",
+            FuncType::NEG => "NEG",
+            FuncType::NOISE { .. } => "NOISE",
+            FuncType::XORCST(_) => "XORCST",
+            FuncType::ANDCST(_) => "ANDCST",
+            FuncType::ADDCST(_) => "ADDCST",
+            FuncType::MULCST(_) => "MULCST",
+            FuncType::LOOKUP(_) => "LOOKUP",
+            FuncType::LOOKUP_PARA(_) => "LOOKUP_PARA",
+            FuncType::LOOKUPMAP(_) => "LOOKUPMAP",
+            FuncType::CUSTOM(_) => "CUSTOM",
+        };
+        FuncInfo {
+            op,
+            arity: self.neighboors.len(),
+            nc,
+        }
+    }
+}
+
+/// Metadata of a function node (see `Func::describe`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncInfo {
+    /// Name of the operator (e.g. "XOR").
+    pub op: &'static str,
+    /// Number of adjacent edges.
+    pub arity: usize,
+    /// Size of the field.
+    pub nc: usize,
+}
+
+impl Var {
+    /// Metadata of the variable node.
+    pub fn describe(&self) -> VarInfo {
+        let (para, profiled) = match &self.vartype {
+            VarType::ProfilePara { .. } => (true, true),
+            VarType::NotProfilePara { .. } => (true, false),
+            VarType::ProfileSingle { .. } => (false, true),
+            VarType::NotProfileSingle { .. } => (false, false),
+        };
+        VarInfo {
+            para,
+            profiled,
+            degree: self.neighboors.len(),
+            nc: distri_current(self).shape()[1],
+        }
+    }
+}
+
+/// Metadata of a variable node (see `Var::describe`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarInfo {
+    /// Whether the variable is replicated for each copy.
+    pub para: bool,
+    /// Whether the variable has an initial distribution.
+    pub profiled: bool,
+    /// Number of adjacent edges.
+    pub degree: usize,
+    /// Size of the field of the variable.
+    pub nc: usize,
+}
+
+/// Error in the belief propagation algorithm.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BPError {
+    /// The function node (given by its id) is not supported by the algorithm.
+    UnsupportedFunction { function: usize },
+    /// The table of a LOOKUP function node is not valid.
+    InvalidTable(String),
+    /// The description of the graph is not valid.
+    InvalidGraph(String),
+    /// The distribution of the variable (given by its id) lost all information or is not finite
+    /// after the given iteration.
+    Diverged { variable: usize, iteration: usize },
+    /// The function node (given by its id) has more adjacent edges than allowed.
+    DegreeTooLarge { function: usize, degree: usize },
+    /// The variable or function node (given by its id) has no adjacent edge (see
+    /// `validate_graph`).
+    DisconnectedNode { id: usize, is_function: bool },
+    /// The hard constraints of the graph leave no possible value to the variable (given by its
+    /// id), see `check_feasibility`.
+    Infeasible { variable: usize },
+    /// The graph has too many joint assignments of its variables (over all the copies) to be
+    /// enumerated (see `brute_force_marginals`).
+    StateSpaceTooLarge { states: usize },
+    /// The size of the field, or the memory of the messages on the edges (in bytes), exceeds the
+    /// limits of the workspace (see `BpLimits`).
+    FieldTooLarge { nc: usize, edge_bytes: usize },
+}
+
+impl std::fmt::Display for BPError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BPError::UnsupportedFunction { function } => {
+                write!(f, "BP error: function node {} is not supported.", function)
+            }
+            BPError::InvalidTable(s) => write!(f, "BP error: invalid table, {}", s),
+            BPError::InvalidGraph(s) => write!(f, "BP error: invalid graph, {}", s),
+            BPError::Diverged {
+                variable,
+                iteration,
+            } => write!(
+                f,
+                "BP error: variable {} diverged at iteration {}.",
+                variable, iteration
+            ),
+            BPError::DegreeTooLarge { function, degree } => write!(
+                f,
+                "BP error: function node {} has too many edges ({}).",
+                function, degree
+            ),
+            BPError::DisconnectedNode { id, is_function } => write!(
+                f,
+                "BP error: {} node {} has no edge.",
+                if *is_function { "function" } else { "variable" },
+                id
+            ),
+            BPError::Infeasible { variable } => write!(
+                f,
+                "BP error: variable {} has no value compatible with the constraints.",
+                variable
+            ),
+            BPError::StateSpaceTooLarge { states } => write!(
+                f,
+                "BP error: too many joint assignments to enumerate ({}).",
+                states
+            ),
+            BPError::FieldTooLarge { nc, edge_bytes } => write!(
+                f,
+                "BP error: field of size {} with {} bytes of messages exceeds the limits.",
+                nc, edge_bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BPError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
 }
 
 /// The minimum non-zero probability (to avoid denormalization, etc.)
 const MIN_PROBA: f64 = 1e-20;
 
-/// Clip down to `MIN_PROBA`
+/// The maximum absolute value of a log-likelihood ratio (consistent with `MIN_PROBA`).
+const MAX_LLR: f64 = 46.0;
+
+/// Clamping of the probabilities in the edge messages, which avoids zeros (see
+/// `RunBpOptions::clamp`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clamp {
+    /// Clip down to the fixed floor `MIN_PROBA` (1e-20).
+    Fixed,
+    /// Clip down to `ratio` times the maximum of each message, bounding its dynamic range
+    /// independently of its scale (e.g., 1e-12). The ratio must be in (0, 1).
+    Relative(f64),
+}
+
+impl Default for Clamp {
+    fn default() -> Self {
+        Clamp::Fixed
+    }
+}
+
+/// Floor of a message whose largest value (in absolute value) is `max` (see `Clamp`).
+fn clamp_floor(max: f64, clamp: Clamp) -> f64 {
+    match clamp {
+        Clamp::Fixed => MIN_PROBA,
+        Clamp::Relative(ratio) => (ratio * max).max(f64::MIN_POSITIVE),
+    }
+}
+
+/// Clip down to the floor of the messages along the last axis (see `Clamp`).
 fn make_non_zero<S: ndarray::DataMut + ndarray::RawData<Elem = f64>, D: ndarray::Dimension>(
     x: &mut ndarray::ArrayBase<S, D>,
+    clamp: Clamp,
+) {
+    match clamp {
+        Clamp::Fixed => x.mapv_inplace(|y| y.max(MIN_PROBA)),
+        Clamp::Relative(_) => {
+            let axis = Axis(x.ndim() - 1);
+            x.lanes_mut(axis).into_iter().for_each(|mut msg| {
+                let floor = clamp_floor(msg.fold(0.0, |acc: f64, y| acc.max(*y)), clamp);
+                msg.mapv_inplace(|y| y.max(floor));
+            });
+        }
+    }
+}
+
+/// Clip the transformed message `spectrum` such that it has no zero (see `Clamp`).
+fn make_non_zero_spectrum<S: DataMut<Elem = Complex<f64>>>(
+    spectrum: &mut ArrayBase<S, Ix1>,
+    clamp: Clamp,
 ) {
-    x.mapv_inplace(|y| y.max(MIN_PROBA));
+    let floor = clamp_floor(spectrum.fold(0.0, |acc: f64, x| acc.max(x.norm())), clamp);
+    spectrum.mapv_inplace(|x| {
+        if x.norm_sqr() == 0.0 {
+            Complex::new(floor, floor)
+        } else {
+            x
+        }
+    });
+}
+
+/// Clip the Walsh-Hadamard transformed message `msg` (which may be negative) such that it has no
+/// zero (see `Clamp`).
+fn make_non_zero_walsh<S: DataMut<Elem = f64>>(msg: &mut ArrayBase<S, Ix1>, clamp: Clamp) {
+    let floor = clamp_floor(msg.fold(0.0, |acc: f64, x| acc.max(x.abs())), clamp);
+    msg.mapv_inplace(|x| {
+        if x.is_sign_positive() {
+            x.max(floor)
+        } else {
+            x.min(-floor)
+        }
+    });
 }
 
 /// Walsh-Hadamard transform (non-normalized).
@@ -108,6 +760,22 @@ fn fwht(a: &mut [f64], len: usize) {
     }
 }
 
+/// Walsh-Hadamard transform of `a`, whose length must be a non-zero power of 2.
+/// The transform is its own inverse up to a factor `a.len()`: if `normalize` is set, the result
+/// is divided by `a.len()`, such that a non-normalized transform followed by a normalized one is
+/// the identity.
+pub fn walsh_hadamard(a: &mut [f64], normalize: bool) {
+    let len = a.len();
+    assert!(
+        len.is_power_of_two(),
+        "Length of the transform must be a non-zero power of 2."
+    );
+    fwht(a, len);
+    if normalize {
+        a.iter_mut().for_each(|x| *x /= len as f64);
+    }
+}
+
 /// Find the prime factors of an integer.
 pub fn prime_factors(order: u32) -> Vec<u32> {
     let sqrt_order = (order as f64).sqrt();
@@ -158,6 +826,48 @@ pub fn find_gen(p: u32) -> Vec<u32> {
     gen_list
 }
 
+/// Finds a generator in Z_p^* (not necessarily the smallest one, unlike `find_gen`).
+/// Candidates are drawn at random (seeded by `seed`, for reproducibility) and tested against
+/// the distinct prime factors of p-1, with a fallback to a deterministic scan.
+pub fn find_first_gen_fast(p: u32, seed: u64) -> u32 {
+    let order = p - 1;
+    // `prime_factors` omits the factor larger than the square root of order (if any).
+    let mut prime_list = prime_factors(order);
+    prime_list.dedup();
+    let mut rest = order;
+    for prime in prime_list.iter() {
+        while rest % prime == 0 {
+            rest /= prime;
+        }
+    }
+    if rest > 1 {
+        prime_list.push(rest);
+    }
+
+    if order > 2 {
+        // A random element is a generator with probability phi(p-1)/(p-1).
+        let mut state = seed;
+        for _ in 0..64 {
+            let gen = 2 + (splitmix64(&mut state) % (order as u64 - 1)) as u32;
+            if test_gen(order, &prime_list, gen) {
+                return gen;
+            }
+        }
+    }
+    (1..p)
+        .find(|gen| test_gen(order, &prime_list, *gen))
+        .expect("p must be prime")
+}
+
+/// SplitMix64 pseudo-random generator.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
 /// Generates a lookup table of discrete logarithm in Z_p^*
 pub fn gen_log_table(p: u32) -> Vec<u32> {
     let gen_list = find_gen(p);
@@ -171,7 +881,7 @@ pub fn gen_log_table(p: u32) -> Vec<u32> {
 
 /// Make it such that the sum of the probabilities in the distribution is 1.0.
 /// `distri` can be a ParaDistri or a SingleDistri.
-fn normalize_distri(distri: &mut Array2<f64>) {
+fn normalize_distri<S: DataMut<Elem = f64>>(distri: &mut ArrayBase<S, Ix2>) {
     *distri /= &distri
         .sum_axis(Axis(1))
         .insert_axis(Axis(1))
@@ -179,10 +889,75 @@ fn normalize_distri(distri: &mut Array2<f64>) {
         .unwrap();
 }
 
+/// Divide each row of `distri` by its maximum, such that the largest probability is 1.0.
+/// Rows that are all zero are left unchanged.
+fn rescale_max<S: DataMut<Elem = f64>>(distri: &mut ArrayBase<S, Ix2>) {
+    distri.outer_iter_mut().for_each(|mut row| {
+        let max = row.fold(0.0, |acc: f64, x| acc.max(*x));
+        if max > 0.0 {
+            row /= max;
+        }
+    });
+}
+
+/// Same as `normalize_distri`, but when the sum of a row underflows, the row is first rescaled
+/// by its maximum (as in the logsumexp trick), such that the relative information is preserved.
+fn normalize_distri_stable<S: DataMut<Elem = f64>>(distri: &mut ArrayBase<S, Ix2>) {
+    distri.outer_iter_mut().for_each(|mut row| {
+        let mut sum = row.sum();
+        if !(sum >= f64::MIN_POSITIVE) {
+            let max = row.fold(0.0, |acc: f64, x| acc.max(*x));
+            if max > 0.0 {
+                row /= max;
+                sum = row.sum();
+            }
+        }
+        row /= sum;
+    });
+}
+
+/// Normalize `distri`, using `normalize_distri_stable` if `stable_norm` is set.
+fn normalize<S: DataMut<Elem = f64>>(distri: &mut ArrayBase<S, Ix2>, stable_norm: bool) {
+    if stable_norm {
+        normalize_distri_stable(distri);
+    } else {
+        normalize_distri(distri);
+    }
+}
+
+/// Replace `msg` by `distri / msg` (normalized).
+/// Given the current distribution `distri` of a variable, this maps the message from a function
+/// node to the variable to the message from the variable to the function node, and conversely.
+fn divide_msg<S: DataMut<Elem = f64>>(
+    distri: &Array2<f64>,
+    msg: &mut ArrayBase<S, Ix2>,
+    stable_norm: bool,
+    clamp: Clamp,
+) {
+    let distri = distri.broadcast(msg.shape()).unwrap();
+    msg.zip_mut_with(&distri, |msg, distri| *msg = *distri / *msg);
+    normalize(msg, stable_norm);
+    make_non_zero(msg, clamp);
+}
+
+/// Current distribution of a variable.
+fn distri_current(var: &Var) -> &Array2<f64> {
+    match &var.vartype {
+        VarType::ProfilePara { distri_current, .. }
+        | VarType::NotProfilePara { distri_current }
+        | VarType::ProfileSingle { distri_current, .. }
+        | VarType::NotProfileSingle { distri_current } => distri_current,
+    }
+}
+
 /// Update `distri` with the information from an `edge`.
-fn update_para_var_distri(distri: &mut ParaDistri, edge: &Array2<f64>) {
+fn update_para_var_distri<S: Data<Elem = f64>>(
+    distri: &mut ParaDistri,
+    edge: &ArrayBase<S, Ix2>,
+    stable_norm: bool,
+) {
     *distri *= edge;
-    normalize_distri(distri);
+    normalize(distri, stable_norm);
 }
 
 /// Update the distributions of `variables` based on the messages on `edges` coming from the
@@ -191,65 +966,93 @@ fn update_para_var_distri(distri: &mut ParaDistri, edge: &Array2<f64>) {
 /// Messages are read from and written to `edges`, where `edges[i][j]` is the message to/from the
 /// `j`-th adjacent edge to the variable node `i`.
 pub fn update_variables(edges: &mut [Vec<&mut Array2<f64>>], variables: &mut [Var]) {
+    par_update_variables(edges, variables, false, Clamp::Fixed);
+}
+
+/// Same as `update_variables`, for any storage of the messages.
+/// If `stable_norm` is set, the distributions are normalized with `normalize_distri_stable` and
+/// all the factors are rescaled by their maximum before being multiplied, which avoids
+/// underflows when the probabilities are tiny. The messages are clipped according to `clamp`.
+fn par_update_variables<S: MsgData>(
+    edges: &mut [Vec<&mut ArrayBase<S, Ix2>>],
+    variables: &mut [Var],
+    stable_norm: bool,
+    clamp: Clamp,
+) {
     variables
         .par_iter_mut()
         .zip(edges.par_iter_mut())
-        .for_each(|(var, neighboors)| {
-            // update the current distri
-            match &mut var.vartype {
-                VarType::ProfilePara {
-                    distri_orig,
-                    distri_current,
-                } => {
-                    distri_current.assign(&distri_orig);
-                    neighboors
-                        .iter()
-                        .for_each(|msg| update_para_var_distri(distri_current, msg));
-                }
-                VarType::ProfileSingle {
-                    distri_orig,
-                    distri_current,
-                } => {
-                    distri_current.assign(&distri_orig);
-                    neighboors.iter().for_each(|msg| {
-                        msg.outer_iter().for_each(|msg| {
-                            *distri_current *= &msg;
-                            normalize_distri(distri_current);
-                        });
-                    });
-                }
-                VarType::NotProfilePara { distri_current } => {
-                    distri_current.fill(1.0);
-                    neighboors
-                        .iter()
-                        .for_each(|msg| update_para_var_distri(distri_current, msg));
-                }
-                VarType::NotProfileSingle { distri_current } => {
-                    distri_current.fill(1.0);
-                    neighboors.iter().for_each(|msg| {
-                        msg.outer_iter().for_each(|msg| {
-                            *distri_current *= &msg;
-                            normalize_distri(distri_current);
-                        });
-                    });
-                }
+        .for_each(|(var, neighboors)| update_variable(var, neighboors, stable_norm, clamp));
+}
+
+/// Update a single variable node, see `update_variables`.
+fn update_variable<S: DataMut<Elem = f64>>(
+    var: &mut Var,
+    neighboors: &mut [&mut ArrayBase<S, Ix2>],
+    stable_norm: bool,
+    clamp: Clamp,
+) {
+    if stable_norm {
+        // The scaling of the messages does not matter, since the distributions are normalized.
+        neighboors.iter_mut().for_each(|msg| rescale_max(msg));
+    }
+    // update the current distri
+    match &mut var.vartype {
+        VarType::ProfilePara {
+            distri_orig,
+            distri_current,
+        } => {
+            distri_current.assign(&distri_orig);
+            if stable_norm {
+                rescale_max(distri_current);
             }
-            // send back the messages
-            match &mut var.vartype {
-                VarType::ProfilePara { distri_current, .. }
-                | VarType::NotProfilePara { distri_current }
-                | VarType::ProfileSingle { distri_current, .. }
-                | VarType::NotProfileSingle { distri_current } => {
-                    neighboors.iter_mut().for_each(|msg| {
-                        let distri_current = distri_current.broadcast(msg.shape()).unwrap();
-                        msg.zip_mut_with(&distri_current, |msg, distri| *msg = *distri / *msg);
-                        normalize_distri(*msg);
-                        make_non_zero(msg);
-                    });
-                    make_non_zero(distri_current);
-                }
+            neighboors
+                .iter()
+                .for_each(|msg| update_para_var_distri(distri_current, msg, stable_norm));
+        }
+        VarType::ProfileSingle {
+            distri_orig,
+            distri_current,
+        } => {
+            distri_current.assign(&distri_orig);
+            if stable_norm {
+                rescale_max(distri_current);
             }
-        });
+            neighboors.iter().for_each(|msg| {
+                msg.outer_iter().for_each(|msg| {
+                    *distri_current *= &msg;
+                    normalize(distri_current, stable_norm);
+                });
+            });
+        }
+        VarType::NotProfilePara { distri_current } => {
+            distri_current.fill(1.0);
+            neighboors
+                .iter()
+                .for_each(|msg| update_para_var_distri(distri_current, msg, stable_norm));
+        }
+        VarType::NotProfileSingle { distri_current } => {
+            distri_current.fill(1.0);
+            neighboors.iter().for_each(|msg| {
+                msg.outer_iter().for_each(|msg| {
+                    *distri_current *= &msg;
+                    normalize(distri_current, stable_norm);
+                });
+            });
+        }
+    }
+    // send back the messages
+    match &mut var.vartype {
+        VarType::ProfilePara { distri_current, .. }
+        | VarType::NotProfilePara { distri_current }
+        | VarType::ProfileSingle { distri_current, .. }
+        | VarType::NotProfileSingle { distri_current } => {
+            neighboors
+                .iter_mut()
+                .for_each(|msg| divide_msg(distri_current, msg, stable_norm, clamp));
+            make_non_zero(distri_current, clamp);
+        }
+    }
 }
 
 /// Compute the messages from the function nodes to the variable nodes based on the messages from
@@ -257,35 +1060,224 @@ pub fn update_variables(edges: &mut [Vec<&mut Array2<f64>>], variables: &mut [Va
 /// Messages are read from and written to `edges`, where `edges[i][j]` is the message to/from the
 /// `j`-th adjacent edge to the function node `i`.
 pub fn update_functions(functions: &[Func], edges: &mut [Vec<&mut Array2<f64>>]) {
+    par_update_functions(functions, edges, Clamp::Fixed);
+}
+
+/// Same as `update_functions`, for any storage of the messages, which are clipped according to
+/// `clamp`.
+fn par_update_functions<S: MsgData>(
+    functions: &[Func],
+    edges: &mut [Vec<&mut ArrayBase<S, Ix2>>],
+    clamp: Clamp,
+) {
     functions
         .par_iter()
         .zip(edges.par_iter_mut())
-        .for_each(|(function, edge)| match &function.functype {
+        .for_each(|(function, edge)| update_function(function, edge, clamp));
+}
+
+/// Number of updates and accumulated update time of the function nodes of a kind (see
+/// `update_functions_profiled`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpStats {
+    pub count: u64,
+    pub time: Duration,
+}
+
+/// Same as `update_functions`, also returning the number of updates and the time spent for each
+/// kind of function node (see `op_kind`), e.g., to find out that MUL nodes are computed with the
+/// slow `naive` algorithm.
+pub fn update_functions_profiled(
+    functions: &[Func],
+    edges: &mut [Vec<&mut Array2<f64>>],
+) -> BTreeMap<&'static str, OpStats> {
+    let timings: Vec<(&'static str, Duration)> = functions
+        .par_iter()
+        .zip(edges.par_iter_mut())
+        .map(|(function, edge)| {
+            let kind = op_kind(&function.functype, edge[0].shape()[1]);
+            let start = Instant::now();
+            update_function(function, edge, Clamp::Fixed);
+            (kind, start.elapsed())
+        })
+        .collect();
+    let mut stats = BTreeMap::new();
+    for (kind, time) in timings {
+        let entry: &mut OpStats = stats.entry(kind).or_default();
+        entry.count += 1;
+        entry.time += time;
+    }
+    stats
+}
+
+/// Kind of the computation of a function node over a field of size `nc`: "AND" (also NAND and
+/// NOR), "XOR", "ADD" (also NOISE), "MUL-fast" (with a transform, when `nc` is prime or a power
+/// of two), "MUL-naive", "LOOKUP" (also LOOKUP_PARA, LOOKUPMAP and NEG), "CST" (operations with
+/// a public variable) or "CUSTOM".
+pub fn op_kind(functype: &FuncType, nc: usize) -> &'static str {
+    match functype {
+        FuncType::AND | FuncType::NAND | FuncType::NOR => "AND",
+        FuncType::XOR => "XOR",
+        FuncType::ADD | FuncType::NOISE { .. } => "ADD",
+        FuncType::MUL => {
+            if mul_is_fast(nc) {
+                "MUL-fast"
+            } else {
+                "MUL-naive"
+            }
+        }
+        FuncType::LOOKUP(_) | FuncType::LOOKUP_PARA(_) | FuncType::LOOKUPMAP(_) | FuncType::NEG => {
+            "LOOKUP"
+        }
+        FuncType::XORCST(_) | FuncType::ANDCST(_) | FuncType::ADDCST(_) | FuncType::MULCST(_) => {
+            "CST"
+        }
+        FuncType::CUSTOM(_) => "CUSTOM",
+    }
+}
+
+/// Whether MUL function nodes over a field of size `nc` are computed with a fast transform
+/// (see `mults` when `nc` is prime and `mults_pow2` when it is a power of two) rather than with
+/// `naive`.
+fn mul_is_fast(nc: usize) -> bool {
+    prime_factors(nc.try_into().unwrap()).len() == 0 || nc.is_power_of_two()
+}
+
+/// Update a single function node, see `update_functions`.
+fn update_function<S: MsgData>(function: &Func, edge: &mut [&mut ArrayBase<S, Ix2>], clamp: Clamp) {
+    // the operators expect the output first
+    edge[..=function.output].rotate_right(1);
+    update_function_msgs(function, edge, clamp);
+    edge[..=function.output].rotate_left(1);
+    if let Some(enabled) = &function.enabled {
+        edge.iter_mut().for_each(|msg| {
+            msg.outer_iter_mut()
+                .zip(enabled.iter())
+                .filter(|(_, enabled)| !**enabled)
+                .for_each(|(mut msg, _)| msg.fill(1.0 / (msg.len() as f64)));
+        });
+    }
+}
+
+/// Largest field size for which XOR function nodes with two inputs are computed with `naive`
+/// rather than with the Walsh-Hadamard transform.
+pub const XOR_NAIVE_MAX_NC: usize = 16;
+
+/// Largest field size for which ADD function nodes with two inputs are computed with `naive`
+/// rather than with the FFT.
+pub const ADD_NAIVE_MAX_NC: usize = 8;
+
+/// Whether a XOR or ADD function node with `arity` edges over a field of size `nc` is computed
+/// with the naive O(nc^2) algorithm, which is faster than the transforms for small fields.
+/// The naive algorithm only supports two inputs.
+pub fn prefer_naive(functype: &FuncType, arity: usize, nc: usize) -> bool {
+    arity == 3
+        && match functype {
+            FuncType::XOR => nc <= XOR_NAIVE_MAX_NC,
+            FuncType::ADD => nc <= ADD_NAIVE_MAX_NC,
+            _ => false,
+        }
+}
+
+/// Whether the function node `functype` is computed with `naive` for the messages `edge` (see
+/// `prefer_naive`), which requires all the edges to have the same size.
+fn use_naive<S: Data<Elem = f64>>(functype: &FuncType, edge: &[&mut ArrayBase<S, Ix2>]) -> bool {
+    let nc = edge[0].shape()[1];
+    edge.iter().all(|e| e.shape()[1] == nc) && prefer_naive(functype, edge.len(), nc)
+}
+
+/// Compute the messages of a function node, for all the copies.
+fn update_function_msgs<S: MsgData>(
+    function: &Func,
+    edge: &mut [&mut ArrayBase<S, Ix2>],
+    clamp: Clamp,
+) {
+    match &function.functype {
+        FuncType::ADD if function.batched && !use_naive(&FuncType::ADD, edge) => {
+            adds_batched_with_clamp(edge, clamp)
+        }
+        FuncType::XOR if function.batched && !use_naive(&FuncType::XOR, edge) => {
+            xors_batched_with_clamp(edge, clamp)
+        }
+        functype => functype.update_msgs(edge, clamp),
+    }
+}
+
+/// Storage of the messages of an edge: an owned array (see `run_bp_edges`), or a view in the
+/// contiguous array of all the messages (see `run_bp_edges_contiguous`).
+trait MsgData: DataMut<Elem = f64> + Send {
+    /// Call `FactorOp::update`, which only takes owned arrays.
+    fn factor_op_update(op: &dyn FactorOp, edge: &mut [&mut ArrayBase<Self, Ix2>])
+    where
+        Self: Sized;
+}
+
+impl MsgData for OwnedRepr<f64> {
+    fn factor_op_update(op: &dyn FactorOp, edge: &mut [&mut Array2<f64>]) {
+        op.update(edge);
+    }
+}
+
+impl<'a> MsgData for ViewRepr<&'a mut f64> {
+    fn factor_op_update(op: &dyn FactorOp, edge: &mut [&mut ArrayViewMut2<'a, f64>]) {
+        let mut owned: Vec<Array2<f64>> = edge.iter().map(|msg| msg.view().to_owned()).collect();
+        op.update(&mut owned.iter_mut().collect::<Vec<_>>());
+        edge.iter_mut()
+            .zip(owned.iter())
+            .for_each(|(msg, owned)| msg.assign(owned));
+    }
+}
+
+impl FactorOp for FuncType {
+    fn update(&self, edge: &mut [&mut Array2<f64>]) {
+        self.update_msgs(edge, Clamp::Fixed);
+    }
+}
+
+impl FuncType {
+    /// Compute the messages of a function node with this operator (see `FactorOp::update`),
+    /// clipped according to `clamp`.
+    fn update_msgs<S: MsgData>(&self, edge: &mut [&mut ArrayBase<S, Ix2>], clamp: Clamp) {
+        match self {
             // TODO: if nc is prime, the update for MUL can be computed more efficiently by mapping
             // classes to their discrete logarithm, and by applying FFT.
             FuncType::AND => {
-                naive(edge.as_mut(), &function.functype);
+                naive(edge, self);
+            }
+            FuncType::NAND | FuncType::NOR => {
+                // checked when the graph is validated (see `check_edges`)
+                debug_assert!(edge[0].shape()[1].is_power_of_two());
+                naive(edge, self);
             }
             FuncType::ADD => {
-                adds(edge.as_mut());
+                if use_naive(self, edge) {
+                    naive(edge, self);
+                } else {
+                    adds_with_clamp(edge, clamp);
+                }
             }
             FuncType::XOR => {
-                xors(edge.as_mut());
+                if use_naive(self, edge) {
+                    naive(edge, self);
+                } else {
+                    xors_with_clamp(edge, clamp);
+                }
             }
             FuncType::MUL => {
                 let nc = edge[0].shape()[1];
-                if prime_factors(nc.try_into().unwrap()).len() == 0 {
-                    // Fast transform only works when nc is prime.
-                    mults(edge.as_mut());
+                if !mul_is_fast(nc) {
+                    naive(edge, self);
+                } else if nc.is_power_of_two() {
+                    mults_pow2_with_clamp(edge, clamp);
                 } else {
-                    naive(edge.as_mut(), &function.functype);
+                    mults_with_clamp(edge, clamp);
                 }
             }
             FuncType::XORCST(values)
             | FuncType::ANDCST(values)
             | FuncType::ADDCST(values)
             | FuncType::MULCST(values) => {
-                let [output_msg, input1_msg]: &mut [_; 2] = edge.as_mut_slice().try_into().unwrap();
+                let [output_msg, input1_msg]: &mut [_; 2] = edge.try_into().unwrap();
                 let nc = input1_msg.shape()[1];
                 (
                     input1_msg.outer_iter_mut(),
@@ -300,52 +1292,147 @@ pub fn update_functions(functions: &[Func], edges: &mut [Vec<&mut Array2<f64>>])
                             in1_msg_scratch.fill(0.0);
                             out_msg_scratch.fill(0.0);
                             let value = value.first().unwrap();
-                            for i1 in 0..nc {
-                                let o: usize = match &function.functype {
-                                    FuncType::XORCST(values) => ((i1 as u32) ^ value) as usize,
-                                    FuncType::ANDCST(values) => ((i1 as u32) & value) as usize,
-                                    FuncType::ADDCST(values) => {
-                                        (((i1 as u32) + value) % (nc as u32)) as usize
-                                    }
-                                    FuncType::MULCST(values) => {
-                                        (((i1 as u32) * value) % (nc as u32)) as usize
+                            if let FuncType::ADDCST(_) = self {
+                                if *value != UNKNOWN_CST && value % (nc as u32) == 0 {
+                                    // Identity: the messages are just exchanged.
+                                    Zip::from(&mut input1_msg)
+                                        .and(&mut output_msg)
+                                        .for_each(std::mem::swap);
+                                    return;
+                                }
+                            }
+                            if *value == UNKNOWN_CST {
+                                // Binary operator, with a uniform message from the constant.
+                                for i1 in 0..nc {
+                                    for c in 0..(nc as u32) {
+                                        let o = cst_output(self, i1, c, nc);
+                                        in1_msg_scratch[i1] += output_msg[o];
+                                        out_msg_scratch[o] += input1_msg[i1];
                                     }
-                                    _ => unreachable!(),
-                                };
-                                in1_msg_scratch[i1] += output_msg[o];
-                                out_msg_scratch[o] += input1_msg[i1];
+                                }
+                            } else {
+                                for i1 in 0..nc {
+                                    let o = cst_output(self, i1, *value, nc);
+                                    in1_msg_scratch[i1] += output_msg[o];
+                                    out_msg_scratch[o] += input1_msg[i1];
+                                }
                             }
                             input1_msg.assign(in1_msg_scratch);
                             output_msg.assign(out_msg_scratch);
                         },
                     );
             }
-            FuncType::LOOKUP(table) => {
-                let [output_msg, input1_msg]: &mut [_; 2] = edge.as_mut_slice().try_into().unwrap();
-                let nc = input1_msg.shape()[1];
-                (input1_msg.outer_iter_mut(), output_msg.outer_iter_mut())
-                    .into_par_iter()
-                    .for_each_init(
-                        || (Array1::zeros(nc), Array1::zeros(nc)),
-                        |(in1_msg_scratch, out_msg_scratch), (mut input1_msg, mut output_msg)| {
-                            in1_msg_scratch.fill(0.0);
-                            out_msg_scratch.fill(0.0);
-                            for i1 in 0..nc {
-                                let o: usize = table[i1] as usize;
-                                // This requires table to be bijective. Otherwise, we would have to
-                                // divide the messge on the output by the number of matching inputs
-                                // to get the message to forward on the input edge.
-                                in1_msg_scratch[i1] += output_msg[o];
-                                out_msg_scratch[o] += input1_msg[i1];
-                            }
-                            input1_msg.assign(in1_msg_scratch);
-                            output_msg.assign(out_msg_scratch);
-                        },
-                    );
-            }
-        });
+            FuncType::NEG => lookup_update(&neg_map(edge[1].shape()[1]), edge),
+            FuncType::NOISE { kernel } => noise_update(kernel, edge, clamp),
+            FuncType::LOOKUP(table) => lookup_update(table, edge),
+            FuncType::LOOKUP_PARA(tables) => lookup_para_update(tables, edge),
+            FuncType::LOOKUPMAP(map) => lookup_update(map.as_ref(), edge),
+            FuncType::CUSTOM(op) => S::factor_op_update(op.as_ref(), edge),
+        }
+    }
+}
+
+/// Compute the messages of a lookup function node, for all the copies.
+fn lookup_update<L: LookupMap + ?Sized, S: DataMut<Elem = f64> + Send>(
+    map: &L,
+    edge: &mut [&mut ArrayBase<S, Ix2>],
+) {
+    let [output_msg, input1_msg]: &mut [_; 2] = edge.try_into().unwrap();
+    // The input and output may have different sizes.
+    let nc = input1_msg.shape()[1];
+    let nc_out = output_msg.shape()[1];
+    (input1_msg.outer_iter_mut(), output_msg.outer_iter_mut())
+        .into_par_iter()
+        .for_each_init(
+            || (Array1::zeros(nc), Array1::zeros(nc_out)),
+            |(in1_msg_scratch, out_msg_scratch), (mut input1_msg, mut output_msg)| {
+                in1_msg_scratch.fill(0.0);
+                out_msg_scratch.fill(0.0);
+                for i1 in 0..nc {
+                    let o: usize = map.image(i1);
+                    // The input i1 is compatible only with the output o, hence this
+                    // is valid even if the table is not bijective.
+                    in1_msg_scratch[i1] += output_msg[o];
+                    out_msg_scratch[o] += input1_msg[i1];
+                }
+                input1_msg.assign(in1_msg_scratch);
+                output_msg.assign(out_msg_scratch);
+            },
+        );
+}
+
+/// Compute the messages of a lookup function node with a table for each copy (see
+/// `FuncType::LOOKUP_PARA`), for all the copies.
+fn lookup_para_update<S: DataMut<Elem = f64> + Send>(
+    tables: &Array2<u32>,
+    edge: &mut [&mut ArrayBase<S, Ix2>],
+) {
+    let [output_msg, input1_msg]: &mut [_; 2] = edge.try_into().unwrap();
+    let nc = input1_msg.shape()[1];
+    let nc_out = output_msg.shape()[1];
+    // the parallel iteration would silently stop at the shortest of the arrays
+    assert_eq!(
+        tables.dim(),
+        (input1_msg.shape()[0], nc),
+        "LOOKUP_PARA tables must have shape (n, nc_in)."
+    );
+    (
+        input1_msg.outer_iter_mut(),
+        output_msg.outer_iter_mut(),
+        tables.outer_iter(),
+    )
+        .into_par_iter()
+        .for_each_init(
+            || (Array1::zeros(nc), Array1::zeros(nc_out)),
+            |(in1_msg_scratch, out_msg_scratch), (mut input1_msg, mut output_msg, table)| {
+                in1_msg_scratch.fill(0.0);
+                out_msg_scratch.fill(0.0);
+                for i1 in 0..nc {
+                    let o = table[i1] as usize;
+                    in1_msg_scratch[i1] += output_msg[o];
+                    out_msg_scratch[o] += input1_msg[i1];
+                }
+                input1_msg.assign(in1_msg_scratch);
+                output_msg.assign(out_msg_scratch);
+            },
+        );
+}
+
+/// Mapping of the NEG function node over [0, nc).
+fn neg_map(nc: usize) -> AffineMap {
+    AffineMap {
+        a: nc as u64 - 1,
+        b: 0,
+        nc,
+    }
+}
+
+/// Outputs of a function node with a public value, for the input `i1` (one per value if unknown).
+fn cst_outputs(functype: &FuncType, i1: usize, value: u32, nc: usize) -> Vec<usize> {
+    if value == UNKNOWN_CST {
+        (0..(nc as u32))
+            .map(|c| cst_output(functype, i1, c, nc))
+            .collect()
+    } else {
+        vec![cst_output(functype, i1, value, nc)]
+    }
+}
+
+/// Output of a function node with a public value, for the input `i1`.
+fn cst_output(functype: &FuncType, i1: usize, value: u32, nc: usize) -> usize {
+    match functype {
+        FuncType::XORCST(_) => ((i1 as u32) ^ value) as usize,
+        FuncType::ANDCST(_) => ((i1 as u32) & value) as usize,
+        FuncType::ADDCST(_) => (((i1 as u32) + value) % (nc as u32)) as usize,
+        FuncType::MULCST(_) => (((i1 as u32) * value) % (nc as u32)) as usize,
+        _ => unreachable!(),
+    }
 }
-pub fn naive(inputs: &mut [&mut Array2<f64>], functype: &FuncType) {
+
+pub fn naive<S: DataMut<Elem = f64> + Send>(
+    inputs: &mut [&mut ArrayBase<S, Ix2>],
+    functype: &FuncType,
+) {
     let [output_msg, input1_msg, input2_msg]: &mut [_; 3] =
         inputs.try_into().unwrap();
     let nc = input1_msg.shape()[1];
@@ -370,6 +1457,10 @@ pub fn naive(inputs: &mut [&mut Array2<f64>], functype: &FuncType) {
                         // Unifies operators that can only be binary
                         let o = match functype {
                             FuncType::AND => i1 & i2,
+                            FuncType::NAND => !(i1 & i2) & (nc - 1),
+                            FuncType::NOR => !(i1 | i2) & (nc - 1),
+                            FuncType::XOR => i1 ^ i2,
+                            FuncType::ADD => (i1 + i2) % nc,
                             FuncType::MUL => {
                                 (((i1 * i2) as u32) % (nc as u32)) as usize
                             }
@@ -388,249 +1479,582 @@ pub fn naive(inputs: &mut [&mut Array2<f64>], functype: &FuncType) {
 }
 
 /// Compute an ADD function node between all edges.
-pub fn adds(inputs: &mut [&mut Array2<f64>]) {
+/// The product of the spectrums of all the messages is computed once, and the message to each
+/// edge is obtained by dividing it by the spectrum of the message from that edge.
+/// Each spectrum is scaled by its DC component (the sum of the distribution), such that all its
+/// coefficients have a modulus at most 1 and the product does not need renormalization.
+/// The first edge is the output: since the inputs are the output minus the other inputs, its
+/// spectrum is conjugated in the product, as well as the messages to the inputs.
+/// The inputs may have fewer values than the output (e.g., a 4-bit and an 8-bit operand into an
+/// 8-bit output): their messages are zero-padded to the size of the output, and the messages to
+/// them are restricted to their size.
+pub fn adds<S: DataMut<Elem = f64>>(inputs: &mut [&mut ArrayBase<S, Ix2>]) {
+    adds_with_clamp(inputs, Clamp::Fixed);
+}
+
+/// Same as `adds`, with the messages clipped according to `clamp`.
+fn adds_with_clamp<S: DataMut<Elem = f64>>(inputs: &mut [&mut ArrayBase<S, Ix2>], clamp: Clamp) {
     let n_runs = inputs[0].shape()[0];
     let nc = inputs[0].shape()[1];
-        
+    assert!(
+        inputs.iter().all(|input| input.shape()[1] <= nc),
+        "Inputs of ADD cannot be larger than its output."
+    );
+    if inputs.iter().any(|input| input.shape()[1] != nc) {
+        let mut padded: Vec<Array2<f64>> = inputs
+            .iter()
+            .map(|input| {
+                let mut padded = Array2::zeros((n_runs, nc));
+                padded
+                    .slice_mut(s![.., ..input.shape()[1]])
+                    .assign(&**input);
+                padded
+            })
+            .collect();
+        adds_with_clamp(&mut padded.iter_mut().collect::<Vec<_>>(), clamp);
+        for (input, padded) in inputs.iter_mut().zip(padded.iter()) {
+            let nc_input = input.shape()[1];
+            input.assign(&padded.slice(s![.., ..nc_input]));
+            normalize_distri(input);
+            make_non_zero(input, clamp);
+        }
+        return;
+    }
+
     // Sets the FFT operator
     let mut real_planner = RealFftPlanner::<f64>::new();
     let r2c = real_planner.plan_fft_forward(nc);
     let c2r = real_planner.plan_fft_inverse(nc);
 
+    let mut spectrums: Vec<Array1<Complex<f64>>> = (0..inputs.len())
+        .map(|_| Array1::zeros(nc / 2 + 1))
+        .collect();
     for run in 0..n_runs {
-        let mut spectrums: Vec<Array1<Complex<f64>>> = Vec::new();
-        let mut acc = Array1::<Complex<f64>>::ones(nc / 2 + 1);
-        inputs.iter_mut().for_each(|input| {
-            let mut input = input.slice_mut(s![run, ..]);
-            let input_fft_s = input.as_slice_mut().unwrap();
-            let mut spectrum = Array1::<Complex<f64>>::zeros(nc / 2 + 1);
-            let spec = spectrum.as_slice_mut().unwrap();
+        let mut msgs: Vec<ArrayViewMut1<f64>> =
+            inputs.iter_mut().map(|input| input.row_mut(run)).collect();
+        add_copy(&mut msgs, &mut spectrums, r2c.as_ref(), c2r.as_ref(), clamp);
+    }
+}
+
+/// ADD function node for the messages of a single copy (see `adds`), using the `spectrums`
+/// scratch-pad (one per message).
+fn add_copy(
+    msgs: &mut [ArrayViewMut1<f64>],
+    spectrums: &mut [Array1<Complex<f64>>],
+    r2c: &dyn realfft::RealToComplex<f64>,
+    c2r: &dyn realfft::ComplexToReal<f64>,
+    clamp: Clamp,
+) {
+    let mut acc = Array1::<Complex<f64>>::ones(spectrums[0].len());
+    spectrums
+        .iter_mut()
+        .zip(msgs.iter_mut())
+        .enumerate()
+        .for_each(|(i, (spectrum, input))| {
             // Computes the FFT
-            r2c.process(input_fft_s, spec).unwrap();
-            // Clips the transformed
-            spectrum.mapv_inplace(|x| {
-                if x.norm_sqr() == 0.0 {
-                    Complex::new(MIN_PROBA, MIN_PROBA)
-                } else {
-                    x
-                }
-            });
-            spectrums.push(spectrum);
+            r2c.process(
+                input.as_slice_mut().unwrap(),
+                spectrum.as_slice_mut().unwrap(),
+            )
+            .unwrap();
+            let dc = spectrum[0];
+            // Scales and clips the transformed
+            spectrum.mapv_inplace(|x| x / dc);
+            make_non_zero_spectrum(spectrum, clamp);
+            if i == 0 {
+                spectrum.mapv_inplace(|x| x.conj());
+            }
             // Accumulates through the operands
-            acc.zip_mut_with(&spectrums[spectrums.len() - 1], |x, y| *x = *x * y);
-            acc /= acc.sum();
+            acc *= &*spectrum;
         });
-        assert_eq!(inputs.len(), spectrums.len());
-        // Invert accumulation input_wise and invert transform.
-        spectrums
-            .iter_mut()
-            .zip(inputs.iter_mut())
-            .for_each(|(spectrum, input)| {
-                let mut input = input.slice_mut(s![run, ..]);
+    // Invert accumulation input_wise and invert transform.
+    spectrums
+        .iter_mut()
+        .zip(msgs.iter_mut())
+        .enumerate()
+        .for_each(|(i, (spectrum, input))| {
+            if i == 0 {
                 spectrum.zip_mut_with(&acc, |x, y| *x = *y / *x);
-                let input_fft_s = input.as_slice_mut().unwrap();
-                let spec = spectrum.as_slice_mut().unwrap();
-                c2r.process(spec, input_fft_s).unwrap();
-                make_non_zero(&mut input);
-                let s = input.sum();
-                input /= s;
-                make_non_zero(&mut input);
-            });
+            } else {
+                spectrum.zip_mut_with(&acc, |x, y| *x = (*y / *x).conj());
+            }
+            c2r.process(
+                spectrum.as_slice_mut().unwrap(),
+                input.as_slice_mut().unwrap(),
+            )
+            .unwrap();
+            make_non_zero(input, clamp);
+            let s = input.sum();
+            *input /= s;
+            make_non_zero(input, clamp);
+        });
+}
+
+/// Same as `adds`, with the copies processed in parallel (each thread having its own
+/// scratch-pad) rather than sequentially, which is faster when there are many copies (see
+/// `Func::with_batched`).
+pub fn adds_batched<S: DataMut<Elem = f64>>(inputs: &mut [&mut ArrayBase<S, Ix2>]) {
+    adds_batched_with_clamp(inputs, Clamp::Fixed);
+}
+
+/// Same as `adds_batched`, with the messages clipped according to `clamp`.
+fn adds_batched_with_clamp<S: DataMut<Elem = f64>>(
+    inputs: &mut [&mut ArrayBase<S, Ix2>],
+    clamp: Clamp,
+) {
+    let nc = inputs[0].shape()[1];
+    if inputs.iter().any(|input| input.shape()[1] != nc) {
+        // the inputs are padded to the size of the output
+        adds_with_clamp(inputs, clamp);
+        return;
+    }
+    let mut real_planner = RealFftPlanner::<f64>::new();
+    let r2c = real_planner.plan_fft_forward(nc);
+    let c2r = real_planner.plan_fft_inverse(nc);
+    let n_msgs = inputs.len();
+    copies_msgs(inputs).par_iter_mut().for_each_init(
+        || -> Vec<Array1<Complex<f64>>> {
+            (0..n_msgs).map(|_| Array1::zeros(nc / 2 + 1)).collect()
+        },
+        |spectrums, msgs| add_copy(msgs, spectrums, r2c.as_ref(), c2r.as_ref(), clamp),
+    );
+}
+
+/// Messages of `inputs`, grouped by copy.
+fn copies_msgs<'a, S: DataMut<Elem = f64>>(
+    inputs: &'a mut [&mut ArrayBase<S, Ix2>],
+) -> Vec<Vec<ArrayViewMut1<'a, f64>>> {
+    let mut copies: Vec<Vec<ArrayViewMut1<f64>>> = (0..inputs[0].shape()[0])
+        .map(|_| Vec::with_capacity(inputs.len()))
+        .collect();
+    for input in inputs.iter_mut() {
+        for (copy, msg) in copies.iter_mut().zip(input.outer_iter_mut()) {
+            copy.push(msg);
+        }
+    }
+    copies
+}
+
+/// ADD function node for the messages `msgs` of a single copy, without the belief propagation
+/// plumbing (e.g., for benchmarking).
+pub fn add_msgs(msgs: &mut [Array1<f64>]) {
+    let nc = msgs[0].len();
+    let mut real_planner = RealFftPlanner::<f64>::new();
+    let r2c = real_planner.plan_fft_forward(nc);
+    let c2r = real_planner.plan_fft_inverse(nc);
+    let mut spectrums: Vec<Array1<Complex<f64>>> =
+        (0..msgs.len()).map(|_| Array1::zeros(nc / 2 + 1)).collect();
+    let mut msgs: Vec<ArrayViewMut1<f64>> = msgs.iter_mut().map(|msg| msg.view_mut()).collect();
+    add_copy(
+        &mut msgs,
+        &mut spectrums,
+        r2c.as_ref(),
+        c2r.as_ref(),
+        Clamp::Fixed,
+    );
+}
+
+/// Distribution of the sum modulo `nc` of independent variables distributed according to
+/// `inputs` (i.e., their circular convolution), computed with a single product in the FFT domain.
+/// The result is normalized.
+pub fn add_distris_many(inputs: &[Array1<f64>], nc: usize) -> Array1<f64> {
+    assert!(
+        !inputs.is_empty() && inputs.iter().all(|input| input.len() == nc),
+        "add_distris_many requires at least one input, all of size nc."
+    );
+    let mut real_planner = RealFftPlanner::<f64>::new();
+    let r2c = real_planner.plan_fft_forward(nc);
+    let c2r = real_planner.plan_fft_inverse(nc);
+    let mut acc = Array1::<Complex<f64>>::ones(nc / 2 + 1);
+    let mut spectrum = Array1::<Complex<f64>>::zeros(nc / 2 + 1);
+    // the FFT uses its input as scratch-pad
+    let mut scratch = Array1::<f64>::zeros(nc);
+    for input in inputs {
+        scratch.assign(input);
+        r2c.process(
+            scratch.as_slice_mut().unwrap(),
+            spectrum.as_slice_mut().unwrap(),
+        )
+        .unwrap();
+        acc *= &spectrum;
+    }
+    let mut res = Array1::<f64>::zeros(nc);
+    c2r.process(acc.as_slice_mut().unwrap(), res.as_slice_mut().unwrap())
+        .unwrap();
+    // rounding errors may give slightly negative probabilities
+    res.mapv_inplace(|x| x.max(0.0));
+    let s = res.sum();
+    res /= s;
+    res
+}
+
+/// Compute the messages of an additive noise function node (see `FuncType::NOISE`), for all the
+/// copies: this is an ADD function node (see `add_copy`) whose second input has the fixed
+/// message `kernel`. The messages are clipped according to `clamp`.
+fn noise_update<S: DataMut<Elem = f64>>(
+    kernel: &Array1<f64>,
+    edge: &mut [&mut ArrayBase<S, Ix2>],
+    clamp: Clamp,
+) {
+    let [output_msg, input1_msg]: &mut [_; 2] = edge.try_into().unwrap();
+    let nc = output_msg.shape()[1];
+    assert!(
+        input1_msg.shape()[1] == nc && kernel.len() == nc,
+        "NOISE requires an input and a kernel of the same size as its output."
+    );
+    let mut real_planner = RealFftPlanner::<f64>::new();
+    let r2c = real_planner.plan_fft_forward(nc);
+    let c2r = real_planner.plan_fft_inverse(nc);
+    let mut spectrums: Vec<Array1<Complex<f64>>> =
+        (0..3).map(|_| Array1::zeros(nc / 2 + 1)).collect();
+    // the FFT uses its input as scratch-pad, and the message to the kernel is discarded
+    let mut kernel_msg = Array1::zeros(nc);
+    for run in 0..output_msg.shape()[0] {
+        kernel_msg.assign(kernel);
+        let mut msgs = vec![
+            output_msg.row_mut(run),
+            input1_msg.row_mut(run),
+            kernel_msg.view_mut(),
+        ];
+        add_copy(&mut msgs, &mut spectrums, r2c.as_ref(), c2r.as_ref(), clamp);
     }
 }
 
-/// Compute a MULT function node between all edges.
+/// Compute a MULT function node between all edges (output, input1, input2).
 /// Only works if nc is a prime number.
-pub fn mults(inputs: &mut [&mut Array2<f64>]) {
+/// The products with zero are handled directly. The non-zero values are indexed by their discrete
+/// logarithm (see `gen_log_table`), such that their products become additions over Z_{nc-1},
+/// which are computed with a real FFT: the output is the convolution of the inputs, and each
+/// input is the correlation of the output with the other input (as in `mults_pow2`).
+pub fn mults<S: DataMut<Elem = f64> + Send>(inputs: &mut [&mut ArrayBase<S, Ix2>]) {
+    mults_with_clamp(inputs, Clamp::Fixed);
+}
 
-    // Deal with the 0-th entry
-    let [output_msg, input1_msg, input2_msg]: &mut [_; 3] =
-        inputs.try_into().unwrap();
+/// Same as `mults`, with the messages clipped according to `clamp`.
+fn mults_with_clamp<S: DataMut<Elem = f64> + Send>(
+    inputs: &mut [&mut ArrayBase<S, Ix2>],
+    clamp: Clamp,
+) {
+    let [output_msg, input1_msg, input2_msg]: &mut [_; 3] = inputs.try_into().unwrap();
     let nc = input1_msg.shape()[1];
+    assert_eq!(prime_factors(nc.try_into().unwrap()).len(), 0);
+    // alog[j] is the non-zero value with logarithm j
+    let alog: Vec<u32> = gen_log_table(nc.try_into().unwrap());
+    let nc_1 = nc - 1;
+    let mut real_planner = RealFftPlanner::<f64>::new();
+    let r2c = real_planner.plan_fft_forward(nc_1);
+    let c2r = real_planner.plan_fft_inverse(nc_1);
+    // spectrum of the non-zero values of `p`, in the logarithm domain
+    let spectrum = |p: &[f64]| {
+        let mut x: Vec<f64> = alog.iter().map(|v| p[*v as usize]).collect();
+        let mut spec = vec![Complex::new(0.0, 0.0); nc_1 / 2 + 1];
+        r2c.process(&mut x, &mut spec).unwrap();
+        spec
+    };
+    // add the inverse transform of `spec` to the non-zero values of `res`
+    let inverse = |mut spec: Vec<Complex<f64>>, res: &mut [f64]| {
+        let mut x = vec![0.0; nc_1];
+        c2r.process(&mut spec, &mut x).unwrap();
+        alog.iter()
+            .zip(x.iter())
+            .for_each(|(v, x)| res[*v as usize] += x / nc_1 as f64);
+    };
     (
         input1_msg.outer_iter_mut(),
         input2_msg.outer_iter_mut(),
         output_msg.outer_iter_mut(),
     )
         .into_par_iter()
-        // Use for_each_init to limit the number of a allocation of the message
-        // scratch-pad.
-        .for_each_init(
-            || (Array1::zeros(nc), Array1::zeros(nc), Array1::zeros(nc)),
-            |(in1_msg_scratch, in2_msg_scratch, out_msg_scratch),
-            (mut input1_msg, mut input2_msg, mut output_msg)| {
-                out_msg_scratch.fill(0.0);
-                in1_msg_scratch.fill(0.0);
-                in2_msg_scratch.fill(0.0);
-
-                for i1 in 0..1 {
-                    for i2 in 0..nc {
-                        // Unifies operators that can only be binary
-                        let o = (((i1 * i2) as u32) % (nc as u32)) as usize;
-                        assert_eq!(o, 0);
-
-                        in1_msg_scratch[i1] += input2_msg[i2] * output_msg[o];
-                        in2_msg_scratch[i2] += input1_msg[i1] * output_msg[o];
-                        out_msg_scratch[o] += input1_msg[i1] * input2_msg[i2];
-                    }
-                }
-                for i1 in 1..nc {
-                    for i2 in 0..1 {
-                        // Unifies operators that can only be binary
-                        let o = (((i1 * i2) as u32) % (nc as u32)) as usize;
-                        assert_eq!(o, 0);
+        .for_each(|(mut input1_msg, mut input2_msg, mut output_msg)| {
+            let p1 = input1_msg.to_vec();
+            let p2 = input2_msg.to_vec();
+            let po = output_msg.to_vec();
+            let (s1, s2): (f64, f64) = (p1.iter().sum(), p2.iter().sum());
+            // products with a zero operand
+            let mut new1 = vec![p2[0] * po[0]; nc];
+            let mut new2 = vec![p1[0] * po[0]; nc];
+            let mut newo = vec![0.0; nc];
+            new1[0] = s2 * po[0];
+            new2[0] = s1 * po[0];
+            newo[0] = p1[0] * s2 + (s1 - p1[0]) * p2[0];
+            // products of non-zero values
+            let (f1, f2, fo) = (spectrum(&p1), spectrum(&p2), spectrum(&po));
+            let product = |a: &[Complex<f64>], b: &[Complex<f64>], conj: bool| {
+                a.iter()
+                    .zip(b.iter())
+                    .map(|(a, b)| if conj { a * b.conj() } else { a * b })
+                    .collect::<Vec<_>>()
+            };
+            inverse(product(&f1, &f2, false), &mut newo[..]);
+            inverse(product(&fo, &f2, true), &mut new1[..]);
+            inverse(product(&fo, &f1, true), &mut new2[..]);
+            input1_msg.assign(&Array1::from(new1));
+            input2_msg.assign(&Array1::from(new2));
+            output_msg.assign(&Array1::from(newo));
+            make_non_zero(&mut input1_msg, clamp);
+            make_non_zero(&mut input2_msg, clamp);
+            make_non_zero(&mut output_msg, clamp);
+        });
+}
 
-                        in1_msg_scratch[i1] += input2_msg[i2] * output_msg[o];
-                        in2_msg_scratch[i2] += input1_msg[i1] * output_msg[o];
-                        out_msg_scratch[o] += input1_msg[i1] * input2_msg[i2];
-                    }
-                }
-                input1_msg[0] = in1_msg_scratch[0];
-                input2_msg[0] = in2_msg_scratch[0];
-                output_msg[0]= out_msg_scratch[0];
-            },
-            );
-    // println!("After processing 0-th entry");
-    // inputs.iter_mut().for_each(|input| {
-    //     println!("{}", input);
-    // });
-    // println!("**************************");
+/// Group of the odd residues modulo 2^m (for m >= 1), which is isomorphic to Z_2 x Z_{2^(m-2)}
+/// for m >= 2 with u = (-1)^s * 5^e mod 2^m, and trivial for m = 1.
+struct OddUnits {
+    /// Number of values of s (1 or 2).
+    signs: usize,
+    /// Number of values of e.
+    len: usize,
+    /// Index s*len+e of each odd residue u in [0, 2^m) (unused for even values).
+    index: Vec<usize>,
+    fft: Arc<dyn Fft<f64>>,
+    ifft: Arc<dyn Fft<f64>>,
+}
 
-    let n_runs = inputs[0].shape()[0];
-    let nc = inputs[0].shape()[1];
-    assert_eq!(prime_factors(nc.try_into().unwrap()).len(), 0);
+impl OddUnits {
+    fn new(m: u32, planner: &mut FftPlanner<f64>) -> Self {
+        let (signs, len) = if m == 1 { (1, 1) } else { (2, 1 << (m - 2)) };
+        let modulus = 1usize << m;
+        let mut index = vec![0; modulus];
+        let mut u = 1;
+        for e in 0..len {
+            index[u] = e;
+            if signs == 2 {
+                index[modulus - u] = len + e;
+            }
+            u = (5 * u) % modulus;
+        }
+        OddUnits {
+            signs,
+            len,
+            index,
+            fft: planner.plan_fft_forward(len),
+            ifft: planner.plan_fft_inverse(len),
+        }
+    }
 
-    // Gets the log table
-    let log_table: Vec<u32> = gen_log_table(nc.try_into().unwrap());
+    /// Number of odd residues.
+    fn size(&self) -> usize {
+        self.signs * self.len
+    }
 
-    // Applies the alog
-    inputs.iter_mut().for_each(|input| {
-        for run in 0..n_runs {
-            let mut input = input.slice_mut(s![run, ..]);
-            let mut input_perm = input.as_slice_mut().unwrap();
-            let tmp = input_perm.to_vec();
-            for (i, log) in (1..nc).zip(log_table.iter()) {
-                input_perm[i as usize] = tmp[(*log) as usize];
-            }
+    /// Distribution over the odd residues of the values of `distri` with 2-adic valuation `val`
+    /// (i.e., the values u*2^val with u odd), reduced modulo 2^m.
+    fn project(&self, distri: &[f64], val: u32) -> Vec<f64> {
+        let mask = self.index.len() - 1;
+        let mut res = vec![0.0; self.size()];
+        for u in (1..(distri.len() >> val)).step_by(2) {
+            res[self.index[u & mask]] += distri[u << val];
         }
-    });
+        res
+    }
 
-    //println!("Before FFT");
-    //inputs.iter_mut().for_each(|input| {
-    //    println!("{}", input);
-    //});
+    /// Fourier transform over the group.
+    fn spectrum(&self, f: &[f64]) -> Vec<Complex<f64>> {
+        let mut x: Vec<Complex<f64>> = f.iter().map(|f| Complex::new(*f, 0.0)).collect();
+        x.chunks_mut(self.len).for_each(|x| self.fft.process(x));
+        self.butterfly(&mut x);
+        x
+    }
 
-    // Sets the FFT operator
-    let nc_1 = nc-1;
-    let mut real_planner = RealFftPlanner::<f64>::new();
-    let r2c = real_planner.plan_fft_forward(nc_1);
-    let c2r = real_planner.plan_fft_inverse(nc_1);
+    /// Inverse Fourier transform over the group (the imaginary part is dropped).
+    fn inverse(&self, mut x: Vec<Complex<f64>>) -> Vec<f64> {
+        self.butterfly(&mut x);
+        x.chunks_mut(self.len).for_each(|x| self.ifft.process(x));
+        x.iter().map(|x| x.re / self.size() as f64).collect()
+    }
 
-    for run in 0..n_runs {
-        let mut spectrums: Vec<Array1<Complex<f64>>> = Vec::new();
-        let mut acc = Array1::<Complex<f64>>::ones(nc_1 / 2 + 1);
-        inputs.iter_mut().for_each(|input| {
-            let mut input = input.slice_mut(s![run, 1..]);
-            let input_fft_s = input.as_slice_mut().unwrap();
-            let mut spectrum = Array1::<Complex<f64>>::zeros(nc_1 / 2 + 1);
-            let spec = spectrum.as_slice_mut().unwrap();
-            // Computes the FFT
-            r2c.process(input_fft_s, spec).unwrap();
-            // Clips the transformed
-            spectrum.mapv_inplace(|x| {
-                if x.norm_sqr() == 0.0 {
-                    Complex::new(MIN_PROBA, MIN_PROBA)
-                } else {
-                    x
-                }
-            });
-            spectrums.push(spectrum);
-            // Accumulates through the operands
-            acc.zip_mut_with(&spectrums[spectrums.len() - 1], |x, y| *x = *x * y);
-            acc /= acc.sum();
-        });
-        assert_eq!(inputs.len(), spectrums.len());
-        // Invert accumulation input_wise and invert transform.
-        spectrums
-            .iter_mut()
-            .zip(inputs.iter_mut())
-            .for_each(|(spectrum, input)| {
-                let P0 = input.slice_mut(s![run, 0]).as_slice_mut().unwrap().to_vec();
-                let mut input = input.slice_mut(s![run, 1..]);
-                spectrum.zip_mut_with(&acc, |x, y| *x = *y / *x);
-                let input_fft_s = input.as_slice_mut().unwrap();
-                let spec = spectrum.as_slice_mut().unwrap();
-                c2r.process(spec, input_fft_s).unwrap();
-                make_non_zero(&mut input);
-                let s = input.sum();
-                // Normalization is sligthly trickier here ;-)
-                input /= s;
-                input *= (1.0 as f64) - P0[0];
-                make_non_zero(&mut input);
+    /// Transform over the Z_2 component (its own inverse, up to a factor 2).
+    fn butterfly(&self, x: &mut [Complex<f64>]) {
+        if self.signs == 2 {
+            let (pos, neg) = x.split_at_mut(self.len);
+            pos.iter_mut().zip(neg.iter_mut()).for_each(|(p, n)| {
+                let (s, d) = (*p + *n, *p - *n);
+                *p = s;
+                *n = d;
             });
+        }
     }
+}
 
-    // println!("After FFT");
-    // inputs.iter_mut().for_each(|input| {
-    //     println!("{}", input);
-    // });
+/// Compute a MUL function node over Z_{2^k} between all edges (output, input1, input2).
+/// Each non-zero value is written u*2^v with u odd: the valuation of a product is the sum of the
+/// valuations (zero if it reaches k), and its odd part is the product of the odd parts, reduced
+/// modulo 2^(k-v). For each pair of valuations, the odd parts are combined with a transform over
+/// the group of the odd residues (see `OddUnits`), while the products that reach zero are
+/// handled directly.
+pub fn mults_pow2<S: DataMut<Elem = f64> + Send>(inputs: &mut [&mut ArrayBase<S, Ix2>]) {
+    mults_pow2_with_clamp(inputs, Clamp::Fixed);
+}
 
-    // Applies the log
-    inputs.iter_mut().for_each(|input| {
-        for run in 0..n_runs {
-            let mut input = input.slice_mut(s![run, ..]);
-            let mut input_perm = input.as_slice_mut().unwrap();
-            let tmp = input_perm.to_vec();
-            for (i, log) in (1..nc).zip(log_table.iter()) {
-                input_perm[(*log) as usize] = tmp[i as usize];
+/// Same as `mults_pow2`, with the messages clipped according to `clamp`.
+fn mults_pow2_with_clamp<S: DataMut<Elem = f64> + Send>(
+    inputs: &mut [&mut ArrayBase<S, Ix2>],
+    clamp: Clamp,
+) {
+    let [output_msg, input1_msg, input2_msg]: &mut [_; 3] = inputs.try_into().unwrap();
+    let nc = input1_msg.shape()[1];
+    assert!(
+        nc.is_power_of_two() && nc > 1,
+        "MUL over Z_2^k requires nc to be a power of two."
+    );
+    let k = nc.trailing_zeros();
+    let mut planner = FftPlanner::new();
+    // units[m-1] is the group of the odd residues modulo 2^m
+    let units: Vec<OddUnits> = (1..=k).map(|m| OddUnits::new(m, &mut planner)).collect();
+    let val = |x: usize| if x == 0 { k } else { x.trailing_zeros() };
+    (
+        input1_msg.outer_iter_mut(),
+        input2_msg.outer_iter_mut(),
+        output_msg.outer_iter_mut(),
+    )
+        .into_par_iter()
+        .for_each(|(mut input1_msg, mut input2_msg, mut output_msg)| {
+            let p1 = input1_msg.to_vec();
+            let p2 = input2_msg.to_vec();
+            let po = output_msg.to_vec();
+            // total probability of each valuation
+            let mass = |p: &[f64]| {
+                let mut mass = vec![0.0; k as usize + 1];
+                p.iter()
+                    .enumerate()
+                    .for_each(|(x, p)| mass[val(x) as usize] += p);
+                mass
+            };
+            let (mass1, mass2) = (mass(&p1), mass(&p2));
+            let mut new1 = vec![0.0; nc];
+            let mut new2 = vec![0.0; nc];
+            let mut newo = vec![0.0; nc];
+            // messages to the inputs, per valuation, from the products that are zero
+            let mut zero1 = vec![0.0; k as usize + 1];
+            let mut zero2 = vec![0.0; k as usize + 1];
+            for v1 in 0..=k {
+                for v2 in 0..=k {
+                    if v1 + v2 >= k {
+                        newo[0] += mass1[v1 as usize] * mass2[v2 as usize];
+                        zero1[v1 as usize] += mass2[v2 as usize] * po[0];
+                        zero2[v2 as usize] += mass1[v1 as usize] * po[0];
+                        continue;
+                    }
+                    let vo = v1 + v2;
+                    let group = &units[(k - vo - 1) as usize];
+                    let mask = group.index.len() - 1;
+                    let f1 = group.spectrum(&group.project(&p1, v1));
+                    let f2 = group.spectrum(&group.project(&p2, v2));
+                    let fo = group.spectrum(&group.project(&po, vo));
+                    let o = group.inverse(f1.iter().zip(f2.iter()).map(|(a, b)| a * b).collect());
+                    let g1 = group.inverse(
+                        fo.iter()
+                            .zip(f2.iter())
+                            .map(|(a, b)| a * b.conj())
+                            .collect(),
+                    );
+                    let g2 = group.inverse(
+                        fo.iter()
+                            .zip(f1.iter())
+                            .map(|(a, b)| a * b.conj())
+                            .collect(),
+                    );
+                    for u in (1..(nc >> vo)).step_by(2) {
+                        newo[u << vo] += o[group.index[u]];
+                    }
+                    for u in (1..(nc >> v1)).step_by(2) {
+                        new1[u << v1] += g1[group.index[u & mask]];
+                    }
+                    for u in (1..(nc >> v2)).step_by(2) {
+                        new2[u << v2] += g2[group.index[u & mask]];
+                    }
+                }
             }
-        }
-    });
-    // println!("After Permutation");
-    // inputs.iter_mut().for_each(|input| {
-    //     println!("{}", input);
-    // });
-    // println!("**************************");
-
+            for x in 0..nc {
+                new1[x] += zero1[val(x) as usize];
+                new2[x] += zero2[val(x) as usize];
+            }
+            input1_msg.assign(&Array1::from(new1));
+            input2_msg.assign(&Array1::from(new2));
+            output_msg.assign(&Array1::from(newo));
+            make_non_zero(&mut input1_msg, clamp);
+            make_non_zero(&mut input2_msg, clamp);
+            make_non_zero(&mut output_msg, clamp);
+        });
 }
 
 /// Compute a XOR function node between all edges.
-pub fn xors(inputs: &mut [&mut Array2<f64>]) {
+pub fn xors<S: DataMut<Elem = f64>>(inputs: &mut [&mut ArrayBase<S, Ix2>]) {
+    xors_with_clamp(inputs, Clamp::Fixed);
+}
+
+/// Same as `xors`, with the messages clipped according to `clamp`.
+fn xors_with_clamp<S: DataMut<Elem = f64>>(inputs: &mut [&mut ArrayBase<S, Ix2>], clamp: Clamp) {
     let n_runs = inputs[0].shape()[0];
-    let nc = inputs[0].shape()[1];
     for run in 0..n_runs {
-        let mut acc = Array1::<f64>::ones(nc);
-        // Accumulate in a Walsh transformed domain.
-        inputs.iter_mut().for_each(|input| {
-            let mut input = input.slice_mut(s![run, ..]);
-            let input_fwt_s = input.as_slice_mut().unwrap();
-            fwht(input_fwt_s, nc);
-            // non zero with input_fwt_s possibly negative
-            input.mapv_inplace(|x| {
-                if x.is_sign_positive() {
-                    x.max(MIN_PROBA)
-                } else {
-                    x.min(-MIN_PROBA)
-                }
-            });
-            acc.zip_mut_with(&input, |x, y| *x = *x * y);
-            acc /= acc.sum();
-        });
-        // Invert accumulation input-wise and invert transform.
-        inputs.iter_mut().for_each(|input| {
-            let mut input = input.slice_mut(s![run, ..]);
-            input.zip_mut_with(&acc, |x, y| *x = *y / *x);
-            let input_fwt_s = input.as_slice_mut().unwrap();
-            fwht(input_fwt_s, nc);
-            make_non_zero(&mut input);
-            let s = input.sum();
-            input /= s;
-            make_non_zero(&mut input);
-        });
+        let mut msgs: Vec<ArrayViewMut1<f64>> =
+            inputs.iter_mut().map(|input| input.row_mut(run)).collect();
+        xor_copy(&mut msgs, clamp);
     }
 }
 
+/// XOR function node for the messages of a single copy (see `xors`).
+fn xor_copy(msgs: &mut [ArrayViewMut1<f64>], clamp: Clamp) {
+    let mut acc = Array1::<f64>::ones(msgs[0].len());
+    // Accumulate in a Walsh transformed domain.
+    msgs.iter_mut().for_each(|input| {
+        walsh_hadamard(input.as_slice_mut().unwrap(), false);
+        // non zero with input_fwt_s possibly negative
+        make_non_zero_walsh(input, clamp);
+        acc.zip_mut_with(input, |x, y| *x = *x * y);
+        acc /= acc.sum();
+    });
+    // Invert accumulation input-wise and invert transform.
+    msgs.iter_mut().for_each(|input| {
+        input.zip_mut_with(&acc, |x, y| *x = *y / *x);
+        walsh_hadamard(input.as_slice_mut().unwrap(), false);
+        make_non_zero(input, clamp);
+        let s = input.sum();
+        *input /= s;
+        make_non_zero(input, clamp);
+    });
+}
+
+/// Same as `xors`, with the copies processed in parallel rather than sequentially, which is
+/// faster when there are many copies (see `Func::with_batched`).
+pub fn xors_batched<S: DataMut<Elem = f64>>(inputs: &mut [&mut ArrayBase<S, Ix2>]) {
+    xors_batched_with_clamp(inputs, Clamp::Fixed);
+}
+
+/// Same as `xors_batched`, with the messages clipped according to `clamp`.
+fn xors_batched_with_clamp<S: DataMut<Elem = f64>>(
+    inputs: &mut [&mut ArrayBase<S, Ix2>],
+    clamp: Clamp,
+) {
+    copies_msgs(inputs)
+        .par_iter_mut()
+        .for_each(|msgs| xor_copy(msgs, clamp));
+}
+
+/// XOR function node for the messages `msgs` of a single copy, without the belief propagation
+/// plumbing (e.g., for benchmarking).
+pub fn xor_msgs(msgs: &mut [Array1<f64>]) {
+    let mut msgs: Vec<ArrayViewMut1<f64>> = msgs.iter_mut().map(|msg| msg.view_mut()).collect();
+    xor_copy(&mut msgs, Clamp::Fixed);
+}
+
+/// MUL function node for the messages `msgs` (output, input1, input2) of a single copy, without
+/// the belief propagation plumbing (e.g., for benchmarking).
+/// It uses the same algorithm as in a graph (see `mul_is_fast`).
+pub fn mul_msgs(msgs: &mut [Array1<f64>]) {
+    let nc = msgs[0].len();
+    let mut inputs: Vec<Array2<f64>> = msgs
+        .iter()
+        .map(|msg| msg.clone().into_shape((1, nc)).unwrap())
+        .collect();
+    FuncType::MUL.update_msgs(&mut inputs.iter_mut().collect::<Vec<_>>(), Clamp::Fixed);
+    msgs.iter_mut()
+        .zip(inputs.iter())
+        .for_each(|(msg, input)| msg.assign(&input.row(0)));
+}
+
 /// Run the belief propagation algorithm on the python representation of a factor graph.
+/// Returns `BPError::FieldTooLarge` for graphs beyond the default limits (see `BpLimits`).
 pub fn run_bp(
     functions: &[Func],
     variables: &mut [Var],
@@ -643,87 +2067,3230 @@ pub fn run_bp(
     n: usize,
     // show a progress bar
     progress: bool,
-) -> Result<(), ()> {
-    // Scratch array containing all the edge's messages.
-    let mut edges: Vec<Array2<f64>> = vec![Array2::<f64>::ones((n, nc)); edge];
+) -> Result<(), BPError> {
+    run_bp_with_options(
+        functions,
+        variables,
+        it,
+        edge,
+        nc,
+        n,
+        progress,
+        RunBpOptions::default(),
+    )
+}
+
+/// Same as `run_bp`, with the `options` of the propagation.
+pub fn run_bp_with_options(
+    functions: &[Func],
+    variables: &mut [Var],
+    it: usize,
+    edge: usize,
+    nc: usize,
+    n: usize,
+    progress: bool,
+    options: RunBpOptions,
+) -> Result<(), BPError> {
+    run_bp_workspace(
+        functions,
+        variables,
+        it,
+        edge,
+        nc,
+        n,
+        progress,
+        options,
+        &mut BpWorkspace::new(),
+    )
+}
 
-    // Mapping of each edge to its (function node id, position in function node).
-    let mut vec_funcs_id: Vec<(usize, usize)> = vec![(0, 0); edge];
-    // Mapping of each edge to its (variable node id, position in variable node).
-    let mut vec_vars_id: Vec<(usize, usize)> = vec![(0, 0); edge];
+/// Options of the belief propagation (see `run_bp_with_options`), the defaults being those of
+/// `run_bp`.
+/// Only `run_bp_with_options`, `run_bp_workspace` and `run_bp_components` take these options:
+/// the other entry points clamp the messages with `Clamp::Fixed`, and use the
+/// underflow-resistant normalization only if they have a `stable_norm` argument.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RunBpOptions {
+    /// Initial messages on the edges.
+    pub init: InitStrategy,
+    /// Use the underflow-resistant normalization (see `normalize_distri_stable`), which
+    /// preserves the information of distributions whose probabilities are all tiny.
+    pub stable_norm: bool,
+    /// Clamping of the messages (see `Clamp`), applied after each update of the function and
+    /// variable nodes and on the transformed messages in the FFT-based operations.
+    pub clamp: Clamp,
+    /// Use the log-likelihood ratio implementation (see `run_bp_binary`) if the graph supports
+    /// it: nc = 2, only XOR, XORCST and LOOKUP function nodes, variables with 2 values, and the
+    /// other options at their default. It is faster, but does not use the workspace (see
+    /// `run_bp_workspace`) and its results differ slightly, as its messages are not clipped to
+    /// `MIN_PROBA`.
+    pub binary: bool,
+}
 
-    // map all python functions to rust ones + generate the mapping in vec_functs_id
-    let functions_rust = functions;
-    for (i, f) in functions.iter().enumerate() {
-        f.neighboors.iter().enumerate().for_each(|(j, x)| {
-            vec_funcs_id[*x] = (i, j);
-        });
+/// Initial messages from the variables to the function nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitStrategy {
+    /// Uniform messages for all the variables.
+    Uniform,
+    /// Initial distribution of the variable for profiled variables, uniform otherwise (see
+    /// `init_edges`).
+    Prior,
+    /// Random normalized messages, generated from the seed (e.g., to study the sensitivity of
+    /// the belief propagation to its initialization on loopy graphs).
+    Random(u64),
+}
+
+impl Default for InitStrategy {
+    fn default() -> Self {
+        InitStrategy::Prior
     }
+}
 
-    // map all python var to rust ones
-    // generate the edge mapping in vec_vars_id
-    // init the messages along the edges with initial distributions
-    for (i, var) in variables.iter().enumerate() {
-        var.neighboors.iter().enumerate().for_each(|(j, x)| {
-            vec_vars_id[*x] = (i, j);
-        });
-        match &var.vartype {
-            VarType::ProfilePara { distri_orig, .. }
-            | VarType::ProfileSingle { distri_orig, .. } => var.neighboors.iter().for_each(|x| {
-                let v = &mut edges[*x];
-                let distri_orig = distri_orig.broadcast(v.shape()).unwrap();
-                v.assign(&distri_orig);
-            }),
-            _ => {}
+/// Buffers used by the belief propagation algorithm, that can be re-used across runs.
+/// When running repeatedly on graphs with the same number of edges, copies and field size,
+/// `run_bp_workspace` allocates the edge messages only once for the first run, while `run_bp`
+/// allocates `edge` arrays of shape `(n, nc)` at every run.
+/// The workspace may also hold a thread pool, in which the parallel loops are run (instead of the
+/// global rayon pool), and the limits on the size of the graphs (see `BpLimits`).
+#[derive(Default)]
+pub struct BpWorkspace {
+    edges: Vec<Array2<f64>>,
+    thread_pool: Option<rayon::ThreadPool>,
+    layout: EdgeLayout,
+    edges_contiguous: Array3<f64>,
+    limits: BpLimits,
+}
+
+/// Default maximum size of the field (see `BpLimits`).
+pub const DEFAULT_MAX_NC: usize = 1 << 16;
+
+/// Default maximum memory of the messages on the edges, in bytes (see `BpLimits`).
+pub const DEFAULT_MAX_EDGE_BYTES: usize = 1 << 34;
+
+/// Limits on the size of a graph, checked by `run_bp_workspace` before allocating the messages,
+/// such that a mistaken field size returns `BPError::FieldTooLarge` instead of exhausting the
+/// memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BpLimits {
+    /// Maximum size of the field `nc`.
+    pub max_nc: usize,
+    /// Maximum memory of the messages on all the edges, in bytes.
+    pub max_edge_bytes: usize,
+}
+
+impl Default for BpLimits {
+    fn default() -> Self {
+        BpLimits {
+            max_nc: DEFAULT_MAX_NC,
+            max_edge_bytes: DEFAULT_MAX_EDGE_BYTES,
         }
     }
+}
 
-    let mut bp_iter = || {
-        // This is a technique for runtime borrow-checking: we take reference on all the edges
-        // at once, put them into options, then extract the references out of the options, one
-        // at a time and out-of-order.
-        let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f64>>> =
-            edges.iter_mut().map(|x| Some(x)).collect();
-        let mut edge_for_func: Vec<Vec<&mut Array2<f64>>> = functions_rust
+impl BpLimits {
+    /// Check that the field size `nc` and the messages of a graph with `edge` edges and `n`
+    /// copies are within the limits.
+    fn check(&self, variables: &[Var], edge: usize, nc: usize, n: usize) -> Result<(), BPError> {
+        let edge_bytes = edge_widths(variables, edge, nc)
             .iter()
-            .map(|f| {
-                f.neighboors
+            .fold(0usize, |acc, width| {
+                acc.saturating_add(
+                    width
+                        .saturating_mul(n)
+                        .saturating_mul(std::mem::size_of::<f64>()),
+                )
+            });
+        if nc > self.max_nc || edge_bytes > self.max_edge_bytes {
+            Err(BPError::FieldTooLarge { nc, edge_bytes })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Storage of the messages on the edges of the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeLayout {
+    /// One `(n, nc)` array per edge.
+    Separate,
+    /// All the messages in a single contiguous `(edge, n, nc)` array (see
+    /// `run_bp_edges_contiguous`), which improves the cache locality and could be offloaded to
+    /// an accelerator. This is only used when all the variables have `nc` values, and the
+    /// `Separate` layout is used otherwise.
+    Contiguous,
+}
+
+impl Default for EdgeLayout {
+    fn default() -> Self {
+        EdgeLayout::Separate
+    }
+}
+
+impl BpWorkspace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Workspace running the belief propagation in `thread_pool`.
+    pub fn with_thread_pool(thread_pool: rayon::ThreadPool) -> Self {
+        Self {
+            thread_pool: Some(thread_pool),
+            ..Self::default()
+        }
+    }
+
+    /// Store the messages with `layout`.
+    pub fn with_layout(self, layout: EdgeLayout) -> Self {
+        Self { layout, ..self }
+    }
+
+    /// Check the size of the graphs against `limits` (instead of `BpLimits::default()`).
+    pub fn with_limits(self, limits: BpLimits) -> Self {
+        Self { limits, ..self }
+    }
+}
+
+/// Initial messages for a graph (see `init_edges`), re-using the buffers in `edges` when
+/// possible.
+fn reuse_edges<'a>(
+    edges: &'a mut Vec<Array2<f64>>,
+    variables: &[Var],
+    edge: usize,
+    nc: usize,
+    n: usize,
+    init: InitStrategy,
+) -> &'a mut [Array2<f64>] {
+    let widths = edge_widths(variables, edge, nc);
+    edges.truncate(edge);
+    edges
+        .iter_mut()
+        .zip(widths.iter())
+        .filter(|(x, nc)| x.dim() != (n, **nc))
+        .for_each(|(x, nc)| *x = Array2::ones((n, *nc)));
+    let len = edges.len();
+    edges.extend(widths[len..].iter().map(|nc| Array2::ones((n, *nc))));
+    init_messages(variables, edges, init);
+    edges
+}
+
+/// Same as `run_bp`, using the buffers and thread pool in `workspace`.
+pub fn run_bp_workspace(
+    functions: &[Func],
+    variables: &mut [Var],
+    it: usize,
+    edge: usize,
+    nc: usize,
+    n: usize,
+    progress: bool,
+    options: RunBpOptions,
+    workspace: &mut BpWorkspace,
+) -> Result<(), BPError> {
+    let init = options.init;
+    check_options(&options);
+    check_edges(functions, variables, edge, nc)?;
+    workspace.limits.check(variables, edge, nc, n)?;
+    let BpWorkspace {
+        edges,
+        thread_pool,
+        layout,
+        edges_contiguous,
+        ..
+    } = workspace;
+    let mut run = || {
+        // The binary implementation always starts from the priors, with the fixed clamping.
+        if options.binary
+            && nc == 2
+            && init == InitStrategy::Prior
+            && !options.stable_norm
+            && options.clamp == Clamp::Fixed
+            && functions.iter().all(binary_supported)
+            && edge_widths(variables, edge, nc).iter().all(|w| *w == 2)
+        {
+            return run_bp_binary(functions, variables, it, edge, n, progress);
+        }
+        if *layout == EdgeLayout::Contiguous
+            && edge_widths(variables, edge, nc).iter().all(|w| *w == nc)
+        {
+            if edges_contiguous.dim() != (edge, n, nc) {
+                *edges_contiguous = Array3::ones((edge, n, nc));
+            }
+            let mut edges: Vec<ArrayViewMut2<f64>> = edges_contiguous.outer_iter_mut().collect();
+            init_messages(variables, &mut edges, init);
+            return bp_edges_contiguous(
+                functions,
+                variables,
+                edges_contiguous,
+                it,
+                progress,
+                options.stable_norm,
+                options.clamp,
+            );
+        }
+        // Scratch array containing all the edge's messages.
+        let edges = reuse_edges(edges, variables, edge, nc, n, init);
+        bp_edges(
+            functions,
+            variables,
+            edges,
+            it,
+            progress,
+            options.stable_norm,
+            options.clamp,
+        )
+    };
+    match thread_pool {
+        Some(thread_pool) => thread_pool.install(run),
+        None => run(),
+    }
+}
+
+/// Check that the options of a run are valid (panics otherwise).
+fn check_options(options: &RunBpOptions) {
+    if let Clamp::Relative(ratio) = options.clamp {
+        assert!(
+            ratio > 0.0 && ratio < 1.0,
+            "The relative clamping ratio must be in (0, 1)."
+        );
+    }
+}
+
+/// Check that the nodes are adjacent to the `edge` edges of the graph, that the XOR function
+/// nodes have at least one input and a valid field size (see `xor_field_size`), that the inputs
+/// of the ADD function nodes are not larger than their output, and that the NAND and NOR
+/// function nodes have power-of-two field sizes.
+fn check_edges(
+    functions: &[Func],
+    variables: &[Var],
+    edge: usize,
+    nc: usize,
+) -> Result<(), BPError> {
+    let neighboors = functions
+        .iter()
+        .map(|f| &f.neighboors)
+        .chain(variables.iter().map(|v| &v.neighboors));
+    if let Some(e) = neighboors.flatten().find(|e| **e >= edge) {
+        return Err(BPError::InvalidGraph(format!(
+            "edge {} is out of range, the graph has {} edges.",
+            e, edge
+        )));
+    }
+    let widths = edge_widths(variables, edge, nc);
+    for (id, func) in functions.iter().enumerate() {
+        if let FuncType::XOR = func.functype {
+            if func.neighboors.len() < 2 {
+                return Err(BPError::InvalidGraph(format!(
+                    "XOR function {} has no input.",
+                    id
+                )));
+            }
+            xor_field_size(id, func, &widths)?;
+        }
+        if let FuncType::ADD = func.functype {
+            let neighboors = func.ordered_neighboors();
+            if let Some((&output, inputs)) = neighboors.split_first() {
+                if let Some(e) = inputs.iter().find(|e| widths[**e] > widths[output]) {
+                    return Err(BPError::InvalidGraph(format!(
+                        "ADD function {} has an input of size {} on edge {}, which is larger \
+                        than its output of size {}.",
+                        id, widths[*e], e, widths[output]
+                    )));
+                }
+            }
+        }
+        let op = match func.functype {
+            FuncType::NAND => Some("NAND"),
+            FuncType::NOR => Some("NOR"),
+            _ => None,
+        };
+        if let Some(op) = op {
+            if let Some(e) = func
+                .neighboors
+                .iter()
+                .find(|e| !widths[**e].is_power_of_two())
+            {
+                return Err(BPError::InvalidGraph(format!(
+                    "{} function {} has field size {} on edge {}, which is not a power of two.",
+                    op, id, widths[*e], e
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the description of a graph with `edge` edges over a field of size `nc` before running
+/// belief propagation on it: in addition to the checks of `run_bp` (the edges are in range and
+/// the XOR function nodes are valid), every variable and function node must have at least one
+/// edge. A node without edges does not contribute to the propagation, and usually comes from a
+/// mistake in the construction of the graph.
+pub fn validate_graph(
+    functions: &[Func],
+    variables: &[Var],
+    edge: usize,
+    nc: usize,
+) -> Result<(), BPError> {
+    check_edges(functions, variables, edge, nc)?;
+    if let Some(id) = variables.iter().position(|v| v.neighboors.is_empty()) {
+        return Err(BPError::DisconnectedNode {
+            id,
+            is_function: false,
+        });
+    }
+    if let Some(id) = functions.iter().position(|f| f.neighboors.is_empty()) {
+        return Err(BPError::DisconnectedNode {
+            id,
+            is_function: true,
+        });
+    }
+    Ok(())
+}
+
+/// Largest number of combinations of the values of its adjacent variables that are enumerated for
+/// a function node by `check_feasibility` (the function nodes with more combinations are not
+/// used to restrict the possible values).
+pub const FEASIBILITY_MAX_COMBINATIONS: usize = 1 << 16;
+
+/// Check that the hard constraints of a graph with `edge` edges can be satisfied, before running
+/// belief propagation on it (otherwise, the distributions collapse to `MIN_PROBA`, see
+/// `BPError::Diverged`).
+/// The possible values of each variable (and copy) are its values with a probability above
+/// `MIN_PROBA` in its initial distribution (e.g., a single value for a clamped variable), and all
+/// the values for the variables that are not profiled. They are restricted through the function
+/// nodes, until a fixed point: a value of a variable is possible if it is part of a combination
+/// of possible values of the variables adjacent to the function node that is compatible with it.
+/// Returns `BPError::Infeasible` if a variable has no possible value left.
+/// CUSTOM function nodes, the disabled copies, the edges without variable, and the function nodes
+/// with more than `FEASIBILITY_MAX_COMBINATIONS` combinations of possible values are skipped, such
+/// that this check may miss some conflicts.
+pub fn check_feasibility(
+    functions: &[Func],
+    variables: &[Var],
+    edge: usize,
+) -> Result<(), BPError> {
+    let mut edge_var = vec![None; edge];
+    for (v, var) in variables.iter().enumerate() {
+        var.neighboors.iter().for_each(|e| edge_var[*e] = Some(v));
+    }
+    let mut support: Vec<Array2<bool>> = variables
+        .iter()
+        .map(|var| match &var.vartype {
+            VarType::ProfilePara { distri_orig, .. }
+            | VarType::ProfileSingle { distri_orig, .. } => distri_orig.mapv(|p| p > MIN_PROBA),
+            VarType::NotProfilePara { distri_current }
+            | VarType::NotProfileSingle { distri_current } => {
+                Array2::from_elem(distri_current.dim(), true)
+            }
+        })
+        .collect();
+    let check_empty = |v: usize, support: &Array2<bool>| {
+        if support.outer_iter().any(|s| s.iter().all(|x| !*x)) {
+            Err(BPError::Infeasible { variable: v })
+        } else {
+            Ok(())
+        }
+    };
+    for (v, support) in support.iter().enumerate() {
+        check_empty(v, support)?;
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for func in functions.iter() {
+            if let FuncType::CUSTOM(_) = func.functype {
+                continue;
+            }
+            let vars: Vec<usize> = match func
+                .ordered_neighboors()
+                .iter()
+                .map(|e| edge_var[*e])
+                .collect()
+            {
+                Some(vars) => vars,
+                None => continue,
+            };
+            let n = vars
+                .iter()
+                .map(|v| support[*v].shape()[0])
+                .max()
+                .unwrap_or(0);
+            let nc_out = support[vars[0]].shape()[1];
+            for k in 0..n {
+                if !func.enabled.as_ref().map_or(true, |e| e[k]) {
+                    continue;
+                }
+                // copy k of the variables (the single variables have a single copy)
+                let rows: Vec<usize> = vars
+                    .iter()
+                    .map(|v| k.min(support[*v].shape()[0] - 1))
+                    .collect();
+                let values: Vec<Vec<usize>> = vars
+                    .iter()
+                    .zip(rows.iter())
+                    .map(|(v, row)| {
+                        let s = support[*v].row(*row);
+                        (0..s.len()).filter(|x| s[*x]).collect()
+                    })
+                    .collect();
+                let combinations = values
+                    .iter()
+                    .fold(1usize, |acc, x| acc.saturating_mul(x.len()));
+                if combinations > FEASIBILITY_MAX_COMBINATIONS {
+                    continue;
+                }
+                // enumerate the combinations, marking the values that are part of a compatible one
+                let mut compatible: Vec<Vec<bool>> =
+                    values.iter().map(|x| vec![false; x.len()]).collect();
+                let mut idx = vec![0; values.len()];
+                let mut combination = vec![0; values.len()];
+                for _ in 0..combinations {
+                    combination
+                        .iter_mut()
+                        .zip(idx.iter().zip(values.iter()))
+                        .for_each(|(c, (i, x))| *c = x[*i]);
+                    let factor =
+                        func_factor(&func.functype, combination[0], &combination[1..], k, nc_out)
+                            .unwrap();
+                    if factor > 0.0 {
+                        compatible
+                            .iter_mut()
+                            .zip(idx.iter())
+                            .for_each(|(c, i)| c[*i] = true);
+                    }
+                    for (i, x) in idx.iter_mut().zip(values.iter()) {
+                        *i += 1;
+                        if *i < x.len() {
+                            break;
+                        }
+                        *i = 0;
+                    }
+                }
+                for ((v, row), (values, compatible)) in vars
+                    .iter()
+                    .zip(rows.iter())
+                    .zip(values.iter().zip(compatible.iter()))
+                {
+                    for (x, _) in values.iter().zip(compatible.iter()).filter(|(_, c)| !**c) {
+                        support[*v][[*row, *x]] = false;
+                        changed = true;
+                    }
+                    check_empty(*v, &support[*v])?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Field size of the XOR function node `func` (with id `id`), inferred from the size of the
+/// messages on its edges `widths` (that is, from the adjacent variables, see `edge_widths`).
+/// All the edges must have the same size, which must be a power of two for the Walsh-Hadamard
+/// transform.
+fn xor_field_size(id: usize, func: &Func, widths: &[usize]) -> Result<usize, BPError> {
+    let e0 = func.neighboors[0];
+    let nc = widths[e0];
+    if let Some(e) = func.neighboors.iter().find(|e| widths[**e] != nc) {
+        return Err(BPError::InvalidGraph(format!(
+            "XOR function {} has edges with different field sizes: edge {} has size {}, edge {} \
+            has size {}.",
+            id, e0, nc, e, widths[*e]
+        )));
+    }
+    if !nc.is_power_of_two() {
+        return Err(BPError::InvalidGraph(format!(
+            "XOR function {} has field size {}, which is not a power of two.",
+            id, nc
+        )));
+    }
+    Ok(nc)
+}
+
+/// Generate the initial messages on the edges of the graph: the initial distribution of the
+/// variable for profiled variables, uniform otherwise.
+pub fn init_edges(variables: &[Var], edge: usize, nc: usize, n: usize) -> Vec<Array2<f64>> {
+    let mut edges: Vec<Array2<f64>> = edge_widths(variables, edge, nc)
+        .into_iter()
+        .map(|nc| Array2::<f64>::ones((n, nc)))
+        .collect();
+    reset_edges(variables, &mut edges);
+    edges
+}
+
+/// Size of the messages on each edge: the size of the distribution of the adjacent variable, or
+/// `nc` if there is no such variable.
+fn edge_widths(variables: &[Var], edge: usize, nc: usize) -> Vec<usize> {
+    let mut widths = vec![nc; edge];
+    for var in variables.iter() {
+        let width = distri_current(var).shape()[1];
+        var.neighboors.iter().for_each(|e| widths[*e] = width);
+    }
+    widths
+}
+
+/// Add nodes to a graph between two runs of belief propagation (see `run_bp_edges`), such that
+/// the propagation continues from the current messages instead of restarting.
+/// The new nodes must only be adjacent to new edges (with ids from `edges.len()` to `edge`), and
+/// `new_var_edges` lists the new edges adjacent to existing variables, as (variable, edge) pairs.
+/// The existing messages are kept, while the messages on the new edges are initialized to the
+/// current distribution of their variable (which is the message from the variable to a function
+/// node that did not send any information yet).
+pub fn extend_graph(
+    functions: &mut Vec<Func>,
+    variables: &mut Vec<Var>,
+    edges: &mut Vec<Array2<f64>>,
+    new_functions: Vec<Func>,
+    new_variables: Vec<Var>,
+    new_var_edges: &[(usize, usize)],
+    edge: usize,
+    nc: usize,
+    n: usize,
+) -> Result<(), BPError> {
+    let old_edge = edges.len();
+    let is_new = |e: &usize| (old_edge..edge).contains(e);
+    let mut new_edges = new_functions
+        .iter()
+        .flat_map(|f| f.neighboors.iter())
+        .chain(new_variables.iter().flat_map(|v| v.neighboors.iter()))
+        .chain(new_var_edges.iter().map(|(_, e)| e));
+    if let Some(e) = new_edges.find(|e| !is_new(e)) {
+        return Err(BPError::InvalidGraph(format!(
+            "edge {} is not a new edge.",
+            e
+        )));
+    }
+    if let Some((v, _)) = new_var_edges.iter().find(|(v, _)| *v >= variables.len()) {
+        return Err(BPError::InvalidGraph(format!(
+            "variable {} does not exist.",
+            v
+        )));
+    }
+    for (v, e) in new_var_edges.iter() {
+        variables[*v].neighboors.push(*e);
+    }
+    functions.extend(new_functions);
+    variables.extend(new_variables);
+    let widths = edge_widths(variables, edge, nc);
+    edges.extend(widths[old_edge..].iter().map(|nc| Array2::ones((n, *nc))));
+    for var in variables.iter() {
+        let distri = distri_current(var);
+        var.neighboors.iter().filter(|e| is_new(e)).for_each(|e| {
+            let msg = &mut edges[*e];
+            msg.assign(&distri.broadcast(msg.shape()).unwrap());
+        });
+    }
+    Ok(())
+}
+
+/// Set the messages on `edges` to their initial value (see `init_edges`).
+fn reset_edges<S: DataMut<Elem = f64>>(variables: &[Var], edges: &mut [ArrayBase<S, Ix2>]) {
+    edges.iter_mut().for_each(|x| x.fill(1.0));
+    for var in variables.iter() {
+        match &var.vartype {
+            VarType::ProfilePara { distri_orig, .. }
+            | VarType::ProfileSingle { distri_orig, .. } => var.neighboors.iter().for_each(|x| {
+                let v = &mut edges[*x];
+                let distri_orig = distri_orig.broadcast(v.shape()).unwrap();
+                v.assign(&distri_orig);
+            }),
+            _ => {}
+        }
+    }
+}
+
+/// Set the messages on `edges` to their initial value according to `init`.
+fn init_messages<S: DataMut<Elem = f64>>(
+    variables: &[Var],
+    edges: &mut [ArrayBase<S, Ix2>],
+    init: InitStrategy,
+) {
+    match init {
+        InitStrategy::Uniform => edges.iter_mut().for_each(|x| x.fill(1.0)),
+        InitStrategy::Prior => reset_edges(variables, edges),
+        InitStrategy::Random(seed) => {
+            let mut state = seed;
+            edges.iter_mut().for_each(|x| {
+                // Uniform in (0, 1], such that the messages are non-zero.
+                x.mapv_inplace(|_| {
+                    ((splitmix64(&mut state) >> 11) + 1) as f64 / (1u64 << 53) as f64
+                });
+                normalize_distri(x);
+            });
+        }
+    }
+}
+
+/// Run the belief propagation algorithm, starting from the messages in `edges` (e.g. generated
+/// by `init_edges`).
+/// On return, `edges` contains the messages from the variables to the function nodes, such that
+/// the propagation can be continued later on (see `run_bp_partial`).
+/// The messages are normalized and clamped as with the default `RunBpOptions`.
+pub fn run_bp_edges(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    progress: bool,
+) -> Result<(), BPError> {
+    bp_edges(
+        functions,
+        variables,
+        edges,
+        it,
+        progress,
+        false,
+        Clamp::Fixed,
+    )
+}
+
+/// Same as `run_bp_edges`, with the underflow-resistant normalization if `stable_norm` is set,
+/// and the messages clipped according to `clamp`.
+fn bp_edges(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    progress: bool,
+    stable_norm: bool,
+    clamp: Clamp,
+) -> Result<(), BPError> {
+    let bp_iter = |iteration| {
+        functions_step(functions, edges, clamp);
+        variables_step(variables, edges, stable_norm, clamp);
+        check_divergence(variables, iteration)
+    };
+
+    bp_loop(it, progress, bp_iter)
+}
+
+/// Same as `run_bp_edges`, with the messages of all the edges in a single contiguous array of
+/// shape `(edge, n, nc)` (see `EdgeLayout::Contiguous`), which requires all the variables to have
+/// `nc` values.
+/// The messages are clamped with `Clamp::Fixed` (see `RunBpOptions::clamp`).
+pub fn run_bp_edges_contiguous(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut Array3<f64>,
+    it: usize,
+    progress: bool,
+    stable_norm: bool,
+) -> Result<(), BPError> {
+    bp_edges_contiguous(
+        functions,
+        variables,
+        edges,
+        it,
+        progress,
+        stable_norm,
+        Clamp::Fixed,
+    )
+}
+
+/// Same as `run_bp_edges_contiguous`, with the messages clipped according to `clamp`.
+fn bp_edges_contiguous(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut Array3<f64>,
+    it: usize,
+    progress: bool,
+    stable_norm: bool,
+    clamp: Clamp,
+) -> Result<(), BPError> {
+    let bp_iter = |iteration| {
+        let mut edges: Vec<ArrayViewMut2<f64>> = edges.outer_iter_mut().collect();
+        functions_step(functions, &mut edges, clamp);
+        variables_step(variables, &mut edges, stable_norm, clamp);
+        check_divergence(variables, iteration)
+    };
+
+    bp_loop(it, progress, bp_iter)
+}
+
+/// Update all the function nodes (see `update_functions`), given all the edges.
+fn functions_step<S: MsgData>(functions: &[Func], edges: &mut [ArrayBase<S, Ix2>], clamp: Clamp) {
+    // This is a technique for runtime borrow-checking: we take reference on all the edges
+    // at once, put them into options, then extract the references out of the options, one
+    // at a time and out-of-order.
+    let mut edge_opt_ref_mut: Vec<Option<&mut ArrayBase<S, Ix2>>> =
+        edges.iter_mut().map(|x| Some(x)).collect();
+    let mut edge_for_func: Vec<Vec<&mut ArrayBase<S, Ix2>>> = functions
+        .iter()
+        .map(|f| {
+            f.neighboors
+                .iter()
+                .map(|e| edge_opt_ref_mut[*e].take().unwrap())
+                .collect()
+        })
+        .collect();
+    par_update_functions(functions, &mut edge_for_func, clamp);
+}
+
+/// Update all the variable nodes (see `update_variables`), given all the edges.
+fn variables_step<S: MsgData>(
+    variables: &mut [Var],
+    edges: &mut [ArrayBase<S, Ix2>],
+    stable_norm: bool,
+    clamp: Clamp,
+) {
+    let mut edge_opt_ref_mut: Vec<Option<&mut ArrayBase<S, Ix2>>> =
+        edges.iter_mut().map(|x| Some(x)).collect();
+    let mut edge_for_var: Vec<Vec<&mut ArrayBase<S, Ix2>>> = variables
+        .iter()
+        .map(|f| {
+            f.neighboors
+                .iter()
+                .map(|e| edge_opt_ref_mut[*e].take().unwrap())
+                .collect()
+        })
+        .collect();
+    par_update_variables(&mut edge_for_var, variables, stable_norm, clamp);
+}
+
+/// Same as `run_bp_edges`, stopping as soon as every variable is decided: in each copy, its most
+/// probable value exceeds the second most probable one by at least `margin` (in normalized
+/// probability), such that further iterations would not change the decoded assignment (see
+/// `best_guess`).
+/// The messages are clamped with `Clamp::Fixed` (see `RunBpOptions::clamp`).
+/// Returns the number of iterations that have been run.
+pub fn run_bp_until_decided(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    margin: f64,
+    stable_norm: bool,
+) -> Result<usize, BPError> {
+    for iteration in 0..it {
+        functions_step(functions, edges, Clamp::Fixed);
+        variables_step(variables, edges, stable_norm, Clamp::Fixed);
+        check_divergence(variables, iteration)?;
+        if variables.iter().all(|var| is_decided(var, margin)) {
+            return Ok(iteration + 1);
+        }
+    }
+    Ok(it)
+}
+
+/// Whether the most probable value of `var` exceeds the second most probable one by at least
+/// `margin` in all the copies (see `run_bp_until_decided`).
+fn is_decided(var: &Var, margin: f64) -> bool {
+    distri_current(var).outer_iter().all(|d| {
+        let sum = d.sum();
+        let (first, second) = d.iter().fold((0.0, 0.0), |(first, second), p| {
+            if *p > first {
+                (*p, first)
+            } else {
+                (first, second.max(*p))
+            }
+        });
+        (first - second) / sum >= margin
+    })
+}
+
+/// Run residual belief propagation: instead of updating all the function nodes then all the
+/// variable nodes at each iteration (flooding), the messages from the function nodes to the
+/// variable nodes are updated one at a time, in decreasing order of their residual (the largest
+/// absolute change of the normalized message that the update would make), which is tracked in a
+/// priority queue. After each update, the variable node at the end of the edge is updated, and
+/// the residuals of the function nodes that receive a new message from it are recomputed. This
+/// often converges with much fewer message updates on cyclic graphs.
+/// `edges` contains the initial messages from the variables (e.g. generated by `init_edges`), and
+/// on return the messages from the variables to the function nodes (as for `run_bp_edges`).
+/// The propagation stops after `max_updates` message updates, or as soon as all the residuals are
+/// below `tol`.
+/// The messages are normalized and clamped as with the default `RunBpOptions`.
+/// Returns the number of message updates (one iteration of flooding updates one message per
+/// edge).
+pub fn run_bp_residual(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    max_updates: usize,
+    tol: f64,
+) -> Result<usize, BPError> {
+    let mut edge_func = vec![None; edges.len()];
+    for (f, func) in functions.iter().enumerate() {
+        func.neighboors.iter().for_each(|e| edge_func[*e] = Some(f));
+    }
+    let mut edge_var = vec![None; edges.len()];
+    for (v, var) in variables.iter().enumerate() {
+        var.neighboors.iter().for_each(|e| edge_var[*e] = Some(v));
+    }
+    // messages from the function nodes (normalized)
+    let mut f2v: Vec<Array2<f64>> = edges
+        .iter()
+        .map(|msg| Array2::from_elem(msg.dim(), 1.0 / msg.shape()[1] as f64))
+        .collect();
+    let mut queue = ResidualQueue {
+        pending: f2v.clone(),
+        residuals: vec![0.0; edges.len()],
+        heap: BinaryHeap::new(),
+    };
+    for func in functions.iter() {
+        queue.push_function(func, edges, &f2v);
+    }
+    let mut updates = 0;
+    while updates < max_updates {
+        let e = match queue.pop(tol) {
+            Some(e) => e,
+            None => break,
+        };
+        f2v[e].assign(&queue.pending[e]);
+        queue.residuals[e] = 0.0;
+        updates += 1;
+        if let Some(v) = edge_var[e] {
+            residual_update_variable(&mut variables[v], edges, &f2v);
+            for e2 in variables[v].neighboors.iter().filter(|e2| **e2 != e) {
+                if let Some(f) = edge_func[*e2] {
+                    queue.push_function(&functions[f], edges, &f2v);
+                }
+            }
+        }
+    }
+    variables
+        .iter_mut()
+        .for_each(|var| residual_update_variable(var, edges, &f2v));
+    check_divergence(variables, updates)?;
+    Ok(updates)
+}
+
+/// Pending messages from the function nodes to the variable nodes in `run_bp_residual`, ordered
+/// by residual.
+struct ResidualQueue {
+    /// Message that an update would send on each edge (normalized).
+    pending: Vec<Array2<f64>>,
+    /// Residual of the pending message on each edge.
+    residuals: Vec<f64>,
+    /// Entries (residual, edge), where the bit patterns of the residuals are ordered as the
+    /// (non-negative) residuals. Stale entries, whose residual changed since, are skipped.
+    heap: BinaryHeap<(u64, usize)>,
+}
+
+impl ResidualQueue {
+    /// Compute the messages that an update of `func` would send, given the messages `edges` from
+    /// the variables, and their residuals with respect to the current messages `f2v`.
+    fn push_function(&mut self, func: &Func, edges: &[Array2<f64>], f2v: &[Array2<f64>]) {
+        let mut msgs: Vec<Array2<f64>> =
+            func.neighboors.iter().map(|e| edges[*e].clone()).collect();
+        update_function(func, &mut msgs.iter_mut().collect::<Vec<_>>(), Clamp::Fixed);
+        for (e, mut msg) in func.neighboors.iter().zip(msgs.into_iter()) {
+            normalize_distri(&mut msg);
+            self.residuals[*e] = ConvergenceMetric::Absolute.distance(&msg, &f2v[*e]);
+            self.pending[*e] = msg;
+            self.heap.push((self.residuals[*e].to_bits(), *e));
+        }
+    }
+
+    /// Edge with the largest residual, if it is at least `tol`.
+    fn pop(&mut self, tol: f64) -> Option<usize> {
+        while let Some((r, e)) = self.heap.pop() {
+            if r != self.residuals[e].to_bits() {
+                continue;
+            }
+            if self.residuals[e] < tol {
+                return None;
+            }
+            return Some(e);
+        }
+        None
+    }
+}
+
+/// Update the variable node `var` given the messages `f2v` from the function nodes, and write its
+/// messages to the function nodes to `edges` (see `run_bp_residual`).
+fn residual_update_variable(var: &mut Var, edges: &mut [Array2<f64>], f2v: &[Array2<f64>]) {
+    let mut msgs: Vec<Array2<f64>> = var.neighboors.iter().map(|e| f2v[*e].clone()).collect();
+    update_variable(
+        var,
+        &mut msgs.iter_mut().collect::<Vec<_>>(),
+        false,
+        Clamp::Fixed,
+    );
+    for (e, msg) in var.neighboors.iter().zip(msgs.into_iter()) {
+        edges[*e] = msg;
+    }
+}
+
+/// Message truncated to its `k` largest probabilities in each copy, where the other values share
+/// the remaining probability mass uniformly (see `run_bp_topk`).
+#[derive(Debug, Clone)]
+pub struct TopKMsg {
+    /// Values kept in each copy, with shape (n, k).
+    pub indices: Array2<u32>,
+    /// Probabilities of the values kept, with shape (n, k).
+    pub probas: Array2<f64>,
+    /// Probability of each value that is not kept, for each copy (n,).
+    pub tail: Array1<f64>,
+    /// Size of the field.
+    pub nc: usize,
+}
+
+impl TopKMsg {
+    /// Truncate the message `msg` with shape (n, nc) (not necessarily normalized) to its `k`
+    /// largest probabilities.
+    pub fn compress(msg: &Array2<f64>, k: usize) -> Self {
+        let (n, nc) = msg.dim();
+        let k = std::cmp::min(k, nc);
+        let mut res = TopKMsg {
+            indices: Array2::zeros((n, k)),
+            probas: Array2::zeros((n, k)),
+            tail: Array1::zeros(n),
+            nc,
+        };
+        let mut order: Vec<usize> = (0..nc).collect();
+        for (copy, msg) in msg.outer_iter().enumerate() {
+            let sum = msg.sum();
+            if k < nc {
+                order.select_nth_unstable_by(k, |a, b| msg[*b].partial_cmp(&msg[*a]).unwrap());
+            }
+            let mut kept = 0.0;
+            for (j, i) in order[..k].iter().enumerate() {
+                res.indices[[copy, j]] = *i as u32;
+                res.probas[[copy, j]] = msg[*i] / sum;
+                kept += msg[*i] / sum;
+            }
+            res.tail[copy] = if k < nc {
+                ((1.0 - kept) / ((nc - k) as f64)).max(MIN_PROBA)
+            } else {
+                MIN_PROBA
+            };
+        }
+        res
+    }
+
+    /// Dense message with shape (n, nc).
+    pub fn to_dense(&self) -> Array2<f64> {
+        let mut res = Array2::zeros((self.tail.len(), self.nc));
+        for (copy, mut res) in res.outer_iter_mut().enumerate() {
+            res.fill(self.tail[copy]);
+            for (i, p) in self
+                .indices
+                .row(copy)
+                .iter()
+                .zip(self.probas.row(copy).iter())
+            {
+                res[*i as usize] = *p;
+            }
+        }
+        res
+    }
+
+    /// Message of the image of the variable by `map`, a permutation of [0, nc) in each copy.
+    fn permute<F: Fn(usize, usize) -> usize>(&self, map: F) -> Self {
+        let mut res = self.clone();
+        res.indices
+            .outer_iter_mut()
+            .enumerate()
+            .for_each(|(copy, mut indices)| indices.mapv_inplace(|i| map(copy, i as usize) as u32));
+        res
+    }
+}
+
+/// Permutation of [0, nc) of a function node with two edges and its inverse, for each copy, as
+/// (copy, input) -> output and (copy, output) -> input maps.
+type PermutationMaps = (
+    Box<dyn Fn(usize, usize) -> usize + Sync>,
+    Box<dyn Fn(usize, usize) -> usize + Sync>,
+);
+
+/// Permutation of the function node `functype` over a field of size `nc` (see `run_bp_topk`),
+/// or None if the function node is not a permutation.
+fn permutation_maps(functype: &FuncType, nc: usize) -> Option<PermutationMaps> {
+    match functype {
+        FuncType::XORCST(values) if values.iter().all(|v| (*v as usize) < nc) => {
+            let values = values.clone();
+            let values2 = values.clone();
+            Some((
+                Box::new(move |k, i| i ^ values[k] as usize),
+                Box::new(move |k, o| o ^ values2[k] as usize),
+            ))
+        }
+        FuncType::ADDCST(values) if values.iter().all(|v| *v != UNKNOWN_CST) => {
+            let values = values.mapv(|v| v as usize % nc);
+            let values2 = values.clone();
+            Some((
+                Box::new(move |k, i| (i + values[k]) % nc),
+                Box::new(move |k, o| (o + nc - values2[k]) % nc),
+            ))
+        }
+        FuncType::NEG => Some((
+            Box::new(move |_, i| (nc - i) % nc),
+            Box::new(move |_, o| (nc - o) % nc),
+        )),
+        FuncType::LOOKUP(table) if is_permutation(table, nc) => {
+            let table = table.clone();
+            let mut inverse = vec![0; nc];
+            table
+                .iter()
+                .enumerate()
+                .for_each(|(i, o)| inverse[*o as usize] = i);
+            Some((
+                Box::new(move |_, i| table[i] as usize),
+                Box::new(move |_, o| inverse[o]),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Run the belief propagation algorithm where the messages on the edges are truncated to their
+/// `k` largest probabilities (see `TopKMsg`), which bounds the memory of the messages to
+/// `O(edge*n*k)` instead of `O(edge*n*nc)` for large fields. The distributions of the variables
+/// are not truncated.
+/// This only supports function nodes that are permutations of [0, nc) (XORCST, ADDCST with known
+/// public values, NEG and bijective LOOKUP), whose messages are permuted without being expanded.
+///
+/// The truncation replaces the probabilities of the values that are not kept by their mean, hence
+/// the accuracy depends on how concentrated the messages are: the results are close to `run_bp`
+/// when the messages have at most `k` non-negligible probabilities (e.g., with strong leakage),
+/// but the information in the tail of flat messages is lost.
+///
+/// `RunBpOptions` is not supported: the messages are initialized with `InitStrategy::Prior`,
+/// and normalized and clamped as with the defaults.
+pub fn run_bp_topk(
+    functions: &[Func],
+    variables: &mut [Var],
+    it: usize,
+    edge: usize,
+    nc: usize,
+    n: usize,
+    k: usize,
+) -> Result<(), BPError> {
+    let maps = functions
+        .iter()
+        .enumerate()
+        .map(|(id, func)| {
+            permutation_maps(&func.functype, nc)
+                .ok_or(BPError::UnsupportedFunction { function: id })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(var) = variables
+        .iter()
+        .position(|var| distri_current(var).shape()[1] != nc)
+    {
+        return Err(BPError::InvalidGraph(format!(
+            "variable {} does not have nc values.",
+            var
+        )));
+    }
+    let mut edges: Vec<TopKMsg> = init_edges(variables, edge, nc, n)
+        .iter()
+        .map(|msg| TopKMsg::compress(msg, k))
+        .collect();
+    for iteration in 0..it {
+        let msgs: Vec<(usize, TopKMsg)> =
+            functions
+                .par_iter()
+                .zip(maps.par_iter())
+                .flat_map_iter(|(func, (forward, inverse))| {
+                    let neighboors = func.ordered_neighboors();
+                    let (output, input) = (neighboors[0], neighboors[1]);
+                    let mut msgs = [
+                        (output, edges[input].permute(forward)),
+                        (input, edges[output].permute(inverse)),
+                    ];
+                    if let Some(enabled) = &func.enabled {
+                        for (_, msg) in msgs.iter_mut() {
+                            enabled.iter().enumerate().filter(|(_, e)| !**e).for_each(
+                                |(copy, _)| {
+                                    msg.probas.row_mut(copy).fill(1.0 / nc as f64);
+                                    msg.tail[copy] = 1.0 / nc as f64;
+                                },
+                            );
+                        }
+                    }
+                    msgs
+                })
+                .collect();
+        msgs.into_iter().for_each(|(e, msg)| edges[e] = msg);
+
+        let msgs: Vec<(usize, TopKMsg)> = variables
+            .par_iter_mut()
+            .flat_map_iter(|var| {
+                // The messages are only expanded while the variable is updated.
+                let mut dense: Vec<Array2<f64>> = var
+                    .neighboors
+                    .iter()
+                    .map(|e| edges[*e].to_dense())
+                    .collect();
+                update_variable(
+                    var,
+                    &mut dense.iter_mut().collect::<Vec<_>>(),
+                    false,
+                    Clamp::Fixed,
+                );
+                var.neighboors
+                    .iter()
+                    .zip(dense.iter())
+                    .map(|(e, msg)| (*e, TopKMsg::compress(msg, k)))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        msgs.into_iter().for_each(|(e, msg)| edges[e] = msg);
+        check_divergence(variables, iteration)?;
+    }
+    Ok(())
+}
+
+/// Damping of the messages from the function nodes to the variable nodes: at each iteration, the
+/// messages are replaced by `(1-factor)*new + factor*previous`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Damping {
+    /// Constant damping factor, in [0, 1).
+    Fixed(f64),
+    /// The damping factor is `clamp(residual * k, 0, max)`, where `residual` is the residual of
+    /// the previous iteration (see `run_bp_damped`): the damping is heavy far from convergence
+    /// and vanishes near the fixed point.
+    Adaptive { k: f64, max: f64 },
+}
+
+impl Damping {
+    /// Damping factor, given the residual of the previous iteration.
+    fn factor(&self, residual: f64) -> f64 {
+        match self {
+            Damping::Fixed(factor) => *factor,
+            Damping::Adaptive { k, max } => (residual * k).max(0.0).min(*max),
+        }
+    }
+}
+
+/// Distance between successive variable distributions, used as residual by `run_bp_damped`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConvergenceMetric {
+    /// Largest absolute change `|p_new - p_old|`.
+    Absolute,
+    /// Largest relative change `|p_new - p_old| / (p_old + eps)`, which does not depend on how
+    /// concentrated the distributions are (small probabilities must also converge).
+    Relative { eps: f64 },
+}
+
+impl ConvergenceMetric {
+    /// Distance from the distribution `old` to `new`.
+    fn distance(&self, new: &Array2<f64>, old: &Array2<f64>) -> f64 {
+        Zip::from(new)
+            .and(old)
+            .fold(0.0, |acc: f64, new, old| match self {
+                ConvergenceMetric::Absolute => acc.max((new - old).abs()),
+                ConvergenceMetric::Relative { eps } => acc.max((new - old).abs() / (old + eps)),
+            })
+    }
+}
+
+/// Run at most `it` iterations of belief propagation on the graph, with damping of the messages
+/// from the function nodes (see `run_bp_edges`).
+/// The residual of an iteration is the largest absolute change of the variable distributions.
+/// The iterations stop as soon as the residual is below `tol`.
+/// The messages are normalized and clamped as with the default `RunBpOptions` (as in all the
+/// `run_bp_damped*` variants).
+/// Returns the residual of each iteration.
+pub fn run_bp_damped(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    damping: Damping,
+    tol: f64,
+) -> Result<Vec<f64>, BPError> {
+    run_bp_damped_metric(
+        functions,
+        variables,
+        edges,
+        it,
+        damping,
+        tol,
+        ConvergenceMetric::Absolute,
+    )
+}
+
+/// Same as `run_bp_damped`, where the residual of an iteration is the change of the variable
+/// distributions measured with `metric`.
+pub fn run_bp_damped_metric(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    damping: Damping,
+    tol: f64,
+    metric: ConvergenceMetric,
+) -> Result<Vec<f64>, BPError> {
+    run_bp_damped_diagnostic(functions, variables, edges, it, damping, tol, metric, false)
+        .map(|residuals| residuals.iterations)
+}
+
+/// Detection of a limit cycle of the messages (see `run_bp_damped_limit_cycle`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitCycle {
+    /// Oscillation above which the messages are considered to be still moving.
+    pub threshold: f64,
+    /// Number of consecutive iterations over which the oscillation must plateau.
+    pub window: usize,
+}
+
+/// Residuals of a run of `run_bp_damped_diagnostic`.
+#[derive(Debug, Clone)]
+pub struct Residuals {
+    /// Residual of each iteration (see `run_bp_damped_metric`).
+    pub iterations: Vec<f64>,
+    /// Change of the distribution of each variable at the last iteration, if requested: the
+    /// variables that did not converge point to the problematic parts of the graph.
+    pub per_variable: Option<Array1<f64>>,
+    /// Oscillation of the messages at each iteration: L2 norm of the change of the (normalized
+    /// and damped) messages from the function nodes over all the edges. NaN for the first
+    /// iteration.
+    pub oscillation: Vec<f64>,
+    /// Whether a limit cycle is suspected (see `run_bp_damped_limit_cycle`).
+    pub limit_cycle: bool,
+}
+
+/// Same as `run_bp_damped_metric`, also returning the residual of each variable at the last
+/// iteration if `per_variable` is set.
+pub fn run_bp_damped_diagnostic(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    damping: Damping,
+    tol: f64,
+    metric: ConvergenceMetric,
+    per_variable: bool,
+) -> Result<Residuals, BPError> {
+    run_bp_damped_limit_cycle(
+        functions,
+        variables,
+        edges,
+        it,
+        damping,
+        tol,
+        metric,
+        per_variable,
+        None,
+    )
+}
+
+/// Same as `run_bp_damped_diagnostic`, where a limit cycle of the messages (on a cyclic graph,
+/// oscillations that never damp out) is suspected if `cycle` is set and the oscillation of the
+/// messages (see `Residuals::oscillation`) plateaus: it stays above `cycle.threshold` during
+/// `cycle.window` consecutive iterations, at the end of which it is at least half of the
+/// oscillation at their start.
+pub fn run_bp_damped_limit_cycle(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    damping: Damping,
+    tol: f64,
+    metric: ConvergenceMetric,
+    per_variable: bool,
+    cycle: Option<LimitCycle>,
+) -> Result<Residuals, BPError> {
+    let mut residuals = Vec::new();
+    let mut oscillation = Vec::new();
+    let mut limit_cycle = false;
+    let mut var_residuals = Array1::zeros(variables.len());
+    let mut previous_msgs: Option<Vec<Array2<f64>>> = None;
+    for iteration in 0..it {
+        functions_step(functions, edges, Clamp::Fixed);
+        // The scaling of the messages does not matter, normalize them before mixing them.
+        edges.par_iter_mut().for_each(normalize_distri);
+        if let Some(previous_msgs) = &previous_msgs {
+            let factor = damping.factor(*residuals.last().unwrap());
+            edges
+                .par_iter_mut()
+                .zip(previous_msgs.par_iter())
+                .for_each(|(msg, previous)| {
+                    Zip::from(msg)
+                        .and(previous)
+                        .for_each(|m, p| *m = (1.0 - factor) * *m + factor * p);
+                });
+            let energy: f64 = edges
+                .par_iter()
+                .zip(previous_msgs.par_iter())
+                .map(|(msg, previous)| {
+                    Zip::from(msg)
+                        .and(previous)
+                        .fold(0.0, |acc, m, p| acc + (m - p) * (m - p))
+                })
+                .sum();
+            oscillation.push(energy.sqrt());
+        } else {
+            oscillation.push(f64::NAN);
+        }
+        if let Some(cycle) = cycle {
+            if cycle.window > 0 && oscillation.len() >= cycle.window {
+                let last = &oscillation[(oscillation.len() - cycle.window)..];
+                if last.iter().all(|x| *x > cycle.threshold)
+                    && last[cycle.window - 1] >= 0.5 * last[0]
+                {
+                    limit_cycle = true;
+                }
+            }
+        }
+        previous_msgs = Some(edges.to_vec());
+        let previous_distri: Vec<Array2<f64>> = variables
+            .iter()
+            .map(|v| distri_current(v).clone())
+            .collect();
+        variables_step(variables, edges, false, Clamp::Fixed);
+        check_divergence(variables, iteration)?;
+        var_residuals = variables
+            .iter()
+            .zip(previous_distri.iter())
+            .map(|(v, previous)| metric.distance(distri_current(v), previous))
+            .collect();
+        let residual = var_residuals.fold(0.0, |acc: f64, x| acc.max(*x));
+        residuals.push(residual);
+        if residual < tol {
+            break;
+        }
+    }
+    Ok(Residuals {
+        iterations: residuals,
+        per_variable: if per_variable {
+            Some(var_residuals)
+        } else {
+            None
+        },
+        oscillation,
+        limit_cycle,
+    })
+}
+
+/// Same as `run_bp_edges`, but also records the scale of the messages from the function nodes,
+/// for debugging (e.g., to assess the strength of the evidence or to diagnose convergence).
+/// At each iteration, the messages from the function nodes are normalized before updating the
+/// variables (which does not change the result, since the variable updates do not depend on the
+/// scaling of the messages), and the sums of the messages before normalization are recorded: the
+/// unnormalized message on edge `e` for copy `k` at iteration `i` is the normalized one multiplied
+/// by `scales[i][e][k]`.
+/// The messages are clamped with `Clamp::Fixed` (see `RunBpOptions::clamp`).
+/// Returns the scales, with axes (iteration, edge, copy).
+pub fn run_bp_debug(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    it: usize,
+    stable_norm: bool,
+) -> Result<Vec<Vec<Array1<f64>>>, BPError> {
+    let mut scales = Vec::with_capacity(it);
+    for iteration in 0..it {
+        functions_step(functions, edges, Clamp::Fixed);
+        scales.push(edges.par_iter().map(|msg| msg.sum_axis(Axis(1))).collect());
+        edges
+            .par_iter_mut()
+            .for_each(|msg| normalize(msg, stable_norm));
+        variables_step(variables, edges, stable_norm, Clamp::Fixed);
+        check_divergence(variables, iteration)?;
+    }
+    Ok(scales)
+}
+
+/// Same as `run_bp`, but the messages on the edges are stored in single precision, which halves
+/// the memory used by the edges. The messages adjacent to a node are converted to double
+/// precision before its update (including the transforms in `xors`, `adds` and `mults`), and
+/// rounded back to single precision afterwards.
+/// The messages are clamped with `Clamp::Fixed` (see `RunBpOptions::clamp`).
+pub fn run_bp_mixed(
+    functions: &[Func],
+    variables: &mut [Var],
+    it: usize,
+    edge: usize,
+    nc: usize,
+    n: usize,
+    progress: bool,
+    stable_norm: bool,
+) -> Result<(), BPError> {
+    let mut edges: Vec<Array2<f32>> = edge_widths(variables, edge, nc)
+        .into_iter()
+        .map(|nc| Array2::<f32>::ones((n, nc)))
+        .collect();
+    for var in variables.iter() {
+        match &var.vartype {
+            VarType::ProfilePara { distri_orig, .. }
+            | VarType::ProfileSingle { distri_orig, .. } => var.neighboors.iter().for_each(|x| {
+                let v = &mut edges[*x];
+                let distri_orig = distri_orig.broadcast(v.shape()).unwrap();
+                v.zip_mut_with(&distri_orig, |v, d| *v = *d as f32);
+            }),
+            _ => {}
+        }
+    }
+    bp_loop(it, progress, |iteration| {
+        let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f32>>> =
+            edges.iter_mut().map(Some).collect();
+        let mut edge_for_func: Vec<Vec<&mut Array2<f32>>> = functions
+            .iter()
+            .map(|f| {
+                f.neighboors
+                    .iter()
+                    .map(|e| edge_opt_ref_mut[*e].take().unwrap())
+                    .collect()
+            })
+            .collect();
+        functions
+            .par_iter()
+            .zip(edge_for_func.par_iter_mut())
+            .for_each(|(function, edge)| {
+                with_f64(edge, |edge| update_function(function, edge, Clamp::Fixed));
+            });
+
+        let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f32>>> =
+            edges.iter_mut().map(Some).collect();
+        let mut edge_for_var: Vec<Vec<&mut Array2<f32>>> = variables
+            .iter()
+            .map(|v| {
+                v.neighboors
+                    .iter()
+                    .map(|e| edge_opt_ref_mut[*e].take().unwrap())
+                    .collect()
+            })
+            .collect();
+        variables
+            .par_iter_mut()
+            .zip(edge_for_var.par_iter_mut())
+            .for_each(|(var, edge)| {
+                with_f64(edge, |edge| {
+                    update_variable(var, edge, stable_norm, Clamp::Fixed)
+                });
+            });
+        check_divergence(variables, iteration)
+    })
+}
+
+/// Apply `f` to the single precision messages `msgs`, converted to double precision.
+fn with_f64<F: FnOnce(&mut [&mut Array2<f64>])>(msgs: &mut [&mut Array2<f32>], f: F) {
+    let mut msgs_f64: Vec<Array2<f64>> = msgs.iter().map(|msg| msg.mapv(f64::from)).collect();
+    f(&mut msgs_f64.iter_mut().collect::<Vec<_>>());
+    msgs.iter_mut()
+        .zip(msgs_f64.iter())
+        .for_each(|(msg, msg_f64)| msg.zip_mut_with(msg_f64, |m, x| *m = *x as f32));
+}
+
+/// Check that the distributions of the variables still hold information: a distribution
+/// diverged if it has non-finite values or if all its values are clipped to `MIN_PROBA` (e.g.,
+/// due to incompatible constraints).
+fn check_divergence(variables: &[Var], iteration: usize) -> Result<(), BPError> {
+    match variables
+        .iter()
+        .position(|var| distri_diverged(distri_current(var)))
+    {
+        Some(variable) => Err(BPError::Diverged {
+            variable,
+            iteration,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Whether the distribution of a variable diverged (see `check_divergence`).
+fn distri_diverged(distri: &Array2<f64>) -> bool {
+    distri.outer_iter().any(|distri| {
+        distri.iter().any(|x| !x.is_finite()) || distri.iter().all(|x| *x <= MIN_PROBA)
+    })
+}
+
+/// Run `it` iterations of `bp_iter` (which is given the iteration number), possibly showing a
+/// progress bar. Stops at the first error.
+fn bp_loop<F: FnMut(usize) -> Result<(), BPError>>(
+    it: usize,
+    progress: bool,
+    mut bp_iter: F,
+) -> Result<(), BPError> {
+    if progress {
+        // loading bar
+        let pb = ProgressBar::new(it as u64);
+        pb.set_style(ProgressStyle::default_spinner().template(
+        "{msg} {spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len}, ETA {eta})",
+    )
+    .on_finish(ProgressFinish::AndClear));
+        pb.set_message("Calculating BP...");
+        for i in (0..it).progress_with(pb) {
+            bp_iter(i)?;
+        }
+    } else {
+        for i in 0..it {
+            bp_iter(i)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reset the current distribution of the variables to their initial state: the initial
+/// distribution for profiled variables, and uniform otherwise.
+pub fn reset_variables(variables: &mut [Var]) {
+    variables.iter_mut().for_each(|var| match &mut var.vartype {
+        VarType::ProfilePara {
+            distri_orig,
+            distri_current,
+        }
+        | VarType::ProfileSingle {
+            distri_orig,
+            distri_current,
+        } => distri_current.assign(distri_orig),
+        VarType::NotProfilePara { distri_current }
+        | VarType::NotProfileSingle { distri_current } => distri_current.fill(1.0),
+    });
+}
+
+/// Index and value of the maximum of `distri`.
+/// Ties are broken deterministically: the lowest index wins.
+fn argmax(distri: ndarray::ArrayView1<f64>) -> (usize, f64) {
+    distri
+        .iter()
+        .enumerate()
+        .fold((0, f64::NEG_INFINITY), |(i_max, max), (i, p)| {
+            if *p > max {
+                (i, *p)
+            } else {
+                (i_max, max)
+            }
+        })
+}
+
+/// Scale of the probabilities read out by `get_marginals` and `best_guess_scaled`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProbaScale {
+    /// Probabilities.
+    Linear,
+    /// Natural logarithm of the probabilities, clipped down to `MIN_PROBA` before the logarithm.
+    Log,
+}
+
+impl ProbaScale {
+    /// Read out a normalized probability.
+    fn apply(&self, p: f64) -> f64 {
+        match self {
+            ProbaScale::Linear => p,
+            ProbaScale::Log => p.max(MIN_PROBA).ln(),
+        }
+    }
+}
+
+/// Normalized distribution of each variable (axes (copy, value), a single copy for single
+/// variables), in the given scale.
+pub fn get_marginals(variables: &[Var], scale: ProbaScale) -> Vec<Array2<f64>> {
+    variables
+        .iter()
+        .map(|var| {
+            let mut distri = distri_current(var).clone();
+            for mut d in distri.outer_iter_mut() {
+                let sum = d.sum();
+                d.mapv_inplace(|p| scale.apply(p / sum));
+            }
+            distri
+        })
+        .collect()
+}
+
+/// Draw `n_samples` random assignments of the variables, where the variables (and their copies)
+/// are independent and distributed according to their current distribution (e.g., the marginals
+/// after belief propagation), for Monte Carlo analyses. The draws are reproducible for a given
+/// `seed`.
+/// return array axes for each variable (sample, copy)
+pub fn sample_marginals(variables: &[Var], n_samples: usize, seed: u64) -> Vec<Array2<usize>> {
+    let mut state = seed;
+    variables
+        .iter()
+        .map(|var| {
+            let distri = distri_current(var);
+            // cumulative distribution of each copy
+            let mut cdf = distri.clone();
+            cdf.outer_iter_mut().for_each(|mut c| {
+                let sum = c.sum();
+                c.accumulate_axis_inplace(Axis(0), |prev, cur| *cur += *prev);
+                c /= sum;
+            });
+            Array2::from_shape_fn((n_samples, distri.shape()[0]), |(_, k)| {
+                // uniform in [0, 1)
+                let u = (splitmix64(&mut state) >> 11) as f64 / (1u64 << 53) as f64;
+                let c = cdf.row(k);
+                (0..c.len()).find(|x| u < c[*x]).unwrap_or(c.len() - 1)
+            })
+        })
+        .collect()
+}
+
+/// Natural logarithm of the normalized distribution of the single variables `single_ids` (e.g.,
+/// the subkeys), clipped down to `MIN_PROBA` before the logarithm (see `ProbaScale::Log`), as
+/// consumed by the rank estimation algorithms.
+/// Panics if one of the variables is not single.
+pub fn get_key_logp(variables: &[Var], single_ids: &[usize]) -> Vec<Array1<f64>> {
+    single_ids
+        .iter()
+        .map(|id| {
+            let var = &variables[*id];
+            assert!(
+                matches!(
+                    var.vartype,
+                    VarType::ProfileSingle { .. } | VarType::NotProfileSingle { .. }
+                ),
+                "Variable {} is not single.",
+                id
+            );
+            let distri = distri_current(var).row(0);
+            let sum = distri.sum();
+            distri.mapv(|p| ProbaScale::Log.apply(p / sum))
+        })
+        .collect()
+}
+
+/// Same as `get_marginals`, stacked in a single array with axes (variable, copy, value).
+/// All the variables must be para and have the same number of copies and field size.
+pub fn get_marginals_stacked(variables: &[Var], scale: ProbaScale) -> Result<Array3<f64>, BPError> {
+    if let Some(i) = variables.iter().position(|var| {
+        matches!(
+            var.vartype,
+            VarType::ProfileSingle { .. } | VarType::NotProfileSingle { .. }
+        )
+    }) {
+        return Err(BPError::InvalidGraph(format!(
+            "cannot stack single variable {}",
+            i
+        )));
+    }
+    let marginals = get_marginals(variables, scale);
+    let shape = marginals.first().map(|m| m.dim()).unwrap_or((0, 0));
+    if let Some(i) = marginals.iter().position(|m| m.dim() != shape) {
+        return Err(BPError::InvalidGraph(format!(
+            "shape {:?} of variable {} differs from shape {:?} of variable 0",
+            marginals[i].dim(),
+            i,
+            shape
+        )));
+    }
+    let mut stacked = Array3::zeros((marginals.len(), shape.0, shape.1));
+    for (mut s, m) in stacked.outer_iter_mut().zip(marginals.iter()) {
+        s.assign(m);
+    }
+    Ok(stacked)
+}
+
+/// Most probable value of each variable and its (normalized) probability, for each copy (a single
+/// copy for single variables).
+/// If several values have the same probability, the lowest one is returned (see
+/// `best_guess_ties` to get all of them).
+pub fn best_guess(variables: &[Var]) -> Vec<(Array1<usize>, Array1<f64>)> {
+    best_guess_scaled(variables, ProbaScale::Linear)
+}
+
+/// Same as `best_guess`, with the probabilities in the given scale.
+pub fn best_guess_scaled(
+    variables: &[Var],
+    scale: ProbaScale,
+) -> Vec<(Array1<usize>, Array1<f64>)> {
+    variables
+        .iter()
+        .map(|var| {
+            let distri = distri_current(var);
+            let (guess, proba): (Vec<usize>, Vec<f64>) = distri
+                .outer_iter()
+                .map(|d| {
+                    let (guess, max) = argmax(d);
+                    (guess, scale.apply(max / d.sum()))
+                })
+                .unzip();
+            (Array1::from(guess), Array1::from(proba))
+        })
+        .collect()
+}
+
+/// All the most probable values (in increasing order) of each variable, for each copy (a single
+/// copy for single variables).
+pub fn best_guess_ties(variables: &[Var]) -> Vec<Vec<Vec<usize>>> {
+    variables
+        .iter()
+        .map(|var| {
+            distri_current(var)
+                .outer_iter()
+                .map(|d| {
+                    let (_, max) = argmax(d);
+                    d.iter()
+                        .enumerate()
+                        .filter(|(_, p)| **p == max)
+                        .map(|(i, _)| i)
+                        .collect()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Probability of the `correct` value in the marginal `distri` of a single variable (with shape
+/// `(1, nc)`, see `get_marginals`), which does not need to be normalized: a soft metric of the
+/// success of a first-order recovery (e.g., of a key byte by a SASCA).
+pub fn single_success_probability(distri: &Array2<f64>, correct: usize) -> f64 {
+    assert_eq!(
+        distri.shape()[0],
+        1,
+        "distri must be the marginal of a single variable."
+    );
+    assert!(
+        correct < distri.shape()[1],
+        "correct must be smaller than nc."
+    );
+    distri[[0, correct]] / distri.sum()
+}
+
+/// Whether the `correct` value is the most probable one in the marginal `distri` of a single
+/// variable (with shape `(1, nc)`): the hard metric of the success of a first-order recovery
+/// (see `single_success_probability`). As in `best_guess`, the lowest value is the most probable
+/// one in case of ties.
+pub fn is_correct_argmax(distri: &Array2<f64>, correct: usize) -> bool {
+    assert_eq!(
+        distri.shape()[0],
+        1,
+        "distri must be the marginal of a single variable."
+    );
+    assert!(
+        correct < distri.shape()[1],
+        "correct must be smaller than nc."
+    );
+    argmax(distri.row(0)).0 == correct
+}
+
+/// Rank (starting at 1) of the value `correct` in the distribution `distri`, in decreasing order
+/// of probability, where ties are broken as in `best_guess` (the lowest value first): the rank is
+/// 1 if and only if `correct` is the best guess.
+fn key_rank(distri: ndarray::ArrayView1<f64>, correct: usize) -> u64 {
+    let p = distri[correct];
+    1 + distri
+        .iter()
+        .enumerate()
+        .filter(|(v, q)| **q > p || (**q == p && *v < correct))
+        .count() as u64
+}
+
+/// Rank of the `correct` value in the marginals of a single variable over many independent
+/// experiments (each with shape `(1, nc)`, see `single_success_probability`), for a rank-based
+/// evaluation of an attack. The ranks start at 1, and ties are broken as in `best_guess`.
+/// Returns the mean rank and the rank for each experiment.
+pub fn rank_statistics(marginals: &[Array2<f64>], correct: &[usize]) -> (f64, Array1<u64>) {
+    assert_eq!(
+        marginals.len(),
+        correct.len(),
+        "There must be a correct value per marginal."
+    );
+    let ranks: Array1<u64> = marginals
+        .iter()
+        .zip(correct.iter())
+        .map(|(distri, correct)| {
+            assert_eq!(
+                distri.shape()[0],
+                1,
+                "Marginals must be the ones of a single variable."
+            );
+            assert!(
+                *correct < distri.shape()[1],
+                "correct must be smaller than nc."
+            );
+            key_rank(distri.row(0), *correct)
+        })
+        .collect();
+    let mean = ranks.iter().sum::<u64>() as f64 / ranks.len() as f64;
+    (mean, ranks)
+}
+
+/// Entropy (in bits) of the distribution `distri`, which does not need to be normalized.
+fn entropy(distri: ndarray::ArrayView1<f64>) -> f64 {
+    let sum = distri.sum();
+    -distri
+        .iter()
+        .filter(|p| **p > 0.0)
+        .map(|p| {
+            let p = p / sum;
+            p * p.log2()
+        })
+        .sum::<f64>()
+}
+
+/// Information gained on each variable by the belief propagation, in bits, for each copy (a
+/// single copy for single variables): the entropy of the initial distribution minus the entropy
+/// of the current distribution for profiled variables, and `log2(nc)` (the entropy of the uniform
+/// distribution) minus the entropy of the current distribution otherwise.
+pub fn information_gain(variables: &[Var]) -> Vec<Array1<f64>> {
+    variables
+        .iter()
+        .map(|var| {
+            let current = distri_current(var).outer_iter().map(entropy);
+            match &var.vartype {
+                VarType::ProfilePara { distri_orig, .. }
+                | VarType::ProfileSingle { distri_orig, .. } => distri_orig
+                    .outer_iter()
+                    .zip(current)
+                    .map(|(orig, current)| entropy(orig) - current)
+                    .collect(),
+                VarType::NotProfilePara { distri_current }
+                | VarType::NotProfileSingle { distri_current } => {
+                    let max = (distri_current.shape()[1] as f64).log2();
+                    current.map(|current| max - current).collect()
+                }
+            }
+        })
+        .collect()
+}
+
+/// Turn the current distribution of a single variable into its initial distribution, such that
+/// subsequent runs of belief propagation use it as (fixed) evidence.
+/// This is useful for staged attacks, where the para variables are then replaced.
+/// Panics if `var` is a para variable.
+pub fn freeze_single(var: &mut Var) {
+    let distri = match &var.vartype {
+        VarType::ProfileSingle { distri_current, .. }
+        | VarType::NotProfileSingle { distri_current } => distri_current.clone(),
+        _ => panic!("Only single variables can be frozen."),
+    };
+    var.vartype = VarType::ProfileSingle {
+        distri_orig: distri.clone(),
+        distri_current: distri,
+    };
+}
+
+/// Initial distribution of the variable `id`, or `None` if it is not profiled (or if there is no
+/// such variable).
+pub fn get_prior(variables: &[Var], id: usize) -> Option<&Array2<f64>> {
+    match &variables.get(id)?.vartype {
+        VarType::ProfilePara { distri_orig, .. } | VarType::ProfileSingle { distri_orig, .. } => {
+            Some(distri_orig)
+        }
+        VarType::NotProfilePara { .. } | VarType::NotProfileSingle { .. } => None,
+    }
+}
+
+/// Set the initial distribution of the variable `id` to `distri`, which is taken into account
+/// from the next run of belief propagation (the current distribution is left unchanged). A
+/// variable that is not profiled becomes profiled (para or single as before).
+/// `distri` must have the shape of the current distribution of the variable, that is `(n, nc)`
+/// for a para variable and `(1, nc)` for a single one, and must be non-negative.
+pub fn set_prior(variables: &mut [Var], id: usize, distri: &Array2<f64>) -> Result<(), BPError> {
+    let n_vars = variables.len();
+    let var = variables.get_mut(id).ok_or_else(|| {
+        BPError::InvalidGraph(format!(
+            "variable {} is out of range, the graph has {} variables.",
+            id, n_vars
+        ))
+    })?;
+    let dim = distri_current(var).dim();
+    if distri.dim() != dim {
+        return Err(BPError::InvalidGraph(format!(
+            "prior of variable {} has shape {:?}, expected {:?}.",
+            id,
+            distri.dim(),
+            dim
+        )));
+    }
+    if !distri.iter().all(|p| *p >= 0.0 && p.is_finite()) {
+        return Err(BPError::InvalidGraph(format!(
+            "prior of variable {} must be finite and non-negative.",
+            id
+        )));
+    }
+    let vartype = std::mem::replace(
+        &mut var.vartype,
+        VarType::NotProfileSingle {
+            distri_current: Array2::zeros((0, 0)),
+        },
+    );
+    var.vartype = match vartype {
+        VarType::ProfilePara { distri_current, .. }
+        | VarType::NotProfilePara { distri_current } => VarType::ProfilePara {
+            distri_orig: distri.clone(),
+            distri_current,
+        },
+        VarType::ProfileSingle { distri_current, .. }
+        | VarType::NotProfileSingle { distri_current } => VarType::ProfileSingle {
+            distri_orig: distri.clone(),
+            distri_current,
+        },
+    };
+    Ok(())
+}
+
+/// Set of nodes updated by a partial run of belief propagation.
+#[derive(Debug, Clone, Default)]
+pub struct Frontier {
+    /// Ids of the function nodes, in increasing order.
+    pub functions: Vec<usize>,
+    /// Ids of the variable nodes, in increasing order.
+    pub variables: Vec<usize>,
+}
+
+/// Find the nodes that are at most `hops` function nodes away from the `dirty` variables.
+/// Every variable adjacent to a selected function node is also selected.
+pub fn frontier(
+    functions: &[Func],
+    variables: &[Var],
+    edge: usize,
+    dirty: &[usize],
+    hops: usize,
+) -> Frontier {
+    // Mapping of each edge to its function node id.
+    let mut edge_func: Vec<usize> = vec![0; edge];
+    for (i, f) in functions.iter().enumerate() {
+        f.neighboors.iter().for_each(|x| edge_func[*x] = i);
+    }
+    // Mapping of each edge to its variable node id.
+    let mut edge_var: Vec<usize> = vec![0; edge];
+    for (i, v) in variables.iter().enumerate() {
+        v.neighboors.iter().for_each(|x| edge_var[*x] = i);
+    }
+
+    let mut active_funcs = vec![false; functions.len()];
+    let mut active_vars = vec![false; variables.len()];
+    let mut new_vars: Vec<usize> = dirty.to_vec();
+    new_vars.iter().for_each(|v| active_vars[*v] = true);
+    for _ in 0..hops {
+        let mut new_funcs = Vec::new();
+        for v in new_vars.iter() {
+            for e in variables[*v].neighboors.iter() {
+                let f = edge_func[*e];
+                if !active_funcs[f] {
+                    active_funcs[f] = true;
+                    new_funcs.push(f);
+                }
+            }
+        }
+        new_vars.clear();
+        for f in new_funcs.iter() {
+            for e in functions[*f].neighboors.iter() {
+                let v = edge_var[*e];
+                if !active_vars[v] {
+                    active_vars[v] = true;
+                    new_vars.push(v);
+                }
+            }
+        }
+    }
+    let select = |active: Vec<bool>| {
+        active
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, a)| if a { Some(i) } else { None })
+            .collect()
+    };
+    Frontier {
+        functions: select(active_funcs),
+        variables: select(active_vars),
+    }
+}
+
+/// Run the belief propagation algorithm only on the nodes within `hops` function nodes of the
+/// `dirty` variables (e.g. the variables whose initial distribution changed), re-using the
+/// messages in `edges` from a previous run (see `run_bp_edges`) for the rest of the graph.
+/// As for `run_bp_edges`, the messages are normalized and clamped as with the default
+/// `RunBpOptions`.
+/// Returns the set of updated nodes.
+pub fn run_bp_partial(
+    functions: &[Func],
+    variables: &mut [Var],
+    edges: &mut [Array2<f64>],
+    dirty: &[usize],
+    hops: usize,
+    it: usize,
+) -> Result<Frontier, BPError> {
+    let frontier = frontier(functions, variables, edges.len(), dirty, hops);
+    let mut edge_active_func = vec![false; edges.len()];
+    for f in frontier.functions.iter() {
+        functions[*f]
+            .neighboors
+            .iter()
+            .for_each(|e| edge_active_func[*e] = true);
+    }
+
+    // Edges hold the messages from variables to function nodes. For the edges that go to a
+    // function node that is not updated, we recover the message from the function node based on
+    // the current distribution of the variable. At the first step, we do this for all the edges
+    // of the updated variables, in order to account for the new evidence.
+    let var_step = |edges: &mut [Array2<f64>], variables: &mut [Var], first: bool| {
+        let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f64>>> =
+            edges.iter_mut().map(|x| Some(x)).collect();
+        let mut active_vars: Vec<(&mut Var, Vec<&mut Array2<f64>>)> = Vec::new();
+        let mut selected = frontier.variables.iter().peekable();
+        for (i, var) in variables.iter_mut().enumerate() {
+            if selected.peek() == Some(&&i) {
+                selected.next();
+                let mut neighboors: Vec<&mut Array2<f64>> = Vec::new();
+                for e in var.neighboors.iter() {
+                    let msg = edge_opt_ref_mut[*e].take().unwrap();
+                    if first || !edge_active_func[*e] {
+                        divide_msg(distri_current(var), msg, false, Clamp::Fixed);
+                    }
+                    neighboors.push(msg);
+                }
+                active_vars.push((var, neighboors));
+            }
+        }
+        active_vars
+            .par_iter_mut()
+            .for_each(|(var, neighboors)| update_variable(var, neighboors, false, Clamp::Fixed));
+    };
+
+    var_step(edges, variables, true);
+    for _ in 0..it {
+        let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f64>>> =
+            edges.iter_mut().map(|x| Some(x)).collect();
+        let mut active: Vec<(&Func, Vec<&mut Array2<f64>>)> = frontier
+            .functions
+            .iter()
+            .map(|f| {
+                let f = &functions[*f];
+                let edge = f
+                    .neighboors
+                    .iter()
+                    .map(|e| edge_opt_ref_mut[*e].take().unwrap())
+                    .collect();
+                (f, edge)
+            })
+            .collect();
+        active
+            .par_iter_mut()
+            .for_each(|(function, edge)| update_function(function, edge, Clamp::Fixed));
+        var_step(edges, variables, false);
+    }
+
+    Ok(frontier)
+}
+
+/// Connected components of a factor graph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Components {
+    /// Component of each function node.
+    pub functions: Vec<usize>,
+    /// Component of each variable node.
+    pub variables: Vec<usize>,
+    /// Number of components. Components are numbered in order of their first variable node.
+    pub n_components: usize,
+}
+
+/// Find the root of `x` in the union-find forest `parent`, with path halving.
+fn find_root(parent: &mut [usize], mut x: usize) -> usize {
+    while parent[x] != x {
+        parent[x] = parent[parent[x]];
+        x = parent[x];
+    }
+    x
+}
+
+/// Compute the connected components of the factor graph, using union-find over the variables.
+pub fn connected_components(functions: &[Func], variables: &[Var], edge: usize) -> Components {
+    // Mapping of each edge to its variable node id (None for the edges without variable).
+    let mut edge_var: Vec<Option<usize>> = vec![None; edge];
+    for (i, v) in variables.iter().enumerate() {
+        v.neighboors.iter().for_each(|x| edge_var[*x] = Some(i));
+    }
+    let mut parent: Vec<usize> = (0..variables.len()).collect();
+    for f in functions.iter() {
+        let mut vars = f.neighboors.iter().filter_map(|e| edge_var[*e]);
+        if let Some(v0) = vars.next() {
+            let r0 = find_root(&mut parent, v0);
+            for v in vars {
+                let r = find_root(&mut parent, v);
+                parent[r] = r0;
+            }
+        }
+    }
+    let mut root_component: Vec<Option<usize>> = vec![None; variables.len()];
+    let mut n_components = 0;
+    let var_components: Vec<usize> = (0..variables.len())
+        .map(|i| {
+            let r = find_root(&mut parent, i);
+            *root_component[r].get_or_insert_with(|| {
+                n_components += 1;
+                n_components - 1
+            })
+        })
+        .collect();
+    let func_components: Vec<usize> = functions
+        .iter()
+        .map(|f| match f.neighboors.iter().find_map(|e| edge_var[*e]) {
+            Some(v) => var_components[v],
+            None => {
+                n_components += 1;
+                n_components - 1
+            }
+        })
+        .collect();
+    Components {
+        functions: func_components,
+        variables: var_components,
+        n_components,
+    }
+}
+
+/// Variable and function node adjacent to each edge (None if there is no such node).
+fn edge_endpoints(
+    functions: &[Func],
+    variables: &[Var],
+    edge: usize,
+) -> (Vec<Option<usize>>, Vec<Option<usize>>) {
+    let mut edge_var = vec![None; edge];
+    let mut edge_func = vec![None; edge];
+    for (i, v) in variables.iter().enumerate() {
+        v.neighboors.iter().for_each(|x| edge_var[*x] = Some(i));
+    }
+    for (i, f) in functions.iter().enumerate() {
+        f.neighboors.iter().for_each(|x| edge_func[*x] = Some(i));
+    }
+    (edge_var, edge_func)
+}
+
+/// Whether the factor graph is a forest (i.e., it has no cycle), in which case belief
+/// propagation is exact once the messages went through the whole graph.
+/// Uses union-find over the variable and function nodes.
+pub fn is_acyclic(functions: &[Func], variables: &[Var], edge: usize) -> bool {
+    let (edge_var, edge_func) = edge_endpoints(functions, variables, edge);
+    // Variable nodes, then function nodes.
+    let mut parent: Vec<usize> = (0..(variables.len() + functions.len())).collect();
+    edge_var
+        .iter()
+        .zip(edge_func.iter())
+        .all(|(v, f)| match (v, f) {
+            (Some(v), Some(f)) => {
+                let rv = find_root(&mut parent, *v);
+                let rf = find_root(&mut parent, variables.len() + *f);
+                parent[rv] = rf;
+                rv != rf
+            }
+            _ => true,
+        })
+}
+
+/// Cycles of the factor graph, each given as the list of its edges, in order.
+/// The cycles form a basis (every cycle of the graph is a combination of them): there is one
+/// cycle for each edge that is not in a spanning forest of the graph. The graph is acyclic if
+/// and only if there is no cycle.
+pub fn find_cycles(functions: &[Func], variables: &[Var], edge: usize) -> Vec<Vec<usize>> {
+    let nv = variables.len();
+    let (edge_var, edge_func) = edge_endpoints(functions, variables, edge);
+    let neighboors = |node: usize| {
+        if node < nv {
+            &variables[node].neighboors
+        } else {
+            &functions[node - nv].neighboors
+        }
+    };
+    let other = |node: usize, e: usize| {
+        if node < nv {
+            edge_func[e].map(|f| nv + f)
+        } else {
+            edge_var[e]
+        }
+    };
+    // Spanning forest (breadth-first): edge to the parent and depth of each node.
+    let n_nodes = nv + functions.len();
+    let mut parent_edge: Vec<Option<usize>> = vec![None; n_nodes];
+    let mut depth: Vec<Option<usize>> = vec![None; n_nodes];
+    let mut in_tree = vec![false; edge];
+    for root in 0..n_nodes {
+        if depth[root].is_some() {
+            continue;
+        }
+        depth[root] = Some(0);
+        let mut queue = std::collections::VecDeque::from(vec![root]);
+        while let Some(node) = queue.pop_front() {
+            for e in neighboors(node).iter() {
+                if let Some(next) = other(node, *e) {
+                    if depth[next].is_none() {
+                        depth[next] = Some(depth[node].unwrap() + 1);
+                        parent_edge[next] = Some(*e);
+                        in_tree[*e] = true;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+    }
+    // Each edge that is not in the forest closes a cycle with the paths to the common ancestor.
+    (0..edge)
+        .filter(|e| !in_tree[*e])
+        .filter_map(|e| Some((e, edge_var[e]?, nv + edge_func[e]?)))
+        .map(|(e, mut a, mut b)| {
+            let mut path_a = vec![e];
+            let mut path_b = Vec::new();
+            while a != b {
+                if depth[a] >= depth[b] {
+                    let pe = parent_edge[a].unwrap();
+                    path_a.push(pe);
+                    a = other(a, pe).unwrap();
+                } else {
+                    let pe = parent_edge[b].unwrap();
+                    path_b.push(pe);
+                    b = other(b, pe).unwrap();
+                }
+            }
+            path_a.extend(path_b.into_iter().rev());
+            path_a
+        })
+        .collect()
+}
+
+/// Graphviz (DOT) description of the factor graph, e.g. for visualization with `dot -Tsvg`.
+/// Variable nodes `v<id>` are ellipses (double for single variables, filled for profiled ones),
+/// function nodes `f<id>` are boxes labeled with their operator, and the graph edges are labeled
+/// with their id. Edges that are not connected at both ends are omitted.
+pub fn to_dot(functions: &[Func], variables: &[Var]) -> String {
+    let edge = functions
+        .iter()
+        .flat_map(|f| f.neighboors.iter())
+        .chain(variables.iter().flat_map(|v| v.neighboors.iter()))
+        .max()
+        .map_or(0, |e| e + 1);
+    let (edge_var, edge_func) = edge_endpoints(functions, variables, edge);
+    let mut dot = String::from("graph factor_graph {\n");
+    for (i, var) in variables.iter().enumerate() {
+        let info = var.describe();
+        dot.push_str(&format!(
+            "    v{} [shape=ellipse, label=\"v{}\", peripheries={}{}];\n",
+            i,
+            i,
+            if info.para { 1 } else { 2 },
+            if info.profiled { ", style=filled" } else { "" }
+        ));
+    }
+    for (i, func) in functions.iter().enumerate() {
+        // the name of the operator does not depend on the size of the field
+        dot.push_str(&format!(
+            "    f{} [shape=box, label=\"f{}\\n{}\"];\n",
+            i,
+            i,
+            func.describe(0).op
+        ));
+    }
+    for (e, (var, func)) in edge_var.iter().zip(edge_func.iter()).enumerate() {
+        if let (Some(var), Some(func)) = (var, func) {
+            dot.push_str(&format!("    v{} -- f{} [label=\"{}\"];\n", var, func, e));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Exact marginals of the variables of an acyclic factor graph (see `is_acyclic`), without
+/// iterations: each tree is rooted (at its single variable, if any), and the messages are passed
+/// once from the leaves to the root, then once from the root to the leaves.
+/// This gives the same result as `run_bp` with enough iterations, and can serve as a reference
+/// for the belief propagation.
+/// Since the copies are connected through the single variables, a tree cannot contain more than
+/// one single variable if n > 1.
+/// The messages are normalized and clamped as with the default `RunBpOptions`.
+pub fn infer_exact(
+    functions: &[Func],
+    variables: &mut [Var],
+    edge: usize,
+    nc: usize,
+    n: usize,
+) -> Result<(), BPError> {
+    if !is_acyclic(functions, variables, edge) {
+        return Err(BPError::InvalidGraph("graph is not acyclic.".to_owned()));
+    }
+    let nv = variables.len();
+    let (edge_var, edge_func) = edge_endpoints(functions, variables, edge);
+    // Variable nodes, then function nodes.
+    let neighboors: Vec<Vec<usize>> = variables
+        .iter()
+        .map(|v| v.neighboors.clone())
+        .chain(functions.iter().map(|f| f.neighboors.clone()))
+        .collect();
+    let other = |node: usize, e: usize| {
+        if node < nv {
+            edge_func[e].map(|f| nv + f)
+        } else {
+            edge_var[e]
+        }
+    };
+    let single: Vec<bool> = variables
+        .iter()
+        .map(|v| {
+            matches!(
+                v.vartype,
+                VarType::ProfileSingle { .. } | VarType::NotProfileSingle { .. }
+            )
+        })
+        .collect();
+
+    // Breadth-first order of the nodes of each tree, and edge to the parent of each node.
+    let n_nodes = nv + functions.len();
+    let mut parent_edge: Vec<Option<usize>> = vec![None; n_nodes];
+    let mut visited = vec![false; n_nodes];
+    let mut order: Vec<usize> = Vec::with_capacity(n_nodes);
+    let roots = (0..nv).filter(|v| single[*v]).chain(0..n_nodes);
+    for root in roots {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let start = order.len();
+        order.push(root);
+        let mut i = start;
+        while i < order.len() {
+            let node = order[i];
+            i += 1;
+            for e in neighboors[node].iter() {
+                if let Some(next) = other(node, *e) {
+                    if !visited[next] {
+                        visited[next] = true;
+                        parent_edge[next] = Some(*e);
+                        order.push(next);
+                    }
+                }
+            }
+        }
+        if n > 1
+            && order[start..]
+                .iter()
+                .filter(|x| **x < nv && single[**x])
+                .count()
+                > 1
+        {
+            return Err(BPError::InvalidGraph(
+                "a tree contains several single variables.".to_owned(),
+            ));
+        }
+    }
+
+    // Messages towards the roots and towards the leaves.
+    let widths = edge_widths(variables, edge, nc);
+    let mut up: Vec<Array2<f64>> = widths.iter().map(|w| Array2::ones((n, *w))).collect();
+    let mut down = up.clone();
+    // Outgoing messages of a node (in the order of its neighboors), given its incoming messages.
+    let update = |node: usize, variables: &mut [Var], up: &[Array2<f64>], down: &[Array2<f64>]| {
+        let mut msgs: Vec<Array2<f64>> = neighboors[node]
+            .iter()
+            .map(|e| {
+                if parent_edge[node] == Some(*e) {
+                    down[*e].clone()
+                } else {
+                    up[*e].clone()
+                }
+            })
+            .collect();
+        let mut msgs_ref: Vec<&mut Array2<f64>> = msgs.iter_mut().collect();
+        if node < nv {
+            update_variable(&mut variables[node], &mut msgs_ref, false, Clamp::Fixed);
+        } else {
+            update_function(&functions[node - nv], &mut msgs_ref, Clamp::Fixed);
+            // A zero probability would give 0/0 when the variable computes its extrinsic
+            // messages (e.g., for the values outside the image of a LOOKUP table).
+            msgs_ref
+                .iter_mut()
+                .for_each(|msg| make_non_zero(*msg, Clamp::Fixed));
+        }
+        msgs
+    };
+    for node in order.iter().rev() {
+        if let Some(pe) = parent_edge[*node] {
+            let msgs = update(*node, variables, &up, &down);
+            let i = neighboors[*node].iter().position(|e| *e == pe).unwrap();
+            up[pe] = msgs.into_iter().nth(i).unwrap();
+        }
+    }
+    // The variables get their exact distribution from all the incoming messages.
+    for node in order.iter() {
+        let msgs = update(*node, variables, &up, &down);
+        for (e, msg) in neighboors[*node].iter().zip(msgs.into_iter()) {
+            if parent_edge[*node] != Some(*e) {
+                down[*e] = msg;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Largest number of joint assignments of the variables enumerated by `brute_force_marginals`.
+pub const BRUTE_FORCE_MAX_STATES: usize = 1 << 20;
+
+/// Exact marginals of the variables (in the order of `variables`, and with the same shapes as
+/// `get_marginals`), computed by enumerating all the joint assignments of the variables and
+/// weighting each one by the initial distributions and by the factors of the function nodes
+/// (see `func_factor`), for a graph over a field of size `nc` with `n` copies.
+/// This holds for any graph (even with cycles), and can serve as a reference for the belief
+/// propagation on small graphs: since the copies are independent given the single variables,
+/// there are `n * nc**(#single + #para)` joint assignments (with the size of each variable
+/// instead of `nc`), and `BPError::StateSpaceTooLarge` is returned above
+/// `BRUTE_FORCE_MAX_STATES`.
+/// The marginals are NaN if no assignment satisfies the constraints (see `check_feasibility`).
+pub fn brute_force_marginals(
+    functions: &[Func],
+    variables: &[Var],
+    nc: usize,
+    n: usize,
+) -> Result<Vec<Array2<f64>>, BPError> {
+    let widths: Vec<usize> = variables
+        .iter()
+        .map(|var| distri_current(var).shape()[1])
+        .collect();
+    let (singles, paras): (Vec<usize>, Vec<usize>) =
+        (0..variables.len()).partition(|v| !variables[*v].describe().para);
+    let n_states = |vars: &[usize]| {
+        vars.iter()
+            .fold(1usize, |acc, v| acc.saturating_mul(widths[*v]))
+    };
+    let (n_single_states, n_para_states) = (n_states(&singles), n_states(&paras));
+    let states = n_single_states
+        .saturating_mul(n_para_states)
+        .saturating_mul(n);
+    if states > BRUTE_FORCE_MAX_STATES {
+        return Err(BPError::StateSpaceTooLarge { states });
+    }
+    let mut edge_var = BTreeMap::new();
+    for (v, var) in variables.iter().enumerate() {
+        var.neighboors.iter().for_each(|e| {
+            edge_var.insert(*e, v);
+        });
+    }
+    let func_vars = functions
+        .iter()
+        .enumerate()
+        .map(|(id, func)| {
+            if let FuncType::CUSTOM(_) = func.functype {
+                return Err(BPError::UnsupportedFunction { function: id });
+            }
+            func.ordered_neighboors()
+                .iter()
+                .map(|e| {
+                    edge_var.get(e).cloned().ok_or_else(|| {
+                        BPError::InvalidGraph(format!(
+                            "edge {} of function node {} has no variable.",
+                            e, id
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<usize>, BPError>>()
+        })
+        .collect::<Result<Vec<_>, BPError>>()?;
+    let prior = |v: usize, k: usize, x: usize| match &variables[v].vartype {
+        VarType::ProfilePara { distri_orig, .. } => distri_orig[[k, x]],
+        VarType::ProfileSingle { distri_orig, .. } => distri_orig[[0, x]],
+        VarType::NotProfilePara { .. } | VarType::NotProfileSingle { .. } => 1.0,
+    };
+    // sets the values of `vars` to the `state`-th assignment (in mixed radix)
+    let decode = |values: &mut [usize], vars: &[usize], mut state: usize| {
+        for v in vars.iter() {
+            values[*v] = state % widths[*v];
+            state /= widths[*v];
+        }
+    };
+
+    let mut values = vec![0; variables.len()];
+    let mut inputs = Vec::new();
+    // log-probability of each assignment of the single variables, and for each copy, the
+    // marginals of the para variables given this assignment
+    let mut log_p_singles = Array1::<f64>::zeros(n_single_states);
+    let mut para_marginals: Vec<Vec<Array2<f64>>> = Vec::with_capacity(n_single_states);
+    for s in 0..n_single_states {
+        decode(&mut values, &singles, s);
+        log_p_singles[s] = singles.iter().map(|v| prior(*v, 0, values[*v]).ln()).sum();
+        let mut marginals: Vec<Array2<f64>> = paras
+            .iter()
+            .map(|v| Array2::zeros((n, widths[*v])))
+            .collect();
+        for k in 0..n {
+            let mut z = 0.0;
+            for p in 0..n_para_states {
+                decode(&mut values, &paras, p);
+                let mut w: f64 = paras.iter().map(|v| prior(*v, k, values[*v])).product();
+                for (func, vars) in functions.iter().zip(func_vars.iter()) {
+                    if w == 0.0 {
+                        break;
+                    }
+                    if func.enabled.as_ref().map_or(true, |e| e[k]) {
+                        inputs.clear();
+                        inputs.extend(vars[1..].iter().map(|v| values[*v]));
+                        w *= func_factor(&func.functype, values[vars[0]], &inputs, k, nc).unwrap();
+                    }
+                }
+                z += w;
+                for (v, marginal) in paras.iter().zip(marginals.iter_mut()) {
+                    marginal[[k, values[*v]]] += w;
+                }
+            }
+            log_p_singles[s] += z.ln();
+            for marginal in marginals.iter_mut() {
+                let mut marginal = marginal.row_mut(k);
+                marginal /= z;
+            }
+        }
+        para_marginals.push(marginals);
+    }
+    let max = log_p_singles.fold(f64::NEG_INFINITY, |acc, x| acc.max(*x));
+    let mut p_singles = log_p_singles.mapv(|x| (x - max).exp());
+    p_singles /= p_singles.sum();
+
+    let mut res: Vec<Array2<f64>> = variables
+        .iter()
+        .map(|var| Array2::zeros(distri_current(var).raw_dim()))
+        .collect();
+    for (s, (p, marginals)) in p_singles.iter().zip(para_marginals.iter()).enumerate() {
+        decode(&mut values, &singles, s);
+        for v in singles.iter() {
+            res[*v][[0, values[*v]]] += p;
+        }
+        for (v, marginal) in paras.iter().zip(marginals.iter()) {
+            res[*v].scaled_add(*p, marginal);
+        }
+    }
+    Ok(res)
+}
+
+/// Run the belief propagation algorithm independently (and in parallel) on each connected
+/// component of the factor graph, with the checks and the `options` of `run_bp_with_options`
+/// (except `RunBpOptions::binary`).
+/// Each component runs at most `it` iterations, and stops as soon as the largest absolute change
+/// of the distributions of its variables in an iteration is below `tol` (e.g., 0.0 to run all
+/// the iterations). Without early stopping, the result is the same as `run_bp_with_options`.
+/// Returns the connected components, and the number of iterations run by each of them.
+pub fn run_bp_components(
+    functions: &[Func],
+    variables: &mut [Var],
+    it: usize,
+    // number of edges in the graph
+    edge: usize,
+    // size of the field
+    nc: usize,
+    // number of copies in the graph (n_runs)
+    n: usize,
+    options: RunBpOptions,
+    tol: f64,
+) -> Result<(Components, Vec<usize>), BPError> {
+    check_options(&options);
+    check_edges(functions, variables, edge, nc)?;
+    BpLimits::default().check(variables, edge, nc, n)?;
+    let components = connected_components(functions, variables, edge);
+    let mut edges = init_edges(variables, edge, nc, n);
+    init_messages(variables, &mut edges, options.init);
+
+    // Split the graph: for each component, its function nodes, variable nodes (with their ids)
+    // and edges, where the neighboors of the nodes are re-numbered as indices in the edges of the
+    // component. The edges without variable belong to the component of their function node.
+    let mut local_edge_id: Vec<Option<usize>> = vec![None; edge];
+    let mut comp_edges: Vec<Vec<Array2<f64>>> =
+        (0..components.n_components).map(|_| Vec::new()).collect();
+    for (var, c) in variables.iter().zip(components.variables.iter()) {
+        for e in var.neighboors.iter() {
+            local_edge_id[*e] = Some(comp_edges[*c].len());
+            comp_edges[*c].push(std::mem::take(&mut edges[*e]));
+        }
+    }
+    let mut comp_funcs: Vec<Vec<(&Func, Vec<usize>)>> =
+        (0..components.n_components).map(|_| Vec::new()).collect();
+    for (f, c) in functions.iter().zip(components.functions.iter()) {
+        let neighboors = f
+            .neighboors
+            .iter()
+            .map(|e| {
+                *local_edge_id[*e].get_or_insert_with(|| {
+                    comp_edges[*c].push(std::mem::take(&mut edges[*e]));
+                    comp_edges[*c].len() - 1
+                })
+            })
+            .collect();
+        comp_funcs[*c].push((f, neighboors));
+    }
+    let mut comp_vars: Vec<Vec<(usize, &mut Var, Vec<usize>)>> =
+        (0..components.n_components).map(|_| Vec::new()).collect();
+    for (id, (var, c)) in variables
+        .iter_mut()
+        .zip(components.variables.iter())
+        .enumerate()
+    {
+        let neighboors = var
+            .neighboors
+            .iter()
+            .map(|e| local_edge_id[*e].unwrap())
+            .collect();
+        comp_vars[*c].push((id, var, neighboors));
+    }
+
+    let iterations = comp_funcs
+        .into_par_iter()
+        .zip(comp_vars.into_par_iter())
+        .zip(comp_edges.into_par_iter())
+        .map(|((funcs, mut vars), mut edges)| {
+            for iteration in 0..it {
+                let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f64>>> =
+                    edges.iter_mut().map(Some).collect();
+                let mut edge_for_func: Vec<Vec<&mut Array2<f64>>> = funcs
+                    .iter()
+                    .map(|(_, nb)| {
+                        nb.iter()
+                            .map(|e| edge_opt_ref_mut[*e].take().unwrap())
+                            .collect()
+                    })
+                    .collect();
+                funcs
+                    .par_iter()
+                    .zip(edge_for_func.par_iter_mut())
+                    .for_each(|((f, _), edge)| update_function(f, edge, options.clamp));
+                let previous: Vec<Array2<f64>> = if tol > 0.0 {
+                    vars.iter()
+                        .map(|(_, var, _)| distri_current(var).clone())
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f64>>> =
+                    edges.iter_mut().map(Some).collect();
+                let mut edge_for_var: Vec<Vec<&mut Array2<f64>>> = vars
+                    .iter()
+                    .map(|(_, _, nb)| {
+                        nb.iter()
+                            .map(|e| edge_opt_ref_mut[*e].take().unwrap())
+                            .collect()
+                    })
+                    .collect();
+                vars.par_iter_mut()
+                    .zip(edge_for_var.par_iter_mut())
+                    .for_each(|((_, var, _), neighboors)| {
+                        update_variable(var, neighboors, options.stable_norm, options.clamp)
+                    });
+                if let Some((variable, _, _)) = vars
+                    .iter()
+                    .find(|(_, var, _)| distri_diverged(distri_current(var)))
+                {
+                    return Err(BPError::Diverged {
+                        variable: *variable,
+                        iteration,
+                    });
+                }
+                if tol > 0.0 {
+                    let residual = vars.iter().zip(previous.iter()).fold(
+                        0.0,
+                        |acc: f64, ((_, var, _), previous)| {
+                            acc.max(
+                                ConvergenceMetric::Absolute.distance(distri_current(var), previous),
+                            )
+                        },
+                    );
+                    if residual < tol {
+                        return Ok(iteration + 1);
+                    }
+                }
+            }
+            Ok(it)
+        })
+        .collect::<Result<Vec<usize>, BPError>>()?;
+
+    Ok((components, iterations))
+}
+
+/// Joint distribution of the output and of the input of a function node with two adjacent
+/// edges (XORCST, ANDCST, ADDCST, MULCST, NEG, LOOKUP, LOOKUP_PARA and LOOKUPMAP), given the
+/// messages from the variables to the function node in `edges` (e.g. after `run_bp_edges`).
+/// For the copies where the function node is disabled, the variables are independent.
+/// Panics if the function node is not supported.
+/// return array axes (n, output, input)
+pub fn pairwise_joint(func: &Func, edges: &[Array2<f64>]) -> Array3<f64> {
+    let neighboors = func.ordered_neighboors();
+    let output_msg = &edges[neighboors[0]];
+    let input_msg = &edges[neighboors[1]];
+    let (n, nc) = input_msg.dim();
+    let nc_out = output_msg.shape()[1];
+    let mut joint = Array3::zeros((n, nc_out, nc));
+    joint
+        .outer_iter_mut()
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(k, mut joint)| {
+            let enabled = func.enabled.as_ref().map(|e| e[k]).unwrap_or(true);
+            for i in 0..nc {
+                if enabled {
+                    let outputs = match &func.functype {
+                        FuncType::XORCST(values)
+                        | FuncType::ANDCST(values)
+                        | FuncType::ADDCST(values)
+                        | FuncType::MULCST(values) => cst_outputs(&func.functype, i, values[k], nc),
+                        FuncType::NEG => vec![neg_map(nc).image(i)],
+                        FuncType::LOOKUP(table) => vec![table[i] as usize],
+                        FuncType::LOOKUP_PARA(tables) => vec![tables[[k, i]] as usize],
+                        FuncType::LOOKUPMAP(map) => vec![map.image(i)],
+                        _ => panic!("pairwise_joint requires a function node with two edges."),
+                    };
+                    for o in outputs {
+                        joint[[o, i]] += output_msg[[k, o]] * input_msg[[k, i]];
+                    }
+                } else {
+                    for o in 0..nc_out {
+                        joint[[o, i]] = output_msg[[k, o]] * input_msg[[k, i]];
+                    }
+                }
+            }
+            let sum = joint.sum();
+            joint /= sum;
+        });
+    joint
+}
+
+/// Mutual information (in bits) between the output and the input of a function node, based on
+/// their joint distribution (see `pairwise_joint`).
+/// return array axes (n,)
+pub fn mutual_information(func: &Func, edges: &[Array2<f64>]) -> Array1<f64> {
+    let joint = pairwise_joint(func, edges);
+    let mut mi = Array1::zeros(joint.shape()[0]);
+    (mi.axis_iter_mut(Axis(0)), joint.outer_iter())
+        .into_par_iter()
+        .for_each(|(mut mi, joint)| {
+            let p_out = joint.sum_axis(Axis(1));
+            let p_in = joint.sum_axis(Axis(0));
+            let res: f64 = joint
+                .indexed_iter()
+                .filter(|(_, p)| **p > 0.0)
+                .map(|((o, i), p)| {
+                    p * (p.max(MIN_PROBA) / (p_out[o] * p_in[i]).max(MIN_PROBA)).log2()
+                })
+                .sum();
+            mi.fill(res);
+        });
+    mi
+}
+
+/// Factor of a function node for the given output and inputs, in the copy `k`: the number of
+/// public values for which the function maps the inputs to the output (one or zero if the public
+/// value is known, or for the function nodes without public value).
+/// Returns `None` if the function cannot be evaluated (CUSTOM operators).
+/// For NOISE function nodes, this is the weight of the difference in the kernel.
+fn func_factor(
+    functype: &FuncType,
+    output: usize,
+    inputs: &[usize],
+    k: usize,
+    nc: usize,
+) -> Option<f64> {
+    match functype {
+        FuncType::XORCST(values)
+        | FuncType::ANDCST(values)
+        | FuncType::ADDCST(values)
+        | FuncType::MULCST(values) => Some(
+            cst_outputs(functype, *inputs.last().unwrap(), values[k], nc)
+                .iter()
+                .filter(|o| **o == output)
+                .count() as f64,
+        ),
+        FuncType::NOISE { kernel } => {
+            Some(kernel[(output + nc - *inputs.last().unwrap()) % nc] / kernel.sum())
+        }
+        _ => func_output(functype, inputs, k, nc).map(|o| (o == output) as u8 as f64),
+    }
+}
+
+/// Output of a function node for the given inputs, in the copy `k`.
+/// Returns `None` if the function cannot be evaluated (CUSTOM operators) or is not deterministic
+/// (NOISE).
+fn func_output(functype: &FuncType, inputs: &[usize], k: usize, nc: usize) -> Option<usize> {
+    let inputs = inputs.iter().map(|x| *x as u64);
+    let nc = nc as u64;
+    let res = match functype {
+        FuncType::AND => inputs.fold(!0, |acc, x| acc & x),
+        FuncType::NAND => !inputs.fold(!0, |acc, x| acc & x) & (nc - 1),
+        FuncType::NOR => !inputs.fold(0, |acc, x| acc | x) & (nc - 1),
+        FuncType::XOR => inputs.fold(0, |acc, x| acc ^ x),
+        FuncType::ADD => inputs.fold(0, |acc, x| (acc + x) % nc),
+        FuncType::MUL => inputs.fold(1, |acc, x| (acc * x) % nc),
+        FuncType::XORCST(values)
+        | FuncType::ANDCST(values)
+        | FuncType::ADDCST(values)
+        | FuncType::MULCST(values) => {
+            return Some(cst_output(
+                functype,
+                inputs.last().unwrap() as usize,
+                values[k],
+                nc as usize,
+            ))
+        }
+        FuncType::NEG => (nc - inputs.last().unwrap()) % nc,
+        FuncType::LOOKUP(table) => table[inputs.last().unwrap() as usize] as u64,
+        FuncType::LOOKUP_PARA(tables) => tables[[k, inputs.last().unwrap() as usize]] as u64,
+        FuncType::LOOKUPMAP(map) => map.image(inputs.last().unwrap() as usize) as u64,
+        FuncType::NOISE { .. } | FuncType::CUSTOM(_) => return None,
+    };
+    Some(res as usize)
+}
+
+/// Belief of each function node, that is the distribution of the values of its adjacent
+/// variables, given the messages from the variables to the function nodes in `edges` (e.g. after
+/// `run_bp_edges`). This is the product of the incoming messages and of the factor (for the
+/// copies where the function node is disabled, the factor is constant).
+/// Since the belief has `nc**degree` values per copy, an error is returned for function nodes with
+/// more than `max_degree` adjacent edges.
+/// return array axes for each function node (n, output, input1, input2, ...)
+pub fn factor_beliefs(
+    functions: &[Func],
+    edges: &[Array2<f64>],
+    max_degree: usize,
+) -> Result<Vec<ArrayD<f64>>, BPError> {
+    functions
+        .iter()
+        .enumerate()
+        .map(|(id, func)| {
+            let degree = func.neighboors.len();
+            if degree > max_degree {
+                return Err(BPError::DegreeTooLarge {
+                    function: id,
+                    degree,
+                });
+            }
+            if let FuncType::CUSTOM(_) = func.functype {
+                return Err(BPError::UnsupportedFunction { function: id });
+            }
+            let msgs: Vec<&Array2<f64>> = func
+                .ordered_neighboors()
+                .iter()
+                .map(|e| &edges[*e])
+                .collect();
+            let n = msgs[0].shape()[0];
+            let nc_out = msgs[0].shape()[1];
+            let mut shape = vec![n];
+            shape.extend(msgs.iter().map(|msg| msg.shape()[1]));
+            let mut belief = ArrayD::zeros(IxDyn(&shape));
+            belief
+                .outer_iter_mut()
+                .into_par_iter()
+                .enumerate()
+                .for_each(|(k, mut belief)| {
+                    let enabled = func.enabled.as_ref().map(|e| e[k]).unwrap_or(true);
+                    belief.indexed_iter_mut().for_each(|(idx, b)| {
+                        let idx = ndarray::Dimension::slice(&idx);
+                        let factor = if enabled {
+                            func_factor(&func.functype, idx[0], &idx[1..], k, nc_out).unwrap()
+                        } else {
+                            1.0
+                        };
+                        if factor > 0.0 {
+                            *b = factor
+                                * msgs
+                                    .iter()
+                                    .zip(idx.iter())
+                                    .map(|(msg, i)| msg[[k, *i]])
+                                    .product::<f64>();
+                        }
+                    });
+                    let sum = belief.sum();
+                    belief /= sum;
+                });
+            Ok(belief)
+        })
+        .collect()
+}
+
+/// Marginalize the distribution `distri` (with shape `(n, 2**bits)`) of a `bits`-bit variable
+/// down to its `byte`-th byte (byte 0 being the least significant one).
+/// The byte must be fully contained in the variable, that is `8*(byte+1) <= bits`: for widths
+/// that are not a multiple of 8, the most significant (partial) byte cannot be extracted.
+/// return array axes (n, 256)
+pub fn marginalize_bytes(distri: &Array2<f64>, bits: u32, byte: u32) -> Array2<f64> {
+    assert_eq!(
+        distri.shape()[1],
+        1 << bits,
+        "distri must have shape (n, 2**bits)."
+    );
+    assert!(
+        8 * (byte + 1) <= bits,
+        "byte must be fully contained in the variable."
+    );
+    let mut res = Array2::zeros((distri.shape()[0], 256));
+    res.outer_iter_mut()
+        .zip(distri.outer_iter())
+        .for_each(|(mut res, distri)| {
+            distri.iter().enumerate().for_each(|(v, p)| {
+                res[(v >> (8 * byte)) & 0xff] += p;
+            });
+        });
+    res
+}
+
+/// Gray code of the value `i`.
+fn gray_code(i: usize) -> usize {
+    i ^ (i >> 1)
+}
+
+/// Permute the distribution `distri` (over `nc` values, a power of two) from the standard binary
+/// ordering of the values to the Gray-code ordering: the `i`-th probability of the result is the
+/// one of the value `i ^ (i >> 1)` (e.g., before a node whose leakage model is natural in
+/// Gray-code order). See `from_gray_order` for the inverse.
+pub fn to_gray_order(distri: &Array1<f64>) -> Array1<f64> {
+    assert!(
+        distri.len().is_power_of_two(),
+        "Gray-code ordering requires nc to be a power of two."
+    );
+    (0..distri.len()).map(|i| distri[gray_code(i)]).collect()
+}
+
+/// Permute the distribution `distri` from the Gray-code ordering of the values back to the
+/// standard binary ordering (inverse of `to_gray_order`).
+pub fn from_gray_order(distri: &Array1<f64>) -> Array1<f64> {
+    assert!(
+        distri.len().is_power_of_two(),
+        "Gray-code ordering requires nc to be a power of two."
+    );
+    let mut res = Array1::zeros(distri.len());
+    distri
+        .iter()
+        .enumerate()
+        .for_each(|(i, p)| res[gray_code(i)] = *p);
+    res
+}
+
+/// Combine the single-node marginals of a variable obtained from independent BP runs (e.g., on
+/// distinct trace windows for the same secret) by multiplying them, as independent pieces of
+/// evidence.
+/// Each run is clipped down to `MIN_PROBA` before the product, such that a run cannot fully
+/// exclude a value, and the result is normalized after each run to avoid underflows.
+/// All runs must have the same shape `(n, nc)`.
+/// return array axes (n, nc)
+pub fn combine_single_marginals(runs: &[Array2<f64>]) -> Array2<f64> {
+    assert!(!runs.is_empty(), "At least one run is needed.");
+    let mut res = Array2::<f64>::ones(runs[0].dim());
+    for run in runs {
+        assert_eq!(run.dim(), res.dim(), "All runs must have the same shape.");
+        Zip::from(&mut res)
+            .and(run)
+            .for_each(|r, p| *r *= p.max(MIN_PROBA));
+        res.outer_iter_mut().for_each(|mut r| {
+            let sum = r.sum();
+            r /= sum;
+        });
+        make_non_zero(&mut res, Clamp::Fixed);
+    }
+    res
+}
+
+/// Whether a function node is supported by `run_bp_binary`: the LOOKUP tables must be
+/// permutations of GF(2).
+fn binary_supported(function: &Func) -> bool {
+    match &function.functype {
+        FuncType::XOR | FuncType::XORCST(_) => true,
+        FuncType::LOOKUP(table) => {
+            table.len() == 2 && table.iter().all(|x| *x < 2) && table[0] != table[1]
+        }
+        _ => false,
+    }
+}
+
+/// Log-likelihood ratio ln(p[0]/p[1]) of each copy of a distribution over GF(2).
+fn distri_to_llr(distri: &Array2<f64>) -> Array1<f64> {
+    distri
+        .outer_iter()
+        .map(|p| (p[0].max(MIN_PROBA) / p[1].max(MIN_PROBA)).ln())
+        .collect()
+}
+
+/// Set each copy of `distri` to the distribution over GF(2) given by its log-likelihood ratio.
+fn llr_to_distri(llr: &Array1<f64>, distri: &mut Array2<f64>) {
+    distri
+        .outer_iter_mut()
+        .zip(llr.iter())
+        .for_each(|(mut p, llr)| {
+            p[0] = 1.0 / (1.0 + (-llr).exp());
+            p[1] = 1.0 - p[0];
+        });
+    make_non_zero(distri, Clamp::Fixed);
+}
+
+/// Compute the messages from a function node to the variable nodes, in log-likelihood ratio
+/// representation (see `run_bp_binary`).
+fn update_function_llr(function: &Func, edge: &mut [&mut Array1<f64>]) {
+    edge[..=function.output].rotate_right(1);
+    update_function_llr_msgs(function, edge);
+    edge[..=function.output].rotate_left(1);
+    if let Some(enabled) = &function.enabled {
+        edge.iter_mut().for_each(|llr| {
+            llr.iter_mut()
+                .zip(enabled.iter())
+                .filter(|(_, enabled)| !**enabled)
+                .for_each(|(llr, _)| *llr = 0.0);
+        });
+    }
+}
+
+/// Compute the log-likelihood ratio messages of a function node, for all the copies.
+fn update_function_llr_msgs(function: &Func, edge: &mut [&mut Array1<f64>]) {
+    match &function.functype {
+        FuncType::XOR => {
+            // tanh rule: tanh(l_j/2) = prod_{i != j} tanh(l_i/2)
+            let n = edge[0].len();
+            let mut th = vec![0.0; edge.len()];
+            let mut prefix = vec![0.0; edge.len()];
+            for copy in 0..n {
+                th.iter_mut()
+                    .zip(edge.iter())
+                    .for_each(|(th, llr)| *th = (llr[copy] / 2.0).tanh());
+                prefix.iter_mut().zip(th.iter()).fold(1.0, |acc, (p, th)| {
+                    *p = acc;
+                    acc * th
+                });
+                let mut suffix = 1.0;
+                for (i, llr) in edge.iter_mut().enumerate().rev() {
+                    let prod = prefix[i] * suffix;
+                    llr[copy] = (2.0 * prod.atanh()).max(-MAX_LLR).min(MAX_LLR);
+                    suffix *= th[i];
+                }
+            }
+        }
+        FuncType::XORCST(values) => {
+            let [output_llr, input1_llr]: &mut [_; 2] = edge.try_into().unwrap();
+            output_llr
+                .iter_mut()
+                .zip(input1_llr.iter_mut())
+                .zip(values.iter())
+                .for_each(|((output_llr, input1_llr), value)| {
+                    if *value == UNKNOWN_CST {
+                        // XOR with a uniform bit: no information.
+                        *output_llr = 0.0;
+                        *input1_llr = 0.0;
+                        return;
+                    }
+                    let sign = if value & 0x1 == 1 { -1.0 } else { 1.0 };
+                    let tmp = *output_llr;
+                    *output_llr = sign * *input1_llr;
+                    *input1_llr = sign * tmp;
+                });
+        }
+        FuncType::LOOKUP(table) => {
+            let [output_llr, input1_llr]: &mut [_; 2] = edge.try_into().unwrap();
+            let sign = if table[0] == 0 { 1.0 } else { -1.0 };
+            output_llr.iter_mut().zip(input1_llr.iter_mut()).for_each(
+                |(output_llr, input1_llr)| {
+                    let tmp = *output_llr;
+                    *output_llr = sign * *input1_llr;
+                    *input1_llr = sign * tmp;
+                },
+            );
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Whether a variable diverged in a copy, given the log-likelihood ratio of its initial
+/// distribution `prior` and of its current distribution `llr`: the latter is not finite, or the
+/// prior is saturated (see `MAX_LLR`) and contradicted by saturated messages from the function
+/// nodes. This is the counterpart of a distribution collapsing to `MIN_PROBA` (see
+/// `check_divergence`).
+fn llr_diverged(prior: f64, llr: f64) -> bool {
+    let extrinsic = llr - prior;
+    !llr.is_finite()
+        || (prior.abs() >= MAX_LLR
+            && extrinsic.abs() >= MAX_LLR
+            && prior.signum() != extrinsic.signum())
+}
+
+/// Update a variable node, in log-likelihood ratio representation (see `run_bp_binary`).
+/// `prior` is the log-likelihood ratio of the initial distribution of the variable.
+/// Returns whether the variable diverged in any copy (see `llr_diverged`).
+fn update_variable_llr(
+    var: &mut Var,
+    prior: &Array1<f64>,
+    neighboors: &mut [&mut Array1<f64>],
+) -> bool {
+    let mut llr = prior.clone();
+    let para = matches!(
+        var.vartype,
+        VarType::ProfilePara { .. } | VarType::NotProfilePara { .. }
+    );
+    neighboors.iter().for_each(|msg| {
+        if para {
+            llr += &**msg;
+        } else {
+            llr[0] += msg.sum();
+        }
+    });
+    neighboors.iter_mut().for_each(|msg| {
+        let llr = llr.broadcast(msg.len()).unwrap();
+        msg.zip_mut_with(&llr, |msg, llr| {
+            *msg = (*llr - *msg).max(-MAX_LLR).min(MAX_LLR)
+        });
+    });
+    match &mut var.vartype {
+        VarType::ProfilePara { distri_current, .. }
+        | VarType::NotProfilePara { distri_current }
+        | VarType::ProfileSingle { distri_current, .. }
+        | VarType::NotProfileSingle { distri_current } => llr_to_distri(&llr, distri_current),
+    }
+    prior
+        .iter()
+        .zip(llr.iter())
+        .any(|(prior, llr)| llr_diverged(*prior, *llr))
+}
+
+/// Run the belief propagation algorithm on a factor graph over GF(2) (i.e., nc = 2).
+/// Messages are represented by a single log-likelihood ratio per copy, and the function nodes
+/// are updated with the LDPC-style check node rules. This supports only XOR, XORCST and LOOKUP
+/// function nodes, and variables with 2 values (see also `RunBpOptions::binary`).
+pub fn run_bp_binary(
+    functions: &[Func],
+    variables: &mut [Var],
+    it: usize,
+    // number of edges in the graph
+    edge: usize,
+    // number of copies in the graph (n_runs)
+    n: usize,
+    // show a progress bar
+    progress: bool,
+) -> Result<(), BPError> {
+    if let Some(function) = functions.iter().position(|f| !binary_supported(f)) {
+        return Err(BPError::UnsupportedFunction { function });
+    }
+    if let Some(v) = variables
+        .iter()
+        .position(|var| distri_current(var).shape()[1] != 2)
+    {
+        return Err(BPError::InvalidGraph(format!(
+            "variable {} does not have 2 values.",
+            v
+        )));
+    }
+    let priors: Vec<Array1<f64>> = variables
+        .iter()
+        .map(|var| match &var.vartype {
+            VarType::ProfilePara { distri_orig, .. }
+            | VarType::ProfileSingle { distri_orig, .. } => distri_to_llr(distri_orig),
+            VarType::NotProfilePara { distri_current }
+            | VarType::NotProfileSingle { distri_current } => {
+                Array1::zeros(distri_current.shape()[0])
+            }
+        })
+        .collect();
+    let mut edges: Vec<Array1<f64>> = vec![Array1::zeros(n); edge];
+    for (var, prior) in variables.iter().zip(priors.iter()) {
+        var.neighboors.iter().for_each(|x| {
+            edges[*x].assign(&prior.broadcast(n).unwrap());
+        });
+    }
+
+    let bp_iter = |iteration| {
+        let mut edge_opt_ref_mut: Vec<Option<&mut Array1<f64>>> =
+            edges.iter_mut().map(|x| Some(x)).collect();
+        let mut edge_for_func: Vec<Vec<&mut Array1<f64>>> = functions
+            .iter()
+            .map(|f| {
+                f.neighboors
                     .iter()
                     .map(|e| edge_opt_ref_mut[*e].take().unwrap())
                     .collect()
             })
             .collect();
-        update_functions(&functions_rust, &mut edge_for_func);
-        let mut edge_opt_ref_mut: Vec<Option<&mut Array2<f64>>> =
+        functions
+            .par_iter()
+            .zip(edge_for_func.par_iter_mut())
+            .for_each(|(function, edge)| update_function_llr(function, edge));
+        let mut edge_opt_ref_mut: Vec<Option<&mut Array1<f64>>> =
             edges.iter_mut().map(|x| Some(x)).collect();
-        let mut edge_for_var: Vec<Vec<&mut Array2<f64>>> = variables
+        let mut edge_for_var: Vec<Vec<&mut Array1<f64>>> = variables
             .iter()
-            .map(|f| {
-                f.neighboors
+            .map(|v| {
+                v.neighboors
                     .iter()
                     .map(|e| edge_opt_ref_mut[*e].take().unwrap())
                     .collect()
             })
             .collect();
-        update_variables(&mut edge_for_var, variables);
-    };
-
-    if progress {
-        // loading bar
-        let pb = ProgressBar::new(it as u64);
-        pb.set_style(ProgressStyle::default_spinner().template(
-        "{msg} {spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len}, ETA {eta})",
-    )
-    .on_finish(ProgressFinish::AndClear));
-        pb.set_message("Calculating BP...");
-        for _ in (0..it).progress_with(pb) {
-            bp_iter();
-        }
-    } else {
-        for _ in 0..it {
-            bp_iter();
+        let diverged: Vec<bool> = variables
+            .par_iter_mut()
+            .zip(priors.par_iter())
+            .zip(edge_for_var.par_iter_mut())
+            .map(|((var, prior), neighboors)| update_variable_llr(var, prior, neighboors))
+            .collect();
+        match diverged.iter().position(|diverged| *diverged) {
+            Some(variable) => Err(BPError::Diverged {
+                variable,
+                iteration,
+            }),
+            None => Ok(()),
         }
-    }
-
-    Ok(())
+    };
+    bp_loop(it, progress, bp_iter)
 }
@@ -0,0 +1,427 @@
+//! Compilation of an algebraic circuit description into a factor graph.
+//!
+//! The circuit is a sequence of assignments, one per line (empty lines are ignored and comments
+//! start with `#`), such as
+//!
+//! ```text
+//! x = p ^ k
+//! t = sbox[x]
+//! u = t + (sbox[k] * 3)
+//! ```
+//!
+//! The right-hand side of an assignment is an expression made of variables, public values
+//! (see `Circuit::add_public`), integer constants, table lookups `table[expr]` (see
+//! `Circuit::add_table`) and the binary operators `*`, `+`, `&` and `^` (by decreasing
+//! precedence, as in C and Python). `+` and `*` are computed modulo `nc`.
+//! Names that are used before being assigned are the inputs of the circuit.
+//!
+//! Each operation is mapped to a function node (XOR, AND, ADD, MUL, their CST variants when one
+//! operand is public, or LOOKUP), and each intermediate result to a variable node. Operations
+//! whose operands are all public are evaluated during the compilation.
+
+use crate::belief_propagation::{BPError, Func, FuncType, Var, VarType};
+use ndarray::{Array1, Array2};
+use std::collections::{HashMap, HashSet};
+
+/// A circuit being compiled into a factor graph.
+pub struct Circuit {
+    /// size of the field
+    nc: usize,
+    /// number of copies in the graph (n_runs)
+    n: usize,
+    tables: HashMap<String, Array1<u32>>,
+    publics: HashMap<String, Array1<u32>>,
+    singles: HashSet<String>,
+    priors: HashMap<String, Array2<f64>>,
+    functions: Vec<Func>,
+    vars: Vec<CircuitVar>,
+    /// Index in `vars` of the named variables.
+    names: HashMap<String, usize>,
+    edge: usize,
+}
+
+struct CircuitVar {
+    neighboors: Vec<usize>,
+    single: bool,
+    name: Option<String>,
+}
+
+/// Factor graph resulting from the compilation of a `Circuit`.
+pub struct CircuitGraph {
+    pub functions: Vec<Func>,
+    pub variables: Vec<Var>,
+    /// number of edges in the graph
+    pub edge: usize,
+    /// Index in `variables` of each named variable of the circuit (intermediate results of
+    /// expressions are anonymous).
+    pub names: HashMap<String, usize>,
+}
+
+/// Operand of an operation: either a variable node or a public value (one per copy).
+enum Operand {
+    Var(usize),
+    Public(Array1<u32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Int(u32),
+    Op(char),
+}
+
+fn invalid(msg: String) -> BPError {
+    BPError::InvalidGraph(msg)
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token<'_>>, BPError> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(&rest[..len]));
+            len
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let literal = &rest[..len];
+            let value = if let Some(hex) = literal.strip_prefix("0x") {
+                u32::from_str_radix(hex, 16)
+            } else {
+                literal.parse()
+            };
+            tokens.push(Token::Int(
+                value.map_err(|_| invalid(format!("cannot parse '{}'.", literal)))?,
+            ));
+            len
+        } else if "^&+*[]()".contains(c) {
+            tokens.push(Token::Op(c));
+            1
+        } else {
+            return Err(invalid(format!("unexpected character '{}'.", c)));
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Binary operators, by increasing precedence.
+const OPERATORS: [char; 4] = ['^', '&', '+', '*'];
+
+impl Circuit {
+    /// Empty circuit over a field of size `nc`, with `n` copies.
+    pub fn new(nc: usize, n: usize) -> Self {
+        Circuit {
+            nc,
+            n,
+            tables: HashMap::new(),
+            publics: HashMap::new(),
+            singles: HashSet::new(),
+            priors: HashMap::new(),
+            functions: Vec::new(),
+            vars: Vec::new(),
+            names: HashMap::new(),
+            edge: 0,
+        }
+    }
+
+    /// Declare a lookup table from [0, nc) to [0, nc).
+    pub fn add_table(&mut self, name: &str, table: Array1<u32>) -> Result<(), BPError> {
+        FuncType::lookup_non_bijective(table.clone(), self.nc)?;
+        self.tables.insert(name.to_owned(), table);
+        Ok(())
+    }
+
+    /// Declare a public variable, with one value per copy.
+    pub fn add_public(&mut self, name: &str, values: Array1<u32>) -> Result<(), BPError> {
+        if values.len() != self.n {
+            return Err(invalid(format!("public '{}' must have n values.", name)));
+        }
+        if values.iter().any(|x| *x as usize >= self.nc) {
+            return Err(invalid(format!("public '{}' is not in [0, nc).", name)));
+        }
+        self.publics.insert(name.to_owned(), values);
+        Ok(())
+    }
+
+    /// Declare that the input `name` has the same value for all the copies (e.g., a key).
+    /// This must be done before the input is first used. The results of operations always have one
+    /// value per copy.
+    pub fn set_single(&mut self, name: &str) {
+        self.singles.insert(name.to_owned());
+    }
+
+    /// Set the initial distribution of the variable `name`, with shape `(n, nc)` (or `(1, nc)`
+    /// for single variables).
+    pub fn set_prior(&mut self, name: &str, distri: Array2<f64>) {
+        self.priors.insert(name.to_owned(), distri);
+    }
+
+    /// Compile all the assignments in `desc` (one per line).
+    pub fn compile(&mut self, desc: &str) -> Result<(), BPError> {
+        for (i, line) in desc.lines().enumerate() {
+            let line = line.split('#').next().unwrap();
+            if !line.trim().is_empty() {
+                self.assign(line).map_err(|e| match e {
+                    BPError::InvalidGraph(msg) => invalid(format!("line {}: {}", i + 1, msg)),
+                    e => e,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile an assignment `name = expr`.
+    /// On error, the nodes of the part of the expression compiled so far remain in the circuit.
+    pub fn assign(&mut self, stmt: &str) -> Result<(), BPError> {
+        let mut parts = stmt.splitn(2, '=');
+        let name = parts.next().unwrap().trim();
+        let expr = parts
+            .next()
+            .ok_or_else(|| invalid(format!("expected an assignment, got '{}'.", stmt)))?;
+        if tokenize(name)? != vec![Token::Ident(name)] {
+            return Err(invalid(format!("invalid variable name '{}'.", name)));
+        }
+        if self.singles.contains(name) {
+            return Err(invalid(format!(
+                "'{}' is single, it cannot be assigned.",
+                name
+            )));
+        }
+        if self.names.contains_key(name)
+            || self.publics.contains_key(name)
+            || self.tables.contains_key(name)
+        {
+            return Err(invalid(format!("'{}' is already defined.", name)));
+        }
+        let tokens = tokenize(expr)?;
+        let mut pos = 0;
+        let res = self.parse_expr(&tokens, &mut pos, 0)?;
+        if pos != tokens.len() {
+            return Err(invalid(format!("unexpected token {:?}.", tokens[pos])));
+        }
+        if self.names.contains_key(name) {
+            return Err(invalid(format!(
+                "'{}' is used in its own definition.",
+                name
+            )));
+        }
+        match res {
+            Operand::Var(var) if self.vars[var].name.is_none() => {
+                self.vars[var].name = Some(name.to_owned());
+                self.names.insert(name.to_owned(), var);
+                Ok(())
+            }
+            _ => Err(invalid(format!(
+                "'{}' must be assigned an operation on variables.",
+                name
+            ))),
+        }
+    }
+
+    /// Index of the variable `name` in the variables of the compiled graph.
+    pub fn var(&self, name: &str) -> Option<usize> {
+        self.names.get(name).copied()
+    }
+
+    /// Generate the factor graph.
+    pub fn build(self) -> Result<CircuitGraph, BPError> {
+        let Circuit {
+            nc,
+            n,
+            mut priors,
+            functions,
+            vars,
+            names,
+            edge,
+            ..
+        } = self;
+        if let Some(name) = priors.keys().find(|name| !names.contains_key(*name)) {
+            return Err(invalid(format!("prior of unknown variable '{}'.", name)));
+        }
+        let variables = vars
+            .into_iter()
+            .map(|var| {
+                let shape = (if var.single { 1 } else { n }, nc);
+                let prior = var.name.as_ref().and_then(|name| priors.remove(name));
+                let vartype = match prior {
+                    Some(distri) if distri.dim() != shape => {
+                        return Err(invalid(format!(
+                            "prior of '{}' has shape {:?}, expected {:?}.",
+                            var.name.unwrap(),
+                            distri.dim(),
+                            shape
+                        )))
+                    }
+                    Some(distri) if var.single => VarType::ProfileSingle {
+                        distri_orig: distri.clone(),
+                        distri_current: distri,
+                    },
+                    Some(distri) => VarType::ProfilePara {
+                        distri_orig: distri.clone(),
+                        distri_current: distri,
+                    },
+                    None if var.single => VarType::NotProfileSingle {
+                        distri_current: Array2::ones(shape),
+                    },
+                    None => VarType::NotProfilePara {
+                        distri_current: Array2::ones(shape),
+                    },
+                };
+                Ok(Var {
+                    neighboors: var.neighboors,
+                    vartype,
+                })
+            })
+            .collect::<Result<Vec<_>, BPError>>()?;
+        Ok(CircuitGraph {
+            functions,
+            variables,
+            edge,
+            names,
+        })
+    }
+
+    /// Parse the binary operations of precedence at least `level` (see `OPERATORS`).
+    fn parse_expr(
+        &mut self,
+        tokens: &[Token],
+        pos: &mut usize,
+        level: usize,
+    ) -> Result<Operand, BPError> {
+        if level == OPERATORS.len() {
+            return self.parse_atom(tokens, pos);
+        }
+        let mut res = self.parse_expr(tokens, pos, level + 1)?;
+        while tokens.get(*pos) == Some(&Token::Op(OPERATORS[level])) {
+            *pos += 1;
+            let rhs = self.parse_expr(tokens, pos, level + 1)?;
+            res = self.binary(OPERATORS[level], res, rhs);
+        }
+        Ok(res)
+    }
+
+    fn parse_atom(&mut self, tokens: &[Token], pos: &mut usize) -> Result<Operand, BPError> {
+        let token = tokens
+            .get(*pos)
+            .ok_or_else(|| invalid("unexpected end of expression.".to_owned()))?;
+        *pos += 1;
+        match token {
+            Token::Int(value) if *value as usize >= self.nc => {
+                Err(invalid(format!("constant {} is not in [0, nc).", value)))
+            }
+            Token::Int(value) => Ok(Operand::Public(Array1::from_elem(self.n, *value))),
+            Token::Op('(') => {
+                let res = self.parse_expr(tokens, pos, 0)?;
+                self.expect(tokens, pos, ')')?;
+                Ok(res)
+            }
+            Token::Ident(name) if tokens.get(*pos) == Some(&Token::Op('[')) => {
+                *pos += 1;
+                let input = self.parse_expr(tokens, pos, 0)?;
+                self.expect(tokens, pos, ']')?;
+                let table = self
+                    .tables
+                    .get(*name)
+                    .ok_or_else(|| invalid(format!("unknown table '{}'.", name)))?
+                    .clone();
+                Ok(match input {
+                    Operand::Public(values) => Operand::Public(values.mapv(|x| table[x as usize])),
+                    Operand::Var(var) => self.function(FuncType::LOOKUP(table), &[var]),
+                })
+            }
+            Token::Ident(name) => {
+                if let Some(values) = self.publics.get(*name) {
+                    Ok(Operand::Public(values.clone()))
+                } else if self.tables.contains_key(*name) {
+                    Err(invalid(format!("table '{}' must be indexed.", name)))
+                } else if let Some(var) = self.names.get(*name) {
+                    Ok(Operand::Var(*var))
+                } else {
+                    // New input of the circuit.
+                    let var = self.new_var(self.singles.contains(*name));
+                    self.vars[var].name = Some((*name).to_owned());
+                    self.names.insert((*name).to_owned(), var);
+                    Ok(Operand::Var(var))
+                }
+            }
+            token => Err(invalid(format!("unexpected token {:?}.", token))),
+        }
+    }
+
+    fn expect(&self, tokens: &[Token], pos: &mut usize, c: char) -> Result<(), BPError> {
+        if tokens.get(*pos) != Some(&Token::Op(c)) {
+            return Err(invalid(format!("expected '{}'.", c)));
+        }
+        *pos += 1;
+        Ok(())
+    }
+
+    fn binary(&mut self, op: char, a: Operand, b: Operand) -> Operand {
+        let nc = self.nc as u64;
+        match (a, b) {
+            (Operand::Public(a), Operand::Public(b)) => {
+                let res = ndarray::Zip::from(&a).and(&b).map_collect(|a, b| {
+                    let (a, b) = (*a as u64, *b as u64);
+                    (match op {
+                        '^' => a ^ b,
+                        '&' => a & b,
+                        '+' => (a + b) % nc,
+                        _ => (a * b) % nc,
+                    }) as u32
+                });
+                Operand::Public(res)
+            }
+            (Operand::Var(var), Operand::Public(values))
+            | (Operand::Public(values), Operand::Var(var)) => {
+                let functype = match op {
+                    '^' => FuncType::XORCST(values),
+                    '&' => FuncType::ANDCST(values),
+                    '+' => FuncType::ADDCST(values),
+                    _ => FuncType::MULCST(values),
+                };
+                self.function(functype, &[var])
+            }
+            (Operand::Var(a), Operand::Var(b)) => {
+                let functype = match op {
+                    '^' => FuncType::XOR,
+                    '&' => FuncType::AND,
+                    '+' => FuncType::ADD,
+                    _ => FuncType::MUL,
+                };
+                self.function(functype, &[a, b])
+            }
+        }
+    }
+
+    fn new_var(&mut self, single: bool) -> usize {
+        self.vars.push(CircuitVar {
+            neighboors: Vec::new(),
+            single,
+            name: None,
+        });
+        self.vars.len() - 1
+    }
+
+    fn new_edge(&mut self, var: usize) -> usize {
+        self.vars[var].neighboors.push(self.edge);
+        self.edge += 1;
+        self.edge - 1
+    }
+
+    /// Add a function node with the given inputs, and a new variable node for its output.
+    /// The output variable is always para (see `Circuit::set_single`).
+    fn function(&mut self, functype: FuncType, inputs: &[usize]) -> Operand {
+        let output = self.new_var(false);
+        let neighboors = std::iter::once(output)
+            .chain(inputs.iter().copied())
+            .map(|var| self.new_edge(var))
+            .collect();
+        self.functions.push(Func::new(neighboors, functype));
+        Operand::Var(output)
+    }
+}
@@ -1,5 +1,11 @@
 pub mod belief_propagation;
+pub mod circuit;
 pub mod lda;
 pub(crate) mod matrixmul;
+pub mod mttest;
 pub mod snr;
+#[cfg(feature = "standalone")]
+pub mod standalone;
 pub mod ttest;
+#[cfg(feature = "fixed_point")]
+pub mod ttest_fixed;
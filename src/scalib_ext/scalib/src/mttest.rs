@@ -0,0 +1,226 @@
+//! Estimation for multivariate higher-order T-test.
+//!
+//! The multivariate Ttest combines d samples of the traces (a tuple of sample indices) into their
+//! centered product prod_i (x_i - u_i), where u_i is the mean of the sample x_i in the class, and
+//! computes a first-order Ttest on this combined variable. This detects the leakage of d-th order
+//! masked implementations, where each share leaks in a different sample, and the moments of order
+//! lower than d do not depend on the secret.
+//!
+//! Since the means are not known before the end of the accumulation, the combined variable is not
+//! computed directly. Instead, for each tuple and class, the central co-moments
+//! sum(prod_i (x_i - u_i)**e_i) for all the exponents e in {0,1,2}^d are updated in one pass,
+//! which generalizes the update rule of the univariate Ttest (see `crate::ttest`).
+//!
+//! Memory: for each tuple, 2*(3^d + d) f64 values (co-moments and means of both classes).
+
+use crate::ttest::TtestError;
+use ndarray::{Array1, Array2, Array3, ArrayView1, ArrayView2, Axis};
+use num_integer::binomial;
+use rayon::prelude::*;
+
+pub struct MTtest {
+    /// Central co-moments with shape (n_tuples,2,3^d), where cs[[t, y, e]] is
+    /// sum(prod_i (x_i-u_i)**e_i) and the exponents are the base-3 digits of e (e_i for the
+    /// sample tuples[[t, i]]).
+    /// cs[[.., .., 0]] is the number of traces in the class.
+    cs: Array3<f64>,
+    /// Means of the samples of each tuple with shape (n_tuples,2,d)
+    means: Array3<f64>,
+    /// number of samples per class (2,)
+    n_samples: Array1<u64>,
+    /// Indices of the samples combined by each tuple with shape (n_tuples,d)
+    tuples: Array2<usize>,
+    /// Number of samples per trace
+    ns: usize,
+    /// For each exponent e, the exponents f < e (componentwise) as (f, e-f, binomial(e, f)),
+    /// with the binomial coefficient being the product over the samples.
+    sub_exponents: Vec<Vec<(usize, usize, f64)>>,
+}
+
+impl MTtest {
+    /// Create a new multivariate Ttest state.
+    /// ns: traces length
+    /// tuples: indices in [0, ns) of the d samples combined by each test, with shape (n_tuples,d)
+    pub fn new(ns: usize, tuples: Array2<usize>) -> Self {
+        let (n_tuples, d) = tuples.dim();
+        assert!(d > 0, "Tuples must contain at least one sample.");
+        assert!(
+            tuples.iter().all(|i| *i < ns),
+            "Samples must be smaller than ns."
+        );
+        let n_exp = 3usize.pow(d as u32);
+        let digits = |e: usize| (0..d).map(move |i| (e / 3usize.pow(i as u32)) % 3);
+        let sub_exponents = (0..n_exp)
+            .map(|e| {
+                (0..e)
+                    .filter(|f| digits(*f).zip(digits(e)).all(|(f, e)| f <= e))
+                    .map(|f| {
+                        let cb: usize = digits(f)
+                            .zip(digits(e))
+                            .map(|(f, e)| binomial(e, f))
+                            .product();
+                        (f, e - f, cb as f64)
+                    })
+                    .collect()
+            })
+            .collect();
+        MTtest {
+            cs: Array3::zeros((n_tuples, 2, n_exp)),
+            means: Array3::zeros((n_tuples, 2, d)),
+            n_samples: Array1::zeros((2,)),
+            tuples,
+            ns,
+            sub_exponents,
+        }
+    }
+
+    /// Update the multivariate Ttest state with n fresh traces
+    /// traces: the leakage traces with shape (n,ns)
+    /// y: realization of random variables with shape (n,)
+    //
+    // Updated with a single measurement t (for a class with n traces, including t), the means
+    // become u' = u + delta with delta = (t - u)/n, and the co-moments become
+    //
+    // CS_{e,Q'} = sum_{f<=e}(
+    //         binomial(e,f)
+    //         * CS_{f,Q}
+    //         * prod_i (-delta_i)**(e_i-f_i)
+    //         )
+    //      + prod_i ((n-1)*delta_i)**e_i
+    //
+    // where binomial(e,f) = prod_i binomial(e_i,f_i). The co-moments with f<e are not yet
+    // updated when the exponents are processed in decreasing order.
+    pub fn update(
+        &mut self,
+        traces: ArrayView2<i16>,
+        y: ArrayView1<u16>,
+    ) -> Result<(), TtestError> {
+        if traces.shape()[1] != self.ns {
+            return Err(TtestError::InvalidShape(format!(
+                "traces have {} samples, expected {}.",
+                traces.shape()[1],
+                self.ns
+            )));
+        }
+        if traces.shape()[0] != y.len() {
+            return Err(TtestError::InvalidShape(format!(
+                "{} traces but {} classes.",
+                traces.shape()[0],
+                y.len()
+            )));
+        }
+        if let Some(y) = y.iter().find(|y| **y > 1) {
+            return Err(TtestError::InvalidClass(format!(
+                "class {} is not 0 or 1.",
+                y
+            )));
+        }
+        // total number of traces in the class, including this one
+        let counts: Vec<f64> = y
+            .iter()
+            .map(|y| {
+                let y = *y as usize;
+                self.n_samples[y] += 1;
+                self.n_samples[y] as f64
+            })
+            .collect();
+        let n_exp = self.cs.shape()[2];
+        let sub_exponents = &self.sub_exponents;
+
+        (
+            self.cs.outer_iter_mut(),
+            self.means.outer_iter_mut(),
+            self.tuples.outer_iter(),
+        )
+            .into_par_iter()
+            .for_each_init(
+                || (Array1::<f64>::zeros(n_exp), Array1::<f64>::zeros(n_exp)),
+                |(minus_delta_pows, new_pows), (mut cs, mut means, tuple)| {
+                    traces
+                        .outer_iter()
+                        .zip(y.iter())
+                        .zip(counts.iter())
+                        .for_each(|((trace, y), n)| {
+                            let y = *y as usize;
+                            let mut means = means.row_mut(y);
+                            let delta: Vec<f64> = tuple
+                                .iter()
+                                .zip(means.iter())
+                                .map(|(i, u)| (trace[*i] as f64 - u) / n)
+                                .collect();
+
+                            // minus_delta_pows[e] = prod_i (-delta_i)**e_i
+                            // new_pows[e] = prod_i ((n-1)*delta_i)**e_i
+                            minus_delta_pows[0] = 1.0;
+                            new_pows[0] = 1.0;
+                            let mut stride = 1;
+                            for delta in delta.iter() {
+                                for e in stride..(3 * stride) {
+                                    minus_delta_pows[e] = minus_delta_pows[e - stride] * -delta;
+                                    new_pows[e] = new_pows[e - stride] * (n - 1.0) * delta;
+                                }
+                                stride *= 3;
+                            }
+
+                            // apply the one-pass update rule
+                            let mut cs = cs.row_mut(y);
+                            let cs = cs.as_slice_mut().unwrap();
+                            for e in (0..n_exp).rev() {
+                                cs[e] += new_pows[e]
+                                    + sub_exponents[e]
+                                        .iter()
+                                        .map(|(f, diff, cb)| cb * cs[*f] * minus_delta_pows[*diff])
+                                        .sum::<f64>();
+                            }
+                            means.zip_mut_with(&Array1::from(delta), |u, delta| *u += delta);
+                        });
+                },
+            );
+        Ok(())
+    }
+
+    /// Generate the actual multivariate Ttest metric based on the current state: the first-order
+    /// Ttest on the centered product of the samples of each tuple.
+    /// return array axes (n_tuples,)
+    //
+    // with the combined variable z = prod_i (x_i - u_i), and all-ones exponent 1:
+    //      ui = CS_{1,Q} / n
+    //      vi = CS_{2*1,Q} / n - ui**2
+    pub fn get_ttest(&self) -> Array1<f64> {
+        let n_exp = self.cs.shape()[2];
+        // all the digits are 1 (resp. 2)
+        let ones = (n_exp - 1) / 2;
+        let twos = n_exp - 1;
+        self.cs
+            .outer_iter()
+            .map(|cs| {
+                let (u, v): (Vec<f64>, Vec<f64>) = cs
+                    .axis_iter(Axis(0))
+                    .map(|cs| {
+                        let n = cs[0];
+                        let u = cs[ones] / n;
+                        (u, cs[twos] / n - u.powi(2))
+                    })
+                    .unzip();
+                let n0 = cs[[0, 0]];
+                let n1 = cs[[1, 0]];
+                (u[0] - u[1]) / f64::sqrt(v[0] / n0 + v[1] / n1)
+            })
+            .collect()
+    }
+
+    /// Mean of the centered product of the samples of each tuple for each class.
+    /// return array axes (class,n_tuples)
+    pub fn get_means(&self) -> Array2<f64> {
+        let ones = (self.cs.shape()[2] - 1) / 2;
+        let mut means = self.cs.index_axis(Axis(2), ones).t().to_owned();
+        means /= &self.cs.index_axis(Axis(2), 0).t();
+        means
+    }
+
+    /// Memory used by the accumulators, in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        (self.cs.len() + self.means.len()) * std::mem::size_of::<f64>()
+            + self.n_samples.len() * std::mem::size_of::<u64>()
+    }
+}
@@ -0,0 +1,200 @@
+//! Python-free runner for the belief propagation algorithm.
+//!
+//! The factor graph is read from a text file with one statement per line. Whitespace is
+//! irrelevant, empty lines are ignored and comments start with `#`.
+//! The statements are:
+//!
+//! - `GRAPH nc n edge it`: the size of the field, the number of copies, the number of edges and
+//!   the number of iterations of belief propagation. This must be the first statement.
+//! - `VAR PARA|SINGLE e0 e1 ... [PRIOR p0 p1 ...]`: a variable node adjacent to edges `e0`, `e1`,
+//!   etc. The optional initial distribution is given in row-major order, with shape `(n,nc)` for
+//!   `PARA` variables and `(1,nc)` for `SINGLE` variables.
+//! - `FUNC op e0 e1 ... [VALUES v0 v1 ...]`: a function node adjacent to edges `e0` (the output),
+//!   `e1`, etc. `op` is one of `AND`, `XOR`, `ADD`, `MUL`, `NAND`, `NOR`, `NEG`, `XORCST`,
+//!   `ANDCST`, `ADDCST`, `MULCST` and `LOOKUP`. The values are the `n` public values for the `CST`
+//!   operators and the table for `LOOKUP`.
+//!
+//! The graph is then checked with `belief_propagation::validate_graph`.
+
+use crate::belief_propagation::{self, BPError, Func, FuncType, Var, VarType};
+use ndarray::{Array1, Array2};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A factor graph and the parameters of the belief propagation.
+pub struct Graph {
+    pub functions: Vec<Func>,
+    pub variables: Vec<Var>,
+    /// size of the field
+    pub nc: usize,
+    /// number of copies in the graph (n_runs)
+    pub n: usize,
+    /// number of edges in the graph
+    pub edge: usize,
+    /// number of iterations
+    pub it: usize,
+}
+
+fn invalid(line: usize, msg: &str) -> BPError {
+    BPError::InvalidGraph(format!("line {}: {}", line + 1, msg))
+}
+
+fn parse_list<T: FromStr>(tokens: &[&str], line: usize) -> Result<Vec<T>, BPError> {
+    tokens
+        .iter()
+        .map(|x| {
+            x.parse()
+                .map_err(|_| invalid(line, &format!("cannot parse '{}'.", x)))
+        })
+        .collect()
+}
+
+/// Split `tokens` at the keyword `kw`.
+fn split_kw<'a, 'b>(tokens: &'a [&'b str], kw: &str) -> (&'a [&'b str], Option<&'a [&'b str]>) {
+    match tokens.iter().position(|x| *x == kw) {
+        Some(i) => (&tokens[..i], Some(&tokens[(i + 1)..])),
+        None => (tokens, None),
+    }
+}
+
+/// Parse the text description of a factor graph.
+pub fn parse_graph(desc: &str) -> Result<Graph, BPError> {
+    let mut lines = desc
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i, l.split('#').next().unwrap().split_whitespace().collect()))
+        .filter(|(_, tokens): &(usize, Vec<&str>)| !tokens.is_empty());
+
+    let (i, header) = lines
+        .next()
+        .ok_or_else(|| BPError::InvalidGraph("empty description.".to_owned()))?;
+    if header[0] != "GRAPH" || header.len() != 5 {
+        return Err(invalid(i, "expected 'GRAPH nc n edge it'."));
+    }
+    let params: Vec<usize> = parse_list(&header[1..], i)?;
+    let (nc, n, edge, it) = (params[0], params[1], params[2], params[3]);
+
+    let mut functions = Vec::new();
+    let mut variables = Vec::new();
+    for (i, tokens) in lines {
+        if tokens.len() < 2 {
+            return Err(invalid(i, "incomplete statement."));
+        }
+        match tokens[0] {
+            "VAR" => {
+                let para = match tokens[1] {
+                    "PARA" => true,
+                    "SINGLE" => false,
+                    _ => return Err(invalid(i, "expected PARA or SINGLE.")),
+                };
+                let (neighboors, prior) = split_kw(&tokens[2..], "PRIOR");
+                let neighboors: Vec<usize> = parse_list(neighboors, i)?;
+                let shape = (if para { n } else { 1 }, nc);
+                let vartype = match prior {
+                    Some(prior) => {
+                        let prior: Vec<f64> = parse_list(prior, i)?;
+                        let distri = Array2::from_shape_vec(shape, prior)
+                            .map_err(|_| invalid(i, "wrong number of PRIOR values."))?;
+                        if para {
+                            VarType::ProfilePara {
+                                distri_orig: distri.clone(),
+                                distri_current: distri,
+                            }
+                        } else {
+                            VarType::ProfileSingle {
+                                distri_orig: distri.clone(),
+                                distri_current: distri,
+                            }
+                        }
+                    }
+                    None if para => VarType::NotProfilePara {
+                        distri_current: Array2::ones(shape),
+                    },
+                    None => VarType::NotProfileSingle {
+                        distri_current: Array2::ones(shape),
+                    },
+                };
+                variables.push(Var {
+                    neighboors,
+                    vartype,
+                });
+            }
+            "FUNC" => {
+                let (neighboors, values) = split_kw(&tokens[2..], "VALUES");
+                let neighboors: Vec<usize> = parse_list(neighboors, i)?;
+                let values = match values {
+                    Some(values) => Some(Array1::from(parse_list::<u32>(values, i)?)),
+                    None => None,
+                };
+                let cst = |values: Option<Array1<u32>>| {
+                    let values = values.ok_or_else(|| invalid(i, "missing VALUES."))?;
+                    if values.len() != n {
+                        return Err(invalid(i, "expected n VALUES."));
+                    }
+                    Ok(values)
+                };
+                let functype = match tokens[1] {
+                    "AND" => FuncType::AND,
+                    "XOR" => FuncType::XOR,
+                    "ADD" => FuncType::ADD,
+                    "MUL" => FuncType::MUL,
+                    "NAND" => FuncType::NAND,
+                    "NOR" => FuncType::NOR,
+                    "NEG" => FuncType::NEG,
+                    "XORCST" => FuncType::XORCST(cst(values)?),
+                    "ANDCST" => FuncType::ANDCST(cst(values)?),
+                    "ADDCST" => FuncType::ADDCST(cst(values)?),
+                    "MULCST" => FuncType::MULCST(cst(values)?),
+                    "LOOKUP" => FuncType::lookup_non_bijective(
+                        values.ok_or_else(|| invalid(i, "missing VALUES."))?,
+                        nc,
+                    )?,
+                    op => return Err(invalid(i, &format!("unknown operator '{}'.", op))),
+                };
+                functions.push(Func::new(neighboors, functype));
+            }
+            kw => return Err(invalid(i, &format!("unknown statement '{}'.", kw))),
+        }
+    }
+
+    belief_propagation::validate_graph(&functions, &variables, edge, nc)?;
+
+    Ok(Graph {
+        functions,
+        variables,
+        nc,
+        n,
+        edge,
+        it,
+    })
+}
+
+/// Read the description of a factor graph from a file.
+pub fn read_graph<P: AsRef<Path>>(path: P) -> Result<Graph, BPError> {
+    let desc = std::fs::read_to_string(path)
+        .map_err(|e| BPError::InvalidGraph(format!("cannot read file ({}).", e)))?;
+    parse_graph(&desc)
+}
+
+/// Read a factor graph from a file, run the belief propagation algorithm on it and return the
+/// distributions of all the variables.
+pub fn run_bp_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<Array2<f64>>, BPError> {
+    let Graph {
+        functions,
+        mut variables,
+        nc,
+        n,
+        edge,
+        it,
+    } = read_graph(path)?;
+    belief_propagation::run_bp(&functions, &mut variables, it, edge, nc, n, false)?;
+    Ok(variables
+        .into_iter()
+        .map(|v| match v.vartype {
+            VarType::ProfilePara { distri_current, .. }
+            | VarType::NotProfilePara { distri_current }
+            | VarType::ProfileSingle { distri_current, .. }
+            | VarType::NotProfileSingle { distri_current } => distri_current,
+        })
+        .collect())
+}
@@ -8,9 +8,15 @@
 //! This is based on the one-pass algorithm proposed in
 //! <https://eprint.iacr.org/2015/207>.
 
+use crate::belief_propagation::walsh_hadamard;
 use ndarray::{s, Array1, Array2, Array3, ArrayView1, ArrayView2, Axis};
 use num_integer::binomial;
 use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct Ttest {
     /// Central sums of order 1 up to order d*2 with shape (ns,2,2*d),
@@ -22,10 +28,331 @@ pub struct Ttest {
     cs: Array3<f64>,
     /// number of samples per class (2,)
     n_samples: Array1<u64>,
+    /// sum of the weights of the samples per class (2,), equal to `n_samples` for unweighted
+    /// updates (see `Ttest::update_weighted`)
+    weights: Array1<f64>,
     /// order of the test
     d: usize,
     /// Number of samples per trace
     ns: usize,
+    /// Indices (increasing) of the samples for which the moments are accumulated, all the samples
+    /// if None. The accumulators (e.g., `cs`) only have these samples, and the results are
+    /// mapped back to the indices of the traces (see `Ttest::new_window`).
+    samples: Option<Vec<usize>>,
+    /// Number of traces between two snapshots (no snapshots if None)
+    snapshot_interval: Option<u64>,
+    /// Snapshots taken during the updates and not yet retrieved
+    snapshots: Vec<MomentSnapshot>,
+    /// Remaining (increasing) numbers of traces at which the Ttest is recorded
+    ttest_checkpoints: Vec<u64>,
+    /// Ttest (with axes (d,ns)) recorded at the checkpoints and not yet retrieved
+    ttest_snapshots: Vec<Array2<f64>>,
+    /// Number of traces between two evaluations of the convergence of the moments (not
+    /// evaluated if None)
+    convergence_window: Option<u64>,
+    /// Moments of order 1 to 2*d (see `Ttest::order_moments`) at the last evaluation of the
+    /// convergence, with shape (2*d,2,ns)
+    convergence_moments: Option<Array3<f64>>,
+    /// Largest relative change of the moments of each order over the last window (2*d,)
+    convergence: Array1<f64>,
+    /// Histograms of the traces with shape (ns,2,nbins) if enabled, used for the KS statistic.
+    /// The bins are of equal width and cover the whole i16 range.
+    histograms: Option<Array3<u64>>,
+    /// Running estimates of the median for each sample and class, if enabled.
+    medians: Option<Vec<[P2Median; 2]>>,
+    /// Variance at or below which a sample is considered constant in a class (see
+    /// `Ttest::set_variance_tolerance`).
+    variance_tol: f64,
+    /// Lower bound on the variances in the Ttest statistic, if any (see
+    /// `Ttest::set_variance_floor`).
+    variance_floor: Option<f64>,
+    /// Whether the variances in the Ttest statistic are Bessel-corrected (see
+    /// `Ttest::set_bessel_correction`).
+    bessel: bool,
+    /// Flag checked between chunks of `interrupt_chunk` traces during the updates, which are
+    /// abandoned once it is set (see `Ttest::set_interrupt_flag`).
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Number of traces between two checks of `interrupt`
+    interrupt_chunk: usize,
+    /// File to which the state is saved every given number of traces during the updates, if
+    /// any (see `Ttest::set_checkpoint_file`).
+    checkpoint: Option<(PathBuf, u64)>,
+}
+
+/// Magic bytes at the start of a saved Ttest state (see `Ttest::save`).
+const STATE_MAGIC: &[u8; 8] = b"SCALIBTT";
+/// Version of the format of the saved Ttest states.
+const STATE_VERSION: u32 = 1;
+
+fn write_u64s<W: Write>(w: &mut W, x: impl IntoIterator<Item = u64>) -> std::io::Result<()> {
+    x.into_iter()
+        .try_for_each(|x| w.write_all(&x.to_le_bytes()))
+}
+
+fn write_f64s<W: Write>(w: &mut W, x: impl IntoIterator<Item = f64>) -> std::io::Result<()> {
+    x.into_iter()
+        .try_for_each(|x| w.write_all(&x.to_le_bytes()))
+}
+
+fn read_u64s<R: Read>(r: &mut R, n: usize) -> std::io::Result<Vec<u64>> {
+    let mut buf = [0u8; 8];
+    (0..n)
+        .map(|_| {
+            r.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        })
+        .collect()
+}
+
+fn read_f64s<R: Read>(r: &mut R, n: usize) -> std::io::Result<Vec<f64>> {
+    Ok(read_u64s(r, n)?.into_iter().map(f64::from_bits).collect())
+}
+
+fn read_u64<R: Read>(r: &mut R) -> std::io::Result<u64> {
+    Ok(read_u64s(r, 1)?[0])
+}
+
+fn invalid_state(msg: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid Ttest state, {}", msg),
+    )
+}
+
+/// Ttest metric of order up to `d_max` from the central sums `cs` (see `Ttest::cs`) and the
+/// (possibly weighted) number of samples per class `n_samples`.
+/// return array axes (d,ns)
+pub(crate) fn ttest_from_central_sums(
+    cs: &Array3<f64>,
+    n_samples: &Array1<f64>,
+    d_max: usize,
+) -> Array2<f64> {
+    welch_from_central_sums(cs, n_samples, d_max, None, false).0
+}
+
+/// Same as `ttest_from_central_sums`, also returning the Welch-Satterthwaite degrees of freedom
+/// of each statistic. The variances of both classes are clamped to at least `variance_floor`, if
+/// any, and are Bessel-corrected if `bessel` is set (see `Ttest::set_bessel_correction`).
+/// return arrays axes (d,ns)
+fn welch_from_central_sums(
+    cs: &Array3<f64>,
+    n_samples: &Array1<f64>,
+    d_max: usize,
+    variance_floor: Option<f64>,
+    bessel: bool,
+) -> (Array2<f64>, Array2<f64>) {
+    let mut ttest = Array2::<f64>::zeros((d_max, cs.shape()[0]));
+    let mut dof = Array2::<f64>::zeros((d_max, cs.shape()[0]));
+
+    let n0 = n_samples[[0]];
+    let n1 = n_samples[[1]];
+    // correction of the variance estimates
+    let (c0, c1) = if bessel {
+        (n0 / (n0 - 1.0), n1 / (n1 - 1.0))
+    } else {
+        (1.0, 1.0)
+    };
+
+    (
+        ttest.axis_chunks_iter_mut(Axis(1), 20),
+        dof.axis_chunks_iter_mut(Axis(1), 20),
+        cs.axis_chunks_iter(Axis(0), 20),
+    )
+        .into_par_iter()
+        .for_each(|(mut ttest, mut dof, cs)| {
+            ttest
+                .axis_iter_mut(Axis(1))
+                .zip(dof.axis_iter_mut(Axis(1)))
+                .zip(cs.axis_iter(Axis(0)))
+                .for_each(|((mut ttest, mut dof), cs)| {
+                    let mut u0;
+                    let mut u1;
+                    let mut v0;
+                    let mut v1;
+                    for d in 1..(d_max + 1) {
+                        if d == 1 {
+                            u0 = cs[[0, 0]];
+                            u1 = cs[[1, 0]];
+
+                            v0 = c0 * cs[[0, 1]] / n0;
+                            v1 = c1 * cs[[1, 1]] / n1;
+                        } else if d == 2 {
+                            u0 = cs[[0, 1]] / n0;
+                            u1 = cs[[1, 1]] / n1;
+
+                            v0 = c0 * (cs[[0, 3]] / n0 - ((cs[[0, 1]] / n0).powi(2)));
+                            v1 = c1 * (cs[[1, 3]] / n1 - ((cs[[1, 1]] / n1).powi(2)));
+                        } else {
+                            // standardized by the (corrected) variance
+                            let var0 = c0 * cs[[0, 1]] / n0;
+                            let var1 = c1 * cs[[1, 1]] / n1;
+                            u0 = (cs[[0, d - 1]] / n0) / var0.powf(d as f64 / 2.0);
+                            u1 = (cs[[1, d - 1]] / n1) / var1.powf(d as f64 / 2.0);
+
+                            v0 = c0 * (cs[[0, (2 * d) - 1]] / n0 - ((cs[[0, d - 1]] / n0).powi(2)));
+                            v0 /= var0.powi(d as i32);
+
+                            v1 = c1 * (cs[[1, (2 * d) - 1]] / n1 - ((cs[[1, d - 1]] / n1).powi(2)));
+                            v1 /= var1.powi(d as i32);
+                        }
+                        if let Some(floor) = variance_floor {
+                            v0 = v0.max(floor);
+                            v1 = v1.max(floor);
+                        }
+
+                        ttest[d - 1] = (u0 - u1) / f64::sqrt((v0 / n0) + (v1 / n1));
+                        dof[d - 1] = ((v0 / n0) + (v1 / n1)).powi(2)
+                            / ((v0 / n0).powi(2) / (n0 - 1.0) + (v1 / n1).powi(2) / (n1 - 1.0));
+                    }
+                });
+        });
+    (ttest, dof)
+}
+
+/// Streaming estimation of the median with the P² algorithm
+/// ("The P² algorithm for dynamic calculation of quantiles and histograms without storing
+/// observations", R. Jain and I. Chlamtac, 1985).
+#[derive(Debug, Clone, Default)]
+struct P2Median {
+    /// Heights of the markers (the first observations, until there are 5 of them).
+    q: [f64; 5],
+    /// Positions of the markers.
+    n: [f64; 5],
+    /// Desired positions of the markers.
+    np: [f64; 5],
+    /// Number of observations.
+    count: u64,
+}
+
+impl P2Median {
+    /// Increments of the desired positions for the median.
+    const DN: [f64; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
+
+    fn update(&mut self, x: f64) {
+        if self.count < 5 {
+            self.q[self.count as usize] = x;
+            self.count += 1;
+            if self.count == 5 {
+                self.q.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                self.n = [0.0, 1.0, 2.0, 3.0, 4.0];
+                self.np = [0.0, 1.0, 2.0, 3.0, 4.0];
+            }
+            return;
+        }
+        self.count += 1;
+        let q = &mut self.q;
+        let n = &mut self.n;
+        // cell of x, updating the extreme markers
+        let k = if x < q[0] {
+            q[0] = x;
+            0
+        } else if x >= q[4] {
+            q[4] = x;
+            3
+        } else {
+            (1..5).find(|i| x < q[*i]).unwrap() - 1
+        };
+        n[(k + 1)..].iter_mut().for_each(|n| *n += 1.0);
+        self.np
+            .iter_mut()
+            .zip(Self::DN.iter())
+            .for_each(|(np, dn)| *np += dn);
+        // adjust the middle markers
+        for i in 1..4 {
+            let d = self.np[i] - n[i];
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let parabolic = q[i]
+                    + d / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]));
+                q[i] = if q[i - 1] < parabolic && parabolic < q[i + 1] {
+                    parabolic
+                } else {
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+                };
+                n[i] += d;
+            }
+        }
+    }
+
+    fn median(&self) -> f64 {
+        if self.count >= 5 {
+            self.q[2]
+        } else if self.count == 0 {
+            f64::NAN
+        } else {
+            let mut q = self.q[..(self.count as usize)].to_vec();
+            q.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let c = q.len();
+            (q[(c - 1) / 2] + q[c / 2]) / 2.0
+        }
+    }
+}
+
+/// Error in the Ttest computation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TtestError {
+    /// The shape of the traces or of the classes does not match the Ttest state.
+    InvalidShape(String),
+    /// The weights of the traces are not finite and non-negative.
+    InvalidWeights(String),
+    /// The classes of the traces are not 0 or 1.
+    InvalidClass(String),
+    /// The update is not supported with the enabled statistics (e.g., weighted updates with the
+    /// KS statistic, see `Ttest::update_weighted`).
+    Unsupported(String),
+    /// The update was interrupted (see `Ttest::set_interrupt_flag`) after the given number of
+    /// traces were applied.
+    Interrupted { applied: usize },
+    /// The state could not be saved to or loaded from its checkpoint file (see
+    /// `Ttest::set_checkpoint_file`).
+    Io(String),
+}
+
+impl std::fmt::Display for TtestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TtestError::InvalidShape(s) => write!(f, "Ttest error: invalid shape, {}", s),
+            TtestError::InvalidWeights(s) => write!(f, "Ttest error: invalid weights, {}", s),
+            TtestError::InvalidClass(s) => write!(f, "Ttest error: invalid class, {}", s),
+            TtestError::Unsupported(s) => write!(f, "Ttest error: unsupported update, {}", s),
+            TtestError::Interrupted { applied } => write!(
+                f,
+                "Ttest error: update interrupted after {} traces.",
+                applied
+            ),
+            TtestError::Io(s) => write!(f, "Ttest error: checkpoint file, {}", s),
+        }
+    }
+}
+
+impl std::error::Error for TtestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+/// Correction of the p-values for multiple comparisons (see `Ttest::get_pvalue_corrected`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PValueCorrection {
+    /// Bonferroni correction, which controls the family-wise error rate: the p-values are
+    /// multiplied by the number of tests.
+    Bonferroni,
+    /// Benjamini-Hochberg procedure, which controls the false discovery rate.
+    BenjaminiHochberg,
+}
+
+/// Snapshot of the running estimates of the first moments in a Ttest state.
+#[derive(Debug, Clone)]
+pub struct MomentSnapshot {
+    /// number of samples per class (2,)
+    pub n_samples: Array1<u64>,
+    /// mean per class with shape (2,ns)
+    pub mean: Array2<f64>,
+    /// variance per class with shape (2,ns)
+    pub variance: Array2<f64>,
 }
 
 impl Ttest {
@@ -36,13 +363,533 @@ impl Ttest {
         Ttest {
             cs: Array3::<f64>::zeros((ns, 2, 2 * d)),
             n_samples: Array1::<u64>::zeros((2,)),
+            weights: Array1::<f64>::zeros((2,)),
             d: d,
             ns: ns,
+            samples: None,
+            snapshot_interval: None,
+            snapshots: Vec::new(),
+            ttest_checkpoints: Vec::new(),
+            ttest_snapshots: Vec::new(),
+            convergence_window: None,
+            convergence_moments: None,
+            convergence: Array1::from_elem(2 * d, f64::NAN),
+            histograms: None,
+            medians: None,
+            variance_tol: 0.0,
+            variance_floor: None,
+            bessel: false,
+            interrupt: None,
+            interrupt_chunk: 0,
+            checkpoint: None,
+        }
+    }
+
+    /// Create a new Ttest state that only accumulates the moments of the samples at the indices
+    /// `samples` (e.g., a time window where the leakage is expected). The traces given to the
+    /// updates still have ns samples, and the results have NaN for the other samples.
+    /// ns: traces length
+    /// d: order of the Ttest
+    /// samples: strictly increasing indices in [0, ns)
+    pub fn new_window(ns: usize, d: usize, samples: Vec<usize>) -> Self {
+        assert!(
+            samples.windows(2).all(|w| w[0] < w[1]),
+            "Samples must be strictly increasing."
+        );
+        assert!(
+            samples.last().map_or(true, |s| *s < ns),
+            "Samples must be smaller than ns."
+        );
+        let mut ttest = Self::new(samples.len(), d);
+        ttest.ns = ns;
+        ttest.samples = Some(samples);
+        ttest
+    }
+
+    /// Number of samples for which the moments are accumulated.
+    fn ns_acc(&self) -> usize {
+        self.cs.shape()[0]
+    }
+
+    /// Map the results of the accumulated samples (on axis 1) back to the samples of the traces.
+    fn expand(&self, res: Array2<f64>) -> Array2<f64> {
+        match &self.samples {
+            None => res,
+            Some(samples) => {
+                let mut expanded = Array2::from_elem((res.shape()[0], self.ns), f64::NAN);
+                for (j, i) in samples.iter().enumerate() {
+                    expanded.column_mut(*i).assign(&res.column(j));
+                }
+                expanded
+            }
+        }
+    }
+
+    /// Concatenate the samples of two Ttest states accumulated on the same traces (e.g., by
+    /// different workers on disjoint sample ranges): the samples of `other` come after the ones
+    /// of `self`. The states must have the same order, number of traces per class, and enabled
+    /// statistics (KS histograms and running medians).
+    /// The snapshots, checkpoints and convergence evaluation (see `set_snapshot_interval`,
+    /// `set_ttest_checkpoints` and `set_convergence_window`), the interruption flag (see
+    /// `set_interrupt_flag`) and the checkpoint file (see `set_checkpoint_file`) are not kept,
+    /// and the variance tolerance, floor and Bessel correction are the ones of `self`.
+    pub fn concat_samples(&self, other: &Ttest) -> Result<Ttest, TtestError> {
+        if self.d != other.d {
+            return Err(TtestError::InvalidShape(format!(
+                "orders {} and {} differ.",
+                self.d, other.d
+            )));
         }
+        if self.n_samples != other.n_samples || self.weights != other.weights {
+            return Err(TtestError::InvalidShape(format!(
+                "numbers of traces per class {} and {} differ.",
+                self.n_samples, other.n_samples
+            )));
+        }
+        let histograms = match (&self.histograms, &other.histograms) {
+            (None, None) => None,
+            (Some(h1), Some(h2)) if h1.shape()[2] == h2.shape()[2] => {
+                Some(ndarray::concatenate(Axis(0), &[h1.view(), h2.view()]).unwrap())
+            }
+            _ => {
+                return Err(TtestError::InvalidShape(
+                    "KS histograms are not enabled with the same number of bins.".to_owned(),
+                ))
+            }
+        };
+        let medians = match (&self.medians, &other.medians) {
+            (None, None) => None,
+            (Some(m1), Some(m2)) => Some(m1.iter().chain(m2.iter()).cloned().collect()),
+            _ => {
+                return Err(TtestError::InvalidShape(
+                    "running medians are not enabled in both states.".to_owned(),
+                ))
+            }
+        };
+        let indices = |t: &Ttest| t.samples.clone().unwrap_or_else(|| (0..t.ns).collect());
+        let samples = if self.samples.is_none() && other.samples.is_none() {
+            None
+        } else {
+            let mut samples = indices(self);
+            samples.extend(indices(other).iter().map(|i| i + self.ns));
+            Some(samples)
+        };
+        Ok(Ttest {
+            cs: ndarray::concatenate(Axis(0), &[self.cs.view(), other.cs.view()]).unwrap(),
+            n_samples: self.n_samples.clone(),
+            weights: self.weights.clone(),
+            d: self.d,
+            ns: self.ns + other.ns,
+            samples,
+            snapshot_interval: None,
+            snapshots: Vec::new(),
+            ttest_checkpoints: Vec::new(),
+            ttest_snapshots: Vec::new(),
+            convergence_window: None,
+            convergence_moments: None,
+            convergence: Array1::from_elem(2 * self.d, f64::NAN),
+            histograms,
+            medians,
+            variance_tol: self.variance_tol,
+            variance_floor: self.variance_floor,
+            bessel: self.bessel,
+            interrupt: None,
+            interrupt_chunk: 0,
+            checkpoint: None,
+        })
+    }
+
+    /// Track an approximation of the running median of each sample and class, such that it can
+    /// be used as a robust center for the higher-order statistics (see `get_ttest_robust`).
+    /// Must be called before the first update.
+    pub fn enable_robust_center(&mut self) {
+        assert!(
+            self.n_samples.sum() == 0,
+            "Robust centering must be enabled before the first update."
+        );
+        self.medians = Some(vec![Default::default(); self.ns_acc()]);
+    }
+
+    /// Accumulate histograms of the traces with `nbins` bins (at most 65536) for each class,
+    /// such that the Kolmogorov-Smirnov statistic can be computed with `get_ks`.
+    /// Must be called before the first update.
+    pub fn enable_ks(&mut self, nbins: usize) {
+        assert!(
+            nbins > 0 && nbins <= 1 << 16,
+            "Number of bins must be in [1, 65536]."
+        );
+        assert!(
+            self.n_samples.sum() == 0,
+            "KS must be enabled before the first update."
+        );
+        self.histograms = Some(Array3::zeros((self.ns_acc(), 2, nbins)));
+    }
+
+    /// Consider a sample as constant when its variance is at most `tol` in both classes (0 by
+    /// default, that is, only exactly constant samples). The Ttest metrics (and p-values) of the
+    /// constant samples are NaN instead of infinite or arbitrarily large (e.g., for a stuck ADC
+    /// channel), see `get_zero_variance`.
+    pub fn set_variance_tolerance(&mut self, tol: f64) {
+        assert!(tol >= 0.0, "Variance tolerance must be non-negative.");
+        self.variance_tol = tol;
+    }
+
+    /// Clamp the variances `v0` and `v1` of both classes to at least `floor` in the Ttest
+    /// statistic `(u0-u1)/sqrt(v0/n0 + v1/n1)` (and in its degrees of freedom), which avoids
+    /// spuriously large values when a class has few traces with a tiny variance (e.g., with a
+    /// quantized ADC). For the orders above 2, the floor applies to the variance of the
+    /// standardized moment. No floor if None (default).
+    pub fn set_variance_floor(&mut self, floor: Option<f64>) {
+        assert!(
+            floor.map_or(true, |f| f >= 0.0),
+            "Variance floor must be non-negative."
+        );
+        self.variance_floor = floor;
+    }
+
+    /// Use the Bessel-corrected (sample) variance estimates, with the denominator `n-1` instead of
+    /// `n`, in the Ttest statistic (and its degrees of freedom and p-values): in the variance terms
+    /// of the statistic, and in the standardization of the higher-order moments. The population
+    /// variance (denominator `n`) is used by default.
+    pub fn set_bessel_correction(&mut self, enabled: bool) {
+        self.bessel = enabled;
+    }
+
+    /// Check `flag` every `chunk` traces during the updates (e.g., set from a signal handler to
+    /// honor a Ctrl-C during a long update). Once it is set, the update returns
+    /// `TtestError::Interrupted` and the state only contains the chunks applied before (the
+    /// flag is not reset). Not checked if None (default).
+    pub fn set_interrupt_flag(&mut self, flag: Option<Arc<AtomicBool>>, chunk: usize) {
+        assert!(
+            flag.is_none() || chunk != 0,
+            "Interruption chunk must be non-zero."
+        );
+        self.interrupt = flag;
+        self.interrupt_chunk = chunk;
+    }
+
+    /// Save the state to the file `path` (see `save`) every `interval` traces during the updates,
+    /// such that a crashed acquisition can be resumed with `resume`. The file is replaced
+    /// atomically (through a temporary file next to it). Not saved if None (default).
+    /// If saving fails, the update returns `TtestError::Io` and the state contains the traces
+    /// applied before.
+    pub fn set_checkpoint_file(&mut self, path: Option<PathBuf>, interval: u64) {
+        assert!(
+            path.is_none() || interval != 0,
+            "Checkpoint interval must be non-zero."
+        );
+        self.checkpoint = path.map(|p| (p, interval));
+    }
+
+    /// Resume the accumulation from the checkpoint file `path` if it exists, and otherwise
+    /// start from `new`. In both cases, the state is then saved to `path` every `interval`
+    /// traces (see `set_checkpoint_file`).
+    /// The settings that are not saved (e.g., `set_snapshot_interval`, `set_interrupt_flag`) and
+    /// the variance tolerance, floor and Bessel correction are the ones of `new`, and its Ttest checkpoints (see
+    /// `set_ttest_checkpoints`) that are already reached are dropped. `new` must have the same
+    /// order, samples and enabled statistics as the saved state, and no traces.
+    pub fn resume(new: Ttest, path: PathBuf, interval: u64) -> Result<Ttest, TtestError> {
+        assert!(
+            new.n_samples.sum() == 0,
+            "The state to resume must not have any traces."
+        );
+        let mut res = new;
+        if path.exists() {
+            let saved = Ttest::load(&path).map_err(|e| TtestError::Io(e.to_string()))?;
+            if saved.d != res.d || saved.ns != res.ns || saved.samples != res.samples {
+                return Err(TtestError::InvalidShape(format!(
+                    "saved state has order {} and {} samples, expected {} and {}.",
+                    saved.d, saved.ns, res.d, res.ns
+                )));
+            }
+            let same_hist = match (&saved.histograms, &res.histograms) {
+                (None, None) => true,
+                (Some(h1), Some(h2)) => h1.shape() == h2.shape(),
+                _ => false,
+            };
+            if !same_hist || saved.medians.is_some() != res.medians.is_some() {
+                return Err(TtestError::InvalidShape(
+                    "saved state does not have the same enabled statistics.".to_owned(),
+                ));
+            }
+            res.cs = saved.cs;
+            res.n_samples = saved.n_samples;
+            res.weights = saved.weights;
+            res.histograms = saved.histograms;
+            res.medians = saved.medians;
+            let n_tot = res.n_samples.sum();
+            res.ttest_checkpoints.retain(|c| *c > n_tot);
+        }
+        res.set_checkpoint_file(Some(path), interval);
+        Ok(res)
+    }
+
+    /// Take a snapshot of the means and variances every `interval` traces during the updates
+    /// (no snapshots if None).
+    pub fn set_snapshot_interval(&mut self, interval: Option<u64>) {
+        assert!(interval != Some(0), "Snapshot interval must be non-zero.");
+        self.snapshot_interval = interval;
+    }
+
+    /// Return the snapshots taken since the last call to this function.
+    pub fn take_snapshots(&mut self) -> Vec<MomentSnapshot> {
+        std::mem::take(&mut self.snapshots)
+    }
+
+    /// Record the Ttest (see `get_ttest`) when the total number of traces reaches each of the
+    /// `counts` during the updates (e.g., at logarithmically spaced counts to monitor the
+    /// convergence). This replaces the previously set counts.
+    /// counts: strictly increasing numbers of traces, larger than the current one
+    pub fn set_ttest_checkpoints(&mut self, counts: Vec<u64>) {
+        assert!(
+            counts.windows(2).all(|w| w[0] < w[1]),
+            "Checkpoints must be strictly increasing."
+        );
+        assert!(
+            counts.first().map_or(true, |c| *c > self.n_samples.sum()),
+            "Checkpoints must be larger than the current number of traces."
+        );
+        self.ttest_checkpoints = counts;
+    }
+
+    /// Return the Ttest recorded at the checkpoints reached since the last call to this function
+    /// (see `set_ttest_checkpoints`).
+    /// return array axes (snapshot,d,ns)
+    pub fn take_ttest_checkpoints(&mut self) -> Array3<f64> {
+        let mut res = Array3::zeros((self.ttest_snapshots.len(), self.d, self.ns));
+        for (mut res, ttest) in res.outer_iter_mut().zip(self.ttest_snapshots.drain(..)) {
+            res.assign(&ttest);
+        }
+        res
+    }
+
+    /// Evaluate the convergence of the moments every `window` traces during the updates (not
+    /// evaluated if None), see `get_moment_convergence`. This resets the previous evaluations.
+    pub fn set_convergence_window(&mut self, window: Option<u64>) {
+        assert!(window != Some(0), "Convergence window must be non-zero.");
+        self.convergence_window = window;
+        self.convergence_moments = None;
+        self.convergence.fill(f64::NAN);
     }
+
+    /// Largest relative change `|m_new - m_old| / |m_old|` (over the samples and classes) of the
+    /// moments of each order over the last window of traces (see `set_convergence_window`), e.g.,
+    /// to stop the acquisition once the moments of the highest orders are stable. The moment of
+    /// order 1 is the mean, of order 2 the variance, and the standardized moments above.
+    /// NaN until the moments have been evaluated at the end of two windows.
+    /// return array axes (2*d,)
+    pub fn get_moment_convergence(&self) -> Array1<f64> {
+        self.convergence.clone()
+    }
+
+    /// Moments of order 1 to 2*d of each class (see `get_moment_convergence`).
+    /// return array axes (order,class,ns)
+    fn order_moments(&self) -> Array3<f64> {
+        let mut res = Array3::zeros((2 * self.d, 2, self.ns_acc()));
+        res.index_axis_mut(Axis(0), 0)
+            .assign(&self.cs.slice(s![.., .., 0]).t());
+        let n = self.weights.clone().insert_axis(Axis(1));
+        res.index_axis_mut(Axis(0), 1)
+            .assign(&(&self.cs.slice(s![.., .., 1]).t() / &n));
+        for order in 3..(2 * self.d + 1) {
+            res.index_axis_mut(Axis(0), order - 1)
+                .assign(&self.standardized_moment(order));
+        }
+        res
+    }
+
+    /// Evaluate the convergence of the moments at the end of a window (see
+    /// `set_convergence_window`).
+    fn update_convergence(&mut self) {
+        let moments = self.order_moments();
+        if let Some(previous) = &self.convergence_moments {
+            for (convergence, (new, old)) in self
+                .convergence
+                .iter_mut()
+                .zip(moments.outer_iter().zip(previous.outer_iter()))
+            {
+                *convergence = ndarray::Zip::from(&new)
+                    .and(&old)
+                    .fold(0.0, |acc: f64, new, old| {
+                        acc.max((new - old).abs() / old.abs())
+                    });
+            }
+        }
+        self.convergence_moments = Some(moments);
+    }
+
     /// Update the Ttest state with n fresh traces
     /// traces: the leakage traces with shape (n,ns)
     /// y: realization of random variables with shape (n,)
+    pub fn update(
+        &mut self,
+        traces: ArrayView2<i16>,
+        y: ArrayView1<u16>,
+    ) -> Result<(), TtestError> {
+        self.update_with_weights(traces, y, None)
+    }
+
+    /// Update the Ttest state with n fresh traces, where each trace contributes to the moments
+    /// with its weight (e.g., for importance sampling). The number of samples used in the
+    /// statistics is then the sum of the weights. Weights of 1.0 are equivalent to `update`.
+    /// This is not compatible with the KS statistic and the robust center (returns
+    /// `TtestError::Unsupported`).
+    /// traces: the leakage traces with shape (n,ns)
+    /// y: realization of random variables with shape (n,)
+    /// weights: non-negative weights of the traces with shape (n,)
+    pub fn update_weighted(
+        &mut self,
+        traces: ArrayView2<i16>,
+        y: ArrayView1<u16>,
+        weights: ArrayView1<f64>,
+    ) -> Result<(), TtestError> {
+        if self.histograms.is_some() || self.medians.is_some() {
+            return Err(TtestError::Unsupported(
+                "weighted updates are not supported with KS and robust center.".to_owned(),
+            ));
+        }
+        if weights.len() != y.len() {
+            return Err(TtestError::InvalidShape(format!(
+                "{} weights but {} classes.",
+                weights.len(),
+                y.len()
+            )));
+        }
+        if !weights.iter().all(|w| w.is_finite() && *w >= 0.0) {
+            return Err(TtestError::InvalidWeights(
+                "weights must be finite and non-negative.".to_owned(),
+            ));
+        }
+        self.update_with_weights(traces, y, Some(weights))
+    }
+
+    fn update_with_weights(
+        &mut self,
+        traces: ArrayView2<i16>,
+        y: ArrayView1<u16>,
+        weights: Option<ArrayView1<f64>>,
+    ) -> Result<(), TtestError> {
+        if traces.shape()[1] != self.ns {
+            return Err(TtestError::InvalidShape(format!(
+                "traces have {} samples, expected {}.",
+                traces.shape()[1],
+                self.ns
+            )));
+        }
+        if traces.shape()[0] != y.len() {
+            return Err(TtestError::InvalidShape(format!(
+                "{} traces but {} classes.",
+                traces.shape()[0],
+                y.len()
+            )));
+        }
+        if let Some(y) = y.iter().find(|y| **y > 1) {
+            return Err(TtestError::InvalidClass(format!(
+                "class {} is not 0 or 1.",
+                y
+            )));
+        }
+        // only keep the accumulated samples
+        let selected;
+        let traces = match &self.samples {
+            Some(samples) => {
+                selected = traces.select(Axis(1), samples);
+                selected.view()
+            }
+            None => traces.view(),
+        };
+        if self.snapshot_interval.is_some()
+            || !self.ttest_checkpoints.is_empty()
+            || self.convergence_window.is_some()
+            || self.interrupt.is_some()
+            || self.checkpoint.is_some()
+        {
+            // split the update at the snapshot boundaries, checkpoints, convergence windows,
+            // interruption checks and checkpoint file saves
+            let mut start = 0;
+            while start < y.len() {
+                if start != 0 {
+                    if let Some(flag) = &self.interrupt {
+                        if flag.load(Ordering::Relaxed) {
+                            return Err(TtestError::Interrupted { applied: start });
+                        }
+                    }
+                }
+                let n_tot = self.n_samples.sum();
+                let mut end = y.len();
+                if let Some(interval) = self.snapshot_interval {
+                    end = std::cmp::min(end, start + (interval - n_tot % interval) as usize);
+                }
+                if let Some(checkpoint) = self.ttest_checkpoints.first() {
+                    end = std::cmp::min(end, start + (checkpoint - n_tot) as usize);
+                }
+                if let Some(window) = self.convergence_window {
+                    end = std::cmp::min(end, start + (window - n_tot % window) as usize);
+                }
+                if self.interrupt.is_some() {
+                    end = std::cmp::min(end, start + self.interrupt_chunk);
+                }
+                if let Some((_, interval)) = self.checkpoint {
+                    end = std::cmp::min(end, start + (interval - n_tot % interval) as usize);
+                }
+                self.update_internal(
+                    traces.slice(s![start..end, ..]),
+                    y.slice(s![start..end]),
+                    weights.map(|w| w.slice_move(s![start..end])),
+                );
+                let n_tot = self.n_samples.sum();
+                if let Some(interval) = self.snapshot_interval {
+                    if n_tot % interval == 0 {
+                        self.snapshots.push(MomentSnapshot {
+                            n_samples: self.n_samples.clone(),
+                            mean: self.get_means(),
+                            variance: self.get_variances(),
+                        });
+                    }
+                }
+                if self.ttest_checkpoints.first() == Some(&n_tot) {
+                    self.ttest_checkpoints.remove(0);
+                    self.ttest_snapshots.push(self.get_ttest());
+                }
+                if let Some(window) = self.convergence_window {
+                    if n_tot % window == 0 {
+                        self.update_convergence();
+                    }
+                }
+                if let Some((path, interval)) = &self.checkpoint {
+                    if n_tot % interval == 0 {
+                        self.save_atomic(path)
+                            .map_err(|e| TtestError::Io(e.to_string()))?;
+                    }
+                }
+                start = end;
+            }
+        } else {
+            self.update_internal(traces, y, weights);
+        }
+        Ok(())
+    }
+
+    /// Update the Ttest state with n fresh traces stored in a contiguous slice (e.g. the backing
+    /// of a memory-mapped file), without copying them.
+    /// traces: the leakage traces in row-major order, with shape (n,ns)
+    /// y: realization of random variables with shape (n,)
+    pub fn update_from_slice(
+        &mut self,
+        traces: &[i16],
+        n: usize,
+        y: &[u16],
+    ) -> Result<(), TtestError> {
+        if traces.len() != n * self.ns {
+            return Err(TtestError::InvalidShape(format!(
+                "traces have {} values, expected {}.",
+                traces.len(),
+                n * self.ns
+            )));
+        }
+        let traces = ArrayView2::from_shape((n, self.ns), traces).unwrap();
+        self.update(traces, ArrayView1::from(y))
+    }
     // Q set of all previous traces
     //
     // Initial values, |Q| = n-1
@@ -76,9 +923,44 @@ impl Ttest {
     //        )
     //
     // mu' = mu+delta
-    pub fn update(&mut self, traces: ArrayView2<i16>, y: ArrayView1<u16>) {
+    //
+    // With a weight w for t (and a total weight W of Q'), this generalizes to
+    //
+    // delta = w * (t - mu)/W
+    //
+    // where the last term becomes
+    //      (delta * (W-w))**d * (1/w**(d-1) - (-1/(W-w))**(d-1))
+    fn update_internal(
+        &mut self,
+        traces: ArrayView2<i16>,
+        y: ArrayView1<u16>,
+        weights: Option<ArrayView1<f64>>,
+    ) {
         let d = self.d;
 
+        if let Some(histograms) = &mut self.histograms {
+            let nbins = histograms.shape()[2];
+            (histograms.outer_iter_mut(), traces.axis_iter(Axis(1)))
+                .into_par_iter()
+                .for_each(|(mut histograms, traces)| {
+                    traces.iter().zip(y.iter()).for_each(|(t, y)| {
+                        let bin = ((*t as i32 + (1 << 15)) as usize * nbins) >> 16;
+                        histograms[[*y as usize, bin]] += 1;
+                    });
+                });
+        }
+
+        if let Some(medians) = &mut self.medians {
+            medians
+                .par_iter_mut()
+                .zip(traces.axis_iter(Axis(1)).into_par_iter())
+                .for_each(|(medians, traces)| {
+                    traces.iter().zip(y.iter()).for_each(|(t, y)| {
+                        medians[*y as usize].update(*t as f64);
+                    });
+                });
+        }
+
         // pre computes the combinatorial factors
         let cbs: Vec<(usize, Vec<(f64, usize)>)> = (2..((2 * self.d) + 1))
             .rev()
@@ -91,31 +973,40 @@ impl Ttest {
             .collect();
 
         // contains the data that are the same for all the points in a single traces
-        // Contains tupes (n, y, mults): (f64, usize, Vec<f64>)
-        // n : number of previously processed traces for the class y
+        // Contains tupes (n, w, y, mults): (f64, f64, usize, Vec<f64>)
+        // n : total weight of the processed traces for the class y, including this one
+        // w : weight of the trace (None if zero: the trace is ignored)
         // y : set to update
-        // mults: (n-1)**(j) * (1.0 - (-1.0/(n-1.0))**(j-1) for j in [2..(2*d-1)].rev()
-        let shared_data: Vec<(f64, usize, Vec<f64>)> = y
+        // mults: (n-w)**(j) * (1.0/w**(j-1) - (-1.0/(n-w))**(j-1) for j in [2..(2*d-1)].rev()
+        let shared_data: Vec<(f64, Option<f64>, usize, Vec<f64>)> = y
             .iter()
-            .map(|y| {
+            .enumerate()
+            .map(|(i, y)| {
                 let y = *y as usize;
-                assert!(y <= 1);
+                let w = weights.map_or(1.0, |weights| weights[i]);
 
                 // update the number of observations
-                let n = &mut self.n_samples[y];
-                *n += 1;
-                let n = *n as f64;
+                self.n_samples[y] += 1;
+                let n_prev = self.weights[y];
+                self.weights[y] += w;
+                let n = self.weights[y];
 
                 (
-                    // number of sample on that class
+                    // weight of that class
                     n,
+                    // weight of the trace
+                    if w > 0.0 { Some(w) } else { None },
                     // y value
                     y,
                     // compute the multiplicative factor similar for all trace samples
                     cbs.iter()
                         .map(|(j, _)| {
-                            (n - 1.0).powi(*j as i32)
-                                * (1.0 - (-1.0 / (n - 1.0)).powi(*j as i32 - 1))
+                            if n_prev > 0.0 {
+                                n_prev.powi(*j as i32)
+                                    * (w.powi(1 - *j as i32) - (-1.0 / n_prev).powi(*j as i32 - 1))
+                            } else {
+                                0.0
+                            }
                         })
                         .collect(),
                 )
@@ -130,12 +1021,16 @@ impl Ttest {
                     traces
                         .iter()
                         .zip(shared_data.iter())
-                        .for_each(|(trace, (n, y, mults))| {
+                        .for_each(|(trace, (n, w, y, mults))| {
+                            let w = match w {
+                                Some(w) => *w,
+                                None => return,
+                            };
                             let mut cs_s = cs.slice_mut(s![*y, ..]);
                             let cs = cs_s.as_slice_mut().unwrap();
 
                             // compute the delta
-                            let delta = ((*trace as f64) - cs[0]) / (*n as f64);
+                            let delta = w * ((*trace as f64) - cs[0]) / *n;
 
                             // delta_pows[i] = delta ** (i+1)
                             // We will need all of them next
@@ -146,9 +1041,7 @@ impl Ttest {
 
                             // apply the one-pass update rule
                             cbs.iter().zip(mults.iter()).for_each(|((j, vec), mult)| {
-                                if *n > 1.0 {
-                                    cs[*j - 1] += delta_pows[*j - 1] * mult;
-                                }
+                                cs[*j - 1] += delta_pows[*j - 1] * mult;
                                 vec.iter().for_each(|(cb, k)| {
                                     let a = cs[*j - *k - 1];
                                     if (k & 0x1) == 1 {
@@ -166,6 +1059,12 @@ impl Ttest {
             );
     }
 
+    /// Number of traces accumulated in each class.
+    /// return array axes (2,)
+    pub fn get_n_samples(&self) -> Array1<u64> {
+        self.n_samples.clone()
+    }
+
     /// Generate the actual Ttest metric based on the current state.
     /// return array axes (d,ns)
     //
@@ -186,57 +1085,644 @@ impl Ttest {
     //      vi = (CM_{2*d,Q} - CM_{d,Q}**2) / CM{2,Q}**d
 
     pub fn get_ttest(&self) -> Array2<f64> {
-        let mut ttest = Array2::<f64>::zeros((self.d, self.ns));
-        let cs = &self.cs;
-        let n_samples = &self.n_samples;
+        self.expand(self.ttest_from_cs(&self.cs))
+    }
 
-        let n0 = n_samples[[0]] as f64;
-        let n1 = n_samples[[1]] as f64;
+    /// Ttest metric of the sample `sample` only, without computing it for the other samples
+    /// (e.g., to monitor a point of interest during the updates).
+    /// return array axes (d,)
+    pub fn get_ttest_at(&self, sample: usize) -> Result<Array1<f64>, TtestError> {
+        if sample >= self.ns {
+            return Err(TtestError::InvalidShape(format!(
+                "sample {} is not smaller than ns = {}.",
+                sample, self.ns
+            )));
+        }
+        let j = match &self.samples {
+            None => sample,
+            Some(samples) => match samples.binary_search(&sample) {
+                Ok(j) => j,
+                Err(_) => return Ok(Array1::from_elem(self.d, f64::NAN)),
+            },
+        };
+        let cs = self.cs.slice(s![j..(j + 1), .., ..]).to_owned();
+        Ok(self.ttest_from_cs(&cs).column(0).to_owned())
+    }
+
+    /// Write the Ttest metric (see `get_ttest`) with shape (d,ns) to the file `path`, in the
+    /// NumPy `.npy` format (version 1.0, little-endian f64, C order), such that it can be loaded
+    /// with `numpy.load`.
+    pub fn write_ttest_npy<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let ttest = self.get_ttest();
+        let mut header = format!(
+            "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.d, self.ns
+        );
+        // magic (6 bytes), version (2 bytes) and header length (2 bytes), then the header padded
+        // with spaces and terminated by a newline, such that the data is 64-byte aligned
+        let len = 10 + header.len() + 1;
+        header.extend(std::iter::repeat(' ').take((64 - len % 64) % 64));
+        header.push('\n');
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"\x93NUMPY\x01\x00")?;
+        file.write_all(&(header.len() as u16).to_le_bytes())?;
+        file.write_all(header.as_bytes())?;
+        for x in ttest.iter() {
+            file.write_all(&x.to_le_bytes())?;
+        }
+        file.flush()
+    }
+
+    /// Save the accumulated state (moments, numbers of traces, samples, KS histograms, running
+    /// medians, variance tolerance, floor and Bessel correction) to the file `path`, in a little-endian binary
+    /// format that can be loaded with `load`. The snapshots, Ttest checkpoints, convergence
+    /// evaluation, interruption flag and checkpoint file are not saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(STATE_MAGIC)?;
+        file.write_all(&STATE_VERSION.to_le_bytes())?;
+        write_u64s(&mut file, vec![self.d as u64, self.ns as u64])?;
+        match &self.samples {
+            Some(samples) => {
+                write_u64s(&mut file, vec![1, samples.len() as u64])?;
+                write_u64s(&mut file, samples.iter().map(|x| *x as u64))?;
+            }
+            None => write_u64s(&mut file, vec![0])?,
+        }
+        write_u64s(&mut file, self.n_samples.iter().cloned())?;
+        write_f64s(&mut file, self.weights.iter().cloned())?;
+        write_f64s(&mut file, self.cs.iter().cloned())?;
+        match &self.histograms {
+            Some(histograms) => {
+                write_u64s(&mut file, vec![1, histograms.shape()[2] as u64])?;
+                write_u64s(&mut file, histograms.iter().cloned())?;
+            }
+            None => write_u64s(&mut file, vec![0])?,
+        }
+        match &self.medians {
+            Some(medians) => {
+                write_u64s(&mut file, vec![1])?;
+                for m in medians.iter().flat_map(|m| m.iter()) {
+                    write_f64s(
+                        &mut file,
+                        m.q.iter().chain(m.n.iter()).chain(m.np.iter()).cloned(),
+                    )?;
+                    write_u64s(&mut file, vec![m.count])?;
+                }
+            }
+            None => write_u64s(&mut file, vec![0])?,
+        }
+        write_f64s(&mut file, vec![self.variance_tol])?;
+        match self.variance_floor {
+            Some(floor) => {
+                write_u64s(&mut file, vec![1])?;
+                write_f64s(&mut file, vec![floor])?;
+            }
+            None => write_u64s(&mut file, vec![0])?,
+        }
+        write_u64s(&mut file, vec![self.bessel as u64])?;
+        file.flush()
+    }
+
+    /// Save the state to `path` through a temporary file, such that `path` always contains a
+    /// complete state (see `set_checkpoint_file`).
+    fn save_atomic(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        self.save(&tmp)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Load a state saved with `save`. Fails with `std::io::ErrorKind::InvalidData` if the file
+    /// is not a valid saved state.
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Ttest> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        let mut version = [0u8; 4];
+        file.read_exact(&mut version)?;
+        if &magic != STATE_MAGIC || u32::from_le_bytes(version) != STATE_VERSION {
+            return Err(invalid_state("unknown format."));
+        }
+        let d = read_u64(&mut file)? as usize;
+        let ns = read_u64(&mut file)? as usize;
+        if d == 0 {
+            return Err(invalid_state("order must be non-zero."));
+        }
+        let mut res = match read_u64(&mut file)? {
+            0 => Ttest::new(ns, d),
+            1 => {
+                let len = read_u64(&mut file)? as usize;
+                let samples: Vec<usize> = read_u64s(&mut file, len)?
+                    .into_iter()
+                    .map(|x| x as usize)
+                    .collect();
+                if !samples.windows(2).all(|w| w[0] < w[1])
+                    || !samples.last().map_or(true, |s| *s < ns)
+                {
+                    return Err(invalid_state("invalid samples."));
+                }
+                Ttest::new_window(ns, d, samples)
+            }
+            _ => return Err(invalid_state("invalid samples flag.")),
+        };
+        let ns_acc = res.ns_acc();
+        res.n_samples = Array1::from(read_u64s(&mut file, 2)?);
+        res.weights = Array1::from(read_f64s(&mut file, 2)?);
+        res.cs = Array3::from_shape_vec((ns_acc, 2, 2 * d), read_f64s(&mut file, ns_acc * 4 * d)?)
+            .unwrap();
+        res.histograms = match read_u64(&mut file)? {
+            0 => None,
+            1 => {
+                let nbins = read_u64(&mut file)? as usize;
+                if nbins == 0 || nbins > 1 << 16 {
+                    return Err(invalid_state("invalid number of bins."));
+                }
+                let data = read_u64s(&mut file, ns_acc * 2 * nbins)?;
+                Some(Array3::from_shape_vec((ns_acc, 2, nbins), data).unwrap())
+            }
+            _ => return Err(invalid_state("invalid histograms flag.")),
+        };
+        res.medians = match read_u64(&mut file)? {
+            0 => None,
+            1 => {
+                let mut medians = vec![[P2Median::default(), P2Median::default()]; ns_acc];
+                for m in medians.iter_mut().flat_map(|m| m.iter_mut()) {
+                    let x = read_f64s(&mut file, 15)?;
+                    m.q.copy_from_slice(&x[0..5]);
+                    m.n.copy_from_slice(&x[5..10]);
+                    m.np.copy_from_slice(&x[10..15]);
+                    m.count = read_u64(&mut file)?;
+                }
+                Some(medians)
+            }
+            _ => return Err(invalid_state("invalid medians flag.")),
+        };
+        res.variance_tol = read_f64s(&mut file, 1)?[0];
+        res.variance_floor = match read_u64(&mut file)? {
+            0 => None,
+            1 => Some(read_f64s(&mut file, 1)?[0]),
+            _ => return Err(invalid_state("invalid variance floor flag.")),
+        };
+        res.bessel = match read_u64(&mut file)? {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid_state("invalid Bessel correction flag.")),
+        };
+        Ok(res)
+    }
 
+    /// Samples where the Ttest exceeds `threshold` in absolute value (e.g., 4.5 for TVLA), for
+    /// each order: their indices (increasing) and the corresponding Ttest values.
+    pub fn get_leaky_samples(&self, threshold: f64) -> Vec<(Array1<usize>, Array1<f64>)> {
+        self.get_ttest()
+            .outer_iter()
+            .map(|ttest| {
+                let (indices, values): (Vec<usize>, Vec<f64>) = ttest
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, t)| t.abs() > threshold)
+                    .unzip();
+                (Array1::from(indices), Array1::from(values))
+            })
+            .collect()
+    }
+
+    /// Ttest metric divided by `threshold` (e.g., 4.5 for TVLA), such that the samples with an
+    /// absolute value above 1.0 are the leaky ones (see `get_leaky_samples`).
+    /// return array axes (d,ns)
+    pub fn get_ttest_ratio(&self, threshold: f64) -> Array2<f64> {
+        assert!(threshold > 0.0, "The threshold must be positive.");
+        let mut ttest = self.get_ttest();
+        ttest.par_mapv_inplace(|t| t / threshold);
+        ttest
+    }
+
+    /// Generate the Ttest metric where the statistics of order 2 and above are centered around
+    /// `reference` (with shape (ns,)) instead of the empirical mean of each class.
+    /// return array axes (d,ns)
+    pub fn get_ttest_centered(&self, reference: ArrayView1<f64>) -> Array2<f64> {
+        assert_eq!(reference.len(), self.ns, "Reference must have shape (ns,).");
+        let reference = match &self.samples {
+            Some(samples) => reference.select(Axis(0), samples),
+            None => reference.to_owned(),
+        };
+        let reference = reference.broadcast((2, self.ns_acc())).unwrap();
+        self.expand(self.ttest_recentered(reference))
+    }
+
+    /// Approximation of the median of the traces for each class.
+    /// Requires `enable_robust_center`.
+    /// return array axes (class,ns)
+    pub fn get_medians(&self) -> Array2<f64> {
+        self.expand(self.medians_acc())
+    }
+
+    /// Medians of the accumulated samples (see `get_medians`).
+    fn medians_acc(&self) -> Array2<f64> {
+        let medians = self
+            .medians
+            .as_ref()
+            .expect("Medians require enable_robust_center.");
+        Array2::from_shape_fn((2, self.ns_acc()), |(c, i)| medians[i][c].median())
+    }
+
+    /// Generate the Ttest metric where the statistics of order 2 and above are centered around
+    /// the (approximate) median of each class instead of its mean, which is more robust to
+    /// outliers.
+    /// Requires `enable_robust_center`.
+    /// return array axes (d,ns)
+    pub fn get_ttest_robust(&self) -> Array2<f64> {
+        self.expand(self.ttest_recentered(self.medians_acc().view()))
+    }
+
+    /// Ttest metric where the statistics of order 2 and above of each class are centered around
+    /// `reference` (with shape (2,ns)).
+    fn ttest_recentered(&self, reference: ArrayView2<f64>) -> Array2<f64> {
+        // Sums of (x-reference)**i, using the binomial expansion
+        // sum((x-r)**i) = sum_{j=0..i} binomial(i,j) * (u-r)**(i-j) * sum((x-u)**j).
+        let mut cs = self.cs.clone();
+        let weights = &self.weights;
+        let d = self.d;
         (
-            ttest.axis_chunks_iter_mut(Axis(1), 20),
-            cs.axis_chunks_iter(Axis(0), 20),
+            cs.outer_iter_mut(),
+            self.cs.outer_iter(),
+            reference.axis_iter(Axis(1)),
         )
             .into_par_iter()
-            .for_each(|(mut ttest, cs)| {
-                ttest
-                    .axis_iter_mut(Axis(1))
-                    .zip(cs.axis_iter(Axis(0)))
-                    .for_each(|(mut ttest, cs)| {
-                        let mut u0;
-                        let mut u1;
-                        let mut v0;
-                        let mut v1;
-                        for d in 1..(self.d + 1) {
-                            if d == 1 {
-                                u0 = cs[[0, 0]];
-                                u1 = cs[[1, 0]];
-
-                                v0 = cs[[0, 1]] / n0;
-                                v1 = cs[[1, 1]] / n1;
-                            } else if d == 2 {
-                                u0 = cs[[0, 1]] / n0;
-                                u1 = cs[[1, 1]] / n1;
-
-                                v0 = cs[[0, 3]] / n0 - ((cs[[0, 1]] / n0).powi(2));
-                                v1 = cs[[1, 3]] / n1 - ((cs[[1, 1]] / n1).powi(2));
-                            } else {
-                                u0 = (cs[[0, d - 1]] / n0)
-                                    / ((cs[[0, 1]] / n0).powf(d as f64 / 2.0));
-                                u1 = (cs[[1, d - 1]] / n1)
-                                    / ((cs[[1, 1]] / n1).powf(d as f64 / 2.0));
+            .for_each(|(mut cs, cs_orig, reference)| {
+                for c in 0..2 {
+                    let delta = cs_orig[[c, 0]] - reference[c];
+                    for i in 2..(2 * d + 1) {
+                        // j = 0 and j = 1 (sum((x-u)**1) = 0)
+                        let mut acc = weights[c] * delta.powi(i as i32);
+                        for j in 2..(i + 1) {
+                            acc += (binomial(i, j) as f64)
+                                * delta.powi((i - j) as i32)
+                                * cs_orig[[c, j - 1]];
+                        }
+                        cs[[c, i - 1]] = acc;
+                    }
+                }
+            });
+        let mut ttest = self.ttest_from_cs(&cs);
+        // The first order (difference of the means) does not depend on the reference.
+        ttest
+            .row_mut(0)
+            .assign(&self.ttest_from_cs(&self.cs).row(0));
+        // The recentered sums are not zero for constant samples.
+        self.mask_zero_variance(&mut ttest, &self.cs);
+        ttest
+    }
 
-                                v0 = cs[[0, (2 * d) - 1]] / n0 - ((cs[[0, d - 1]] / n0).powi(2));
-                                v0 /= (cs[[0, 1]] / n0).powi(d as i32);
+    /// Ttest metric from the central sums `cs` (see `Ttest::cs`).
+    fn ttest_from_cs(&self, cs: &Array3<f64>) -> Array2<f64> {
+        let mut ttest =
+            welch_from_central_sums(cs, &self.weights, self.d, self.variance_floor, self.bessel).0;
+        self.mask_zero_variance(&mut ttest, cs);
+        ttest
+    }
 
-                                v1 = cs[[1, (2 * d) - 1]] / n1 - ((cs[[1, d - 1]] / n1).powi(2));
-                                v1 /= (cs[[1, 1]] / n1).powi(d as i32);
-                            }
+    /// Whether the sample with central sums `cs` (with axes (class, order)) is constant, that is,
+    /// its variance is at most the tolerance in both classes (see `set_variance_tolerance`).
+    fn is_zero_variance(&self, cs: ArrayView2<f64>) -> bool {
+        (0..2).all(|c| cs[[c, 1]] / self.weights[c] <= self.variance_tol)
+    }
 
-                            ttest[d - 1] = (u0 - u1) / f64::sqrt((v0 / n0) + (v1 / n1));
-                        }
-                    });
+    /// Set to NaN the results `res` (on axis 1) of the samples with central sums `cs` that are
+    /// constant.
+    fn mask_zero_variance(&self, res: &mut Array2<f64>, cs: &Array3<f64>) {
+        res.axis_iter_mut(Axis(1))
+            .zip(cs.outer_iter())
+            .filter(|(_, cs)| self.is_zero_variance(cs.view()))
+            .for_each(|(mut res, _)| res.fill(f64::NAN));
+    }
+
+    /// Samples that are constant (see `set_variance_tolerance`), for which the Ttest metrics are
+    /// NaN. The samples that are not accumulated (see `new_window`) are not constant.
+    /// return array axes (ns,)
+    pub fn get_zero_variance(&self) -> Array1<bool> {
+        let mut res = Array1::from_elem(self.ns, false);
+        for (j, cs) in self.cs.outer_iter().enumerate() {
+            let i = self.samples.as_ref().map_or(j, |samples| samples[j]);
+            res[i] = self.is_zero_variance(cs);
+        }
+        res
+    }
+
+    /// Number of constant samples (see `get_zero_variance`).
+    pub fn zero_variance_count(&self) -> usize {
+        self.get_zero_variance().iter().filter(|x| **x).count()
+    }
+
+    /// Size in bytes of the accumulators of the Ttest state: the central sums and the number of
+    /// samples (`2*ns*2d*8 + 2*8` bytes), plus the sum of the weights, and the histograms and
+    /// running medians if enabled. This does not include the snapshots not yet retrieved.
+    pub fn memory_bytes(&self) -> usize {
+        let mut bytes = self.cs.len() * std::mem::size_of::<f64>()
+            + self.n_samples.len() * std::mem::size_of::<u64>()
+            + self.weights.len() * std::mem::size_of::<f64>();
+        if let Some(histograms) = &self.histograms {
+            bytes += histograms.len() * std::mem::size_of::<u64>();
+        }
+        if let Some(medians) = &self.medians {
+            bytes += medians.len() * std::mem::size_of::<[P2Median; 2]>();
+        }
+        bytes
+    }
+
+    /// Mean of the traces for each class.
+    /// return array axes (class,ns)
+    pub fn get_means(&self) -> Array2<f64> {
+        self.expand(self.cs.slice(s![.., .., 0]).t().to_owned())
+    }
+
+    /// Walsh-Hadamard spectrum of the mean of the traces for each class (see `get_means` and
+    /// `walsh_hadamard`), to spot leakage that is structured over the bits of the sample index.
+    /// It is normalized such that a mean `a*(-1)**popcount(i & k)` over the samples `i` has the
+    /// coefficient `a` at index `k`.
+    /// Requires ns to be a power of two and all the samples to be accumulated (see `new_window`).
+    /// return array axes (class,ns)
+    pub fn get_walsh_spectrum(&self) -> Result<Array2<f64>, TtestError> {
+        if !self.ns.is_power_of_two() || self.samples.is_some() {
+            return Err(TtestError::InvalidShape(format!(
+                "Walsh spectrum requires all the samples, with ns a power of two (ns = {}).",
+                self.ns
+            )));
+        }
+        let mut spectrum = self.get_means();
+        spectrum
+            .outer_iter_mut()
+            .for_each(|mut m| walsh_hadamard(m.as_slice_mut().unwrap(), true));
+        Ok(spectrum)
+    }
+
+    /// Variance of the traces for each class.
+    /// return array axes (class,ns)
+    pub fn get_variances(&self) -> Array2<f64> {
+        let n = self.weights.clone().insert_axis(Axis(1));
+        self.expand(&self.cs.slice(s![.., .., 1]).t() / &n)
+    }
+
+    /// Skewness of the traces for each class.
+    /// Requires d >= 2.
+    /// return array axes (class,ns)
+    pub fn get_skewness(&self) -> Array2<f64> {
+        assert!(self.d >= 2, "Skewness requires a Ttest of order d >= 2.");
+        self.expand(self.standardized_moment(3))
+    }
+
+    /// Kurtosis (non-excess) of the traces for each class.
+    /// Requires d >= 2.
+    /// return array axes (class,ns)
+    pub fn get_kurtosis(&self) -> Array2<f64> {
+        assert!(self.d >= 2, "Kurtosis requires a Ttest of order d >= 2.");
+        self.expand(self.standardized_moment(4))
+    }
+
+    /// Two-sample Kolmogorov-Smirnov statistic between the two classes, that is the maximum
+    /// distance between their empirical cumulative distribution functions (up to the histogram
+    /// resolution).
+    /// Requires `enable_ks`.
+    /// return array axes (ns,)
+    pub fn get_ks(&self) -> Array1<f64> {
+        let histograms = self
+            .histograms
+            .as_ref()
+            .expect("KS statistic requires enable_ks.");
+        let n = self.n_samples.mapv(|x| x as f64);
+        let mut res = Array1::<f64>::zeros(self.ns_acc());
+        (res.axis_iter_mut(Axis(0)), histograms.outer_iter())
+            .into_par_iter()
+            .for_each(|(mut res, histograms)| {
+                let mut cdf = [0u64; 2];
+                let mut ks: f64 = 0.0;
+                for (h0, h1) in histograms.row(0).iter().zip(histograms.row(1).iter()) {
+                    cdf[0] += h0;
+                    cdf[1] += h1;
+                    ks = ks.max((cdf[0] as f64 / n[0] - cdf[1] as f64 / n[1]).abs());
+                }
+                res.fill(ks);
+            });
+        self.expand(res.insert_axis(Axis(0))).remove_axis(Axis(0))
+    }
+
+    /// Confidence interval with level `1-alpha` on the difference of the means of the two
+    /// classes `u0-u1`, based on the Welch standard error and the Student t-distribution (with
+    /// the Welch-Satterthwaite degrees of freedom).
+    /// The bounds are NaN if a class has less than two traces.
+    /// return array axes (bound,ns), where the bounds are (lower, upper)
+    pub fn get_ci(&self, alpha: f64) -> Array2<f64> {
+        assert!(0.0 < alpha && alpha < 1.0, "alpha must be in (0, 1).");
+        let n = &self.weights;
+        let mut res = Array2::<f64>::from_elem((2, self.ns_acc()), f64::NAN);
+        if n[0] < 2.0 || n[1] < 2.0 {
+            return self.expand(res);
+        }
+        (res.axis_iter_mut(Axis(1)), self.cs.axis_iter(Axis(0)))
+            .into_par_iter()
+            .for_each(|(mut res, cs)| {
+                // squared standard errors of the means, with unbiased variances
+                let se0 = cs[[0, 1]] / (n[0] - 1.0) / n[0];
+                let se1 = cs[[1, 1]] / (n[1] - 1.0) / n[1];
+                let se = (se0 + se1).sqrt();
+                let dof =
+                    (se0 + se1).powi(2) / (se0.powi(2) / (n[0] - 1.0) + se1.powi(2) / (n[1] - 1.0));
+                let delta = student_t_quantile(1.0 - alpha / 2.0, dof) * se;
+                let diff = cs[[0, 0]] - cs[[1, 0]];
+                res[0] = diff - delta;
+                res[1] = diff + delta;
+            });
+        self.expand(res)
+    }
+
+    /// Two-sided p-values of the Ttest statistics, based on the Student t-distribution with the
+    /// Welch-Satterthwaite degrees of freedom.
+    /// return array axes (d,ns)
+    pub fn get_pvalue(&self) -> Array2<f64> {
+        self.expand(self.pvalue_acc())
+    }
+
+    /// P-values of the accumulated samples (see `get_pvalue`).
+    fn pvalue_acc(&self) -> Array2<f64> {
+        let (mut ttest, dof) = welch_from_central_sums(
+            &self.cs,
+            &self.weights,
+            self.d,
+            self.variance_floor,
+            self.bessel,
+        );
+        ndarray::Zip::from(&mut ttest)
+            .and(&dof)
+            .par_for_each(|t, dof| *t = 2.0 * student_t_cdf(-t.abs(), *dof));
+        self.mask_zero_variance(&mut ttest, &self.cs);
+        ttest
+    }
+
+    /// P-values of the Ttest statistics (see `get_pvalue`), corrected for the multiple
+    /// comparisons over all the (accumulated) samples and orders (that is, d*ns tests, minus the
+    /// NaN p-values, which are left NaN).
+    /// return array axes (d,ns)
+    pub fn get_pvalue_corrected(&self, method: PValueCorrection) -> Array2<f64> {
+        let mut pvalues = self.pvalue_acc();
+        // NaN p-values (e.g., constant samples) are kept, and are not counted as tests.
+        let m = pvalues.iter().filter(|p| !p.is_nan()).count() as f64;
+        match method {
+            PValueCorrection::Bonferroni => {
+                pvalues.mapv_inplace(|p| if p * m > 1.0 { 1.0 } else { p * m })
+            }
+            PValueCorrection::BenjaminiHochberg => {
+                // p_(i) * m / i for the i-th smallest p-value, made monotonic from the largest one.
+                let mut order: Vec<usize> = (0..pvalues.len()).collect();
+                let flat = pvalues.as_slice_mut().unwrap();
+                order.sort_by(|a, b| {
+                    flat[*a]
+                        .partial_cmp(&flat[*b])
+                        .unwrap_or_else(|| flat[*a].is_nan().cmp(&flat[*b].is_nan()))
+                });
+                let mut min: f64 = 1.0;
+                for (rank, i) in order.iter().enumerate().rev() {
+                    if flat[*i].is_nan() {
+                        continue;
+                    }
+                    // m / i >= 1, but the rounding of p * m / m may go below p
+                    min = min.min((flat[*i] * m / (rank + 1) as f64).max(flat[*i]));
+                    flat[*i] = min;
+                }
+            }
+        }
+        self.expand(pvalues)
+    }
+
+    /// Standardized moment of order `order` (at most 2*d) for each class:
+    /// CM_{order,Q} / CM_{2,Q}**(order/2)
+    /// return array axes (class,ns)
+    fn standardized_moment(&self, order: usize) -> Array2<f64> {
+        let mut res = Array2::<f64>::zeros((2, self.ns_acc()));
+        let weights = &self.weights;
+        (res.axis_iter_mut(Axis(1)), self.cs.axis_iter(Axis(0)))
+            .into_par_iter()
+            .for_each(|(mut res, cs)| {
+                for c in 0..2 {
+                    let n = weights[c];
+                    res[c] = (cs[[c, order - 1]] / n) / (cs[[c, 1]] / n).powf(order as f64 / 2.0);
+                }
             });
-        return ttest;
+        res
+    }
+}
+
+/// Logarithm of the gamma function (Lanczos approximation), for x > 0.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let a = COEFS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFS[0], |acc, (i, c)| acc + c / (x + (i + 1) as f64));
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + a.ln()
+}
+
+/// Continued fraction for the incomplete beta function (modified Lentz's method).
+fn beta_cf(a: f64, b: f64, x: f64) -> f64 {
+    const EPS: f64 = 1e-15;
+    const FPMIN: f64 = 1e-300;
+    let qab = a + b;
+    let qap = a + 1.0;
+    let qam = a - 1.0;
+    let mut c = 1.0;
+    let mut d = 1.0 - qab * x / qap;
+    if d.abs() < FPMIN {
+        d = FPMIN;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..10_000 {
+        let m = m as f64;
+        let m2 = 2.0 * m;
+        let aa = m * (b - m) * x / ((qam + m2) * (a + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN {
+            d = FPMIN;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN {
+            c = FPMIN;
+        }
+        d = 1.0 / d;
+        h *= d * c;
+        let aa = -(a + m) * (qab + m) * x / ((a + m2) * (qap + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN {
+            d = FPMIN;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN {
+            c = FPMIN;
+        }
+        d = 1.0 / d;
+        let del = d * c;
+        h *= del;
+        if (del - 1.0).abs() < EPS {
+            break;
+        }
+    }
+    h
+}
+
+/// Regularized incomplete beta function I_x(a,b).
+fn inc_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    } else if x >= 1.0 {
+        return 1.0;
+    }
+    let bt = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    if x < (a + 1.0) / (a + b + 2.0) {
+        bt * beta_cf(a, b, x) / a
+    } else {
+        1.0 - bt * beta_cf(b, a, 1.0 - x) / b
+    }
+}
+
+/// Cumulative distribution function of the Student t-distribution with `dof` degrees of freedom.
+fn student_t_cdf(t: f64, dof: f64) -> f64 {
+    let tail = 0.5 * inc_beta(dof / 2.0, 0.5, dof / (dof + t * t));
+    if t > 0.0 {
+        1.0 - tail
+    } else {
+        tail
+    }
+}
+
+/// Quantile of the Student t-distribution with `dof` degrees of freedom, for p in [0.5, 1).
+fn student_t_quantile(p: f64, dof: f64) -> f64 {
+    // bracket the quantile, then bisect
+    let mut lo = 0.0;
+    let mut hi = 1.0;
+    while student_t_cdf(hi, dof) < p {
+        lo = hi;
+        hi *= 2.0;
+    }
+    for _ in 0..100 {
+        let mid = 0.5 * (lo + hi);
+        if student_t_cdf(mid, dof) < p {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
     }
+    0.5 * (lo + hi)
 }
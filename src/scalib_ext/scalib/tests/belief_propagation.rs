@@ -0,0 +1,3329 @@
+use ndarray::{Array1, Array2};
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use scalib::belief_propagation::{self as bp, Clamp, FactorOp, Func, FuncType, Var, VarType};
+use std::sync::Arc;
+
+fn random_distri(n: usize, nc: usize) -> Array2<f64> {
+    let mut distri = Array2::<f64>::random((n, nc), Uniform::new(0.1, 1.0));
+    distri.outer_iter_mut().for_each(|mut d| d /= d.sum());
+    distri
+}
+
+fn profile_para(neighboors: Vec<usize>, distri: Array2<f64>) -> Var {
+    Var {
+        neighboors,
+        vartype: VarType::ProfilePara {
+            distri_orig: distri.clone(),
+            distri_current: distri,
+        },
+    }
+}
+
+fn not_profile_para(neighboors: Vec<usize>, n: usize, nc: usize) -> Var {
+    Var {
+        neighboors,
+        vartype: VarType::NotProfilePara {
+            distri_current: Array2::ones((n, nc)),
+        },
+    }
+}
+
+fn distri_current(var: &Var) -> &Array2<f64> {
+    match &var.vartype {
+        VarType::ProfilePara { distri_current, .. }
+        | VarType::NotProfilePara { distri_current }
+        | VarType::ProfileSingle { distri_current, .. }
+        | VarType::NotProfileSingle { distri_current } => distri_current,
+    }
+}
+
+/// Chain v0 - f0 - v1 - f1 - ... - v_{len}, where each f_i is a random permutation LOOKUP with
+/// v_i as input and v_{i+1} as output. v_0 and v_{len} are profiled.
+fn lookup_chain(len: usize, n: usize, nc: usize) -> (Vec<Func>, Vec<Var>) {
+    let functions: Vec<Func> = (0..len)
+        .map(|i| {
+            let mut table: Vec<u32> = (0..nc as u32).collect();
+            table.rotate_left((3 * i + 1) % nc);
+            Func::new(
+                vec![2 * i + 1, 2 * i],
+                FuncType::LOOKUP(Array1::from(table)),
+            )
+        })
+        .collect();
+    let variables: Vec<Var> = (0..=len)
+        .map(|i| {
+            let mut neighboors = Vec::new();
+            if i > 0 {
+                neighboors.push(2 * i - 1);
+            }
+            if i < len {
+                neighboors.push(2 * i);
+            }
+            if i == 0 || i == len {
+                profile_para(neighboors, random_distri(n, nc))
+            } else {
+                not_profile_para(neighboors, n, nc)
+            }
+        })
+        .collect();
+    (functions, variables)
+}
+
+#[test]
+fn partial_update() {
+    let (n, nc, len) = (4, 16, 10);
+    let (functions, mut variables) = lookup_chain(len, n, nc);
+    let edge = 2 * len;
+    let mut edges = bp::init_edges(&variables, edge, nc, n);
+    bp::run_bp_edges(&functions, &mut variables, &mut edges, len + 1, false).unwrap();
+    let before: Vec<Array2<f64>> = variables
+        .iter()
+        .map(|v| distri_current(v).clone())
+        .collect();
+
+    // New evidence on v0.
+    let new_prior = random_distri(n, nc);
+    if let VarType::ProfilePara { distri_orig, .. } = &mut variables[0].vartype {
+        distri_orig.assign(&new_prior);
+    }
+    let frontier = bp::run_bp_partial(&functions, &mut variables, &mut edges, &[0], 2, 3).unwrap();
+    assert_eq!(frontier.functions, vec![0, 1]);
+    assert_eq!(frontier.variables, vec![0, 1, 2]);
+
+    // Reference: full run with the new evidence.
+    let (_, mut variables_ref) = lookup_chain(len, n, nc);
+    variables_ref
+        .iter_mut()
+        .zip(variables.iter())
+        .for_each(|(v_ref, v)| v_ref.vartype = clone_vartype(&v.vartype));
+    bp::run_bp(&functions, &mut variables_ref, len + 1, edge, nc, n, false).unwrap();
+
+    for i in 0..3 {
+        assert!(distri_current(&variables[i]).abs_diff_eq(distri_current(&variables_ref[i]), 1e-9));
+    }
+    for i in 3..=len {
+        assert_eq!(distri_current(&variables[i]), &before[i]);
+    }
+    assert!(!distri_current(&variables[len]).abs_diff_eq(distri_current(&variables_ref[len]), 1e-6));
+}
+
+fn clone_vartype(vartype: &VarType) -> VarType {
+    match vartype {
+        VarType::ProfilePara { distri_orig, .. } => VarType::ProfilePara {
+            distri_orig: distri_orig.clone(),
+            distri_current: distri_orig.clone(),
+        },
+        VarType::ProfileSingle { distri_orig, .. } => VarType::ProfileSingle {
+            distri_orig: distri_orig.clone(),
+            distri_current: distri_orig.clone(),
+        },
+        VarType::NotProfilePara { distri_current } => VarType::NotProfilePara {
+            distri_current: Array2::ones(distri_current.raw_dim()),
+        },
+        VarType::NotProfileSingle { distri_current } => VarType::NotProfileSingle {
+            distri_current: Array2::ones(distri_current.raw_dim()),
+        },
+    }
+}
+
+#[test]
+fn binary_parity_check() {
+    let (n, nc) = (10, 2);
+    // Loopy parity-check graph: x0 ^ x1 ^ x2 = 0, x2 ^ x3 ^ x4 = 0, x4 ^ x0 ^ x5 = 0,
+    // x5 = x1 ^ p (public p), x6 = not(x3), and a single variable k with x0 = k ^ x6.
+    let functions = vec![
+        Func::new(vec![0, 1, 2], FuncType::XOR),
+        Func::new(vec![3, 4, 5], FuncType::XOR),
+        Func::new(vec![6, 7, 8], FuncType::XOR),
+        Func::new(
+            vec![9, 10],
+            FuncType::XORCST(Array1::from_iter((0..n as u32).map(|x| x % 2))),
+        ),
+        Func::new(vec![11, 12], FuncType::LOOKUP(Array1::from(vec![1, 0]))),
+        Func::new(vec![13, 14, 15], FuncType::XOR),
+    ];
+    let neighboors = vec![
+        vec![0, 7, 13],
+        vec![1, 10],
+        vec![2, 3],
+        vec![4, 12],
+        vec![5, 6],
+        vec![8, 9],
+        vec![11, 15],
+    ];
+    let make_variables = || {
+        let mut variables: Vec<Var> = neighboors
+            .iter()
+            .map(|nb| profile_para(nb.clone(), random_distri(n, nc)))
+            .collect();
+        variables.push(Var {
+            neighboors: vec![14],
+            vartype: VarType::NotProfileSingle {
+                distri_current: Array2::ones((1, nc)),
+            },
+        });
+        variables
+    };
+    let mut variables = make_variables();
+    let mut variables_ref: Vec<Var> = variables
+        .iter()
+        .map(|v| Var {
+            neighboors: v.neighboors.clone(),
+            vartype: clone_vartype(&v.vartype),
+        })
+        .collect();
+
+    bp::run_bp_binary(&functions, &mut variables, 5, 16, n, false).unwrap();
+    let mut edges = bp::init_edges(&variables_ref, 16, nc, n);
+    bp::run_bp_edges(&functions, &mut variables_ref, &mut edges, 5, false).unwrap();
+    for (v, v_ref) in variables.iter().zip(variables_ref.iter()) {
+        assert!(distri_current(v).abs_diff_eq(distri_current(v_ref), 1e-9));
+    }
+
+    // `run_bp` uses the general implementation, unless the binary one is requested
+    for (binary, expected) in [(false, &variables_ref), (true, &variables)].iter() {
+        let mut variables_run: Vec<Var> = variables_ref
+            .iter()
+            .map(|v| Var {
+                neighboors: v.neighboors.clone(),
+                vartype: clone_vartype(&v.vartype),
+            })
+            .collect();
+        let options = bp::RunBpOptions {
+            binary: *binary,
+            ..Default::default()
+        };
+        bp::run_bp_with_options(&functions, &mut variables_run, 5, 16, nc, n, false, options)
+            .unwrap();
+        for (v, v_expected) in variables_run.iter().zip(expected.iter()) {
+            assert_eq!(distri_current(v), distri_current(v_expected));
+        }
+    }
+
+    let and = vec![Func::new(vec![0, 1, 2], FuncType::AND)];
+    assert_eq!(
+        bp::run_bp_binary(&and, &mut make_variables(), 1, 16, n, false),
+        Err(bp::BPError::UnsupportedFunction { function: 0 })
+    );
+}
+
+#[test]
+fn binary_contradiction() {
+    // x0 ^ x1 ^ x2 = 0, with x0 = x1 = 0 and x2 = 1 (contradiction) or x2 = 0
+    let (n, nc) = (3, 2);
+    let functions = vec![Func::new(vec![0, 1, 2], FuncType::XOR)];
+    let make_variables = |x2: usize| -> Vec<Var> {
+        [0, 0, x2]
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let mut distri = Array2::zeros((n, nc));
+                distri.column_mut(*x).fill(1.0);
+                profile_para(vec![i], distri)
+            })
+            .collect()
+    };
+    let mut variables = make_variables(1);
+    assert!(matches!(
+        bp::run_bp_binary(&functions, &mut variables, 5, 3, n, false),
+        Err(bp::BPError::Diverged { .. })
+    ));
+    let mut variables = make_variables(1);
+    let mut edges = bp::init_edges(&variables, 3, nc, n);
+    assert!(matches!(
+        bp::run_bp_edges(&functions, &mut variables, &mut edges, 5, false),
+        Err(bp::BPError::Diverged { .. })
+    ));
+
+    let mut variables = make_variables(0);
+    bp::run_bp_binary(&functions, &mut variables, 5, 3, n, false).unwrap();
+}
+
+#[test]
+fn lookup_permutation() {
+    let nc = 16;
+    let sbox = Array1::<u32>::from(vec![
+        0xc, 0x5, 0x6, 0xb, 0x9, 0x0, 0xa, 0xd, 0x3, 0xe, 0xf, 0x8, 0x4, 0x7, 0x1, 0x2,
+    ]);
+    assert!(bp::is_permutation(&sbox, nc));
+    assert!(bp::FuncType::lookup(sbox.clone(), nc).is_ok());
+
+    let mut dup = sbox.clone();
+    dup[3] = dup[4];
+    assert!(!bp::is_permutation(&dup, nc));
+    assert!(matches!(
+        bp::FuncType::lookup(dup.clone(), nc),
+        Err(bp::BPError::InvalidTable(_))
+    ));
+    assert!(!bp::is_permutation(&sbox, 8));
+
+    // A non-bijective table gives exact marginals.
+    let n = 3;
+    let functions = vec![Func::new(
+        vec![0, 1],
+        bp::FuncType::lookup_non_bijective(dup.clone(), nc).unwrap(),
+    )];
+    let distri_x = random_distri(n, nc);
+    let distri_y = random_distri(n, nc);
+    let mut variables = vec![
+        profile_para(vec![0], distri_y.clone()),
+        profile_para(vec![1], distri_x.clone()),
+    ];
+    bp::run_bp(&functions, &mut variables, 1, 2, nc, n, false).unwrap();
+    let mut ref_x = Array2::<f64>::zeros((n, nc));
+    let mut ref_y = Array2::<f64>::zeros((n, nc));
+    for i in 0..n {
+        for x in 0..nc {
+            let y = dup[x] as usize;
+            ref_x[[i, x]] += distri_x[[i, x]] * distri_y[[i, y]];
+            ref_y[[i, y]] += distri_x[[i, x]] * distri_y[[i, y]];
+        }
+    }
+    ref_x.outer_iter_mut().for_each(|mut d| d /= d.sum());
+    ref_y.outer_iter_mut().for_each(|mut d| d /= d.sum());
+    assert!(distri_current(&variables[0]).abs_diff_eq(&ref_y, 1e-9));
+    assert!(distri_current(&variables[1]).abs_diff_eq(&ref_x, 1e-9));
+    assert!(bp::FuncType::lookup_non_bijective(Array1::from(vec![0, 16]), 2).is_err());
+}
+
+#[test]
+fn components() {
+    let (n, nc) = (5, 8);
+    // Two independent chains: a2 = a0 ^ a1, a3 = a2 ^ a4 and b2 = b0 ^ b1, b3 = b2 ^ b4,
+    // with interleaved variable ids.
+    let functions = vec![
+        Func::new(vec![0, 1, 2], FuncType::XOR),
+        Func::new(vec![3, 4, 5], FuncType::XOR),
+        Func::new(vec![6, 7, 8], FuncType::XOR),
+        Func::new(vec![9, 10, 11], FuncType::XOR),
+    ];
+    let neighboors = vec![
+        vec![1],     // a0
+        vec![7],     // b0
+        vec![2],     // a1
+        vec![8],     // b1
+        vec![0, 4],  // a2
+        vec![6, 10], // b2
+        vec![3],     // a3
+        vec![9],     // b3
+        vec![5],     // a4
+        vec![11],    // b4
+    ];
+    let mut variables: Vec<Var> = neighboors
+        .iter()
+        .map(|nb| profile_para(nb.clone(), random_distri(n, nc)))
+        .collect();
+    variables[4] = not_profile_para(neighboors[4].clone(), n, nc);
+    let mut variables_ref: Vec<Var> = variables
+        .iter()
+        .map(|v| Var {
+            neighboors: v.neighboors.clone(),
+            vartype: clone_vartype(&v.vartype),
+        })
+        .collect();
+
+    let options = bp::RunBpOptions::default();
+    let (components, iterations) =
+        bp::run_bp_components(&functions, &mut variables, 3, 12, nc, n, options, 0.0).unwrap();
+    assert_eq!(iterations, vec![3, 3]);
+    assert_eq!(components.n_components, 2);
+    assert_eq!(components.functions, vec![0, 0, 1, 1]);
+    assert_eq!(components.variables, vec![0, 1, 0, 1, 0, 1, 0, 1, 0, 1]);
+    assert_eq!(
+        bp::connected_components(&functions, &variables, 12),
+        components
+    );
+
+    bp::run_bp(&functions, &mut variables_ref, 3, 12, nc, n, false).unwrap();
+    for (v, v_ref) in variables.iter().zip(variables_ref.iter()) {
+        assert!(distri_current(v).abs_diff_eq(distri_current(v_ref), 1e-12));
+    }
+}
+
+#[test]
+fn components_dangling_edges() {
+    let (n, nc) = (4, 8);
+    // a2 = a0 ^ a1 ^ x, where x (edge 3) has no variable, and b1 = b0 ^ y, where y (edge 6) has no
+    // variable.
+    let functions = vec![
+        Func::new(vec![0, 1, 2, 3], FuncType::XOR),
+        Func::new(vec![4, 5, 6], FuncType::XOR),
+    ];
+    let neighboors = [vec![0], vec![1], vec![2], vec![4], vec![5]];
+    let variables: Vec<Var> = neighboors
+        .iter()
+        .map(|nb| profile_para(nb.clone(), random_distri(n, nc)))
+        .collect();
+    let mut variables_ref: Vec<Var> = variables
+        .iter()
+        .map(|v| Var {
+            neighboors: v.neighboors.clone(),
+            vartype: clone_vartype(&v.vartype),
+        })
+        .collect();
+    let mut variables = variables;
+
+    let options = bp::RunBpOptions::default();
+    let (components, iterations) =
+        bp::run_bp_components(&functions, &mut variables, 2, 7, nc, n, options, 0.0).unwrap();
+    assert_eq!(iterations, vec![2, 2]);
+    assert_eq!(components.functions, vec![0, 1]);
+    assert_eq!(components.variables, vec![0, 0, 0, 1, 1]);
+
+    bp::run_bp(&functions, &mut variables_ref, 2, 7, nc, n, false).unwrap();
+    for (v, v_ref) in variables.iter().zip(variables_ref.iter()) {
+        assert!(distri_current(v).abs_diff_eq(distri_current(v_ref), 1e-12));
+    }
+}
+
+#[test]
+fn components_early_stop() {
+    let (n, nc, len) = (4, 16, 5);
+    // A lookup chain is a tree: the marginals are exact after len iterations, after which the
+    // distributions do not change anymore.
+    let (functions, mut variables) = lookup_chain(len, n, nc);
+    let mut variables_ref: Vec<Var> = variables
+        .iter()
+        .map(|v| Var {
+            neighboors: v.neighboors.clone(),
+            vartype: clone_vartype(&v.vartype),
+        })
+        .collect();
+    let edge = 2 * len;
+    let options = bp::RunBpOptions::default();
+    let (_, iterations) =
+        bp::run_bp_components(&functions, &mut variables, 100, edge, nc, n, options, 1e-12)
+            .unwrap();
+    assert_eq!(iterations.len(), 1);
+    assert!(iterations[0] < 100);
+
+    bp::run_bp(&functions, &mut variables_ref, 100, edge, nc, n, false).unwrap();
+    for (v, v_ref) in variables.iter().zip(variables_ref.iter()) {
+        assert!(distri_current(v).abs_diff_eq(distri_current(v_ref), 1e-9));
+    }
+}
+
+#[test]
+fn components_checks() {
+    let (n, nc) = (1, 2);
+    // Two components: b1 = b0 ^ b2 (consistent) and a0 = a1 ^ a2 with contradictory priors.
+    let functions = vec![
+        Func::new(vec![0, 1, 2], FuncType::XOR),
+        Func::new(vec![3, 4, 5], FuncType::XOR),
+    ];
+    let hard = |x: usize| {
+        let mut distri = Array2::<f64>::zeros((n, nc));
+        distri[[0, x]] = 1.0;
+        distri
+    };
+    let mut variables = vec![
+        profile_para(vec![3], hard(0)),
+        profile_para(vec![0], hard(0)),
+        profile_para(vec![1], hard(0)),
+        profile_para(vec![4], hard(0)),
+        profile_para(vec![2], hard(0)),
+        profile_para(vec![5], hard(1)),
+    ];
+    let options = bp::RunBpOptions::default();
+    assert!(matches!(
+        bp::run_bp_components(&functions, &mut variables, 3, 6, nc, n, options, 0.0),
+        Err(bp::BPError::Diverged { variable, .. }) if [0, 3, 5].contains(&variable)
+    ));
+    variables[5] = profile_para(vec![5], hard(0));
+    assert!(bp::run_bp_components(&functions, &mut variables, 3, 6, nc, n, options, 0.0).is_ok());
+    let functions = vec![Func::new(vec![0, 7], FuncType::XOR)];
+    assert!(matches!(
+        bp::run_bp_components(&functions, &mut variables, 3, 6, nc, n, options, 0.0),
+        Err(bp::BPError::InvalidGraph(_))
+    ));
+}
+
+#[test]
+fn reset_variables() {
+    let (n, nc, len) = (4, 16, 5);
+    let (functions, mut variables) = lookup_chain(len, n, nc);
+    let initial: Vec<Array2<f64>> = variables
+        .iter()
+        .map(|v| match &v.vartype {
+            VarType::ProfilePara { distri_orig, .. } => distri_orig.clone(),
+            _ => Array2::ones((n, nc)),
+        })
+        .collect();
+    bp::run_bp(&functions, &mut variables, 3, 2 * len, nc, n, false).unwrap();
+    let first: Vec<Array2<f64>> = variables
+        .iter()
+        .map(|v| distri_current(v).clone())
+        .collect();
+
+    bp::reset_variables(&mut variables);
+    for (v, init) in variables.iter().zip(initial.iter()) {
+        assert_eq!(distri_current(v), init);
+    }
+    bp::run_bp(&functions, &mut variables, 3, 2 * len, nc, n, false).unwrap();
+    for (v, first) in variables.iter().zip(first.iter()) {
+        assert_eq!(distri_current(v), first);
+    }
+
+    // an update after the reset matches an update of a freshly built graph
+    bp::reset_variables(&mut variables);
+    bp::run_bp(&functions, &mut variables, 5, 2 * len, nc, n, false).unwrap();
+    let mut fresh: Vec<Var> = variables
+        .iter()
+        .zip(initial.iter())
+        .map(|(v, init)| match &v.vartype {
+            VarType::ProfilePara { .. } => profile_para(v.neighboors.clone(), init.clone()),
+            _ => not_profile_para(v.neighboors.clone(), n, nc),
+        })
+        .collect();
+    bp::run_bp(&functions, &mut fresh, 5, 2 * len, nc, n, false).unwrap();
+    for (v, f) in variables.iter().zip(fresh.iter()) {
+        assert_eq!(distri_current(v), distri_current(f));
+    }
+}
+
+#[test]
+fn masked_copies() {
+    let (n, nc) = (6, 16);
+    let table: Vec<u32> = (0..nc as u32).map(|x| (x * 7 + 3) % nc as u32).collect();
+    let mut lookup = Func::new(vec![0, 1], FuncType::LOOKUP(Array1::from(table)));
+    let enabled = Array1::from_iter((0..n).map(|i| i % 2 == 1));
+    lookup.enabled = Some(enabled.clone());
+    let functions = vec![lookup];
+    let distri_y = random_distri(n, nc);
+    let distri_x = random_distri(n, nc);
+    let mut variables = vec![
+        profile_para(vec![0], distri_y.clone()),
+        profile_para(vec![1], distri_x.clone()),
+    ];
+    bp::run_bp(&functions, &mut variables, 2, 2, nc, n, false).unwrap();
+
+    let unmasked = vec![Func::new(vec![0, 1], functions[0].functype.clone())];
+    let mut variables_unmasked = vec![
+        profile_para(vec![0], distri_y.clone()),
+        profile_para(vec![1], distri_x.clone()),
+    ];
+    bp::run_bp(&unmasked, &mut variables_unmasked, 2, 2, nc, n, false).unwrap();
+
+    for i in 0..n {
+        for (v, (v_unmasked, prior)) in variables
+            .iter()
+            .zip(variables_unmasked.iter().zip([&distri_y, &distri_x].iter()))
+        {
+            let d = distri_current(v).row(i).to_owned();
+            if enabled[i] {
+                assert!(d.abs_diff_eq(&distri_current(v_unmasked).row(i), 1e-12));
+            } else {
+                assert!(d.abs_diff_eq(&prior.row(i), 1e-12));
+            }
+        }
+    }
+}
+
+#[test]
+fn stable_normalization() {
+    // y = x ^ 0, with tiny priors whose product underflows.
+    let nc = 4;
+    let n = 1;
+    let prior_x = Array2::from_shape_vec((n, nc), vec![1e-300, 2e-300, 3e-300, 4e-300]).unwrap();
+    let prior_y = Array2::from_shape_vec((n, nc), vec![4e-300, 3e-300, 2e-300, 1e-300]).unwrap();
+    let make_variables = || {
+        vec![
+            profile_para(vec![0], prior_y.clone()),
+            profile_para(vec![1], prior_x.clone()),
+        ]
+    };
+    let functions = vec![Func::new(vec![0, 1], FuncType::XORCST(Array1::zeros(n)))];
+    let expected = Array2::from_shape_vec((n, nc), vec![0.2, 0.3, 0.3, 0.2]).unwrap();
+
+    let mut variables = make_variables();
+    bp::run_bp_with_options(
+        &functions,
+        &mut variables,
+        1,
+        2,
+        nc,
+        n,
+        false,
+        bp::RunBpOptions {
+            stable_norm: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    for var in variables.iter() {
+        assert!(distri_current(var).abs_diff_eq(&expected, 1e-9));
+    }
+
+    // Without it, the information is lost.
+    let mut variables = make_variables();
+    assert!(matches!(
+        bp::run_bp(&functions, &mut variables, 1, 2, nc, n, false),
+        Err(bp::BPError::Diverged { .. })
+    ));
+}
+
+#[test]
+fn addcst_identity() {
+    // y = x + c, with c = 0 or nc for some copies.
+    let nc = 5;
+    let n = 6;
+    let values = Array1::from(vec![0, 3, 5, 1, 0, 10]);
+    let prior_x = random_distri(n, nc);
+    let prior_y = random_distri(n, nc);
+    let mut variables = vec![
+        profile_para(vec![0], prior_y.clone()),
+        profile_para(vec![1], prior_x.clone()),
+    ];
+    let functions = vec![Func::new(vec![0, 1], FuncType::ADDCST(values.clone()))];
+    bp::run_bp(&functions, &mut variables, 1, 2, nc, n, false).unwrap();
+
+    let mut expected_x = Array2::zeros((n, nc));
+    let mut expected_y = Array2::zeros((n, nc));
+    for i in 0..n {
+        for x in 0..nc {
+            let y = (x + values[i] as usize) % nc;
+            let p = prior_x[[i, x]] * prior_y[[i, y]];
+            expected_x[[i, x]] = p;
+            expected_y[[i, y]] = p;
+        }
+    }
+    for (var, mut expected) in variables.iter().zip(vec![expected_y, expected_x]) {
+        let sum = expected
+            .sum_axis(ndarray::Axis(1))
+            .insert_axis(ndarray::Axis(1));
+        expected /= &sum;
+        assert!(distri_current(var).abs_diff_eq(&expected, 1e-9));
+    }
+}
+
+/// Function node that sends uniform messages, hence gives no information.
+struct NoOp;
+
+impl FactorOp for NoOp {
+    fn update(&self, edges: &mut [&mut Array2<f64>]) {
+        edges.iter_mut().for_each(|msg| msg.fill(1.0));
+    }
+}
+
+#[test]
+fn custom_op() {
+    let nc = 4;
+    let n = 3;
+    let prior_x = random_distri(n, nc);
+    let prior_y = random_distri(n, nc);
+    let mut variables = vec![
+        profile_para(vec![0], prior_y.clone()),
+        profile_para(vec![1], prior_x.clone()),
+    ];
+    let functions = vec![Func::new(vec![0, 1], FuncType::CUSTOM(Arc::new(NoOp)))];
+    bp::run_bp(&functions, &mut variables, 2, 2, nc, n, false).unwrap();
+    assert!(distri_current(&variables[0]).abs_diff_eq(&prior_y, 1e-9));
+    assert!(distri_current(&variables[1]).abs_diff_eq(&prior_x, 1e-9));
+}
+
+#[test]
+fn workspace() {
+    let n = 10;
+    let len = 4;
+    let mut workspace = bp::BpWorkspace::new();
+    for nc in [16, 16, 8].iter().copied() {
+        let (functions, variables) = lookup_chain(len, n, nc);
+        let mut variables_ref: Vec<Var> = variables
+            .iter()
+            .map(|v| Var {
+                neighboors: v.neighboors.clone(),
+                vartype: clone_vartype(&v.vartype),
+            })
+            .collect();
+        let mut variables = variables;
+        bp::run_bp(&functions, &mut variables_ref, 3, 2 * len, nc, n, false).unwrap();
+        bp::run_bp_workspace(
+            &functions,
+            &mut variables,
+            3,
+            2 * len,
+            nc,
+            n,
+            false,
+            bp::RunBpOptions::default(),
+            &mut workspace,
+        )
+        .unwrap();
+        for (v, v_ref) in variables.iter().zip(variables_ref.iter()) {
+            assert_eq!(distri_current(v), distri_current(v_ref));
+        }
+    }
+}
+
+#[test]
+fn marginalize_bytes() {
+    let n = 3;
+    let distri = random_distri(n, 256);
+    assert_eq!(bp::marginalize_bytes(&distri, 8, 0), distri);
+
+    let distri = random_distri(n, 1 << 16);
+    let low = bp::marginalize_bytes(&distri, 16, 0);
+    let high = bp::marginalize_bytes(&distri, 16, 1);
+    for i in 0..n {
+        for b in 0..256 {
+            let low_ref: f64 = (0..256).map(|h| distri[[i, (h << 8) | b]]).sum();
+            let high_ref: f64 = (0..256).map(|l| distri[[i, (b << 8) | l]]).sum();
+            assert!((low[[i, b]] - low_ref).abs() < 1e-12);
+            assert!((high[[i, b]] - high_ref).abs() < 1e-12);
+        }
+    }
+}
+
+#[test]
+fn divergence() {
+    // y = x ^ 0, with x = 0 and y = 1.
+    let nc = 4;
+    let n = 2;
+    let mut prior_x = Array2::zeros((n, nc));
+    prior_x.column_mut(0).fill(1.0);
+    let mut prior_y = Array2::zeros((n, nc));
+    prior_y.column_mut(1).fill(1.0);
+    let mut variables = vec![
+        profile_para(vec![0], prior_y),
+        profile_para(vec![1], prior_x),
+    ];
+    let functions = vec![Func::new(vec![0, 1], FuncType::XORCST(Array1::zeros(n)))];
+    assert_eq!(
+        bp::run_bp(&functions, &mut variables, 3, 2, nc, n, false),
+        Err(bp::BPError::Diverged {
+            variable: 0,
+            iteration: 0
+        })
+    );
+}
+
+#[test]
+fn mutual_information() {
+    let nc = 16;
+    let n = 5;
+    let prior_x = random_distri(n, nc);
+    let entropy = prior_x.map_axis(ndarray::Axis(1), |p| {
+        -p.iter().map(|p| p * p.log2()).sum::<f64>()
+    });
+    let edges = vec![Array2::ones((n, nc)), prior_x];
+
+    // y = sbox(x): MI = H(x)
+    let (functions, _) = lookup_chain(1, n, nc);
+    let mi = bp::mutual_information(&functions[0], &edges);
+    assert!(mi.abs_diff_eq(&entropy, 1e-9));
+
+    // y = x & 0: MI = 0
+    let func = Func::new(vec![0, 1], FuncType::ANDCST(Array1::zeros(n)));
+    let mi = bp::mutual_information(&func, &edges);
+    assert!(mi.abs_diff_eq(&Array1::zeros(n), 1e-9));
+}
+
+/// Function node that records the number of threads it runs with and sends uniform messages.
+struct ThreadCount(std::sync::Mutex<Vec<usize>>);
+
+impl FactorOp for ThreadCount {
+    fn update(&self, edges: &mut [&mut Array2<f64>]) {
+        self.0.lock().unwrap().push(rayon::current_num_threads());
+        edges.iter_mut().for_each(|msg| msg.fill(1.0));
+    }
+}
+
+#[test]
+fn thread_pool() {
+    let n = 10;
+    let len = 4;
+    let nc = 16;
+    let (functions, variables) = lookup_chain(len, n, nc);
+    let mut variables_ref: Vec<Var> = variables
+        .iter()
+        .map(|v| Var {
+            neighboors: v.neighboors.clone(),
+            vartype: clone_vartype(&v.vartype),
+        })
+        .collect();
+    let mut variables = variables;
+    let pool = || {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+    };
+    let mut workspace = bp::BpWorkspace::with_thread_pool(pool());
+    bp::run_bp(&functions, &mut variables_ref, 3, 2 * len, nc, n, false).unwrap();
+    bp::run_bp_workspace(
+        &functions,
+        &mut variables,
+        3,
+        2 * len,
+        nc,
+        n,
+        false,
+        bp::RunBpOptions::default(),
+        &mut workspace,
+    )
+    .unwrap();
+    for (v, v_ref) in variables.iter().zip(variables_ref.iter()) {
+        assert_eq!(distri_current(v), distri_current(v_ref));
+    }
+
+    let op = Arc::new(ThreadCount(std::sync::Mutex::new(Vec::new())));
+    let functions = vec![Func::new(vec![0, 1], FuncType::CUSTOM(op.clone()))];
+    let mut variables = vec![
+        profile_para(vec![0], random_distri(n, nc)),
+        profile_para(vec![1], random_distri(n, nc)),
+    ];
+    let mut workspace = bp::BpWorkspace::with_thread_pool(pool());
+    bp::run_bp_workspace(
+        &functions,
+        &mut variables,
+        2,
+        2,
+        nc,
+        n,
+        false,
+        bp::RunBpOptions::default(),
+        &mut workspace,
+    )
+    .unwrap();
+    assert_eq!(*op.0.lock().unwrap(), vec![1, 1]);
+}
+
+#[test]
+fn lookup_resize() {
+    // 6-bit to 4-bit table
+    let nc_in = 64;
+    let nc_out = 16;
+    let n = 5;
+    let table: Array1<u32> = (0..nc_in as u32).map(|x| (x * 7 + 3) % 13).collect();
+    assert!(FuncType::lookup_non_bijective(table.clone(), nc_in).is_ok());
+    assert!(FuncType::lookup_resize(table.clone(), nc_out, nc_out).is_err());
+    let functype = FuncType::lookup_resize(table.clone(), nc_in, nc_out).unwrap();
+    let prior_x = random_distri(n, nc_in);
+    let prior_y = random_distri(n, nc_out);
+    let mut variables = vec![
+        profile_para(vec![0], prior_y.clone()),
+        profile_para(vec![1], prior_x.clone()),
+    ];
+    let functions = vec![Func::new(vec![0, 1], functype)];
+    bp::run_bp(&functions, &mut variables, 1, 2, nc_in, n, false).unwrap();
+
+    // brute-force factor
+    let mut expected_x = Array2::zeros((n, nc_in));
+    let mut expected_y = Array2::zeros((n, nc_out));
+    for k in 0..n {
+        for x in 0..nc_in {
+            let y = table[x] as usize;
+            let p = prior_x[[k, x]] * prior_y[[k, y]];
+            expected_x[[k, x]] += p;
+            expected_y[[k, y]] += p;
+        }
+    }
+    for (var, mut expected) in variables.iter().zip(vec![expected_y, expected_x]) {
+        let sum = expected
+            .sum_axis(ndarray::Axis(1))
+            .insert_axis(ndarray::Axis(1));
+        expected /= &sum;
+        assert!(distri_current(var).abs_diff_eq(&expected, 1e-9));
+    }
+}
+
+#[test]
+fn lookup_resize_binary_input() {
+    // 1-bit to 2-bit table, with an output out of GF(2): the binary algorithm must not be used
+    let n = 5;
+    let table = Array1::from(vec![0, 3]);
+    let functions = vec![Func::new(
+        vec![0, 1],
+        FuncType::lookup_resize(table.clone(), 2, 4).unwrap(),
+    )];
+    let prior_x = random_distri(n, 2);
+    let prior_y = random_distri(n, 4);
+    let make_variables = || {
+        vec![
+            profile_para(vec![0], prior_y.clone()),
+            profile_para(vec![1], prior_x.clone()),
+        ]
+    };
+    assert_eq!(
+        bp::run_bp_binary(&functions, &mut make_variables(), 1, 2, n, false),
+        Err(bp::BPError::UnsupportedFunction { function: 0 })
+    );
+    let mut variables = make_variables();
+    bp::run_bp(&functions, &mut variables, 1, 2, 2, n, false).unwrap();
+
+    let mut expected_x = Array2::zeros((n, 2));
+    let mut expected_y = Array2::zeros((n, 4));
+    for k in 0..n {
+        for x in 0..2 {
+            let y = table[x] as usize;
+            let p = prior_x[[k, x]] * prior_y[[k, y]];
+            expected_x[[k, x]] += p;
+            expected_y[[k, y]] += p;
+        }
+    }
+    for (var, mut expected) in variables.iter().zip(vec![expected_y, expected_x]) {
+        let sum = expected
+            .sum_axis(ndarray::Axis(1))
+            .insert_axis(ndarray::Axis(1));
+        expected /= &sum;
+        assert!(distri_current(var).abs_diff_eq(&expected, 1e-9));
+    }
+}
+
+#[test]
+fn freeze_single() {
+    // y_i = k ^ p_i, with a single key k and leakage on the y_i.
+    let (n, nc) = (8, 16);
+    let p: Array1<u32> = (0..n as u32).map(|i| (i * 5 + 1) % nc as u32).collect();
+    let functions = vec![Func::new(vec![1, 0], FuncType::XORCST(p.clone()))];
+    let mut variables = vec![
+        Var {
+            neighboors: vec![0],
+            vartype: VarType::NotProfileSingle {
+                distri_current: Array2::ones((1, nc)),
+            },
+        },
+        profile_para(vec![1], random_distri(n, nc)),
+    ];
+    bp::run_bp(&functions, &mut variables, 2, 2, nc, n, false).unwrap();
+    let key = distri_current(&variables[0]).clone();
+
+    bp::freeze_single(&mut variables[0]);
+    match &variables[0].vartype {
+        VarType::ProfileSingle { distri_orig, .. } => assert_eq!(distri_orig, &key),
+        _ => panic!("frozen variable must be profiled"),
+    }
+
+    // new stage without leakage: the key does not drift and the para nodes follow it
+    variables[1] = not_profile_para(vec![1], n, nc);
+    bp::run_bp(&functions, &mut variables, 3, 2, nc, n, false).unwrap();
+    assert!(distri_current(&variables[0]).abs_diff_eq(&key, 1e-12));
+    let y = distri_current(&variables[1]);
+    for i in 0..n {
+        for x in 0..nc {
+            let k = x ^ p[i] as usize;
+            assert!((y[[i, x]] - key[[0, k]]).abs() < 1e-12);
+        }
+    }
+}
+
+#[test]
+fn factor_beliefs() {
+    let (n, nc) = (3, 8);
+    let table: Array1<u32> = (0..nc as u32).map(|x| (x * 3 + 1) % nc as u32).collect();
+    let functions = vec![Func::new(vec![0, 1], FuncType::LOOKUP(table.clone()))];
+    let mut variables = vec![
+        profile_para(vec![0], random_distri(n, nc)),
+        profile_para(vec![1], random_distri(n, nc)),
+    ];
+    let mut edges = bp::init_edges(&variables, 2, nc, n);
+    bp::run_bp_edges(&functions, &mut variables, &mut edges, 2, false).unwrap();
+
+    let beliefs = bp::factor_beliefs(&functions, &edges, 2).unwrap();
+    assert_eq!(beliefs.len(), 1);
+    let belief = &beliefs[0];
+    assert_eq!(belief.shape(), &[n, nc, nc]);
+    for k in 0..n {
+        let mut joint = Array2::<f64>::zeros((nc, nc));
+        for x in 0..nc {
+            let y = table[x] as usize;
+            joint[[y, x]] = edges[0][[k, y]] * edges[1][[k, x]];
+        }
+        joint /= joint.sum();
+        for y in 0..nc {
+            for x in 0..nc {
+                assert!((belief[[k, y, x]] - joint[[y, x]]).abs() < 1e-12);
+            }
+        }
+        // on a tree, the marginals of the belief are the variable marginals
+        let p_y = joint.sum_axis(ndarray::Axis(1));
+        let p_x = joint.sum_axis(ndarray::Axis(0));
+        assert!(p_y.abs_diff_eq(&distri_current(&variables[0]).row(k), 1e-9));
+        assert!(p_x.abs_diff_eq(&distri_current(&variables[1]).row(k), 1e-9));
+    }
+
+    assert_eq!(
+        bp::factor_beliefs(&functions, &edges, 1).unwrap_err(),
+        bp::BPError::DegreeTooLarge {
+            function: 0,
+            degree: 2
+        }
+    );
+}
+
+#[test]
+fn adds_many_inputs() {
+    let (n, nc, k) = (3, 17, 6);
+    let msgs: Vec<Array2<f64>> = (0..k)
+        .map(|_| {
+            // peaky distributions
+            let mut distri = random_distri(n, nc).mapv(|x| x.powi(8));
+            distri.outer_iter_mut().for_each(|mut d| d /= d.sum());
+            distri
+        })
+        .collect();
+    let mut res = msgs.clone();
+    bp::adds(&mut res.iter_mut().collect::<Vec<_>>());
+
+    // naive reference: the output is the sum of the inputs, and an input is the output minus the
+    // other inputs
+    for j in 0..k {
+        for run in 0..n {
+            let mut expected = Array1::zeros(nc);
+            expected[0] = 1.0;
+            for (i, msg) in msgs.iter().enumerate().filter(|(i, _)| *i != j) {
+                let negate = j != 0 && i != 0;
+                let mut conv = Array1::zeros(nc);
+                for a in 0..nc {
+                    for b in 0..nc {
+                        let b_signed = if negate { (nc - b) % nc } else { b };
+                        conv[(a + b_signed) % nc] += expected[a] * msg[[run, b]];
+                    }
+                }
+                expected = conv;
+            }
+            expected /= expected.sum();
+            assert!(res[j].row(run).abs_diff_eq(&expected, 1e-12));
+        }
+    }
+}
+
+#[test]
+fn best_guess() {
+    let distri =
+        Array2::from_shape_vec((2, 4), vec![0.1, 0.2, 0.6, 0.1, 2.0, 1.0, 0.5, 0.5]).unwrap();
+    let variables = vec![
+        profile_para(vec![], distri),
+        Var {
+            neighboors: vec![],
+            vartype: VarType::NotProfileSingle {
+                distri_current: Array2::from_shape_vec((1, 4), vec![0.0, 0.0, 0.0, 1.0]).unwrap(),
+            },
+        },
+    ];
+    let guesses = bp::best_guess(&variables);
+    assert_eq!(guesses.len(), 2);
+    assert_eq!(guesses[0].0, Array1::from(vec![2, 0]));
+    assert!(guesses[0]
+        .1
+        .abs_diff_eq(&Array1::from(vec![0.6, 0.5]), 1e-12));
+    assert_eq!(guesses[1].0, Array1::from(vec![3]));
+    assert!(guesses[1].1.abs_diff_eq(&Array1::from(vec![1.0]), 1e-12));
+}
+
+#[test]
+fn lookup_map() {
+    let (n, nc) = (4, 256);
+    let map = bp::AffineMap { a: 5, b: 7, nc };
+    let table: Array1<u32> = (0..nc as u32).map(|x| (5 * x + 7) % nc as u32).collect();
+    assert!(bp::is_permutation(&table, nc));
+    assert_eq!(bp::LookupMap::nc_in(&map), nc);
+
+    let prior_y = random_distri(n, nc);
+    let prior_x = random_distri(n, nc);
+    let run = |functype: FuncType| {
+        let functions = vec![Func::new(vec![0, 1], functype)];
+        let mut variables = vec![
+            profile_para(vec![0], prior_y.clone()),
+            profile_para(vec![1], prior_x.clone()),
+        ];
+        bp::run_bp(&functions, &mut variables, 2, 2, nc, n, false).unwrap();
+        variables
+    };
+    let dense = run(FuncType::LOOKUP(table));
+    let affine = run(FuncType::LOOKUPMAP(Arc::new(map)));
+    for (d, a) in dense.iter().zip(affine.iter()) {
+        assert_eq!(distri_current(d), distri_current(a));
+    }
+}
+
+#[test]
+fn single_copy_kernels() {
+    let n = 3;
+    let check = |nc: usize,
+                 k: usize,
+                 in_graph: &dyn Fn(&mut [&mut Array2<f64>]),
+                 single: &dyn Fn(&mut [Array1<f64>])| {
+        let mut msgs: Vec<Array2<f64>> = (0..k).map(|_| random_distri(n, nc)).collect();
+        let mut copies: Vec<Vec<Array1<f64>>> = (0..n)
+            .map(|run| msgs.iter().map(|msg| msg.row(run).to_owned()).collect())
+            .collect();
+        in_graph(&mut msgs.iter_mut().collect::<Vec<_>>());
+        for (run, copy) in copies.iter_mut().enumerate() {
+            single(copy);
+            for (msg, res) in msgs.iter().zip(copy.iter()) {
+                assert_eq!(msg.row(run), res);
+            }
+        }
+    };
+    check(16, 4, &bp::xors, &bp::xor_msgs);
+    check(20, 4, &bp::adds, &bp::add_msgs);
+    check(17, 3, &bp::mults, &bp::mul_msgs);
+    check(16, 3, &bp::mults_pow2, &bp::mul_msgs);
+    check(
+        12,
+        3,
+        &|msgs| bp::naive(msgs, &FuncType::MUL),
+        &bp::mul_msgs,
+    );
+
+    // The Walsh-Hadamard transform is its own inverse, up to a factor nc.
+    let a = random_distri(1, 16).row(0).to_owned();
+    let mut b = a.clone();
+    bp::walsh_hadamard(b.as_slice_mut().unwrap(), false);
+    bp::walsh_hadamard(b.as_slice_mut().unwrap(), false);
+    assert!((b / 16.0).abs_diff_eq(&a, 1e-12));
+}
+
+#[test]
+fn prefer_naive() {
+    let n = 3;
+    for nc in [2, 4, 8, 16, 32].iter().cloned() {
+        for functype in [FuncType::XOR, FuncType::ADD].iter() {
+            let msgs: Vec<Array2<f64>> = (0..3).map(|_| random_distri(n, nc)).collect();
+            let mut res_naive = msgs.clone();
+            bp::naive(&mut res_naive.iter_mut().collect::<Vec<_>>(), functype);
+            let mut res_transform = msgs.clone();
+            let mut edges = res_transform.iter_mut().collect::<Vec<_>>();
+            match functype {
+                FuncType::XOR => bp::xors(&mut edges),
+                _ => bp::adds(&mut edges),
+            }
+            for (a, b) in res_naive.iter_mut().zip(res_transform.iter()) {
+                a.outer_iter_mut().for_each(|mut d| d /= d.sum());
+                assert!(a.abs_diff_eq(b, 1e-9));
+            }
+        }
+        assert_eq!(
+            bp::prefer_naive(&FuncType::XOR, 3, nc),
+            nc <= bp::XOR_NAIVE_MAX_NC
+        );
+        assert_eq!(
+            bp::prefer_naive(&FuncType::ADD, 3, nc),
+            nc <= bp::ADD_NAIVE_MAX_NC
+        );
+        // the naive algorithm only supports two inputs
+        assert!(!bp::prefer_naive(&FuncType::XOR, 4, nc));
+        assert!(!bp::prefer_naive(&FuncType::ADD, 4, nc));
+    }
+}
+
+#[test]
+fn best_guess_ties() {
+    let nc = 8;
+    let variables = vec![
+        not_profile_para(vec![], 2, nc),
+        profile_para(
+            vec![],
+            Array2::from_shape_vec((1, 4), vec![0.1, 0.4, 0.1, 0.4]).unwrap(),
+        ),
+    ];
+    for _ in 0..3 {
+        let guesses = bp::best_guess(&variables);
+        // lowest index wins
+        assert_eq!(guesses[0].0, Array1::from(vec![0, 0]));
+        assert!(guesses[0]
+            .1
+            .abs_diff_eq(&Array1::from_elem(2, 1.0 / nc as f64), 1e-12));
+        assert_eq!(guesses[1].0, Array1::from(vec![1]));
+    }
+    let ties = bp::best_guess_ties(&variables);
+    assert_eq!(ties[0], vec![(0..nc).collect::<Vec<_>>(); 2]);
+    assert_eq!(ties[1], vec![vec![1, 3]]);
+}
+
+#[test]
+fn fuse() {
+    let (n, nc) = (4, 16);
+    let cst = FuncType::XORCST(Array1::from_elem(n, 5));
+    let table: Array1<u32> = (0..nc as u32).map(|x| (x * 7 + 2) % nc as u32).collect();
+    let lookup = FuncType::LOOKUP(table);
+    let prior_x = random_distri(n, nc);
+    let prior_y = random_distri(n, nc);
+
+    // x -> XORCST -> t -> LOOKUP -> y
+    let functions = vec![
+        Func::new(vec![1, 0], cst.clone()),
+        Func::new(vec![3, 2], lookup.clone()),
+    ];
+    let mut variables = vec![
+        profile_para(vec![0], prior_x.clone()),
+        not_profile_para(vec![1, 2], n, nc),
+        profile_para(vec![3], prior_y.clone()),
+    ];
+    bp::run_bp(&functions, &mut variables, 3, 4, nc, n, false).unwrap();
+
+    // x -> fused -> y
+    let fused = bp::fuse(&[cst, lookup], nc).unwrap();
+    let functions_fused = vec![Func::new(vec![1, 0], fused)];
+    let mut variables_fused = vec![
+        profile_para(vec![0], prior_x),
+        profile_para(vec![1], prior_y),
+    ];
+    bp::run_bp(&functions_fused, &mut variables_fused, 1, 2, nc, n, false).unwrap();
+
+    assert!(distri_current(&variables[0]).abs_diff_eq(distri_current(&variables_fused[0]), 1e-12));
+    assert!(distri_current(&variables[2]).abs_diff_eq(distri_current(&variables_fused[1]), 1e-12));
+
+    // only deterministic chains can be fused
+    assert_eq!(
+        bp::fuse(&[FuncType::XOR], nc).err(),
+        Some(bp::BPError::UnsupportedFunction { function: 0 })
+    );
+    let values = Array1::from_iter(0..n as u32);
+    assert!(bp::fuse(&[FuncType::ADDCST(values)], nc).is_err());
+}
+
+#[test]
+fn adaptive_damping() {
+    // loopy graph of 6 XOR nodes between 6 variables with peaky priors
+    let functions: Vec<Func> = (0..6)
+        .map(|i| Func::new(vec![3 * i, 3 * i + 1, 3 * i + 2], FuncType::XOR))
+        .collect();
+    let priors = vec![
+        (vec![3, 6, 14, 15], [0.347, 0.573, 0.044, 0.036]),
+        (vec![0, 5, 10, 12, 17], [0.031, 0.914, 0.003, 0.052]),
+        (vec![7], [0.142, 0.606, 0.014, 0.238]),
+        (vec![2, 8, 11], [0.962, 0.013, 0.013, 0.012]),
+        (vec![1, 4, 9, 13], [0.524, 0.003, 0.001, 0.471]),
+        (vec![16], [0.039, 0.001, 0.262, 0.698]),
+    ];
+    let iterations = |damping: bp::Damping| {
+        let mut variables: Vec<Var> = priors
+            .iter()
+            .map(|(neighboors, prior)| {
+                profile_para(
+                    neighboors.clone(),
+                    Array2::from_shape_vec((1, 4), prior.to_vec()).unwrap(),
+                )
+            })
+            .collect();
+        let mut edges = bp::init_edges(&variables, 18, 4, 1);
+        let residuals =
+            bp::run_bp_damped(&functions, &mut variables, &mut edges, 300, damping, 1e-8).unwrap();
+        if *residuals.last().unwrap() < 1e-8 {
+            residuals.len()
+        } else {
+            usize::MAX
+        }
+    };
+    let best_fixed = (0..20)
+        .map(|i| iterations(bp::Damping::Fixed(i as f64 / 20.0)))
+        .min()
+        .unwrap();
+    let adaptive = iterations(bp::Damping::Adaptive { k: 0.5, max: 0.3 });
+    assert!(adaptive < best_fixed);
+}
+
+#[test]
+fn describe() {
+    let (functions, variables) = lookup_chain(2, 3, 16);
+    assert_eq!(
+        functions[0].describe(16),
+        bp::FuncInfo {
+            op: "LOOKUP",
+            arity: 2,
+            nc: 16
+        }
+    );
+    let xor = Func::new(vec![0, 1, 2], FuncType::XOR);
+    assert_eq!(xor.describe(4).op, "XOR");
+    assert_eq!(xor.describe(4).arity, 3);
+    assert_eq!(
+        variables[1].describe(),
+        bp::VarInfo {
+            para: true,
+            profiled: false,
+            degree: 2,
+            nc: 16
+        }
+    );
+    assert!(variables[0].describe().profiled);
+}
+
+#[test]
+fn combine_single_marginals() {
+    let nc = 16;
+    let secret = 11;
+    // each run only knows some bits of the secret
+    let masks = [0b0001, 0b0110, 0b1000];
+    let runs: Vec<Array2<f64>> = masks
+        .iter()
+        .map(|m| {
+            let mut distri =
+                Array2::from_shape_fn(
+                    (1, nc),
+                    |(_, v)| {
+                        if v & m == secret & m {
+                            1.0
+                        } else {
+                            0.0
+                        }
+                    },
+                );
+            distri /= distri.sum();
+            distri
+        })
+        .collect();
+    // no single run identifies the secret
+    runs.iter()
+        .for_each(|run| assert!(run.iter().filter(|p| **p > 0.5 / nc as f64).count() > 1));
+    let combined = bp::combine_single_marginals(&runs);
+    assert!((combined[[0, secret]] - 1.0).abs() < 1e-12);
+    assert!(combined.iter().all(|p| *p > 0.0 && p.is_finite()));
+    assert!((combined.sum() - 1.0).abs() < 1e-12);
+
+    // product of evidence, independent of the order of the runs
+    let distri = random_distri(5, nc);
+    let runs = vec![random_distri(5, nc), distri.clone(), random_distri(5, nc)];
+    let mut reference = &(&runs[0] * &runs[1]) * &runs[2];
+    reference.outer_iter_mut().for_each(|mut d| d /= d.sum());
+    assert!(bp::combine_single_marginals(&runs).abs_diff_eq(&reference, 1e-12));
+    let reversed: Vec<_> = runs.iter().rev().cloned().collect();
+    assert!(bp::combine_single_marginals(&reversed).abs_diff_eq(&reference, 1e-12));
+    assert!(bp::combine_single_marginals(&[distri.clone()]).abs_diff_eq(&distri, 1e-12));
+}
+
+#[test]
+fn nand_nor() {
+    let (n, nc) = (3, 16);
+    let mask = nc - 1;
+    let gates: [(FuncType, fn(usize, usize) -> usize); 2] = [
+        (FuncType::NAND, |a, b| !(a & b) & 15),
+        (FuncType::NOR, |a, b| !(a | b) & 15),
+    ];
+    for (functype, gate) in gates.iter() {
+        // Messages against a brute-force factor.
+        let msgs: Vec<Array2<f64>> = (0..3).map(|_| random_distri(n, nc)).collect();
+        let mut edges = msgs.clone();
+        {
+            let mut edges: Vec<&mut Array2<f64>> = edges.iter_mut().collect();
+            functype.update(&mut edges);
+        }
+        let mut reference: Vec<Array2<f64>> = (0..3).map(|_| Array2::zeros((n, nc))).collect();
+        for k in 0..n {
+            for a in 0..nc {
+                for b in 0..nc {
+                    let o = gate(a, b);
+                    reference[0][[k, o]] += msgs[1][[k, a]] * msgs[2][[k, b]];
+                    reference[1][[k, a]] += msgs[0][[k, o]] * msgs[2][[k, b]];
+                    reference[2][[k, b]] += msgs[0][[k, o]] * msgs[1][[k, a]];
+                }
+            }
+        }
+        for (e, r) in edges.iter().zip(reference.iter()) {
+            assert!(e.abs_diff_eq(r, 1e-12));
+        }
+
+        // Against the composition of AND/OR and NOT (XORCST with nc-1), with a|b = !(!a & !b).
+        let distri: Vec<Array2<f64>> = (0..3).map(|_| random_distri(n, nc)).collect();
+        let not = || FuncType::XORCST(Array1::from_elem(n, mask as u32));
+        let mut variables = vec![
+            profile_para(vec![0], distri[0].clone()),
+            profile_para(vec![1], distri[1].clone()),
+            profile_para(vec![2], distri[2].clone()),
+        ];
+        let functions = vec![Func::new(vec![0, 1, 2], functype.clone())];
+        bp::run_bp(&functions, &mut variables, 2, 3, nc, n, false).unwrap();
+
+        let (functions_ref, mut variables_ref, edge) = match functype {
+            // o = !t, t = a & b
+            FuncType::NAND => (
+                vec![
+                    Func::new(vec![0, 1, 2], FuncType::AND),
+                    Func::new(vec![3, 4], not()),
+                ],
+                vec![
+                    profile_para(vec![3], distri[0].clone()),
+                    profile_para(vec![1], distri[1].clone()),
+                    profile_para(vec![2], distri[2].clone()),
+                    not_profile_para(vec![0, 4], n, nc),
+                ],
+                5,
+            ),
+            // o = !a & !b
+            _ => (
+                vec![
+                    Func::new(vec![0, 1], not()),
+                    Func::new(vec![2, 3], not()),
+                    Func::new(vec![4, 5, 6], FuncType::AND),
+                ],
+                vec![
+                    profile_para(vec![4], distri[0].clone()),
+                    profile_para(vec![1], distri[1].clone()),
+                    profile_para(vec![3], distri[2].clone()),
+                    not_profile_para(vec![0, 5], n, nc),
+                    not_profile_para(vec![2, 6], n, nc),
+                ],
+                7,
+            ),
+        };
+        bp::run_bp(&functions_ref, &mut variables_ref, 4, edge, nc, n, false).unwrap();
+        for i in 0..3 {
+            assert!(
+                distri_current(&variables[i]).abs_diff_eq(distri_current(&variables_ref[i]), 1e-9)
+            );
+        }
+    }
+
+    // The field size must be a power of two.
+    for (functype, op) in [(FuncType::NAND, "NAND"), (FuncType::NOR, "NOR")].iter() {
+        let functions = vec![Func::new(vec![0, 1, 2], functype.clone())];
+        let mut variables: Vec<Var> = (0..3).map(|i| not_profile_para(vec![i], 1, 12)).collect();
+        let err = Err(bp::BPError::InvalidGraph(format!(
+            "{} function 0 has field size 12 on edge 0, which is not a power of two.",
+            op
+        )));
+        assert_eq!(bp::validate_graph(&functions, &variables, 3, 12), err);
+        assert_eq!(
+            bp::run_bp(&functions, &mut variables, 1, 3, 12, 1, false),
+            err
+        );
+    }
+}
+
+#[test]
+fn debug_scales() {
+    let (n, nc) = (2, 8);
+    // unnormalized priors, such that the messages are not normalized either
+    let px = Array2::<f64>::random((n, nc), Uniform::new(0.1, 2.0));
+    let py = Array2::<f64>::random((n, nc), Uniform::new(0.1, 2.0));
+    let graph = || {
+        let functions = vec![Func::new(vec![0, 1, 2], FuncType::XOR)];
+        let variables = vec![
+            not_profile_para(vec![0], n, nc),
+            profile_para(vec![1], px.clone()),
+            profile_para(vec![2], py.clone()),
+        ];
+        (functions, variables)
+    };
+
+    let (functions, mut variables) = graph();
+    let mut edges = bp::init_edges(&variables, 3, nc, n);
+    let scales = bp::run_bp_debug(&functions, &mut variables, &mut edges, 1, false).unwrap();
+    assert_eq!(scales.len(), 1);
+    assert_eq!(scales[0].len(), 3);
+    // z only has one neighbor: its distribution is the normalized message.
+    let mut raw = Array2::<f64>::zeros((n, nc));
+    for k in 0..n {
+        for x in 0..nc {
+            for y in 0..nc {
+                raw[[k, x ^ y]] += px[[k, x]] * py[[k, y]];
+            }
+        }
+    }
+    let scale = scales[0][0].view().insert_axis(ndarray::Axis(1));
+    assert!((&scale * distri_current(&variables[0])).abs_diff_eq(&raw, 1e-9));
+    // the message to x is sum(py) for all values of x (z is not profiled)
+    assert!(scales[0][1].abs_diff_eq(&(py.sum_axis(ndarray::Axis(1)) * nc as f64), 1e-9));
+
+    // The normalization of the messages does not change the result.
+    let (functions, mut variables) = graph();
+    let mut edges = bp::init_edges(&variables, 3, nc, n);
+    bp::run_bp_debug(&functions, &mut variables, &mut edges, 3, false).unwrap();
+    let (functions_ref, mut variables_ref) = graph();
+    let mut edges_ref = bp::init_edges(&variables_ref, 3, nc, n);
+    bp::run_bp_edges(&functions_ref, &mut variables_ref, &mut edges_ref, 3, false).unwrap();
+    for (v, v_ref) in variables.iter().zip(variables_ref.iter()) {
+        assert!(distri_current(v).abs_diff_eq(distri_current(v_ref), 1e-12));
+    }
+}
+
+#[test]
+fn relative_convergence() {
+    // loop x2 = x0 ^ x1, x3 = x1 ^ x2, x1 = x3 ^ x2, with peaky priors
+    let functions = vec![
+        Func::new(vec![0, 1, 2], FuncType::XOR),
+        Func::new(vec![3, 4, 5], FuncType::XOR),
+        Func::new(vec![6, 7, 8], FuncType::XOR),
+    ];
+    let priors = vec![
+        (vec![1], [1e-9, 1.0, 1e-6, 1e-8]),
+        (vec![2, 4, 6], [0.3, 1e-7, 0.7, 1e-9]),
+        (vec![0, 5, 8], [1e-8, 1e-9, 0.4, 0.6]),
+        (vec![3, 7], [1e-9, 0.5, 1e-6, 0.5]),
+    ];
+    let iterations = |metric: bp::ConvergenceMetric| {
+        let mut variables: Vec<Var> = priors
+            .iter()
+            .map(|(neighboors, prior)| {
+                profile_para(
+                    neighboors.clone(),
+                    Array2::from_shape_vec((1, 4), prior.to_vec()).unwrap(),
+                )
+            })
+            .collect();
+        let mut edges = bp::init_edges(&variables, 9, 4, 1);
+        let residuals = bp::run_bp_damped_metric(
+            &functions,
+            &mut variables,
+            &mut edges,
+            100,
+            bp::Damping::Fixed(0.5),
+            1e-6,
+            metric,
+        )
+        .unwrap();
+        assert!(*residuals.last().unwrap() < 1e-6);
+        residuals.len()
+    };
+    // The probabilities of the values that are not consistent with the loop keep decreasing by a
+    // constant factor long after the absolute change is negligible.
+    let absolute = iterations(bp::ConvergenceMetric::Absolute);
+    let relative = iterations(bp::ConvergenceMetric::Relative { eps: 1e-30 });
+    assert!(relative > 2 * absolute);
+}
+
+#[test]
+fn information_gain() {
+    let (n, nc) = (2, 16);
+    let mut prior = Array2::<f64>::from_elem((n, nc), 1e-6);
+    prior[[0, 3]] = 1.0;
+    prior[[1, 7]] = 1.0;
+    let table: Array1<u32> = (0..nc as u32).map(|x| (x + 5) % nc as u32).collect();
+    let functions = vec![Func::new(vec![0, 1], FuncType::LOOKUP(table))];
+    let mut variables = vec![
+        // y = table[x], with strong evidence on x
+        not_profile_para(vec![0], n, nc),
+        profile_para(vec![1], prior),
+        // isolated nodes
+        not_profile_para(vec![], n, nc),
+        profile_para(vec![], random_distri(n, nc)),
+    ];
+    bp::run_bp(&functions, &mut variables, 2, 2, nc, n, false).unwrap();
+    let gain = bp::information_gain(&variables);
+    assert_eq!(gain.len(), 4);
+    // y went from uniform (4 bits) to almost certain
+    assert!(gain[0].iter().all(|g| *g > 3.99 && *g <= 4.0));
+    // no new information on x, since the lookup is a bijection and y has no evidence
+    assert!(gain[1].iter().all(|g| g.abs() < 1e-9));
+    assert!(gain[2].iter().all(|g| g.abs() < 1e-9));
+    assert!(gain[3].iter().all(|g| g.abs() < 1e-9));
+}
+
+#[test]
+fn mixed_precision() {
+    // z = x ^ y, w = z + v, u = table[w], with FFT-based XOR and ADD
+    let (n, nc) = (3, 256);
+    let table: Array1<u32> = (0..nc as u32).map(|x| (7 * x + 3) % nc as u32).collect();
+    let priors: Vec<Array2<f64>> = (0..4).map(|_| random_distri(n, nc)).collect();
+    let graph = || {
+        let functions = vec![
+            Func::new(vec![0, 1, 2], FuncType::XOR),
+            Func::new(vec![3, 4, 5], FuncType::ADD),
+            Func::new(vec![6, 7], FuncType::LOOKUP(table.clone())),
+        ];
+        let variables = vec![
+            profile_para(vec![1], priors[0].clone()),
+            profile_para(vec![2], priors[1].clone()),
+            not_profile_para(vec![0, 4], n, nc),
+            profile_para(vec![5], priors[2].clone()),
+            not_profile_para(vec![3, 7], n, nc),
+            profile_para(vec![6], priors[3].clone()),
+        ];
+        (functions, variables)
+    };
+    let (functions, mut variables) = graph();
+    bp::run_bp(&functions, &mut variables, 4, 8, nc, n, false).unwrap();
+    let (functions_mixed, mut variables_mixed) = graph();
+    bp::run_bp_mixed(
+        &functions_mixed,
+        &mut variables_mixed,
+        4,
+        8,
+        nc,
+        n,
+        false,
+        false,
+    )
+    .unwrap();
+    // The messages are rounded to single precision (relative error about 6e-8) at each step.
+    let error = variables
+        .iter()
+        .zip(variables_mixed.iter())
+        .map(|(v, v_mixed)| {
+            let (d, d_mixed) = (distri_current(v), distri_current(v_mixed));
+            (d - d_mixed)
+                .iter()
+                .zip(d.iter())
+                .fold(0.0, |acc: f64, (e, x)| acc.max(e.abs() / x))
+        })
+        .fold(0.0, f64::max);
+    assert!(error > 0.0);
+    assert!(error < 1e-6, "{}", error);
+}
+
+#[test]
+fn cycles() {
+    let (functions, variables) = lookup_chain(4, 2, 16);
+    assert!(bp::is_acyclic(&functions, &variables, 8));
+    assert!(bp::find_cycles(&functions, &variables, 8).is_empty());
+
+    // loop x2 = x0 ^ x1, x3 = x1 ^ x2, x1 = x3 ^ x2
+    let functions = vec![
+        Func::new(vec![0, 1, 2], FuncType::XOR),
+        Func::new(vec![3, 4, 5], FuncType::XOR),
+        Func::new(vec![6, 7, 8], FuncType::XOR),
+    ];
+    let variables = vec![
+        not_profile_para(vec![1], 1, 4),
+        not_profile_para(vec![2, 4, 6], 1, 4),
+        not_profile_para(vec![0, 5, 8], 1, 4),
+        not_profile_para(vec![3, 7], 1, 4),
+    ];
+    assert!(!bp::is_acyclic(&functions, &variables, 9));
+    let cycles = bp::find_cycles(&functions, &variables, 9);
+    // 9 edges, 7 nodes, 1 connected component
+    assert_eq!(cycles.len(), 3);
+    let var_of = |e: usize| variables.iter().position(|v| v.neighboors.contains(&e));
+    let func_of = |e: usize| functions.iter().position(|f| f.neighboors.contains(&e));
+    for cycle in cycles.iter() {
+        assert_eq!(cycle.len() % 2, 0);
+        // consecutive edges alternately share a variable node and a function node
+        let shared: Vec<bool> = (0..cycle.len())
+            .map(|i| {
+                let (e0, e1) = (cycle[i], cycle[(i + 1) % cycle.len()]);
+                assert!(var_of(e0) == var_of(e1) || func_of(e0) == func_of(e1));
+                var_of(e0) == var_of(e1)
+            })
+            .collect();
+        assert!(shared.windows(2).all(|w| w[0] != w[1]));
+    }
+}
+
+#[test]
+fn extend_graph() {
+    let (n, nc, len) = (2, 16, 3);
+    let (mut functions, mut variables) = lookup_chain(len, n, nc);
+    let mut edges = bp::init_edges(&variables, 2 * len, nc, n);
+    bp::run_bp_edges(&functions, &mut variables, &mut edges, len + 1, false).unwrap();
+    let before: Vec<Array2<f64>> = variables
+        .iter()
+        .map(|v| distri_current(v).clone())
+        .collect();
+    let table: Array1<u32> = (0..nc as u32).rev().collect();
+
+    // A new variable without evidence does not bring any information.
+    bp::extend_graph(
+        &mut functions,
+        &mut variables,
+        &mut edges,
+        vec![Func::new(vec![6, 7], FuncType::LOOKUP(table.clone()))],
+        vec![not_profile_para(vec![6], n, nc)],
+        &[(1, 7)],
+        8,
+        nc,
+        n,
+    )
+    .unwrap();
+    bp::run_bp_edges(&functions, &mut variables, &mut edges, 2, false).unwrap();
+    for (v, b) in variables.iter().zip(before.iter()) {
+        assert!(distri_current(v).abs_diff_eq(b, 1e-12));
+    }
+
+    // New evidence on v2 refines the marginals, as a full run on the extended graph.
+    let prior = random_distri(n, nc);
+    bp::extend_graph(
+        &mut functions,
+        &mut variables,
+        &mut edges,
+        vec![Func::new(vec![8, 9], FuncType::LOOKUP(table))],
+        vec![profile_para(vec![8], prior)],
+        &[(2, 9)],
+        10,
+        nc,
+        n,
+    )
+    .unwrap();
+    bp::run_bp_edges(&functions, &mut variables, &mut edges, len + 1, false).unwrap();
+    let mut variables_ref: Vec<Var> = variables
+        .iter()
+        .map(|v| Var {
+            neighboors: v.neighboors.clone(),
+            vartype: clone_vartype(&v.vartype),
+        })
+        .collect();
+    bp::run_bp(&functions, &mut variables_ref, len + 2, 10, nc, n, false).unwrap();
+    for (i, (v, v_ref)) in variables.iter().zip(variables_ref.iter()).enumerate() {
+        assert!(distri_current(v).abs_diff_eq(distri_current(v_ref), 1e-9));
+        if i < before.len() {
+            assert!(!distri_current(v).abs_diff_eq(&before[i], 1e-6));
+        }
+    }
+
+    assert_eq!(
+        bp::extend_graph(
+            &mut functions,
+            &mut variables,
+            &mut edges,
+            vec![],
+            vec![],
+            &[(0, 3)],
+            11,
+            nc,
+            n
+        ),
+        Err(bp::BPError::InvalidGraph(
+            "edge 3 is not a new edge.".to_owned()
+        ))
+    );
+}
+
+#[test]
+fn find_first_gen_fast() {
+    for p in [7919, 32749, 40009, 65521].iter() {
+        let prime_list = bp::prime_factors(p - 1);
+        for seed in 0..5 {
+            let gen = bp::find_first_gen_fast(*p, seed);
+            assert!(gen > 0 && gen < *p);
+            assert!(bp::test_gen(p - 1, &prime_list, gen));
+        }
+    }
+    // 40009 - 1 = 2^3 * 3 * 1667, where 1667 is missed by prime_factors
+    let gen = bp::find_first_gen_fast(40009, 0);
+    assert_ne!((0..24).fold(1, |x, _| x * gen as u64 % 40009), 1);
+    for p in [5, 11, 257].iter() {
+        assert!(bp::find_gen(*p).contains(&bp::find_first_gen_fast(*p, 0)));
+    }
+}
+
+#[test]
+fn log_marginals() {
+    let distri =
+        Array2::from_shape_vec((2, 4), vec![0.1, 0.2, 0.6, 0.1, 2.0, 1.0, 1e-30, 0.0]).unwrap();
+    let variables = vec![profile_para(vec![], distri), not_profile_para(vec![], 2, 4)];
+    let linear = bp::get_marginals(&variables, bp::ProbaScale::Linear);
+    let log = bp::get_marginals(&variables, bp::ProbaScale::Log);
+    assert!(linear[0].row(1).abs_diff_eq(
+        &Array1::from(vec![2.0 / 3.0, 1.0 / 3.0, 1e-30 / 3.0, 0.0]),
+        1e-15
+    ));
+    assert!(linear[1].abs_diff_eq(&Array2::from_elem((2, 4), 0.25), 1e-15));
+    assert!(log[1].abs_diff_eq(&linear[1].mapv(f64::ln), 1e-12));
+    // entries below the floor are clipped
+    let floor = 1e-20f64.ln();
+    assert!(log[0]
+        .row(0)
+        .abs_diff_eq(&linear[0].row(0).mapv(f64::ln), 1e-12));
+    assert!(log[0].row(1).abs_diff_eq(
+        &Array1::from(vec![(2.0f64 / 3.0).ln(), (1.0f64 / 3.0).ln(), floor, floor]),
+        1e-12
+    ));
+
+    let guesses = bp::best_guess(&variables);
+    let log_guesses = bp::best_guess_scaled(&variables, bp::ProbaScale::Log);
+    for (g, lg) in guesses.iter().zip(log_guesses.iter()) {
+        assert_eq!(g.0, lg.0);
+        assert!(lg.1.abs_diff_eq(&g.1.mapv(f64::ln), 1e-12));
+    }
+}
+
+#[test]
+fn adds_mixed_sizes() {
+    // output and second input on 8 bits, first input on 4 bits
+    let n = 2;
+    let sizes = [256, 16, 256];
+    let msgs: Vec<Array2<f64>> = sizes.iter().map(|nc| random_distri(n, *nc)).collect();
+    let mut res = msgs.clone();
+    bp::adds(&mut res.iter_mut().collect::<Vec<_>>());
+
+    for run in 0..n {
+        let (out, a, b) = (msgs[0].row(run), msgs[1].row(run), msgs[2].row(run));
+        let mut expected: Vec<Array1<f64>> = sizes.iter().map(|nc| Array1::zeros(*nc)).collect();
+        for x in 0..16 {
+            for y in 0..256 {
+                let z = (x + y) % 256;
+                expected[0][z] += a[x] * b[y];
+                expected[1][x] += out[z] * b[y];
+                expected[2][y] += out[z] * a[x];
+            }
+        }
+        for (res, mut expected) in res.iter().zip(expected.into_iter()) {
+            expected /= expected.sum();
+            assert!(res.row(run).abs_diff_eq(&expected, 1e-12));
+        }
+    }
+}
+
+#[test]
+fn adds_mixed_sizes_small() {
+    // c = a + b, with c and b on 3 bits and a on 2 bits (computed with the zero-padding rather
+    // than with `naive`), where a is the first or the second input
+    let n = 2;
+    let (prior_a, prior_b, prior_c) = (
+        random_distri(n, 4),
+        random_distri(n, 8),
+        random_distri(n, 8),
+    );
+    for a_first in [true, false].iter().cloned() {
+        let mut variables = vec![
+            profile_para(vec![1], prior_a.clone()),
+            profile_para(vec![2], prior_b.clone()),
+            profile_para(vec![0], prior_c.clone()),
+        ];
+        let inputs = if a_first {
+            vec![0, 1, 2]
+        } else {
+            vec![0, 2, 1]
+        };
+        let functions = vec![Func::new(inputs, FuncType::ADD)];
+        bp::validate_graph(&functions, &variables, 3, 8).unwrap();
+        bp::run_bp(&functions, &mut variables, 2, 3, 8, n, false).unwrap();
+        let marginals = bp::get_marginals(&variables, bp::ProbaScale::Linear);
+
+        let mut expected = vec![
+            Array2::zeros((n, 4)),
+            Array2::zeros((n, 8)),
+            Array2::zeros((n, 8)),
+        ];
+        for run in 0..n {
+            for x in 0..4 {
+                for y in 0..8 {
+                    let z = (x + y) % 8;
+                    let p = prior_a[[run, x]] * prior_b[[run, y]] * prior_c[[run, z]];
+                    expected[0][[run, x]] += p;
+                    expected[1][[run, y]] += p;
+                    expected[2][[run, z]] += p;
+                }
+            }
+        }
+        for (marginal, mut expected) in marginals.iter().zip(expected) {
+            expected.outer_iter_mut().for_each(|mut e| e /= e.sum());
+            assert!(marginal.abs_diff_eq(&expected, 1e-9));
+        }
+    }
+
+    // an input larger than the output is rejected
+    let mut variables = vec![
+        profile_para(vec![1], prior_b.clone()),
+        profile_para(vec![2], prior_b.clone()),
+        profile_para(vec![0], prior_a.clone()),
+    ];
+    let functions = vec![Func::new(vec![0, 1, 2], FuncType::ADD)];
+    assert!(matches!(
+        bp::run_bp(&functions, &mut variables, 2, 3, 8, n, false),
+        Err(bp::BPError::InvalidGraph(_))
+    ));
+}
+
+#[test]
+fn neg_sub() {
+    let (nc, n) = (256, 3);
+    let priors: Vec<Array2<f64>> = (0..3).map(|_| random_distri(n, nc)).collect();
+    let (prior_a, prior_b, prior_c) = (&priors[0], &priors[1], &priors[2]);
+
+    // c = a - b, as ADD(c; a, nb) with nb = NEG(b)
+    let mut variables = vec![
+        profile_para(vec![1], prior_a.clone()),
+        profile_para(vec![4], prior_b.clone()),
+        profile_para(vec![0], prior_c.clone()),
+        not_profile_para(vec![2, 3], n, nc),
+    ];
+    let functions = vec![
+        Func::new(vec![0, 1, 2], FuncType::ADD),
+        Func::new(vec![3, 4], FuncType::NEG),
+    ];
+    bp::run_bp(&functions, &mut variables, 3, 5, nc, n, false).unwrap();
+
+    // c = a - b, as a = c + b
+    let mut variables_sub = vec![
+        profile_para(vec![0], prior_a.clone()),
+        profile_para(vec![2], prior_b.clone()),
+        profile_para(vec![1], prior_c.clone()),
+    ];
+    let functions_sub = vec![Func::new(vec![0, 1, 2], FuncType::ADD)];
+    bp::run_bp(&functions_sub, &mut variables_sub, 1, 3, nc, n, false).unwrap();
+
+    for (var, var_sub) in variables.iter().zip(variables_sub.iter()) {
+        assert!(distri_current(var).abs_diff_eq(distri_current(var_sub), 1e-9));
+    }
+    // nb is the negation of b
+    let nb = distri_current(&variables[3]);
+    let b = distri_current(&variables[1]);
+    for x in 0..nc {
+        assert!(nb.column((nc - x) % nc).abs_diff_eq(&b.column(x), 1e-9));
+    }
+}
+
+#[test]
+fn contract_chains() {
+    let (n, nc) = (4, 16);
+    let table: Array1<u32> = (0..nc as u32).map(|x| (x * 7 + 2) % nc as u32).collect();
+    let priors: Vec<Array2<f64>> = (0..4).map(|_| random_distri(n, nc)).collect();
+
+    // x -> XORCST -> t0 -> NEG -> t1 -> LOOKUP -> y, and z = y + w
+    let graph = || {
+        let functions = vec![
+            Func::new(vec![0, 1], FuncType::NEG),
+            Func::new(vec![7, 8, 6], FuncType::ADD),
+            Func::new(vec![2, 3], FuncType::LOOKUP(table.clone())),
+            Func::new(vec![4, 5], FuncType::XORCST(Array1::from_elem(n, 5))),
+        ];
+        let variables = vec![
+            profile_para(vec![5], priors[0].clone()),
+            not_profile_para(vec![4, 1], n, nc),
+            not_profile_para(vec![0, 3], n, nc),
+            profile_para(vec![2, 6], priors[1].clone()),
+            profile_para(vec![7], priors[2].clone()),
+            profile_para(vec![8], priors[3].clone()),
+        ];
+        (functions, variables)
+    };
+    let (functions, mut variables) = graph();
+    bp::run_bp(&functions, &mut variables, 6, 9, nc, n, false).unwrap();
+
+    let (functions_orig, variables_orig) = graph();
+    let mut contracted = bp::contract_chains(functions_orig, variables_orig, 9, nc);
+    assert_eq!(contracted.functions.len(), 2);
+    assert_eq!(contracted.variables.len(), 4);
+    assert_eq!(contracted.edge, 5);
+    assert_eq!(
+        contracted.var_map,
+        vec![Some(0), None, None, Some(1), Some(2), Some(3)]
+    );
+    bp::run_bp(
+        &contracted.functions,
+        &mut contracted.variables,
+        6,
+        contracted.edge,
+        nc,
+        n,
+        false,
+    )
+    .unwrap();
+    for (v, new_v) in contracted.var_map.iter().enumerate() {
+        if let Some(new_v) = new_v {
+            assert!(distri_current(&variables[v])
+                .abs_diff_eq(distri_current(&contracted.variables[*new_v]), 1e-12));
+        }
+    }
+
+    // an intermediate variable with a prior is kept
+    let (functions, mut variables) = graph();
+    variables[2] = profile_para(vec![0, 3], priors[0].clone());
+    let contracted = bp::contract_chains(functions, variables, 9, nc);
+    assert_eq!(contracted.functions.len(), 3);
+    assert_eq!(contracted.var_map[1], None);
+    assert_eq!(contracted.var_map[2], Some(1));
+}
+
+#[test]
+fn infer_exact() {
+    let (nc, n) = (4, 3);
+    let table = Array1::from(vec![1, 3, 3, 0]);
+    let (pa, pb, pd, pe) = (
+        random_distri(n, nc),
+        random_distri(n, nc),
+        random_distri(n, nc),
+        random_distri(n, nc),
+    );
+    let pk = random_distri(1, nc);
+
+    // c = a ^ b, d = table[c], e = d + k, where k is a single variable
+    let functions = vec![
+        Func::new(vec![2, 0, 1], FuncType::XOR),
+        Func::new(vec![4, 3], FuncType::LOOKUP(table.clone())),
+        Func::new(vec![7, 5, 6], FuncType::ADD),
+    ];
+    let mut variables = vec![
+        profile_para(vec![0], pa.clone()),
+        profile_para(vec![1], pb.clone()),
+        not_profile_para(vec![2, 3], n, nc),
+        profile_para(vec![4, 5], pd.clone()),
+        Var {
+            neighboors: vec![6],
+            vartype: VarType::ProfileSingle {
+                distri_orig: pk.clone(),
+                distri_current: pk.clone(),
+            },
+        },
+        profile_para(vec![7], pe.clone()),
+    ];
+    bp::infer_exact(&functions, &mut variables, 8, nc, n).unwrap();
+
+    // brute-force enumeration: w[k][i][(a, b)] is the weight of (a, b) in copy i given k
+    let mut w = vec![vec![Array2::<f64>::zeros((nc, nc)); n]; nc];
+    for k in 0..nc {
+        for i in 0..n {
+            for a in 0..nc {
+                for b in 0..nc {
+                    let d = table[a ^ b] as usize;
+                    let e = (d + k) % nc;
+                    w[k][i][[a, b]] = pa[[i, a]] * pb[[i, b]] * pd[[i, d]] * pe[[i, e]];
+                }
+            }
+        }
+    }
+    let z: Vec<Vec<f64>> = w
+        .iter()
+        .map(|w| w.iter().map(|w| w.sum()).collect())
+        .collect();
+    let p_k: Vec<f64> = (0..nc)
+        .map(|k| pk[[0, k]] * z[k].iter().product::<f64>())
+        .collect();
+    let mut expected = vec![Array2::<f64>::zeros((n, nc)); 5];
+    for k in 0..nc {
+        for i in 0..n {
+            for a in 0..nc {
+                for b in 0..nc {
+                    let p = p_k[k] / z[k][i] * w[k][i][[a, b]];
+                    let c = a ^ b;
+                    let d = table[c] as usize;
+                    let values = [a, b, c, d, (d + k) % nc];
+                    for (expected, x) in expected.iter_mut().zip(values.iter()) {
+                        expected[[i, *x]] += p;
+                    }
+                }
+            }
+        }
+    }
+    expected.insert(4, Array2::from_shape_vec((1, nc), p_k).unwrap());
+    for (var, mut expected) in variables.iter().zip(expected.into_iter()) {
+        for mut row in expected.outer_iter_mut() {
+            row /= row.sum();
+        }
+        assert!(distri_current(var).abs_diff_eq(&expected, 1e-9));
+    }
+
+    // loopy graph: x ^ y = z and x & y = z
+    let functions = vec![
+        Func::new(vec![0, 1, 2], FuncType::XOR),
+        Func::new(vec![3, 4, 5], FuncType::AND),
+    ];
+    let mut variables = vec![
+        profile_para(vec![1, 4], random_distri(n, nc)),
+        profile_para(vec![2, 5], random_distri(n, nc)),
+        profile_para(vec![0, 3], random_distri(n, nc)),
+    ];
+    assert_eq!(
+        bp::infer_exact(&functions, &mut variables, 6, nc, n),
+        Err(bp::BPError::InvalidGraph(
+            "graph is not acyclic.".to_owned()
+        ))
+    );
+}
+
+#[test]
+fn init_strategy() {
+    let (nc, n) = (4, 3);
+
+    // loop x -> y -> x: the messages circulate without ever being attenuated, hence any
+    // initialization is a fixed point and the result only depends on the initial messages
+    let functions = vec![
+        Func::new(vec![1, 0], FuncType::XORCST(Array1::zeros(n))),
+        Func::new(vec![2, 3], FuncType::XORCST(Array1::zeros(n))),
+    ];
+    let run = |init| {
+        let mut variables = vec![
+            not_profile_para(vec![0, 2], n, nc),
+            not_profile_para(vec![1, 3], n, nc),
+        ];
+        bp::run_bp_with_options(
+            &functions,
+            &mut variables,
+            4,
+            4,
+            nc,
+            n,
+            false,
+            bp::RunBpOptions {
+                init,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        variables
+            .iter()
+            .map(|v| distri_current(v).clone())
+            .collect::<Vec<_>>()
+    };
+    let uniform = Array2::from_elem((n, nc), 1.0 / nc as f64);
+    assert!(run(bp::InitStrategy::Uniform)
+        .iter()
+        .all(|distri| distri.abs_diff_eq(&uniform, 1e-9)));
+    let random_1 = run(bp::InitStrategy::Random(1));
+    assert_eq!(random_1, run(bp::InitStrategy::Random(1)));
+    assert!(!random_1[0].abs_diff_eq(&uniform, 1e-3));
+    assert!(!random_1[0].abs_diff_eq(&run(bp::InitStrategy::Random(2))[0], 1e-3));
+
+    // on a tree, the initialization is forgotten once the messages have crossed the graph
+    let prior_x = random_distri(n, nc);
+    let prior_y = random_distri(n, nc);
+    let functions = vec![Func::new(vec![0, 1], FuncType::XORCST(Array1::zeros(n)))];
+    let run = |init| {
+        let mut variables = vec![
+            profile_para(vec![0], prior_x.clone()),
+            profile_para(vec![1], prior_y.clone()),
+        ];
+        bp::run_bp_with_options(
+            &functions,
+            &mut variables,
+            2,
+            2,
+            nc,
+            n,
+            false,
+            bp::RunBpOptions {
+                init,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        distri_current(&variables[0]).clone()
+    };
+    let expected = run(bp::InitStrategy::Prior);
+    for init in [bp::InitStrategy::Uniform, bp::InitStrategy::Random(3)].iter() {
+        assert!(run(*init).abs_diff_eq(&expected, 1e-9));
+    }
+}
+
+#[test]
+fn contiguous_layout() {
+    let (nc, n) = (16, 5);
+    let table: Array1<u32> = (0..nc as u32).map(|x| (5 * x + 3) % nc as u32).collect();
+    // c = a ^ b, d = table[c], e = d + k, f = e & a, b = g * h, noop(c)
+    let functions = vec![
+        Func::new(vec![2, 0, 1], FuncType::XOR),
+        Func::new(vec![4, 3], FuncType::LOOKUP(table)),
+        Func::new(vec![7, 5, 6], FuncType::ADD),
+        Func::new(vec![10, 8, 9], FuncType::AND),
+        Func::new(vec![11, 12, 13], FuncType::MUL),
+        Func::new(vec![14], FuncType::CUSTOM(Arc::new(NoOp))),
+    ];
+    let mut variables = vec![
+        profile_para(vec![0, 9], random_distri(n, nc)),
+        profile_para(vec![1, 11], random_distri(n, nc)),
+        not_profile_para(vec![2, 3, 14], n, nc),
+        profile_para(vec![4, 5], random_distri(n, nc)),
+        Var {
+            neighboors: vec![6],
+            vartype: VarType::ProfileSingle {
+                distri_orig: random_distri(1, nc),
+                distri_current: Array2::ones((1, nc)),
+            },
+        },
+        not_profile_para(vec![7, 8], n, nc),
+        profile_para(vec![10], random_distri(n, nc)),
+        profile_para(vec![12], random_distri(n, nc)),
+        profile_para(vec![13], random_distri(n, nc)),
+    ];
+    let mut variables_contiguous: Vec<Var> = variables
+        .iter()
+        .map(|v| Var {
+            neighboors: v.neighboors.clone(),
+            vartype: clone_vartype(&v.vartype),
+        })
+        .collect();
+    let mut workspace = bp::BpWorkspace::new();
+    let mut workspace_contiguous = bp::BpWorkspace::new().with_layout(bp::EdgeLayout::Contiguous);
+    for (variables, workspace) in [
+        (&mut variables, &mut workspace),
+        (&mut variables_contiguous, &mut workspace_contiguous),
+    ]
+    .iter_mut()
+    {
+        bp::run_bp_workspace(
+            &functions,
+            variables,
+            5,
+            15,
+            nc,
+            n,
+            false,
+            bp::RunBpOptions::default(),
+            workspace,
+        )
+        .unwrap();
+    }
+    for (v, v_contiguous) in variables.iter().zip(variables_contiguous.iter()) {
+        assert_eq!(distri_current(v), distri_current(v_contiguous));
+    }
+}
+
+#[test]
+fn residual_per_variable() {
+    let (nc, n) = (4, 2);
+    // tree: a ^ 1 = b, loop: x ^ 0 = y and y ^ 0 = x, where the priors are counted again at each
+    // iteration such that the distributions keep sharpening
+    let functions = vec![
+        Func::new(vec![0, 1], FuncType::XORCST(Array1::ones(n))),
+        Func::new(vec![2, 3], FuncType::XORCST(Array1::zeros(n))),
+        Func::new(vec![5, 4], FuncType::XORCST(Array1::zeros(n))),
+    ];
+    // fixed priors on the loop, which are far enough from one-hot after 5 iterations
+    let loop_prior = Array2::from_shape_fn((n, nc), |(_, j)| (nc - j) as f64 / 10.0);
+    let mut variables = vec![
+        profile_para(vec![0], random_distri(n, nc)),
+        profile_para(vec![1], random_distri(n, nc)),
+        profile_para(vec![2, 5], loop_prior.clone()),
+        profile_para(vec![3, 4], loop_prior),
+    ];
+    let mut edges = bp::init_edges(&variables, 6, nc, n);
+    let residuals = bp::run_bp_damped_diagnostic(
+        &functions,
+        &mut variables,
+        &mut edges,
+        5,
+        bp::Damping::Fixed(0.0),
+        0.0,
+        bp::ConvergenceMetric::Absolute,
+        true,
+    )
+    .unwrap();
+    assert_eq!(residuals.iterations.len(), 5);
+    let per_variable = residuals.per_variable.unwrap();
+    assert_eq!(per_variable.len(), 4);
+    assert!(per_variable[0] < 1e-12 && per_variable[1] < 1e-12);
+    assert!(per_variable[2] > 1e-3 && per_variable[3] > 1e-3);
+    assert_eq!(
+        per_variable.fold(0.0, |acc: f64, x| acc.max(*x)),
+        *residuals.iterations.last().unwrap()
+    );
+}
+
+#[test]
+fn xor_many_inputs() {
+    let (n, nc) = (3, 16);
+    // y = x0 ^ x1 ^ x2 ^ x3 ^ x4, where x2 is not profiled
+    let values = Array2::from_shape_fn((n, 5), |(k, i)| (7 * k + 3 * i + 1) % nc);
+    let y: Vec<usize> = values
+        .outer_iter()
+        .map(|v| v.fold(0, |acc, x| acc ^ x))
+        .collect();
+    let noisy = |value: &dyn Fn(usize) -> usize| {
+        Array2::from_shape_fn((n, nc), |(k, j)| if j == value(k) { 0.5 } else { 0.1 })
+    };
+    let functions = vec![Func::xor(0, &[1, 2, 3, 4, 5]).unwrap()];
+    let mut variables: Vec<Var> = (0..5)
+        .map(|i| {
+            if i == 2 {
+                not_profile_para(vec![i + 1], n, nc)
+            } else {
+                profile_para(vec![i + 1], noisy(&|k| values[[k, i]]))
+            }
+        })
+        .collect();
+    variables.insert(0, profile_para(vec![0], noisy(&|k| y[k])));
+    bp::run_bp(&functions, &mut variables, 1, 6, nc, n, false).unwrap();
+    let (guess, _) = &bp::best_guess(&variables)[3];
+    assert_eq!(guess, &values.column(2));
+
+    assert_eq!(
+        Func::xor(0, &[]).err(),
+        Some(bp::BPError::InvalidGraph(
+            "XOR function node has no input.".to_owned()
+        ))
+    );
+    assert!(Func::xor(0, &[1, 2, 1]).is_err());
+    // the graph has fewer edges than the XOR function node
+    assert!(matches!(
+        bp::run_bp(&functions, &mut variables, 1, 5, nc, n, false),
+        Err(bp::BPError::InvalidGraph(_))
+    ));
+}
+
+#[test]
+fn xor_field_size() {
+    let n = 2;
+    // x0 ^ x1 = y, with bytes for x0 and y, and a nibble for x1
+    let functions = vec![Func::xor(0, &[1, 2]).unwrap()];
+    let mut variables = vec![
+        profile_para(vec![0], random_distri(n, 256)),
+        profile_para(vec![1], random_distri(n, 256)),
+        profile_para(vec![2], random_distri(n, 16)),
+    ];
+    let res = bp::run_bp(&functions, &mut variables, 1, 3, 256, n, false);
+    assert_eq!(
+        res,
+        Err(bp::BPError::InvalidGraph(
+            "XOR function 0 has edges with different field sizes: edge 0 has size 256, edge 2 \
+            has size 16."
+                .to_owned()
+        ))
+    );
+
+    // the field size of the XOR function is inferred from its variables, not from nc
+    let mut variables: Vec<Var> = (0..3)
+        .map(|i| profile_para(vec![i], random_distri(n, 6)))
+        .collect();
+    let res = bp::run_bp(&functions, &mut variables, 1, 3, 256, n, false);
+    assert_eq!(
+        res,
+        Err(bp::BPError::InvalidGraph(
+            "XOR function 0 has field size 6, which is not a power of two.".to_owned()
+        ))
+    );
+}
+
+#[test]
+fn until_decided() {
+    let (n, nc, len) = (3, 16, 6);
+    let (functions, mut variables) = lookup_chain(len, n, nc);
+    // strong evidence on v0 only, the other variables are decided by propagation
+    let values: Vec<usize> = (0..n).map(|k| (5 * k + 2) % nc).collect();
+    let distri = Array2::from_shape_fn((n, nc), |(k, j)| if j == values[k] { 0.9 } else { 0.01 });
+    variables[0] = profile_para(variables[0].neighboors.clone(), distri);
+    variables[len] = not_profile_para(variables[len].neighboors.clone(), n, nc);
+    let mut edges = bp::init_edges(&variables, 2 * len, nc, n);
+    let iterations =
+        bp::run_bp_until_decided(&functions, &mut variables, &mut edges, 100, 0.5, false).unwrap();
+    // the evidence reaches v_{len} after len iterations
+    assert_eq!(iterations, len);
+
+    let mut expected = Array1::from(values);
+    for (i, guess) in bp::best_guess(&variables).iter().enumerate() {
+        assert_eq!(guess.0, expected);
+        if let Some(FuncType::LOOKUP(table)) = functions.get(i).map(|f| &f.functype) {
+            expected.mapv_inplace(|x| table[x] as usize);
+        }
+    }
+}
+
+#[test]
+fn op_profiling() {
+    let (n, nc) = (2, 16);
+    // a * b = c (over Z_2^4), c ^ d = e, e = lookup(f)
+    let functions = vec![
+        Func::new(vec![2, 0, 1], FuncType::MUL),
+        Func::new(vec![5, 3, 4], FuncType::XOR),
+        Func::new(vec![7, 6], FuncType::LOOKUP((0..nc as u32).collect())),
+    ];
+    let mut edges: Vec<Array2<f64>> = (0..8).map(|_| random_distri(n, nc)).collect();
+    let mut edges_iter = edges.iter_mut();
+    let mut edge_for_func: Vec<Vec<&mut Array2<f64>>> = functions
+        .iter()
+        .map(|f| {
+            f.neighboors
+                .iter()
+                .map(|_| edges_iter.next().unwrap())
+                .collect()
+        })
+        .collect();
+    let stats = bp::update_functions_profiled(&functions, &mut edge_for_func);
+    assert_eq!(
+        stats.keys().cloned().collect::<Vec<_>>(),
+        vec!["LOOKUP", "MUL-fast", "XOR"]
+    );
+    assert!(stats.values().all(|s| s.count == 1));
+
+    assert_eq!(bp::op_kind(&FuncType::MUL, 17), "MUL-fast");
+    assert_eq!(bp::op_kind(&FuncType::MUL, 12), "MUL-naive");
+    assert_eq!(bp::op_kind(&FuncType::XORCST(Array1::zeros(n)), nc), "CST");
+}
+
+#[test]
+fn unknown_cst() {
+    let (n, nc) = (4, 16);
+    let prior_out = random_distri(n, nc);
+    let prior_in = random_distri(n, nc);
+    let run = |functions: Vec<Func>, mut variables: Vec<Var>, edge: usize| {
+        bp::run_bp(&functions, &mut variables, 1, edge, nc, n, false).unwrap();
+        variables
+    };
+    let variables = || {
+        vec![
+            profile_para(vec![0], prior_out.clone()),
+            profile_para(vec![1], prior_in.clone()),
+        ]
+    };
+    for (cst, op) in [
+        (
+            FuncType::ANDCST as fn(Array1<u32>) -> FuncType,
+            FuncType::AND,
+        ),
+        (FuncType::MULCST, FuncType::MUL),
+        (FuncType::ADDCST, FuncType::ADD),
+    ]
+    .iter()
+    {
+        let values = Array1::from(vec![3, bp::UNKNOWN_CST, 7, bp::UNKNOWN_CST]);
+        let mixed = run(vec![Func::new(vec![0, 1], cst(values))], variables(), 2);
+        // the unknown copies behave as the binary operator with a uniform second input
+        let mut binary_vars = variables();
+        binary_vars.push(not_profile_para(vec![2], n, nc));
+        let binary = run(vec![Func::new(vec![0, 1, 2], op.clone())], binary_vars, 3);
+        // the known copies are not changed
+        let known = run(
+            vec![Func::new(vec![0, 1], cst(Array1::from(vec![3, 0, 7, 0])))],
+            variables(),
+            2,
+        );
+        for v in 0..2 {
+            let mixed = distri_current(&mixed[v]);
+            for k in 0..n {
+                let expected = if k % 2 == 1 { &binary[v] } else { &known[v] };
+                let expected = distri_current(expected).row(k);
+                assert!(mixed
+                    .row(k)
+                    .iter()
+                    .zip(expected.iter())
+                    .all(|(x, y)| (x - y).abs() < 1e-9));
+            }
+        }
+    }
+
+    // XOR with an unknown public bit gives no information, also with the binary implementation
+    let prior = random_distri(n, 2);
+    let mut variables = vec![
+        profile_para(vec![0], prior.clone()),
+        not_profile_para(vec![1], n, 2),
+    ];
+    let values = Array1::from_elem(n, bp::UNKNOWN_CST);
+    bp::run_bp(
+        &[Func::new(vec![0, 1], FuncType::XORCST(values))],
+        &mut variables,
+        1,
+        2,
+        2,
+        n,
+        false,
+    )
+    .unwrap();
+    assert!(distri_current(&variables[1])
+        .iter()
+        .all(|x| (x - 0.5).abs() < 1e-9));
+}
+
+#[test]
+fn topk_messages() {
+    let (n, nc, len, k) = (3, 256, 4, 8);
+    let (mut functions, variables) = lookup_chain(len, n, nc);
+    let xor_values = Array1::from(vec![3, 200, 17]);
+    functions.push(Func::new(
+        vec![2 * len + 1, 2 * len],
+        FuncType::XORCST(xor_values.clone()),
+    ));
+    // peaky and consistent distributions: a few likely values (mapped through the chain) and a
+    // small noise on the others
+    let peaks: Vec<Vec<usize>> = (0..n)
+        .map(|copy| vec![(7 * copy + 1) % nc, (7 * copy + 90) % nc, (copy + 200) % nc])
+        .collect();
+    let mapped = |steps: usize| -> Vec<Vec<usize>> {
+        peaks
+            .iter()
+            .map(|p| {
+                p.iter()
+                    .map(|x| {
+                        functions[..steps]
+                            .iter()
+                            .fold(*x, |x, f| match &f.functype {
+                                FuncType::LOOKUP(table) => table[x] as usize,
+                                _ => unreachable!(),
+                            })
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+    let peaky = |peaks: Vec<Vec<usize>>| {
+        let mut distri = Array2::<f64>::random((n, nc), Uniform::new(1e-6, 2e-6));
+        distri
+            .outer_iter_mut()
+            .zip(peaks.iter())
+            .for_each(|(mut d, p)| {
+                d[p[0]] += 0.6;
+                d[p[1]] += 0.3;
+                d[p[2]] += 0.1;
+            });
+        distri
+    };
+    let end_peaks = mapped(len);
+    let xor_peaks: Vec<Vec<usize>> = end_peaks
+        .iter()
+        .zip(xor_values.iter())
+        .map(|(p, v)| p.iter().map(|x| x ^ *v as usize).collect())
+        .collect();
+    let mut variables: Vec<Var> = variables
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut var)| {
+            if i == len {
+                var.neighboors.push(2 * len);
+            }
+            if i == 0 {
+                profile_para(var.neighboors, peaky(peaks.clone()))
+            } else if i == len {
+                profile_para(var.neighboors, peaky(end_peaks.clone()))
+            } else {
+                var
+            }
+        })
+        .collect();
+    variables.push(profile_para(vec![2 * len + 1], peaky(xor_peaks)));
+    let edge = 2 * len + 2;
+    let mut full: Vec<Var> = variables
+        .iter()
+        .map(|var| Var {
+            neighboors: var.neighboors.clone(),
+            vartype: clone_vartype(&var.vartype),
+        })
+        .collect();
+    bp::run_bp(&functions, &mut full, len + 2, edge, nc, n, false).unwrap();
+    bp::run_bp_topk(&functions, &mut variables, len + 2, edge, nc, n, k).unwrap();
+    for (full, topk) in full.iter().zip(variables.iter()) {
+        let diff = distri_current(full) - distri_current(topk);
+        assert!(diff.iter().all(|x| x.abs() < 1e-3));
+    }
+
+    let functions = vec![Func::new(vec![0, 1, 2], FuncType::XOR)];
+    assert_eq!(
+        bp::run_bp_topk(&functions, &mut variables, 1, edge, nc, n, k),
+        Err(bp::BPError::UnsupportedFunction { function: 0 })
+    );
+}
+
+#[test]
+fn gray_order() {
+    let nc = 16;
+    let x = random_distri(1, nc).row(0).to_owned();
+    assert_eq!(bp::from_gray_order(&bp::to_gray_order(&x)), x);
+    assert_eq!(bp::to_gray_order(&bp::from_gray_order(&x)), x);
+    let gray = [0, 1, 3, 2, 6, 7, 5, 4, 12, 13, 15, 14, 10, 11, 9, 8];
+    let values: Array1<f64> = (0..nc).map(|i| i as f64).collect();
+    assert_eq!(
+        bp::to_gray_order(&values),
+        gray.iter().map(|g| *g as f64).collect::<Array1<f64>>()
+    );
+}
+
+#[test]
+fn get_marginals_stacked() {
+    let (len, n, nc) = (3, 5, 8);
+    let (functions, mut variables) = lookup_chain(len, n, nc);
+    bp::run_bp(&functions, &mut variables, len + 1, 2 * len, nc, n, false).unwrap();
+    let stacked = bp::get_marginals_stacked(&variables, bp::ProbaScale::Linear).unwrap();
+    assert_eq!(stacked.dim(), (len + 1, n, nc));
+    let marginals = bp::get_marginals(&variables, bp::ProbaScale::Linear);
+    for (s, m) in stacked.outer_iter().zip(marginals.iter()) {
+        assert_eq!(s, m);
+    }
+
+    variables.push(Var {
+        neighboors: vec![],
+        vartype: VarType::NotProfileSingle {
+            distri_current: Array2::ones((1, nc)),
+        },
+    });
+    assert!(bp::get_marginals_stacked(&variables, bp::ProbaScale::Linear).is_err());
+    variables.pop();
+    variables.push(not_profile_para(vec![], n + 1, nc));
+    assert!(bp::get_marginals_stacked(&variables, bp::ProbaScale::Linear).is_err());
+}
+
+#[test]
+fn walsh_hadamard() {
+    let nc = 32;
+    let a = random_distri(1, nc).row(0).to_owned();
+    // non-normalized: sum over y of (-1)^popcount(x & y) a[y]
+    let mut b = a.clone();
+    bp::walsh_hadamard(b.as_slice_mut().unwrap(), false);
+    let expected: Array1<f64> = (0..nc)
+        .map(|x| {
+            (0..nc)
+                .map(|y| {
+                    if (x & y).count_ones() % 2 == 0 {
+                        a[y]
+                    } else {
+                        -a[y]
+                    }
+                })
+                .sum()
+        })
+        .collect();
+    assert!(b.abs_diff_eq(&expected, 1e-12));
+    // normalized inverse
+    bp::walsh_hadamard(b.as_slice_mut().unwrap(), true);
+    assert!(b.abs_diff_eq(&a, 1e-12));
+    let mut c = a.clone();
+    bp::walsh_hadamard(c.as_slice_mut().unwrap(), true);
+    assert!(c.abs_diff_eq(&(expected / nc as f64), 1e-12));
+}
+
+#[test]
+#[should_panic]
+fn walsh_hadamard_length() {
+    bp::walsh_hadamard(&mut [1.0, 2.0, 3.0], false);
+}
+
+#[test]
+fn mults_pow2() {
+    let n = 3;
+    for nc in [4, 8, 16, 256].iter().cloned() {
+        let mut msgs: Vec<Array2<f64>> = (0..3).map(|_| random_distri(n, nc)).collect();
+        // an input that is even for the first copy, to stress the products that reach zero
+        msgs[1]
+            .row_mut(0)
+            .iter_mut()
+            .step_by(2)
+            .for_each(|x| *x *= 1e3);
+        let mut res_naive = msgs.clone();
+        bp::naive(
+            &mut res_naive.iter_mut().collect::<Vec<_>>(),
+            &FuncType::MUL,
+        );
+        let mut res_transform = msgs.clone();
+        bp::mults_pow2(&mut res_transform.iter_mut().collect::<Vec<_>>());
+        for (a, b) in res_naive.iter().zip(res_transform.iter()) {
+            assert!(a.abs_diff_eq(b, 1e-9 * a.sum()));
+        }
+        assert_eq!(bp::op_kind(&FuncType::MUL, nc), "MUL-fast");
+    }
+}
+
+#[test]
+fn mul_brute_force() {
+    let n = 3;
+    // prime (see `mults`), power-of-two (see `mults_pow2`) and other (naive) field sizes
+    for nc in [2, 17, 16, 12].iter().cloned() {
+        // c = a * b
+        let functions = vec![Func::new(vec![2, 0, 1], FuncType::MUL)];
+        // a zero with a large probability in the first copy, to stress the products with zero
+        let mut prior_a = random_distri(n, nc);
+        prior_a[[0, 0]] = 10.0;
+        let mut variables = vec![
+            profile_para(vec![0], prior_a),
+            profile_para(vec![1], random_distri(n, nc)),
+            profile_para(vec![2], random_distri(n, nc)),
+        ];
+        let expected = bp::brute_force_marginals(&functions, &variables, nc, n).unwrap();
+        // the graph is a tree: BP is exact
+        bp::run_bp(&functions, &mut variables, 2, 3, nc, n, false).unwrap();
+        let marginals = bp::get_marginals(&variables, bp::ProbaScale::Linear);
+        for (m, e) in marginals.iter().zip(expected.iter()) {
+            assert!(m.abs_diff_eq(e, 1e-9), "nc = {}", nc);
+        }
+    }
+}
+
+#[test]
+fn limit_cycle() {
+    let (n, nc) = (2, 4);
+    // loop v1 = v0 + 1 and v0 = v1, which has no solution: without damping, the messages keep
+    // rotating around the loop
+    let rotate: Array1<u32> = (0..nc as u32).map(|x| (x + 1) % nc as u32).collect();
+    let identity: Array1<u32> = (0..nc as u32).collect();
+    let functions = vec![
+        Func::new(vec![1, 0], FuncType::LOOKUP(rotate.clone())),
+        Func::new(vec![3, 2], FuncType::LOOKUP(identity)),
+    ];
+    let prior =
+        Array2::from_shape_vec((n, nc), vec![0.4, 0.3, 0.2, 0.1, 0.1, 0.2, 0.3, 0.4]).unwrap();
+    let mut variables = vec![
+        profile_para(vec![0, 3], prior.clone()),
+        not_profile_para(vec![1, 2], n, nc),
+    ];
+    let mut edges = bp::init_edges(&variables, 4, nc, n);
+    let cycle = bp::LimitCycle {
+        threshold: 1e-2,
+        window: 10,
+    };
+    let residuals = bp::run_bp_damped_limit_cycle(
+        &functions,
+        &mut variables,
+        &mut edges,
+        30,
+        bp::Damping::Fixed(0.0),
+        0.0,
+        bp::ConvergenceMetric::Absolute,
+        false,
+        Some(cycle),
+    )
+    .unwrap();
+    assert_eq!(residuals.oscillation.len(), 30);
+    assert!(residuals.oscillation[0].is_nan());
+    assert!(residuals.oscillation[1..].iter().all(|x| *x > 0.1));
+    assert!(residuals.limit_cycle);
+
+    // without the loop, the messages converge
+    let functions = vec![Func::new(vec![1, 0], FuncType::LOOKUP(rotate))];
+    let mut variables = vec![
+        profile_para(vec![0], prior),
+        not_profile_para(vec![1], n, nc),
+    ];
+    let mut edges = bp::init_edges(&variables, 2, nc, n);
+    let residuals = bp::run_bp_damped_limit_cycle(
+        &functions,
+        &mut variables,
+        &mut edges,
+        30,
+        bp::Damping::Fixed(0.0),
+        0.0,
+        bp::ConvergenceMetric::Absolute,
+        false,
+        Some(cycle),
+    )
+    .unwrap();
+    assert!(residuals.oscillation[2..].iter().all(|x| *x < 1e-12));
+    assert!(!residuals.limit_cycle);
+}
+
+#[test]
+fn lookup_para() {
+    let (n, nc) = (2, 16);
+    // y = table_k[x] with a different permutation for each copy
+    let tables = Array2::from_shape_fn((n, nc), |(k, i)| ((5 + 2 * k) * i + 3 * k + 1) as u32 % 16);
+    let functype = FuncType::lookup_para(tables.clone(), n, nc, nc).unwrap();
+    let functions = vec![Func::new(vec![1, 0], functype)];
+    let y = [7, 7];
+    let mut distri_y = Array2::from_elem((n, nc), 0.01);
+    distri_y[[0, y[0]]] = 1.0;
+    distri_y[[1, y[1]]] = 1.0;
+    let mut variables = vec![
+        not_profile_para(vec![0], n, nc),
+        profile_para(vec![1], distri_y),
+    ];
+    bp::run_bp(&functions, &mut variables, 2, 2, nc, n, false).unwrap();
+    let guesses = bp::best_guess(&variables);
+    for k in 0..n {
+        let x = guesses[0].0[k];
+        assert_eq!(tables[[k, x]] as usize, y[k]);
+    }
+    // the same output comes from different inputs in both copies
+    assert_ne!(guesses[0].0[0], guesses[0].0[1]);
+
+    assert!(matches!(
+        FuncType::lookup_para(tables.clone(), n + 1, nc, nc),
+        Err(bp::BPError::InvalidTable(_))
+    ));
+    assert!(matches!(
+        FuncType::lookup_para(tables, n, nc, 8),
+        Err(bp::BPError::InvalidTable(_))
+    ));
+}
+
+#[test]
+#[should_panic(expected = "LOOKUP_PARA tables must have shape (n, nc_in).")]
+fn lookup_para_copies() {
+    // tables for 2 copies in a graph with 3 copies
+    let (n, nc) = (3, 4);
+    let tables = Array2::from_shape_fn((2, nc), |(_, i)| i as u32);
+    let functions = vec![Func::new(vec![1, 0], FuncType::LOOKUP_PARA(tables))];
+    let mut variables = vec![
+        not_profile_para(vec![0], n, nc),
+        profile_para(vec![1], random_distri(n, nc)),
+    ];
+    bp::run_bp(&functions, &mut variables, 1, 2, nc, n, false).unwrap();
+}
+
+#[test]
+fn noise() {
+    let (n, nc) = (1, 16);
+    // triangular kernel over the differences {-2, ..., 2}
+    let mut kernel = Array1::zeros(nc);
+    for (d, w) in &[(0, 3.0), (1, 2.0), (nc - 1, 2.0), (2, 1.0), (nc - 2, 1.0)] {
+        kernel[*d] = *w;
+    }
+    let functype = FuncType::noise(kernel.clone(), nc).unwrap();
+    // y = x + noise, with x known
+    let functions = vec![Func::new(vec![1, 0], functype)];
+    let mut distri_x = Array2::zeros((n, nc));
+    distri_x[[0, 5]] = 1.0;
+    let mut variables = vec![
+        profile_para(vec![0], distri_x),
+        not_profile_para(vec![1], n, nc),
+    ];
+    bp::run_bp(&functions, &mut variables, 1, 2, nc, n, false).unwrap();
+    let distri_y = distri_current(&variables[1]);
+    for y in 0..nc {
+        let expected = kernel[(y + nc - 5) % nc] / 9.0;
+        assert!(
+            (distri_y[[0, y]] - expected).abs() < 1e-6,
+            "{} {} {}",
+            y,
+            distri_y[[0, y]],
+            expected
+        );
+    }
+
+    assert!(matches!(
+        FuncType::noise(kernel.clone(), nc + 1),
+        Err(bp::BPError::InvalidTable(_))
+    ));
+    kernel[3] = -1.0;
+    assert!(matches!(
+        FuncType::noise(kernel, nc),
+        Err(bp::BPError::InvalidTable(_))
+    ));
+}
+
+#[test]
+fn validate_graph() {
+    let (n, nc) = (2, 4);
+    // z = x ^ y, and an orphan variable w
+    let mut functions = vec![Func::xor(0, &[1, 2]).unwrap()];
+    let mut variables = vec![
+        not_profile_para(vec![0], n, nc),
+        profile_para(vec![1], random_distri(n, nc)),
+        profile_para(vec![2], random_distri(n, nc)),
+    ];
+    assert_eq!(bp::validate_graph(&functions, &variables, 3, nc), Ok(()));
+    variables.push(not_profile_para(vec![], n, nc));
+    assert_eq!(
+        bp::validate_graph(&functions, &variables, 3, nc),
+        Err(bp::BPError::DisconnectedNode {
+            id: 3,
+            is_function: false
+        })
+    );
+    variables.pop();
+    functions.push(Func::new(vec![], FuncType::ADD));
+    assert_eq!(
+        bp::validate_graph(&functions, &variables, 3, nc),
+        Err(bp::BPError::DisconnectedNode {
+            id: 1,
+            is_function: true
+        })
+    );
+    // the checks of the runs also apply
+    assert!(matches!(
+        bp::validate_graph(&functions, &variables, 2, nc),
+        Err(bp::BPError::InvalidGraph(_))
+    ));
+}
+
+#[test]
+fn success_probability() {
+    // unnormalized marginal where the correct value 2 has probability 0.3
+    let distri = Array2::from_shape_vec((1, 4), vec![1.0, 4.0, 3.0, 2.0]).unwrap();
+    assert!((bp::single_success_probability(&distri, 2) - 0.3).abs() < 1e-12);
+    assert!(!bp::is_correct_argmax(&distri, 2));
+    assert!(bp::is_correct_argmax(&distri, 1));
+    // lowest value in case of ties, as in best_guess
+    let distri = Array2::from_shape_vec((1, 4), vec![0.1, 0.4, 0.1, 0.4]).unwrap();
+    assert!(bp::is_correct_argmax(&distri, 1));
+    assert!(!bp::is_correct_argmax(&distri, 3));
+}
+
+#[test]
+fn residual() {
+    let (n, nc, l) = (1, 4, 20);
+    // cycle of XOR function nodes x[i+1] = x[i] ^ k[i] (with x[l] = x[0]), with weak priors on
+    // the k[i] and a strong prior on x[0]
+    let functions: Vec<Func> = (0..l)
+        .map(|i| Func::xor(3 * i, &[3 * i + 1, 3 * i + 2]).unwrap())
+        .collect();
+    let edge = 3 * l;
+    let priors: Vec<Array2<f64>> = (0..l).map(|_| random_distri(n, nc)).collect();
+    let mut prior_x = Array2::from_elem((n, nc), 0.01);
+    prior_x[[0, 1]] = 1.0;
+    let graph = || {
+        let mut variables: Vec<Var> = (0..l)
+            .map(|i| profile_para(vec![3 * i + 2], priors[i].clone()))
+            .collect();
+        variables.push(profile_para(vec![1, 3 * (l - 1)], prior_x.clone()));
+        variables.extend((1..l).map(|i| not_profile_para(vec![3 * i + 1, 3 * (i - 1)], n, nc)));
+        variables
+    };
+    let run_flooding = |variables: &mut Vec<Var>, tol: f64| {
+        let mut edges = bp::init_edges(variables, edge, nc, n);
+        bp::run_bp_damped(
+            &functions,
+            variables,
+            &mut edges,
+            1000,
+            bp::Damping::Fixed(0.0),
+            tol,
+        )
+        .unwrap()
+        .len()
+    };
+    let mut reference = graph();
+    run_flooding(&mut reference, 1e-14);
+    let reference = bp::get_marginals(&reference, bp::ProbaScale::Linear);
+    let close = |variables: &Vec<Var>| {
+        bp::get_marginals(variables, bp::ProbaScale::Linear)
+            .iter()
+            .zip(reference.iter())
+            .all(|(m, r)| (m - r).iter().all(|x| x.abs() < 1e-5))
+    };
+
+    let mut flooding = graph();
+    let iterations = run_flooding(&mut flooding, 1e-7);
+    assert!(close(&flooding));
+    let mut variables = graph();
+    let mut edges = bp::init_edges(&variables, edge, nc, n);
+    let updates =
+        bp::run_bp_residual(&functions, &mut variables, &mut edges, 100000, 1e-7).unwrap();
+    assert!(close(&variables));
+    // far fewer message updates than flooding, which updates all the edges at each iteration
+    assert!(updates * 5 < iterations * edge);
+}
+
+#[test]
+fn output_edge() {
+    let (n, nc) = (2, 8);
+    let table: Array1<u32> = (0..nc as u32).map(|x| (3 * x + 5) % nc as u32).collect();
+    let distri_x = random_distri(n, nc);
+    let distri_y = random_distri(n, nc);
+    let run = |func: Func, x_edge: usize| {
+        let mut variables = vec![
+            profile_para(vec![x_edge], distri_x.clone()),
+            profile_para(vec![1 - x_edge], distri_y.clone()),
+        ];
+        let functions = vec![func];
+        let mut edges = bp::init_edges(&variables, 2, nc, n);
+        bp::run_bp_edges(&functions, &mut variables, &mut edges, 2, false).unwrap();
+        let joint = bp::pairwise_joint(&functions[0], &edges);
+        (bp::get_marginals(&variables, bp::ProbaScale::Linear), joint)
+    };
+    // y = table[x], where the input x is the first edge and the output y the second one
+    let (marginals, joint) = run(
+        Func::new(vec![0, 1], FuncType::LOOKUP(table.clone())).with_output(1),
+        0,
+    );
+    let (expected, expected_joint) = run(Func::new(vec![0, 1], FuncType::LOOKUP(table.clone())), 1);
+    for (m, e) in marginals.iter().zip(expected.iter()) {
+        assert!((m - e).iter().all(|x| x.abs() < 1e-12));
+    }
+    assert!((&joint - &expected_joint).iter().all(|x| x.abs() < 1e-12));
+    // y only takes the values table[x]
+    for k in 0..n {
+        for x in 0..nc {
+            let p = distri_x[[k, x]] * distri_y[[k, table[x] as usize]];
+            let z: f64 = (0..nc)
+                .map(|x| distri_x[[k, x]] * distri_y[[k, table[x] as usize]])
+                .sum();
+            assert!((marginals[0][[k, x]] - p / z).abs() < 1e-9);
+            assert!((marginals[1][[k, table[x] as usize]] - p / z).abs() < 1e-9);
+        }
+    }
+}
+
+#[test]
+fn rank_statistics() {
+    let nc = 8;
+    // the probability decreases with the value, such that the rank of v is v+1
+    let distri: Array2<f64> = Array2::from_shape_fn((1, nc), |(_, v)| (nc - v) as f64);
+    let marginals = vec![distri.clone(); 4];
+    let (mean, ranks) = bp::rank_statistics(&marginals, &[0, 3, 3, 7]);
+    assert_eq!(ranks, Array1::from(vec![1, 4, 4, 8]));
+    assert_eq!(mean, 4.25);
+    // ties are broken as in best_guess
+    let distri = Array2::from_shape_vec((1, 3), vec![0.2, 0.2, 0.6]).unwrap();
+    let (mean, ranks) = bp::rank_statistics(&[distri.clone(), distri], &[0, 1]);
+    assert_eq!(ranks, Array1::from(vec![2, 3]));
+    assert_eq!(mean, 2.5);
+}
+
+#[test]
+fn get_key_logp() {
+    let single = |distri: Array2<f64>| Var {
+        neighboors: vec![],
+        vartype: VarType::NotProfileSingle {
+            distri_current: distri,
+        },
+    };
+    // unnormalized, with a near-zero entry
+    let k0 = Array2::from_shape_vec((1, 4), vec![1.0, 2.0, 1e-30, 1.0]).unwrap();
+    let k1 = random_distri(1, 4);
+    let variables = vec![
+        single(k0.clone()),
+        not_profile_para(vec![], 2, 4),
+        single(k1.clone()),
+    ];
+    let logp = bp::get_key_logp(&variables, &[2, 0]);
+    assert_eq!(logp.len(), 2);
+    for (logp, distri) in logp.iter().zip([k1, k0].iter()) {
+        let sum = distri.sum();
+        for (l, p) in logp.iter().zip(distri.iter()) {
+            assert!((l - (p / sum).max(1e-20).ln()).abs() < 1e-12);
+        }
+    }
+    // the near-zero entry is clipped to MIN_PROBA
+    assert!((logp[1][2] - 1e-20f64.ln()).abs() < 1e-12);
+    assert!(logp.iter().all(|l| l.iter().all(|x| x.is_finite())));
+}
+
+#[test]
+fn check_feasibility() {
+    let (n, nc) = (2, 8);
+    let table: Array1<u32> = (0..nc as u32).map(|x| (x * x + 1) % nc as u32).collect();
+    let clamped = |values: &[usize]| {
+        let mut distri = Array2::zeros((n, nc));
+        values
+            .iter()
+            .enumerate()
+            .for_each(|(k, v)| distri[[k, *v]] = 1.0);
+        distri
+    };
+    let x = [2, 3];
+    // y = table[x] and z = y ^ 5
+    let functions = vec![
+        Func::new(vec![1, 0], FuncType::LOOKUP(table.clone())),
+        Func::new(vec![3, 2], FuncType::XORCST(Array1::from_elem(n, 5))),
+    ];
+    let graph = |y: Array2<f64>, z: Array2<f64>| {
+        vec![
+            profile_para(vec![0], clamped(&x)),
+            profile_para(vec![1, 2], y),
+            profile_para(vec![3], z),
+        ]
+    };
+    let y: Vec<usize> = x.iter().map(|x| table[*x] as usize).collect();
+    let z: Vec<usize> = y.iter().map(|y| y ^ 5).collect();
+    let variables = graph(random_distri(n, nc), clamped(&z));
+    assert_eq!(bp::check_feasibility(&functions, &variables, 4), Ok(()));
+    // the clamped output of the LOOKUP cannot be produced from the clamped input
+    let variables = graph(clamped(&[y[0], y[1] ^ 1]), random_distri(n, nc));
+    assert_eq!(
+        bp::check_feasibility(&functions, &variables, 4),
+        Err(bp::BPError::Infeasible { variable: 1 })
+    );
+    // the conflict is propagated through both function nodes
+    let variables = graph(random_distri(n, nc), clamped(&[z[0] ^ 1, z[1]]));
+    assert!(matches!(
+        bp::check_feasibility(&functions, &variables, 4),
+        Err(bp::BPError::Infeasible { .. })
+    ));
+}
+
+#[test]
+fn sample_marginals() {
+    let (n, nc, n_samples) = (2, 4, 20000);
+    let mut distri = random_distri(n, nc);
+    // a value that is never drawn
+    distri[[1, 2]] = 0.0;
+    let variables = vec![
+        not_profile_para(vec![], n, nc),
+        profile_para(vec![], distri.clone()),
+    ];
+    let samples = bp::sample_marginals(&variables, n_samples, 42);
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[1].dim(), (n_samples, n));
+    let marginals = bp::get_marginals(&variables, bp::ProbaScale::Linear);
+    for (samples, marginal) in samples.iter().zip(marginals.iter()) {
+        for k in 0..n {
+            for v in 0..nc {
+                let freq =
+                    samples.column(k).iter().filter(|x| **x == v).count() as f64 / n_samples as f64;
+                // more than 5 standard deviations
+                assert!((freq - marginal[[k, v]]).abs() < 0.02);
+            }
+        }
+    }
+    assert!(samples[1].column(1).iter().all(|x| *x != 2));
+    // reproducible draws
+    assert_eq!(bp::sample_marginals(&variables, n_samples, 42), samples);
+    assert_ne!(bp::sample_marginals(&variables, n_samples, 43), samples);
+}
+
+#[test]
+fn add_distris_many() {
+    let nc = 16;
+    // naive circular convolution of two distributions
+    let conv = |a: &Array1<f64>, b: &Array1<f64>| {
+        Array1::from_shape_fn(nc, |z| (0..nc).map(|x| a[x] * b[(z + nc - x) % nc]).sum())
+    };
+    // uniform distributions over [0, 5), [0, 7) and [0, 9): the sum wraps around
+    let inputs: Vec<Array1<f64>> = [5, 7, 9]
+        .iter()
+        .map(|a| Array1::from_shape_fn(nc, |x| if x < *a { 1.0 / *a as f64 } else { 0.0 }))
+        .collect();
+    let expected = conv(&conv(&inputs[0], &inputs[1]), &inputs[2]);
+    let res = bp::add_distris_many(&inputs, nc);
+    assert!(res.abs_diff_eq(&expected, 1e-12));
+
+    let inputs: Vec<Array1<f64>> = random_distri(4, nc)
+        .outer_iter()
+        .map(|d| d.to_owned())
+        .collect();
+    let expected = inputs[1..]
+        .iter()
+        .fold(inputs[0].clone(), |acc, d| conv(&acc, d));
+    assert!(bp::add_distris_many(&inputs, nc).abs_diff_eq(&expected, 1e-12));
+    assert!(bp::add_distris_many(&inputs[..1], nc).abs_diff_eq(&inputs[0], 1e-12));
+}
+
+#[test]
+fn to_dot() {
+    let (n, nc) = (2, 4);
+    // v2 = v0 ^ v1, v3 = table[v2]
+    let functions = vec![
+        Func::new(vec![2, 0, 1], FuncType::XOR),
+        Func::new(vec![4, 3], FuncType::LOOKUP(Array1::from(vec![1, 2, 3, 0]))),
+    ];
+    let variables = vec![
+        profile_para(vec![0], random_distri(n, nc)),
+        Var {
+            neighboors: vec![1],
+            vartype: VarType::ProfileSingle {
+                distri_orig: random_distri(1, nc),
+                distri_current: random_distri(1, nc),
+            },
+        },
+        not_profile_para(vec![2, 3], n, nc),
+        not_profile_para(vec![4], n, nc),
+    ];
+    let dot = bp::to_dot(&functions, &variables);
+    assert!(dot.starts_with("graph factor_graph {\n") && dot.ends_with("}\n"));
+    let lines: Vec<&str> = dot.lines().collect();
+    assert_eq!(
+        lines.iter().filter(|l| l.contains("shape=ellipse")).count(),
+        4
+    );
+    assert_eq!(lines.iter().filter(|l| l.contains("shape=box")).count(), 2);
+    assert_eq!(lines.iter().filter(|l| l.contains(" -- ")).count(), 5);
+    // the single variable is distinguished
+    assert_eq!(dot.matches("peripheries=2").count(), 1);
+    assert!(dot.contains("    v1 [shape=ellipse, label=\"v1\", peripheries=2, style=filled];"));
+    assert!(dot.contains("    f0 [shape=box, label=\"f0\\nXOR\"];"));
+    assert!(dot.contains("    f1 [shape=box, label=\"f1\\nLOOKUP\"];"));
+    assert!(dot.contains("    v2 -- f1 [label=\"3\"];"));
+}
+
+#[test]
+fn batched() {
+    for (n, nc) in [(1, 4), (5, 16), (20, 64)].iter().cloned() {
+        for arity in [3, 4].iter().cloned() {
+            let msgs: Vec<Array2<f64>> = (0..arity).map(|_| random_distri(n, nc)).collect();
+            let mut res = msgs.clone();
+            bp::adds(&mut res.iter_mut().collect::<Vec<_>>());
+            let mut res_batched = msgs.clone();
+            bp::adds_batched(&mut res_batched.iter_mut().collect::<Vec<_>>());
+            assert_eq!(res, res_batched);
+
+            let mut res = msgs.clone();
+            bp::xors(&mut res.iter_mut().collect::<Vec<_>>());
+            let mut res_batched = msgs.clone();
+            bp::xors_batched(&mut res_batched.iter_mut().collect::<Vec<_>>());
+            assert_eq!(res, res_batched);
+        }
+    }
+
+    // in the belief propagation, for an ADD and a XOR function nodes
+    let (n, nc) = (10, 32);
+    let (distri_a, distri_b, distri_c) = (
+        random_distri(n, nc),
+        random_distri(n, nc),
+        random_distri(n, nc),
+    );
+    let run = |batched: bool| {
+        let functions = vec![
+            Func::new(vec![2, 0, 1], FuncType::ADD).with_batched(batched),
+            Func::new(vec![5, 3, 4], FuncType::XOR).with_batched(batched),
+        ];
+        let mut variables = vec![
+            profile_para(vec![0], distri_a.clone()),
+            profile_para(vec![1], distri_b.clone()),
+            not_profile_para(vec![2, 3], n, nc),
+            profile_para(vec![4], distri_c.clone()),
+            not_profile_para(vec![5], n, nc),
+        ];
+        bp::run_bp(&functions, &mut variables, 2, 6, nc, n, false).unwrap();
+        bp::get_marginals(&variables, bp::ProbaScale::Linear)
+    };
+    assert_eq!(run(true), run(false));
+}
+
+#[test]
+fn brute_force_marginals() {
+    let (nc, n) = (4, 3);
+    // c = a ^ b, d = table[c], e = d + k, where k is a single variable
+    let functions = vec![
+        Func::new(vec![2, 0, 1], FuncType::XOR),
+        Func::new(vec![4, 3], FuncType::LOOKUP(Array1::from(vec![1, 3, 0, 2]))),
+        Func::new(vec![7, 5, 6], FuncType::ADD),
+    ];
+    let pk = random_distri(1, nc);
+    let mut variables = vec![
+        profile_para(vec![0], random_distri(n, nc)),
+        profile_para(vec![1], random_distri(n, nc)),
+        not_profile_para(vec![2, 3], n, nc),
+        profile_para(vec![4, 5], random_distri(n, nc)),
+        Var {
+            neighboors: vec![6],
+            vartype: VarType::ProfileSingle {
+                distri_orig: pk.clone(),
+                distri_current: pk,
+            },
+        },
+        profile_para(vec![7], random_distri(n, nc)),
+    ];
+    let expected = bp::brute_force_marginals(&functions, &variables, nc, n).unwrap();
+    assert_eq!(expected[4].dim(), (1, nc));
+    assert_eq!(expected[0].dim(), (n, nc));
+
+    // the graph is a tree: BP is exact
+    bp::run_bp(&functions, &mut variables, 10, 8, nc, n, false).unwrap();
+    let marginals = bp::get_marginals(&variables, bp::ProbaScale::Linear);
+    for (m, e) in marginals.iter().zip(expected.iter()) {
+        assert!(m.abs_diff_eq(e, 1e-9));
+    }
+
+    // 4 copies of 6 variables over 16 values
+    let (functions, variables) = lookup_chain(5, 4, 16);
+    assert_eq!(
+        bp::brute_force_marginals(&functions, &variables, 16, 4),
+        Err(bp::BPError::StateSpaceTooLarge {
+            states: 4 * 16usize.pow(6)
+        })
+    );
+}
+
+#[test]
+fn field_too_large() {
+    // the edges have no variable (hence the size of the field), such that the test itself does
+    // not allocate distributions of size nc
+    let var = |neighboors| Var {
+        neighboors,
+        vartype: VarType::NotProfilePara {
+            distri_current: Array2::ones((1, 0)),
+        },
+    };
+    let functions = vec![Func::new(vec![1, 0], FuncType::NEG)];
+    let mut variables = vec![var(vec![]), var(vec![])];
+    let nc = 1 << 24;
+    assert_eq!(
+        bp::run_bp(&functions, &mut variables, 1, 2, nc, 1, false),
+        Err(bp::BPError::FieldTooLarge {
+            nc,
+            edge_bytes: 2 * nc * 8
+        })
+    );
+
+    // memory limit
+    let (n, nc) = (100, 16);
+    let (functions, mut variables) = lookup_chain(3, n, nc);
+    let limits = bp::BpLimits {
+        max_edge_bytes: 6 * n * nc * 8 - 1,
+        ..Default::default()
+    };
+    let mut workspace = bp::BpWorkspace::new().with_limits(limits);
+    let mut run = |workspace: &mut bp::BpWorkspace| {
+        bp::run_bp_workspace(
+            &functions,
+            &mut variables,
+            1,
+            6,
+            nc,
+            n,
+            false,
+            bp::RunBpOptions::default(),
+            workspace,
+        )
+    };
+    assert_eq!(
+        run(&mut workspace),
+        Err(bp::BPError::FieldTooLarge {
+            nc,
+            edge_bytes: 6 * n * nc * 8
+        })
+    );
+    assert_eq!(run(&mut bp::BpWorkspace::new()), Ok(()));
+}
+
+#[test]
+fn set_prior() {
+    let (n, nc) = (3, 8);
+    // y = table[x], where only y is profiled
+    let table: Vec<u32> = (0..nc as u32).map(|x| (5 * x + 3) % nc as u32).collect();
+    let functions = vec![Func::new(
+        vec![1, 0],
+        FuncType::LOOKUP(Array1::from(table.clone())),
+    )];
+    let py = random_distri(n, nc);
+    let mut variables = vec![
+        not_profile_para(vec![0], n, nc),
+        profile_para(vec![1], py.clone()),
+    ];
+    let run = |variables: &mut [Var]| {
+        bp::run_bp(&functions, variables, 2, 2, nc, n, false).unwrap();
+        bp::get_marginals(variables, bp::ProbaScale::Linear)
+    };
+    assert_eq!(bp::get_prior(&variables, 0), None);
+    assert_eq!(bp::get_prior(&variables, 1), Some(&py));
+    let before = run(&mut variables);
+
+    let px = random_distri(n, nc);
+    bp::set_prior(&mut variables, 0, &px).unwrap();
+    assert_eq!(bp::get_prior(&variables, 0), Some(&px));
+    assert!(variables[0].describe().profiled && variables[0].describe().para);
+    let after = run(&mut variables);
+    let mut expected =
+        Array2::from_shape_fn((n, nc), |(k, x)| px[[k, x]] * py[[k, table[x] as usize]]);
+    expected.outer_iter_mut().for_each(|mut d| d /= d.sum());
+    assert!(after[0].abs_diff_eq(&expected, 1e-9));
+    assert!(!after[0].abs_diff_eq(&before[0], 1e-3));
+
+    assert!(matches!(
+        bp::set_prior(&mut variables, 0, &random_distri(1, nc)),
+        Err(bp::BPError::InvalidGraph(_))
+    ));
+    assert!(matches!(
+        bp::set_prior(&mut variables, 2, &px),
+        Err(bp::BPError::InvalidGraph(_))
+    ));
+    assert_eq!(bp::get_prior(&variables, 2), None);
+}
+
+/// Marginals of a single function node `functype` between profiled variables with the priors
+/// `priors` (one copy, the output first), clipping the messages according to `clamp`.
+fn single_node_marginals(
+    functype: FuncType,
+    priors: &[Vec<f64>],
+    clamp: Clamp,
+) -> Vec<Array2<f64>> {
+    let nc = priors[0].len();
+    let functions = vec![Func::new((0..priors.len()).collect(), functype)];
+    let mut variables: Vec<Var> = priors
+        .iter()
+        .enumerate()
+        .map(|(i, prior)| {
+            profile_para(
+                vec![i],
+                Array2::from_shape_vec((1, nc), prior.clone()).unwrap(),
+            )
+        })
+        .collect();
+    bp::run_bp_with_options(
+        &functions,
+        &mut variables,
+        2,
+        priors.len(),
+        nc,
+        1,
+        false,
+        bp::RunBpOptions {
+            clamp,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    bp::get_marginals(&variables, bp::ProbaScale::Linear)
+}
+
+#[test]
+fn relative_clamp() {
+    // y = x (through an identity table), with a peaky prior on x, with values below the fixed
+    // floor
+    let nc = 4;
+    let prior = vec![1.0, 1e-25, 1e-30, 0.0];
+    let uniform = vec![1.0; nc];
+    let lookup = || FuncType::LOOKUP((0..nc as u32).collect());
+    let fixed = single_node_marginals(lookup(), &[uniform.clone(), prior.clone()], Clamp::Fixed);
+    let fixed = fixed[0].row(0);
+    // all the small values are collapsed to the fixed floor
+    assert!(fixed[1] > 0.0 && fixed[1] == fixed[2] && fixed[2] == fixed[3]);
+
+    let relative = single_node_marginals(lookup(), &[uniform, prior], Clamp::Relative(1e-35));
+    let relative = relative[0].row(0);
+    // the gradation is kept, down to the relative floor
+    assert!(((relative[1] / relative[0]).log10() + 25.0).abs() < 1e-6);
+    assert!(((relative[2] / relative[0]).log10() + 30.0).abs() < 1e-6);
+    assert!(relative[3] > 0.0 && relative[3] <= 1e-35 * relative[0]);
+
+    // XOR and ADD nodes with three inputs (computed with transforms) do not produce zeros
+    let priors = [
+        vec![0.5, 0.5, 0.0, 0.0],
+        vec![1.0, 0.0, 0.0, 0.0],
+        vec![0.5, 0.5, 0.0, 0.0],
+        vec![1.0, 0.0, 0.0, 0.0],
+    ];
+    for functype in [FuncType::XOR, FuncType::ADD] {
+        for marginal in single_node_marginals(functype, &priors, Clamp::Relative(1e-12)) {
+            let max = marginal.fold(0.0, |m: f64, x| m.max(*x));
+            assert!(marginal.iter().all(|x| *x >= 1e-12 * max));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use ndarray::{Array1, Array2};
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use scalib::belief_propagation::{self as bp, BPError, VarType};
+use scalib::circuit::Circuit;
+
+/// AES S-box: inverse in GF(2^8) followed by the affine map.
+fn aes_sbox() -> Array1<u32> {
+    let mul = |mut a: u32, mut b: u32| {
+        let mut res = 0;
+        while b != 0 {
+            if b & 1 != 0 {
+                res ^= a;
+            }
+            a <<= 1;
+            if a & 0x100 != 0 {
+                a ^= 0x11b;
+            }
+            b >>= 1;
+        }
+        res
+    };
+    (0..256)
+        .map(|x| {
+            let inv = (1..256).find(|y| mul(x, *y) == 1).unwrap_or(0);
+            let rot = |i: u32| ((inv << i) | (inv >> (8 - i))) & 0xff;
+            inv ^ rot(1) ^ rot(2) ^ rot(3) ^ rot(4) ^ 0x63
+        })
+        .collect()
+}
+
+fn hw(x: usize) -> f64 {
+    x.count_ones() as f64
+}
+
+#[test]
+fn aes_sbox_lookup() {
+    let (nc, n, key) = (256, 30, 0x2b);
+    let sbox = aes_sbox();
+    assert_eq!(&sbox.as_slice().unwrap()[..4], &[0x63, 0x7c, 0x77, 0x7b]);
+    let p = Array1::<u32>::random(n, Uniform::new(0, 256));
+    // Hamming weight leakage of t, with a gaussian likelihood.
+    let leakage = Array2::from_shape_fn((n, nc), |(i, v)| {
+        let t = sbox[(p[i] ^ key) as usize] as usize;
+        (-(hw(v) - hw(t)).powi(2) / 2.0).exp()
+    });
+
+    let mut circuit = Circuit::new(nc, n);
+    circuit.add_table("sbox", sbox).unwrap();
+    circuit.add_public("p", p).unwrap();
+    circuit.set_single("k");
+    circuit.set_prior("t", leakage);
+    circuit.compile("t = sbox[p ^ k] # first round").unwrap();
+    let k = circuit.var("k").unwrap();
+    let t = circuit.var("t").unwrap();
+    let mut graph = circuit.build().unwrap();
+    assert_eq!(graph.functions.len(), 2);
+    assert_eq!(graph.variables.len(), 3);
+    assert_eq!(graph.edge, 4);
+
+    bp::run_bp(
+        &graph.functions,
+        &mut graph.variables,
+        2,
+        graph.edge,
+        nc,
+        n,
+        false,
+    )
+    .unwrap();
+    assert!(matches!(
+        graph.variables[t].vartype,
+        VarType::ProfilePara { .. }
+    ));
+    let guesses = bp::best_guess(&graph.variables);
+    assert_eq!(guesses[k].0, Array1::from(vec![key as usize]));
+    assert!(guesses[k].1[0] > 0.99);
+}
+
+#[test]
+fn compile_errors() {
+    let mut circuit = Circuit::new(16, 2);
+    circuit.add_table("s", Array1::from_iter(0..16)).unwrap();
+    circuit.add_public("p", Array1::from(vec![1, 2])).unwrap();
+    circuit
+        .compile(
+            "
+        x = (a ^ p) + 3 * b
+        y = s[x] & a  # comment
+        ",
+        )
+        .unwrap();
+    // a, b, (a ^ p), 3 * b, x, s[x], y
+    assert_eq!(circuit.var("y"), Some(6));
+    let invalid = |desc: &str| match Circuit::new(16, 2).compile(desc) {
+        Err(BPError::InvalidGraph(msg)) => msg,
+        _ => panic!("'{}' should not compile.", desc),
+    };
+    assert_eq!(
+        invalid("x = a ^ b\nx = a & b"),
+        "line 2: 'x' is already defined."
+    );
+    assert_eq!(
+        invalid("x = x ^ a"),
+        "line 1: 'x' is used in its own definition."
+    );
+    assert_eq!(
+        invalid("x = a"),
+        "line 1: 'x' must be assigned an operation on variables."
+    );
+    assert_eq!(invalid("x = t[a]"), "line 1: unknown table 't'.");
+    assert_eq!(invalid("x = (a ^ b"), "line 1: expected ')'.");
+    assert_eq!(
+        invalid("x = a ^ 16"),
+        "line 1: constant 16 is not in [0, nc)."
+    );
+    assert_eq!(invalid("x = a ^ $"), "line 1: unexpected character '$'.");
+    assert!(circuit.compile("z = 3 ^ 4").is_err());
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "standalone")]
+
+use ndarray::Array2;
+use scalib::belief_propagation::BPError;
+use scalib::standalone;
+
+#[test]
+fn run_bp_from_file() {
+    // y = table[x] with x known to be in {0, 1} and y known to be in {2, 3}.
+    let desc = "
+        # table: x -> x+1 mod 4
+        GRAPH 4 2 2 1
+        VAR PARA 0 PRIOR 0 0 0.5 0.5   0 0 0.5 0.5 # y
+        VAR PARA 1 PRIOR 0.5 0.5 0 0   0.5 0.5 0 0 # x
+        FUNC LOOKUP 0 1 VALUES 1 2 3 0
+    ";
+    let path = std::env::temp_dir().join("scalib_run_bp_from_file.txt");
+    std::fs::write(&path, desc).unwrap();
+    let distris = standalone::run_bp_from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(distris.len(), 2);
+    let y = Array2::from_shape_vec((2, 4), vec![0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]).unwrap();
+    let x = Array2::from_shape_vec((2, 4), vec![0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0]).unwrap();
+    assert!(distris[0].abs_diff_eq(&y, 1e-9));
+    assert!(distris[1].abs_diff_eq(&x, 1e-9));
+
+    assert!(matches!(
+        standalone::parse_graph("GRAPH 4 2 2 1\nVAR PARA 0 1\nFUNC LOOKUP 0 1"),
+        Err(BPError::InvalidGraph(_))
+    ));
+    assert!(matches!(
+        standalone::parse_graph("GRAPH 4 2 2 1\nVAR PARA 0 1\nFUNC XOR 0 2"),
+        Err(BPError::InvalidGraph(_))
+    ));
+    assert!(matches!(
+        standalone::parse_graph("GRAPH 6 2 3 1\nVAR PARA 0 1 2\nFUNC NAND 0 1 2"),
+        Err(BPError::InvalidGraph(_))
+    ));
+    assert!(matches!(
+        standalone::parse_graph("GRAPH 4 2 2 1\nVAR PARA 0 1\nVAR PARA\nFUNC XOR 0 1"),
+        Err(BPError::DisconnectedNode {
+            id: 1,
+            is_function: false
+        })
+    ));
+}
@@ -0,0 +1,844 @@
+use ndarray::{s, Array1, Array2};
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use scalib::mttest::MTtest;
+use scalib::ttest::{PValueCorrection, Ttest, TtestError};
+use std::convert::TryInto;
+
+#[test]
+fn update_from_slice() {
+    let (n, ns, d) = (1000, 50, 3);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+
+    let flat: Vec<i16> = traces.iter().cloned().collect();
+    let mut ttest_slice = Ttest::new(ns, d);
+    // in two chunks
+    let split = 400;
+    ttest_slice
+        .update_from_slice(
+            &flat[..(split * ns)],
+            split,
+            &y.as_slice().unwrap()[..split],
+        )
+        .unwrap();
+    ttest_slice
+        .update_from_slice(
+            &flat[(split * ns)..],
+            n - split,
+            &y.as_slice().unwrap()[split..],
+        )
+        .unwrap();
+
+    assert!(ttest
+        .get_ttest()
+        .abs_diff_eq(&ttest_slice.get_ttest(), 1e-9));
+    assert!(ttest
+        .get_means()
+        .abs_diff_eq(&ttest_slice.get_means(), 1e-9));
+
+    assert_eq!(
+        ttest_slice.update_from_slice(&flat[1..], n, y.as_slice().unwrap()),
+        Err(TtestError::InvalidShape(format!(
+            "traces have {} values, expected {}.",
+            n * ns - 1,
+            n * ns
+        )))
+    );
+}
+
+#[test]
+fn invalid_class() {
+    let (n, ns, d) = (100, 5, 2);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-100, 100));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut ttest = Ttest::new(ns, d);
+    ttest.enable_ks(16);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let t = ttest.get_ttest();
+    let ks = ttest.get_ks();
+
+    let mut y_invalid = y.clone();
+    y_invalid[n - 1] = 2;
+    assert_eq!(
+        ttest.update(traces.view(), y_invalid.view()),
+        Err(TtestError::InvalidClass(
+            "class 2 is not 0 or 1.".to_owned()
+        ))
+    );
+    // the state is left unchanged
+    assert_eq!(ttest.get_ttest(), t);
+    assert_eq!(ttest.get_ks(), ks);
+}
+
+#[test]
+fn confidence_interval() {
+    // class 0: 1..=6 (mean 3.5, variance 3.5), class 1: 0, 0, 0, 4 (mean 1, variance 4)
+    let traces = Array2::from_shape_vec((10, 1), vec![1, 2, 3, 4, 5, 6, 0, 0, 0, 4]).unwrap();
+    let y = Array1::from(vec![0, 0, 0, 0, 0, 0, 1, 1, 1, 1]);
+    let mut ttest = Ttest::new(1, 1);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let ci = ttest.get_ci(0.05);
+    // se**2 = 3.5/6 + 4/4, dof = 6.2457..., t_{0.975}(dof) = 2.42377...
+    let se = (3.5f64 / 6.0 + 1.0).sqrt();
+    let dof = (3.5f64 / 6.0 + 1.0).powi(2) / ((3.5f64 / 6.0).powi(2) / 5.0 + 1.0 / 3.0);
+    assert!((dof - 6.24567).abs() < 1e-4);
+    let width = 2.0 * 2.42377 * se;
+    assert!((ci[[1, 0]] - ci[[0, 0]] - width).abs() < 1e-4);
+    assert!(((ci[[1, 0]] + ci[[0, 0]]) / 2.0 - 2.5).abs() < 1e-12);
+
+    // single trace in class 1
+    let mut ttest = Ttest::new(1, 1);
+    ttest
+        .update(
+            traces.slice(ndarray::s![..7, ..]),
+            y.slice(ndarray::s![..7]),
+        )
+        .unwrap();
+    assert!(ttest.get_ci(0.05).iter().all(|x| x.is_nan()));
+}
+
+#[cfg(feature = "fixed_point")]
+#[test]
+fn fixed_point() {
+    use scalib::ttest_fixed::FixedTtest;
+    let (n, ns, d) = (2000, 20, 3);
+    // large offset with respect to the spread, to stress the readout
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(10000, 12000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+
+    let mut ttest = Ttest::new(ns, d);
+    let mut fixed = FixedTtest::new(ns, d);
+    for (t, y) in traces
+        .axis_chunks_iter(ndarray::Axis(0), 300)
+        .zip(y.axis_chunks_iter(ndarray::Axis(0), 300))
+    {
+        ttest.update(t, y).unwrap();
+        fixed.update(t, y).unwrap();
+    }
+    // Both accumulations round (the fixed-point one only once the sums exceed 63 bits): they are
+    // expected to agree up to a relative error of about 1e-9 (see `ttest_fixed`).
+    let t_float = ttest.get_ttest();
+    let t_fixed = fixed.get_ttest();
+    assert_eq!(t_float.dim(), (d, ns));
+    t_float.iter().zip(t_fixed.iter()).for_each(|(a, b)| {
+        assert!((a - b).abs() <= 1e-9 * (1.0 + a.abs()), "{} {}", a, b);
+    });
+    assert!(ttest.get_means().abs_diff_eq(&fixed.get_means(), 1e-9));
+
+    assert_eq!(
+        fixed.update(traces.slice(ndarray::s![.., 1..]), y.view()),
+        Err(TtestError::InvalidShape(format!(
+            "traces have {} samples, expected {}.",
+            ns - 1,
+            ns
+        )))
+    );
+    let mut y_invalid = y.clone();
+    y_invalid[3] = 2;
+    assert_eq!(
+        fixed.update(traces.view(), y_invalid.view()),
+        Err(TtestError::InvalidClass(
+            "class 2 is not 0 or 1.".to_owned()
+        ))
+    );
+    // the state is left unchanged
+    assert_eq!(fixed.get_ttest(), t_fixed);
+}
+
+#[test]
+fn weighted_update() {
+    let (n, ns, d) = (300, 10, 3);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let w = Array1::<u32>::random(n, Uniform::new(0, 4));
+
+    // weights of 1.0 are the same as the unweighted update
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let mut ttest_ones = Ttest::new(ns, d);
+    ttest_ones
+        .update_weighted(traces.view(), y.view(), Array1::ones(n).view())
+        .unwrap();
+    assert_eq!(ttest.get_ttest(), ttest_ones.get_ttest());
+
+    // integer weights are the same as duplicating the traces
+    let idx: Vec<usize> = (0..n)
+        .flat_map(|i| std::iter::repeat(i).take(w[i] as usize))
+        .collect();
+    let traces_dup = traces.select(ndarray::Axis(0), &idx);
+    let y_dup = y.select(ndarray::Axis(0), &idx);
+    let mut ttest_dup = Ttest::new(ns, d);
+    ttest_dup.update(traces_dup.view(), y_dup.view()).unwrap();
+    let mut ttest_weighted = Ttest::new(ns, d);
+    let w = w.mapv(|x| x as f64);
+    // in two chunks
+    for (s, e) in [(0, 100), (100, n)].iter() {
+        ttest_weighted
+            .update_weighted(
+                traces.slice(ndarray::s![*s..*e, ..]),
+                y.slice(ndarray::s![*s..*e]),
+                w.slice(ndarray::s![*s..*e]),
+            )
+            .unwrap();
+    }
+    assert!(ttest_weighted
+        .get_ttest()
+        .abs_diff_eq(&ttest_dup.get_ttest(), 1e-8));
+    assert!(ttest_weighted
+        .get_means()
+        .abs_diff_eq(&ttest_dup.get_means(), 1e-9));
+    assert!(ttest_weighted
+        .get_variances()
+        .abs_diff_eq(&ttest_dup.get_variances(), 1e-6));
+
+    assert_eq!(
+        ttest_weighted.update_weighted(traces.view(), y.view(), (-&w).view()),
+        Err(TtestError::InvalidWeights(
+            "weights must be finite and non-negative.".to_owned()
+        ))
+    );
+
+    // not supported with the KS statistic and the robust center, and the state is left unchanged
+    let unsupported = Err(TtestError::Unsupported(
+        "weighted updates are not supported with KS and robust center.".to_owned(),
+    ));
+    let mut ttest_ks = Ttest::new(ns, d);
+    ttest_ks.enable_ks(16);
+    assert_eq!(
+        ttest_ks.update_weighted(traces.view(), y.view(), w.view()),
+        unsupported
+    );
+    assert_eq!(ttest_ks.get_n_samples(), Array1::<u64>::zeros(2));
+    let mut ttest_robust = Ttest::new(ns, d);
+    ttest_robust.enable_robust_center();
+    assert_eq!(
+        ttest_robust.update_weighted(traces.view(), y.view(), w.view()),
+        unsupported
+    );
+}
+
+#[test]
+fn pvalue_correction() {
+    // Welch test on known data, reference from a direct evaluation of the t cdf.
+    let traces =
+        Array2::from_shape_vec((14, 1), vec![0, 1, 2, 3, 4, 5, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap();
+    let y = Array1::from_vec(vec![0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]);
+    let mut ttest = Ttest::new(1, 1);
+    ttest.update(traces.view(), y.view()).unwrap();
+    assert!((ttest.get_ttest()[[0, 0]] + 3.74246964281016).abs() < 1e-12);
+    assert!((ttest.get_pvalue()[[0, 0]] - 0.0028107950740895).abs() < 1e-12);
+
+    let (n, ns, d) = (200, 50, 2);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let pvalue = ttest.get_pvalue();
+    assert_eq!(pvalue.shape(), &[d, ns]);
+    assert!(pvalue.iter().all(|p| *p >= 0.0 && *p <= 1.0));
+
+    // Bonferroni scales by the number of tests
+    let bonferroni = ttest.get_pvalue_corrected(PValueCorrection::Bonferroni);
+    assert_eq!(bonferroni, pvalue.mapv(|p| (p * (d * ns) as f64).min(1.0)));
+
+    // Benjamini-Hochberg lies between the raw and Bonferroni p-values, and keeps their order
+    let bh = ttest.get_pvalue_corrected(PValueCorrection::BenjaminiHochberg);
+    for ((p, b), h) in pvalue.iter().zip(bonferroni.iter()).zip(bh.iter()) {
+        assert!(*p <= *h && *h <= *b);
+    }
+    for (p1, h1) in pvalue.iter().zip(bh.iter()) {
+        for (p2, h2) in pvalue.iter().zip(bh.iter()) {
+            if p1 < p2 {
+                assert!(h1 <= h2);
+            }
+        }
+    }
+
+    // constant samples have NaN p-values, which are kept and are not counted as tests
+    let mut traces_cst = traces.clone();
+    traces_cst.column_mut(0).fill(7);
+    traces_cst.column_mut(1).fill(-3);
+    let mut ttest_cst = Ttest::new(ns, d);
+    ttest_cst.update(traces_cst.view(), y.view()).unwrap();
+    let mut ttest_ref = Ttest::new(ns - 2, d);
+    ttest_ref
+        .update(traces.slice(ndarray::s![.., 2..]), y.view())
+        .unwrap();
+    for method in [
+        PValueCorrection::Bonferroni,
+        PValueCorrection::BenjaminiHochberg,
+    ]
+    .iter()
+    {
+        let corrected = ttest_cst.get_pvalue_corrected(*method);
+        assert!(corrected
+            .slice(ndarray::s![.., ..2])
+            .iter()
+            .all(|p| p.is_nan()));
+        assert_eq!(
+            corrected.slice(ndarray::s![.., 2..]),
+            ttest_ref.get_pvalue_corrected(*method)
+        );
+    }
+}
+
+#[test]
+fn ttest_checkpoints() {
+    let (n, ns, d) = (1000, 20, 2);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let counts = vec![10, 31, 100, 316, 1000];
+
+    let mut ttest = Ttest::new(ns, d);
+    ttest.set_snapshot_interval(Some(250));
+    ttest.set_ttest_checkpoints(counts.clone());
+    // in chunks that do not match the checkpoints
+    for (s, e) in [(0, 50), (50, 400), (400, n)].iter() {
+        ttest
+            .update(
+                traces.slice(ndarray::s![*s..*e, ..]),
+                y.slice(ndarray::s![*s..*e]),
+            )
+            .unwrap();
+    }
+    let snapshots = ttest.take_ttest_checkpoints();
+    assert_eq!(snapshots.shape(), &[counts.len(), d, ns]);
+    for (snapshot, count) in snapshots.outer_iter().zip(counts.iter()) {
+        let mut ttest_ref = Ttest::new(ns, d);
+        ttest_ref
+            .update(
+                traces.slice(ndarray::s![..*count as usize, ..]),
+                y.slice(ndarray::s![..*count as usize]),
+            )
+            .unwrap();
+        assert!(snapshot.abs_diff_eq(&ttest_ref.get_ttest(), 1e-9));
+    }
+    assert_eq!(ttest.take_snapshots().len(), 4);
+    assert_eq!(ttest.take_ttest_checkpoints().shape(), &[0, d, ns]);
+}
+
+#[test]
+fn memory_bytes() {
+    for (ns, d) in [(1, 1), (100, 2), (5000, 3)].iter() {
+        let mut ttest = Ttest::new(*ns, *d);
+        // central sums, number of samples and sum of the weights
+        let bytes = 2 * ns * 2 * d * 8 + 2 * 8 + 2 * 8;
+        assert_eq!(ttest.memory_bytes(), bytes);
+        ttest.enable_ks(256);
+        assert_eq!(ttest.memory_bytes(), bytes + ns * 2 * 256 * 8);
+    }
+}
+
+#[test]
+fn window() {
+    let (n, ns, d) = (500, 30, 3);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let samples = vec![2, 3, 4, 5, 17, 29];
+
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let mut ttest_window = Ttest::new_window(ns, d, samples.clone());
+    ttest_window.update(traces.view(), y.view()).unwrap();
+    assert!(ttest_window.memory_bytes() < ttest.memory_bytes());
+
+    let results = [
+        (ttest.get_ttest(), ttest_window.get_ttest()),
+        (ttest.get_means(), ttest_window.get_means()),
+        (ttest.get_variances(), ttest_window.get_variances()),
+        (ttest.get_kurtosis(), ttest_window.get_kurtosis()),
+        (ttest.get_pvalue(), ttest_window.get_pvalue()),
+    ];
+    for (full, windowed) in results.iter() {
+        assert_eq!(windowed.shape(), full.shape());
+        for i in 0..ns {
+            if samples.contains(&i) {
+                assert_eq!(windowed.column(i), full.column(i));
+            } else {
+                assert!(windowed.column(i).iter().all(|x| x.is_nan()));
+            }
+        }
+    }
+}
+
+#[test]
+fn leaky_samples() {
+    let (n, ns, d) = (2000, 100, 2);
+    // deterministic pseudo-random noise, such that no other sample exceeds the threshold
+    let mut traces = Array2::from_shape_fn((n, ns), |(i, j)| {
+        ((i * 7919 + j * 104729 + i * j * 31) % 200) as i16 - 100
+    });
+    let y = Array1::from_shape_fn(n, |i| ((i * 2654435761) >> 7) as u16 & 1);
+    // first-order leakage in the window [40, 50)
+    for (mut trace, y) in traces.outer_iter_mut().zip(y.iter()) {
+        trace
+            .slice_mut(ndarray::s![40..50])
+            .mapv_inplace(|x| x + 50 * *y as i16);
+    }
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let leaky = ttest.get_leaky_samples(4.5);
+    assert_eq!(leaky.len(), d);
+    assert_eq!(leaky[0].0, Array1::from((40..50).collect::<Vec<usize>>()));
+    let t = ttest.get_ttest();
+    assert_eq!(leaky[0].1, leaky[0].0.mapv(|i| t[[0, i]]));
+    assert!(leaky[0].1.iter().all(|t| *t < -4.5));
+
+    let ratio = ttest.get_ttest_ratio(4.5);
+    assert!(ratio.abs_diff_eq(&t.mapv(|t| t / 4.5), 1e-12));
+    let above: Vec<usize> = (0..ns).filter(|i| ratio[[0, *i]].abs() > 1.0).collect();
+    assert_eq!(Array1::from(above), leaky[0].0);
+}
+
+#[test]
+fn mttest_third_order() {
+    let (n, ns) = (20000, 4);
+    // deterministic pseudo-random values
+    let rand = |x: usize| {
+        let mut z = (x as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z ^ (z >> 31)
+    };
+    let y = Array1::from_shape_fn(n, |i| (rand(4 * i) & 1) as u16);
+    // third-order boolean masking of y: the samples 0, 1 and 2 leak the shares, 3 is noise
+    let traces = Array2::from_shape_fn((n, ns), |(i, j)| {
+        let share = match j {
+            0 | 1 => rand(4 * i + 1 + j) & 1,
+            2 => (y[i] as u64) ^ (rand(4 * i + 1) & 1) ^ (rand(4 * i + 2) & 1),
+            _ => 0,
+        };
+        10 * share as i16 + (rand(n * 4 + i * ns + j) % 11) as i16 - 5
+    });
+
+    // no leakage for the univariate and bivariate tests
+    let mut ttest = Ttest::new(ns, 2);
+    ttest.update(traces.view(), y.view()).unwrap();
+    assert!(ttest.get_ttest().iter().all(|t| t.abs() < 4.5));
+    let mut mttest = MTtest::new(ns, Array2::from(vec![[0, 1], [0, 2], [1, 2]]));
+    mttest.update(traces.view(), y.view()).unwrap();
+    assert!(mttest.get_ttest().iter().all(|t| t.abs() < 4.5));
+
+    // in two chunks
+    let mut mttest = MTtest::new(ns, Array2::from(vec![[0, 1, 2], [0, 1, 3]]));
+    mttest
+        .update(traces.slice(s![..5000, ..]), y.slice(s![..5000]))
+        .unwrap();
+    mttest
+        .update(traces.slice(s![5000.., ..]), y.slice(s![5000..]))
+        .unwrap();
+    let t = mttest.get_ttest();
+    assert!(t[0].abs() > 4.5);
+    assert!(t[1].abs() < 4.5);
+    assert_eq!(mttest.memory_bytes(), 2 * 2 * (27 + 3) * 8 + 2 * 8);
+
+    // two-pass reference for the first tuple
+    let mut u = [0.0; 2];
+    let mut v = [0.0; 2];
+    let mut n_class = [0.0; 2];
+    for c in 0..2 {
+        let class: Vec<_> = traces
+            .outer_iter()
+            .zip(y.iter())
+            .filter(|(_, y)| **y == c as u16)
+            .map(|(trace, _)| trace.mapv(|x| x as f64))
+            .collect();
+        n_class[c] = class.len() as f64;
+        let means: Vec<f64> = (0..3)
+            .map(|i| class.iter().map(|trace| trace[i]).sum::<f64>() / n_class[c])
+            .collect();
+        let z: Vec<f64> = class
+            .iter()
+            .map(|trace| (0..3).map(|i| trace[i] - means[i]).product())
+            .collect();
+        u[c] = z.iter().sum::<f64>() / n_class[c];
+        v[c] = z.iter().map(|z| (z - u[c]).powi(2)).sum::<f64>() / n_class[c];
+    }
+    let expected = (u[0] - u[1]) / f64::sqrt(v[0] / n_class[0] + v[1] / n_class[1]);
+    assert!((t[0] - expected).abs() < 1e-6 * expected.abs());
+    let means = mttest.get_means();
+    assert!((means[[0, 0]] - u[0]).abs() < 1e-6 * u[0].abs());
+    assert!((means[[1, 0]] - u[1]).abs() < 1e-6 * u[1].abs());
+
+    let mut y_invalid = y.clone();
+    y_invalid[n - 1] = 2;
+    assert_eq!(
+        mttest.update(traces.view(), y_invalid.view()),
+        Err(TtestError::InvalidClass(
+            "class 2 is not 0 or 1.".to_owned()
+        ))
+    );
+    // the state is left unchanged
+    assert_eq!(mttest.get_ttest(), t);
+}
+
+#[test]
+fn ttest_at() {
+    let (n, ns, d) = (500, 20, 3);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let t = ttest.get_ttest();
+    for i in 0..ns {
+        assert_eq!(ttest.get_ttest_at(i).unwrap(), t.column(i));
+    }
+    assert!(matches!(
+        ttest.get_ttest_at(ns),
+        Err(TtestError::InvalidShape(_))
+    ));
+
+    let mut windowed = Ttest::new_window(ns, d, vec![3, 7]);
+    windowed.update(traces.view(), y.view()).unwrap();
+    assert_eq!(windowed.get_ttest_at(7).unwrap(), t.column(7));
+    assert!(windowed.get_ttest_at(5).unwrap().iter().all(|x| x.is_nan()));
+}
+
+#[test]
+fn write_ttest_npy() {
+    let (n, ns, d) = (200, 13, 2);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let path = std::env::temp_dir().join(format!("scalib_ttest_{}.npy", std::process::id()));
+    ttest.write_ttest_npy(&path).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+    let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+    assert_eq!((10 + header_len) % 64, 0);
+    let header = std::str::from_utf8(&bytes[10..(10 + header_len)]).unwrap();
+    assert!(header.contains("'descr': '<f8'"));
+    assert!(header.contains("'fortran_order': False"));
+    assert!(header.contains("'shape': (2, 13)"));
+    assert!(header.ends_with('\n'));
+    let data: Vec<f64> = bytes[(10 + header_len)..]
+        .chunks(8)
+        .map(|x| f64::from_le_bytes(x.try_into().unwrap()))
+        .collect();
+    assert_eq!(
+        Array2::from_shape_vec((d, ns), data).unwrap(),
+        ttest.get_ttest()
+    );
+}
+
+#[test]
+fn save_load() {
+    let (n, ns, d) = (300, 11, 2);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut ttest = Ttest::new_window(ns, d, vec![1, 4, 9]);
+    ttest.enable_ks(16);
+    ttest.enable_robust_center();
+    ttest.set_variance_floor(Some(2.0));
+    ttest.update(traces.view(), y.view()).unwrap();
+    let path = std::env::temp_dir().join(format!("scalib_ttest_{}.state", std::process::id()));
+    ttest.save(&path).unwrap();
+    let loaded = Ttest::load(&path).unwrap();
+    std::fs::write(&path, b"not a saved Ttest state").unwrap();
+    let err = Ttest::load(&path).err().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // the samples outside of the window are NaN
+    let same = |a: &[f64], b: &[f64]| {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b.iter())
+                .all(|(a, b)| a == b || (a.is_nan() && b.is_nan()))
+    };
+    assert_eq!(loaded.get_n_samples(), ttest.get_n_samples());
+    assert!(same(
+        loaded.get_ks().as_slice().unwrap(),
+        ttest.get_ks().as_slice().unwrap()
+    ));
+    assert!(same(
+        loaded.get_medians().as_slice().unwrap(),
+        ttest.get_medians().as_slice().unwrap()
+    ));
+    assert!(same(
+        loaded.get_ttest().as_slice().unwrap(),
+        ttest.get_ttest().as_slice().unwrap()
+    ));
+}
+
+#[test]
+fn checkpoint_resume() {
+    let (n, ns, d) = (1000, 20, 3);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut reference = Ttest::new(ns, d);
+    reference.update(traces.view(), y.view()).unwrap();
+
+    let path = std::env::temp_dir().join(format!("scalib_ttest_{}.ckpt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let interval = 128;
+    // first run, which "crashes" after 700 traces: only the last checkpoint is kept
+    let mut ttest = Ttest::resume(Ttest::new(ns, d), path.clone(), interval).unwrap();
+    assert_eq!(ttest.get_n_samples().sum(), 0);
+    ttest
+        .update(traces.slice(s![..300, ..]), y.slice(s![..300]))
+        .unwrap();
+    ttest
+        .update(traces.slice(s![300..700, ..]), y.slice(s![300..700]))
+        .unwrap();
+    drop(ttest);
+
+    // second run, resuming from the checkpoint
+    let mut ttest = Ttest::resume(Ttest::new(ns, d), path.clone(), interval).unwrap();
+    let resumed = ttest.get_n_samples().sum() as usize;
+    assert_eq!(resumed, 640);
+    ttest
+        .update(traces.slice(s![resumed.., ..]), y.slice(s![resumed..]))
+        .unwrap();
+    assert!(matches!(
+        Ttest::resume(Ttest::new(ns, d + 1), path.clone(), interval),
+        Err(TtestError::InvalidShape(_))
+    ));
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(ttest.get_n_samples(), reference.get_n_samples());
+    let (t, t_ref) = (ttest.get_ttest(), reference.get_ttest());
+    t.iter()
+        .zip(t_ref.iter())
+        .for_each(|(a, b)| assert!((a - b).abs() < 1e-8, "{} {}", a, b));
+}
+
+#[test]
+fn walsh_spectrum() {
+    let (n, ns, d) = (4000, 16, 1);
+    let mut traces = Array2::<i16>::random((n, ns), Uniform::new(-100, 100));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    // class 1 leaks along the Walsh function of index 5
+    for (mut t, y) in traces.outer_iter_mut().zip(y.iter()) {
+        if *y == 1 {
+            t.indexed_iter_mut()
+                .for_each(|(i, x)| *x += 50 * (1 - 2 * ((i & 5).count_ones() % 2) as i16));
+        }
+    }
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let spectrum = ttest.get_walsh_spectrum().unwrap();
+    for k in 1..ns {
+        let expected = if k == 5 { 50.0 } else { 0.0 };
+        assert!((spectrum[[1, k]] - expected).abs() < 5.0);
+        assert!(spectrum[[0, k]].abs() < 5.0);
+    }
+    assert!((spectrum[[0, 0]] - ttest.get_means().row(0).mean().unwrap()).abs() < 1e-9);
+
+    assert!(matches!(
+        Ttest::new(12, d).get_walsh_spectrum(),
+        Err(TtestError::InvalidShape(_))
+    ));
+}
+
+#[test]
+fn bessel_correction() {
+    let (ns, d) = (10, 3);
+    for n in [10, 20000].iter() {
+        let n = *n;
+        let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+        // same number of traces in both classes
+        let y = Array1::from_shape_fn(n, |i| (i % 2) as u16);
+        let mut ttest = Ttest::new(ns, d);
+        ttest.update(traces.view(), y.view()).unwrap();
+        let t_pop = ttest.get_ttest();
+        ttest.set_bessel_correction(true);
+        let t_bessel = ttest.get_ttest();
+        // with equal class sizes, the correction scales the statistic by sqrt((n-1)/n)
+        let n_class = (n / 2) as f64;
+        let ratio = (&t_pop * &t_pop) / (&t_bessel * &t_bessel);
+        assert!(ratio
+            .iter()
+            .all(|r| (r - n_class / (n_class - 1.0)).abs() < 1e-9));
+        if n > 1000 {
+            assert!(t_pop.abs_diff_eq(&t_bessel, 1e-2 * t_pop.fold(1.0, |m, t| t.abs().max(m))));
+        }
+    }
+}
+
+#[test]
+fn zero_variance() {
+    let (n, ns, d) = (500, 10, 2);
+    let mut traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    // sample 3 is constant in each class (but different between the classes), and sample 5 has
+    // a small variance
+    traces.column_mut(3).assign(&y.mapv(|y| 42 + y as i16));
+    traces
+        .column_mut(5)
+        .assign(&Array1::<i16>::random(n, Uniform::new(0, 2)));
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let t = ttest.get_ttest();
+    assert!(t.column(3).iter().all(|x| x.is_nan()));
+    assert!(t.iter().all(|x| !x.is_infinite()));
+    assert!(ttest.get_pvalue().column(3).iter().all(|x| x.is_nan()));
+    assert!(ttest.get_ttest_at(3).unwrap().iter().all(|x| x.is_nan()));
+    assert_eq!(ttest.zero_variance_count(), 1);
+    assert!(ttest.get_zero_variance()[3]);
+
+    ttest.set_variance_tolerance(1.0);
+    assert_eq!(ttest.zero_variance_count(), 2);
+    assert!(ttest.get_ttest().column(5).iter().all(|x| x.is_nan()));
+    let others = ttest
+        .get_ttest()
+        .select(ndarray::Axis(1), &[0, 1, 2, 4, 6, 7, 8, 9]);
+    assert!(others.iter().all(|x| x.is_finite()));
+}
+
+#[test]
+fn concat_samples() {
+    let (n, ns, d) = (500, 20, 3);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut full = Ttest::new(ns, d);
+    full.update(traces.view(), y.view()).unwrap();
+
+    let mut first = Ttest::new(ns / 2, d);
+    first
+        .update(traces.slice(s![.., ..(ns / 2)]), y.view())
+        .unwrap();
+    let mut second = Ttest::new(ns - ns / 2, d);
+    second
+        .update(traces.slice(s![.., (ns / 2)..]), y.view())
+        .unwrap();
+    let concat = first.concat_samples(&second).unwrap();
+    assert_eq!(concat.get_ttest(), full.get_ttest());
+    assert_eq!(concat.get_means(), full.get_means());
+
+    // windows are shifted by the number of samples of the first state
+    let mut windowed = Ttest::new_window(ns - ns / 2, d, vec![1, 4]);
+    windowed
+        .update(traces.slice(s![.., (ns / 2)..]), y.view())
+        .unwrap();
+    let t = first.concat_samples(&windowed).unwrap().get_ttest();
+    assert_eq!(t.shape(), &[d, ns]);
+    assert_eq!(t.column(ns / 2 + 4), full.get_ttest().column(ns / 2 + 4));
+    assert!(t.column(ns / 2).iter().all(|x| x.is_nan()));
+
+    // different trace sets
+    let mut other = Ttest::new(ns - ns / 2, d);
+    other
+        .update(
+            traces.slice(s![..(n / 2), (ns / 2)..]),
+            y.slice(s![..(n / 2)]),
+        )
+        .unwrap();
+    assert!(matches!(
+        first.concat_samples(&other),
+        Err(TtestError::InvalidShape(_))
+    ));
+}
+
+#[test]
+fn moment_convergence() {
+    let (n, ns, d, window) = (20000, 5, 2, 1000);
+    // offset noise with rare large spikes: the mean converges much faster than the kurtosis
+    let noise = Array2::<i16>::random((n, ns), Uniform::new(-100, 100));
+    let spikes =
+        Array2::<i16>::random((n, ns), Uniform::new(0, 100))
+            .mapv(|x| if x == 0 { 3000 } else { 0 });
+    let traces = noise + spikes + 10000;
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let mut ttest = Ttest::new(ns, d);
+    ttest.set_convergence_window(Some(window));
+    let mut first_stable = [None; 4];
+    for (i, start) in (0..n).step_by(700).enumerate() {
+        let end = std::cmp::min(n, start + 700);
+        ttest
+            .update(traces.slice(s![start..end, ..]), y.slice(s![start..end]))
+            .unwrap();
+        let convergence = ttest.get_moment_convergence();
+        assert_eq!(convergence.len(), 2 * d);
+        if end < 2 * window as usize {
+            assert!(convergence.iter().all(|x| x.is_nan()));
+            continue;
+        }
+        for (order, first) in first_stable.iter_mut().enumerate() {
+            if first.is_none() && convergence[order] < 1e-3 {
+                *first = Some(i);
+            }
+        }
+    }
+    let first_stable: Vec<usize> = first_stable
+        .iter()
+        .map(|x| x.unwrap_or(usize::MAX))
+        .collect();
+    assert!(first_stable[0] < usize::MAX);
+    assert!(first_stable[0] < first_stable[3]);
+    let convergence = ttest.get_moment_convergence();
+    assert!(convergence[0] < convergence[3]);
+}
+
+#[test]
+fn variance_floor() {
+    let (n, ns, d) = (500, 4, 1);
+    let mut traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let mut y = Array1::<u16>::zeros(n);
+    // class 1 only has 4 traces, with a tiny variance for sample 2 (and a zero one in class 0)
+    y.slice_mut(s![..4]).fill(1);
+    traces
+        .slice_mut(s![..4, 2])
+        .assign(&Array1::from(vec![0, 0, 0, 1]));
+    traces.slice_mut(s![4.., 2]).fill(2);
+    let mut ttest = Ttest::new(ns, d);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let spike = ttest.get_ttest()[[0, 2]];
+    assert!(spike.abs() > 4.5);
+
+    ttest.set_variance_floor(Some(100.0));
+    let t = ttest.get_ttest();
+    assert!(t[[0, 2]].abs() < 1.0);
+    // the samples with large variances are not changed
+    ttest.set_variance_floor(None);
+    assert_eq!(ttest.get_ttest()[[0, 2]], spike);
+    assert_eq!(ttest.get_ttest().column(0), t.column(0));
+}
+
+#[test]
+fn interrupt() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    let (n, ns, d, chunk) = (1000, 8, 2, 150);
+    let traces = Array2::<i16>::random((n, ns), Uniform::new(-1000, 1000));
+    let y = Array1::<u16>::random(n, Uniform::new(0, 2));
+    let flag = Arc::new(AtomicBool::new(false));
+
+    // not interrupted
+    let mut ttest = Ttest::new(ns, d);
+    ttest.set_interrupt_flag(Some(flag.clone()), chunk);
+    ttest.update(traces.view(), y.view()).unwrap();
+    let mut reference = Ttest::new(ns, d);
+    reference.update(traces.view(), y.view()).unwrap();
+    assert_eq!(ttest.get_ttest(), reference.get_ttest());
+
+    // the flag is set during the batch, and seen after the first chunk
+    let mut ttest = Ttest::new(ns, d);
+    ttest.set_interrupt_flag(Some(flag.clone()), chunk);
+    flag.store(true, Ordering::Relaxed);
+    assert_eq!(
+        ttest.update(traces.view(), y.view()),
+        Err(TtestError::Interrupted { applied: chunk })
+    );
+    let mut prefix = Ttest::new(ns, d);
+    prefix
+        .update(traces.slice(s![..chunk, ..]), y.slice(s![..chunk]))
+        .unwrap();
+    assert_eq!(ttest.get_n_samples(), prefix.get_n_samples());
+    assert_eq!(ttest.get_n_samples().sum(), chunk as u64);
+    assert_eq!(ttest.get_ttest(), prefix.get_ttest());
+
+    // resume with the remaining traces
+    flag.store(false, Ordering::Relaxed);
+    ttest
+        .update(traces.slice(s![chunk.., ..]), y.slice(s![chunk..]))
+        .unwrap();
+    assert_eq!(ttest.get_n_samples(), reference.get_n_samples());
+    assert_eq!(ttest.get_ttest(), reference.get_ttest());
+}
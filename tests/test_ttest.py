@@ -1,22 +1,28 @@
 import pytest
-from scalib.metrics import Ttest
+from scalib.metrics import Ttest, MTtest
+from scalib import _scalib_ext
 import numpy as np
 import scipy.stats
 
 
-def reference(traces, x, D):
+def reference(traces, x, D, center=None):
+    # center: None (the means), shape (ns,) for both classes or (2, ns) for each class
+    if center is not None:
+        center = np.reshape(center, (-1, traces.shape[1]))
 
     CM0 = np.zeros((D * 2, traces.shape[1]))
     I0 = np.where(x == 0)[0]
     u0 = np.mean(traces[I0, :], axis=0)
+    c0 = u0 if center is None else center[0]
     for d in range(D * 2):
-        CM0[d, :] = np.mean((traces[I0, :] - u0) ** (d + 1), axis=0)
+        CM0[d, :] = np.mean((traces[I0, :] - c0) ** (d + 1), axis=0)
 
     CM1 = np.zeros((D * 2, traces.shape[1]))
     I1 = np.where(x == 1)[0]
     u1 = np.mean(traces[I1, :], axis=0)
+    c1 = u1 if center is None else center[-1]
     for d in range(D * 2):
-        CM1[d, :] = np.mean((traces[I1, :] - u1) ** (d + 1), axis=0)
+        CM1[d, :] = np.mean((traces[I1, :] - c1) ** (d + 1), axis=0)
     u1_ref = u1
     u0_ref = u0
     t = np.zeros((D, len(traces[0, :])))
@@ -25,8 +31,8 @@ def reference(traces, x, D):
         if d == 1:
             u0 = u0_ref
             u1 = u1_ref
-            v0 = CM0[1, :]
-            v1 = CM1[1, :]
+            v0 = np.var(traces[I0, :], axis=0)
+            v1 = np.var(traces[I1, :], axis=0)
         elif d == 2:
             u0 = CM0[1, :]
             u1 = CM1[1, :]
@@ -113,3 +119,501 @@ def test_ttest_d6():
     ttest.fit_u(traces, labels)
     t = ttest.get_ttest()
     assert np.allclose(t_ref, t, rtol=1e-3)
+
+
+def test_skewness_kurtosis():
+    ns = 100
+    d = 2
+    n = 500
+
+    np.random.seed(0)
+    traces = np.random.randint(0, 10, (n, ns), dtype=np.int16)
+    traces = (traces**2 // 10).astype(np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    skew = ttest.get_skewness()
+    kurt = ttest.get_kurtosis()
+    for c in range(2):
+        t = traces[labels == c, :]
+        assert np.allclose(scipy.stats.skew(t, axis=0), skew[c], rtol=1e-5)
+        assert np.allclose(
+            scipy.stats.kurtosis(t, axis=0, fisher=False), kurt[c], rtol=1e-5
+        )
+
+    with pytest.raises(ValueError):
+        Ttest(ns, 1).get_skewness()
+
+
+def test_snapshots():
+    ns = 10
+    d = 1
+    n = 1000
+
+    traces = np.random.randint(0, 10, (n, ns), dtype=np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+
+    ttest = Ttest(ns, d)
+    ttest.set_snapshot_interval(100)
+    ttest.fit_u(traces[:250], labels[:250])
+    ttest.fit_u(traces[250:], labels[250:])
+    snapshots = ttest.take_snapshots()
+    assert len(snapshots) == n // 100
+    for i, (n_samples, means, variances) in enumerate(snapshots):
+        k = (i + 1) * 100
+        ttest_ref = Ttest(ns, d)
+        ttest_ref.fit_u(traces[:k], labels[:k])
+        assert np.sum(n_samples) == k
+        assert np.allclose(means, ttest_ref.get_means())
+        assert np.allclose(variances, ttest_ref.get_variances())
+    assert ttest.take_snapshots() == []
+
+
+
+def test_ks():
+    ns = 10
+    n = 2000
+
+    np.random.seed(0)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    # same mean, different distributions
+    traces = np.random.choice([-100, 100], (n, ns)).astype(np.int16)
+    traces[labels == 1] = np.random.randint(-5, 6, (np.sum(labels == 1), ns))
+
+    ttest = Ttest(ns, 1, ks_bins=2**16)
+    ttest.fit_u(traces, labels)
+    assert np.all(np.abs(ttest.get_ttest()) < 5)
+    ks = ttest.get_ks()
+    ks_ref = [
+        scipy.stats.ks_2samp(traces[labels == 0, i], traces[labels == 1, i]).statistic
+        for i in range(ns)
+    ]
+    assert np.allclose(ks, ks_ref)
+    assert np.all(ks > 0.4)
+
+    with pytest.raises(ValueError):
+        Ttest(ns, 1).get_ks()
+
+
+def test_wrong_shape():
+    ns = 10
+    n = 20
+    traces = np.random.randint(0, 10, (n, ns + 1), dtype=np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    ttest = _scalib_ext.Ttest(ns, 1)
+    with pytest.raises(ValueError, match="traces have 11 samples, expected 10"):
+        ttest.update(traces, labels)
+    with pytest.raises(ValueError, match="20 traces but 19 classes"):
+        ttest.update(traces[:, :ns], labels[:-1])
+
+
+def test_ttest_reference_center():
+    ns = 100
+    d = 3
+    nc = 2
+    n = 200
+
+    m = np.random.randint(0, 2, (nc, ns))
+    traces = np.random.randint(0, 10, (n, ns), dtype=np.int16)
+    labels = np.random.randint(0, nc, n, dtype=np.uint16)
+    traces += m[labels]
+    center = np.random.uniform(3.0, 6.0, ns)
+
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    t = ttest.get_ttest(center)
+    assert np.allclose(reference(traces, labels, d, center), t, rtol=1e-3)
+    # first order is not affected by the center
+    t_emp = ttest.get_ttest()
+    assert np.allclose(t_emp[0], t[0])
+    assert not np.allclose(t_emp[1:], t[1:], rtol=1e-3)
+
+
+def test_ttest_robust_center():
+    ns = 5
+    n = 20000
+    d = 3
+
+    np.random.seed(0)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    traces = np.random.normal(0, 10, (n, ns)).round().astype(np.int16)
+    outliers = traces.copy()
+    outliers[::400] = 3000
+
+    ttest = Ttest(ns, d, robust_center=True)
+    ttest.fit_u(traces, labels)
+    ttest_out = Ttest(ns, d, robust_center=True)
+    ttest_out.fit_u(outliers, labels)
+
+    for c in range(2):
+        median = np.median(traces[labels == c], axis=0)
+        assert np.allclose(ttest.get_medians()[c], median, atol=1.0)
+    # outliers shift the means but barely move the medians
+    means_shift = np.abs(ttest_out.get_means() - ttest.get_means())
+    medians_shift = np.abs(ttest_out.get_medians() - ttest.get_medians())
+    assert np.all(means_shift > 5.0)
+    assert np.all(medians_shift < 1.0)
+    # order 1 is not affected by the centering
+    t = ttest_out.get_ttest()
+    t_robust = ttest_out.get_ttest_robust()
+    assert np.allclose(t[0], t_robust[0])
+    assert t_robust.shape == (d, ns)
+
+    # a large DC offset does not change the statistics, that match the f64 reference
+    offset = traces + 20000
+    ttest_offset = Ttest(ns, d, robust_center=True)
+    ttest_offset.fit_u(offset, labels)
+    t_offset = ttest_offset.get_ttest_robust()
+    t_ref = reference(offset.astype(np.float64), labels, d, ttest_offset.get_medians())
+    assert np.allclose(t_offset, t_ref, rtol=1e-6)
+    assert np.allclose(t_offset, ttest.get_ttest_robust(), rtol=1e-6)
+
+    with pytest.raises(ValueError):
+        Ttest(ns, d).get_ttest_robust()
+
+
+def test_ci():
+    ns = 4
+    n = 500
+    alpha = 0.05
+
+    np.random.seed(0)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    traces = np.random.randint(-100, 100, (n, ns), dtype=np.int16)
+    traces[labels == 1] += 10
+
+    ttest = Ttest(ns, 1)
+    ttest.fit_u(traces, labels)
+    ci = ttest.get_ci(alpha)
+
+    t0 = traces[labels == 0].astype(np.float64)
+    t1 = traces[labels == 1].astype(np.float64)
+    s0 = np.var(t0, axis=0, ddof=1) / len(t0)
+    s1 = np.var(t1, axis=0, ddof=1) / len(t1)
+    dof = (s0 + s1) ** 2 / (s0 ** 2 / (len(t0) - 1) + s1 ** 2 / (len(t1) - 1))
+    width = 2 * scipy.stats.t.ppf(1 - alpha / 2, dof) * np.sqrt(s0 + s1)
+    diff = np.mean(t0, axis=0) - np.mean(t1, axis=0)
+    assert np.allclose(ci[1] - ci[0], width)
+    assert np.allclose((ci[1] + ci[0]) / 2, diff)
+
+    ttest = Ttest(ns, 1)
+    ttest.fit_u(traces[:1], labels[:1])
+    assert np.all(np.isnan(ttest.get_ci(alpha)))
+    with pytest.raises(ValueError):
+        ttest.get_ci(1.5)
+
+
+def test_weights():
+    ns = 10
+    d = 3
+    n = 300
+
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    traces = np.random.randint(-100, 100, (n, ns), dtype=np.int16)
+    weights = np.random.randint(0, 4, n)
+
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels, weights=weights.astype(np.float64))
+
+    idx = np.repeat(np.arange(n), weights)
+    ttest_dup = Ttest(ns, d)
+    ttest_dup.fit_u(traces[idx], labels[idx])
+    assert np.allclose(ttest.get_ttest(), ttest_dup.get_ttest())
+    assert np.allclose(ttest.get_means(), ttest_dup.get_means())
+
+    ttest_ones = Ttest(ns, d)
+    ttest_ones.fit_u(traces, labels, weights=np.ones(n))
+    ttest_ref = Ttest(ns, d)
+    ttest_ref.fit_u(traces, labels)
+    assert np.array_equal(ttest_ones.get_ttest(), ttest_ref.get_ttest())
+
+    with pytest.raises(ValueError):
+        ttest.fit_u(traces, labels, weights=-np.ones(n))
+    with pytest.raises(ValueError):
+        ttest.fit_u(traces, labels, weights=np.ones(n - 1))
+    with pytest.raises(ValueError):
+        Ttest(ns, d, ks_bins=16).fit_u(traces, labels, weights=np.ones(n))
+
+
+def test_interruptible():
+    ns = 5
+    d = 2
+    n = 10000
+
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    traces = np.random.randint(-100, 100, (n, ns), dtype=np.int16)
+    weights = np.random.randint(0, 4, n).astype(np.float64)
+
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    ttest_chunks = Ttest(ns, d)
+    ttest_chunks.fit_u(traces, labels, interruptible=True)
+    assert np.allclose(ttest.get_ttest(), ttest_chunks.get_ttest())
+
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels, weights=weights)
+    ttest_chunks = Ttest(ns, d)
+    ttest_chunks.fit_u(traces, labels, weights=weights, interruptible=True)
+    assert np.allclose(ttest.get_ttest(), ttest_chunks.get_ttest())
+
+    # invalid weights are rejected before any chunk is applied
+    weights[-1] = -1.0
+    t = ttest_chunks.get_ttest()
+    with pytest.raises(ValueError):
+        ttest_chunks.fit_u(traces, labels, weights=weights, interruptible=True)
+    assert np.array_equal(t, ttest_chunks.get_ttest())
+
+
+def test_pvalue():
+    ns = 10
+    n = 500
+
+    np.random.seed(0)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    traces = np.random.randint(-100, 100, (n, ns), dtype=np.int16)
+    traces[labels == 1, :2] += 20
+
+    ttest = Ttest(ns, 2)
+    ttest.fit_u(traces, labels)
+    pvalue = ttest.get_pvalue()
+    assert pvalue.shape == (2, ns)
+
+    t0 = traces[labels == 0].astype(np.float64)
+    t1 = traces[labels == 1].astype(np.float64)
+    s0 = np.var(t0, axis=0) / len(t0)
+    s1 = np.var(t1, axis=0) / len(t1)
+    dof = (s0 + s1) ** 2 / (s0 ** 2 / (len(t0) - 1) + s1 ** 2 / (len(t1) - 1))
+    t = ttest.get_ttest()[0]
+    assert np.allclose(pvalue[0], 2 * scipy.stats.t.sf(np.abs(t), dof))
+
+    bonferroni = ttest.get_pvalue_corrected("bonferroni")
+    assert np.allclose(bonferroni, np.minimum(pvalue * pvalue.size, 1))
+    bh = ttest.get_pvalue_corrected("bh")
+    assert np.all((pvalue <= bh) & (bh <= bonferroni))
+    with pytest.raises(ValueError):
+        ttest.get_pvalue_corrected("holm")
+
+
+def test_ttest_checkpoints():
+    ns = 10
+    d = 2
+    n = 1000
+
+    traces = np.random.randint(0, 10, (n, ns), dtype=np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    counts = np.logspace(1, 3, 5).astype(int)
+
+    ttest = Ttest(ns, d)
+    ttest.set_ttest_checkpoints(counts)
+    ttest.fit_u(traces[:250], labels[:250])
+    ttest.fit_u(traces[250:], labels[250:])
+    checkpoints = ttest.take_ttest_checkpoints()
+    assert checkpoints.shape == (len(counts), d, ns)
+    for k, t in zip(counts, checkpoints):
+        ttest_ref = Ttest(ns, d)
+        ttest_ref.fit_u(traces[:k], labels[:k])
+        assert np.allclose(t, ttest_ref.get_ttest())
+    assert ttest.take_ttest_checkpoints().shape == (0, d, ns)
+    with pytest.raises(ValueError):
+        ttest.set_ttest_checkpoints([2000, 1500])
+    with pytest.raises(ValueError):
+        ttest.set_ttest_checkpoints([500])
+
+
+def test_ttest_samples():
+    ns = 20
+    d = 2
+    n = 500
+
+    traces = np.random.randint(0, 100, (n, ns), dtype=np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    samples = range(5, 12)
+
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    ttest_window = Ttest(ns, d, samples=samples)
+    ttest_window.fit_u(traces, labels)
+
+    t = ttest_window.get_ttest()
+    assert t.shape == (d, ns)
+    assert np.allclose(t[:, samples], ttest.get_ttest()[:, samples])
+    mask = np.ones(ns, dtype=bool)
+    mask[samples] = False
+    assert np.all(np.isnan(t[:, mask]))
+    with pytest.raises(ValueError):
+        Ttest(ns, d, samples=[3, 2])
+    with pytest.raises(ValueError):
+        Ttest(ns, d, samples=[ns])
+
+
+def test_leaky_samples():
+    ns = 100
+    d = 2
+    n = 2000
+
+    np.random.seed(0)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    traces = np.random.randint(-100, 100, (n, ns), dtype=np.int16)
+    traces[labels == 1, 40:50] += 50
+
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    t = ttest.get_ttest()
+    leaky = ttest.get_leaky_samples(4.5)
+    assert len(leaky) == d
+    for (indices, values), t_d in zip(leaky, t):
+        assert np.array_equal(indices, np.nonzero(np.abs(t_d) > 4.5)[0])
+        assert np.allclose(values, t_d[indices])
+    assert np.array_equal(leaky[0][0], np.arange(40, 50))
+
+    ratio = ttest.get_ttest_ratio()
+    assert np.allclose(ratio, t / 4.5)
+    assert np.array_equal(np.nonzero(np.abs(ratio[0]) > 1.0)[0], leaky[0][0])
+    assert np.allclose(ttest.get_ttest_ratio(2.0), t / 2.0)
+    with pytest.raises(ValueError):
+        ttest.get_ttest_ratio(0.0)
+
+
+def test_mttest():
+    ns = 4
+    n = 20000
+
+    np.random.seed(0)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    shares = np.random.randint(0, 2, (n, 3), dtype=np.int16)
+    shares[:, 2] = labels ^ shares[:, 0] ^ shares[:, 1]
+    traces = np.random.randint(-5, 6, (n, ns), dtype=np.int16)
+    traces[:, :3] += 10 * shares
+
+    tuples = np.array([[0, 1, 2], [0, 1, 3]])
+    mttest = MTtest(ns, tuples)
+    mttest.fit_u(traces[: n // 2], labels[: n // 2])
+    mttest.fit_u(traces[n // 2 :], labels[n // 2 :])
+    t = mttest.get_ttest()
+
+    u = np.zeros((2, len(tuples)))
+    v = np.zeros((2, len(tuples)))
+    n_class = np.zeros(2)
+    for c in range(2):
+        l = traces[labels == c].astype(np.float64)
+        n_class[c] = len(l)
+        l -= np.mean(l, axis=0)
+        z = np.stack([np.prod(l[:, tup], axis=1) for tup in tuples])
+        u[c] = np.mean(z, axis=1)
+        v[c] = np.var(z, axis=1)
+    t_ref = (u[0] - u[1]) / np.sqrt(v[0] / n_class[0] + v[1] / n_class[1])
+    assert np.allclose(t, t_ref, rtol=1e-6)
+    assert np.allclose(mttest.get_means(), u, rtol=1e-6)
+    assert abs(t[0]) > 4.5
+
+    with pytest.raises(ValueError):
+        MTtest(ns, [[0, ns]])
+
+
+def test_ttest_at():
+    ns = 20
+    d = 3
+    n = 500
+
+    traces = np.random.randint(-1000, 1000, (n, ns), dtype=np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    t = ttest.get_ttest()
+    for i in range(ns):
+        assert np.array_equal(ttest.get_ttest_at(i), t[:, i])
+    with pytest.raises(ValueError):
+        ttest.get_ttest_at(ns)
+
+
+def test_zero_variance():
+    ns = 10
+    d = 2
+    n = 500
+
+    traces = np.random.randint(-1000, 1000, (n, ns), dtype=np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    # constant in each set, but different between the sets
+    traces[:, 3] = 42 + labels
+    traces[:, 5] = np.random.randint(0, 2, n)
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    t = ttest.get_ttest()
+    assert np.all(np.isnan(t[:, 3]))
+    assert not np.any(np.isinf(t))
+    assert np.count_nonzero(ttest.get_zero_variance()) == 1
+
+    ttest = Ttest(ns, d, variance_tol=1.0)
+    ttest.fit_u(traces, labels)
+    assert np.array_equal(np.nonzero(ttest.get_zero_variance())[0], [3, 5])
+    assert np.all(np.isnan(ttest.get_ttest()[:, [3, 5]]))
+
+    with pytest.raises(ValueError):
+        Ttest(ns, d, variance_tol=-1.0)
+
+
+def test_concat_samples():
+    ns = 20
+    d = 3
+    n = 500
+
+    traces = np.random.randint(-1000, 1000, (n, ns), dtype=np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    full = Ttest(ns, d)
+    full.fit_u(traces, labels)
+    first = Ttest(ns // 2, d)
+    first.fit_u(traces[:, : ns // 2], labels)
+    second = Ttest(ns - ns // 2, d)
+    second.fit_u(traces[:, ns // 2 :], labels)
+    concat = first.concat_samples(second)
+    assert np.allclose(concat.get_ttest(), full.get_ttest())
+    assert np.allclose(concat.get_ttest_at(ns - 1), full.get_ttest_at(ns - 1))
+
+    other = Ttest(ns - ns // 2, d)
+    other.fit_u(traces[: n // 2, ns // 2 :], labels[: n // 2])
+    with pytest.raises(ValueError):
+        first.concat_samples(other)
+
+
+def test_moment_convergence():
+    ns = 5
+    d = 2
+    n = 20000
+    window = 1000
+
+    # rare large spikes: the mean converges much faster than the kurtosis
+    traces = np.random.randint(-100, 100, (n, ns)) + 10000
+    traces += 3000 * (np.random.randint(0, 100, (n, ns)) == 0)
+    traces = traces.astype(np.int16)
+    labels = np.random.randint(0, 2, n, dtype=np.uint16)
+    ttest = Ttest(ns, d)
+    ttest.set_convergence_window(window)
+    ttest.fit_u(traces[:window], labels[:window])
+    assert np.all(np.isnan(ttest.get_moment_convergence()))
+    ttest.fit_u(traces[window:], labels[window:])
+    convergence = ttest.get_moment_convergence()
+    assert convergence.shape == (2 * d,)
+    assert convergence[0] < 1e-3
+    assert convergence[0] < convergence[3]
+
+
+def test_variance_floor():
+    ns = 4
+    d = 1
+    n = 500
+
+    traces = np.random.randint(-1000, 1000, (n, ns), dtype=np.int16)
+    labels = np.zeros(n, dtype=np.uint16)
+    # set 1 only has 4 traces, with a tiny variance for sample 2
+    labels[:4] = 1
+    traces[:4, 2] = [0, 0, 0, 1]
+    traces[4:, 2] = 2
+    ttest = Ttest(ns, d)
+    ttest.fit_u(traces, labels)
+    assert abs(ttest.get_ttest()[0, 2]) > 4.5
+
+    floored = Ttest(ns, d, variance_floor=100.0)
+    floored.fit_u(traces, labels)
+    assert abs(floored.get_ttest()[0, 2]) < 1.0
+    assert np.allclose(floored.get_ttest()[:, 0], ttest.get_ttest()[:, 0])